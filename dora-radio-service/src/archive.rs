@@ -0,0 +1,137 @@
+// Directory archives for downlink
+//
+// Fetching a whole log directory one file at a time costs a request per file.  Instead
+// the directory can be packed into a gzipped tar archive in the staging area, which
+// is then downlinked like any other chunked download.  Only regular files and
+// symlinks go into the archive, selected by glob patterns on their path relative to
+// the directory and by modification time.  Symlinks are stored as links, never
+// followed.
+//
+// Files still being written to, such as live logs, are archived as they were when
+// they were first looked at: anything appended while they are being read is left
+// out, and if they shrink the rest is filled with zeros, so the archive stays valid.
+
+use crate::glob;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tar::{Builder, Header};
+
+// Which files to put in an archive
+pub struct Filter {
+    // Patterns a file's relative path must match one of.  Everything matches if empty.
+    pub include: Vec<String>,
+    // Patterns of relative paths to leave out
+    pub exclude: Vec<String>,
+    // Only files modified at or after this time, in seconds since the epoch
+    pub modified_after: Option<i64>,
+    // Only files modified before this time
+    pub modified_before: Option<i64>,
+}
+
+impl Filter {
+
+    fn matches(&self, path: &str, mtime: i64) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| glob::matches(pattern, path)))
+            && !self.exclude.iter().any(|pattern| glob::matches(pattern, path))
+            && self.modified_after.map_or(true, |after| mtime >= after)
+            && self.modified_before.map_or(true, |before| mtime < before)
+    }
+}
+
+// Pack the files in `source` that pass the filter into a gzipped tar archive at
+// `dest`.  Entries are named after the directory, e.g. "log/messages" for
+// /var/log/messages.  Returns the number of files archived.
+pub fn create(source: &Path, dest: &Path, filter: &Filter) -> Result<usize, String> {
+    if !fs::metadata(source).map(|meta| meta.is_dir()).unwrap_or(false) {
+        return Err(format!("{} is not a directory", source.display()));
+    }
+    let base = source
+        .file_name()
+        .map(|name| Path::new(name).to_path_buf())
+        .unwrap_or_default();
+
+    let file = File::create(dest).map_err(|err| format!("Failed to create archive: {}", err))?;
+    let mut builder = Builder::new(GzEncoder::new(file, Compression::default()));
+    builder.follow_symlinks(false);
+
+    let mut count = 0;
+    add_dir(&mut builder, source, &base, Path::new(""), filter, &mut count)?;
+
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|file| file.sync_all())
+        .map_err(|err| format!("Failed to write archive: {}", err))?;
+    Ok(count)
+}
+
+fn add_dir(
+    builder: &mut Builder<GzEncoder<File>>,
+    dir: &Path,
+    base: &Path,
+    relative: &Path,
+    filter: &Filter,
+    count: &mut usize,
+) -> Result<(), String> {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .map_err(|err| format!("Failed to read directory {}: {}", dir.display(), err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        let name = match path.file_name() {
+            Some(name) => relative.join(name),
+            None => continue,
+        };
+        // Files can disappear while we're archiving them
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+
+        if meta.is_dir() {
+            add_dir(builder, &path, base, &name, filter, count)?;
+            continue;
+        }
+        if !(meta.file_type().is_file() || meta.file_type().is_symlink())
+            || !filter.matches(&name.to_string_lossy(), meta.mtime())
+        {
+            continue;
+        }
+
+        let result = if meta.is_file() {
+            add_file(builder, &path, &base.join(&name), &meta)
+        } else {
+            builder.append_path_with_name(&path, base.join(&name))
+        };
+        match result {
+            Ok(()) => *count += 1,
+            // Gone since it was listed
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(format!("Failed to archive {}: {}", path.display(), err)),
+        }
+    }
+
+    Ok(())
+}
+
+// Add exactly as many bytes as the header promises, however the file changes meanwhile
+fn add_file(
+    builder: &mut Builder<GzEncoder<File>>,
+    path: &Path,
+    name: &Path,
+    meta: &fs::Metadata,
+) -> io::Result<()> {
+    let file = File::open(path)?;
+    let size = meta.len();
+
+    let mut header = Header::new_gnu();
+    header.set_metadata(meta);
+    let data = file.take(size).chain(io::repeat(0)).take(size);
+    builder.append_data(&mut header, name, data)
+}
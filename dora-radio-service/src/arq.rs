@@ -0,0 +1,671 @@
+// Selective-repeat ARQ between the comms service and the framing
+//
+// With ARQ on, every packet on the link starts with a one-byte type:
+//
+//     DATA   0xA0 | seq (u16, big-endian) | packet
+//     ACK    0xA1 | seq | seq | ...        sequence numbers received
+//     NACK   0xA2 | seq | seq | ...        sequence numbers missing, to resend now
+//     RESET  0xA3                          the sender has started again from seq 0
+//     RESET ACK  0xA4                      the RESET was received
+//     SKIP   0xA5 | seq                    the sender's window now starts at seq
+//
+// Downlinked packets are sent as DATA with consecutive sequence numbers, at most
+// `window` of them unacknowledged at once; the rest wait in a queue.  A packet that
+// isn't acknowledged within the retransmit timeout is sent again, up to `max_retries`
+// times, after which it is abandoned so the window can move on.  A NACK from the
+// ground has the packets it names resent straight away.
+//
+// Once the window has moved past an abandoned packet, a SKIP tells the other end not
+// to wait for it any longer.  SKIP isn't acknowledged, so it goes again whenever a
+// NACK names a packet from before the window.
+//
+// The ground sends its own packets the same way.  Each DATA packet received in the
+// receive window is acknowledged at once, and packets are handed on in sequence order
+// with duplicates dropped; one that arrives early is held until the gap before it is
+// filled, and a NACK goes down for the missing ones.  Packets from the window before
+// it were already handed on, so they are acknowledged again and dropped.  Anything
+// else is from beyond the window, which means we're still waiting on a packet the
+// ground has given up on: it isn't acknowledged, and the NACK for the packet we're
+// waiting on draws a SKIP.  On a SKIP, held packets before the new start are handed
+// on with the gaps left out.
+//
+// Either end sends RESET when it starts a new session from sequence number 0, and the
+// other end answers with RESET ACK.  The service sends one every time it starts, and
+// again each retransmit timeout until it is answered, since the ground would
+// otherwise take the new packets for old duplicates; nothing else is sent until
+// then.
+//
+// ACK, NACK and SKIP packets are never acknowledged or retransmitted on a timer: a
+// lost ACK just means a retransmission, which is acknowledged again.  With uplink
+// authentication on, the trailer goes on the whole ARQ packet and is checked before
+// it gets here (see radio.rs), so forged ARQ packets never reach the sequencing.
+
+use crate::config::ArqConfig;
+use log::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+const DATA: u8 = 0xA0;
+const ACK: u8 = 0xA1;
+const NACK: u8 = 0xA2;
+const RESET: u8 = 0xA3;
+const RESET_ACK: u8 = 0xA4;
+const SKIP: u8 = 0xA5;
+// Bytes added to each packet by the DATA header
+pub const HEADER_LEN: usize = 3;
+// Most sequence numbers put in one NACK
+const MAX_NACKS: usize = 32;
+// A SKIP further ahead than this is an old one arriving late
+const MAX_SKIP: u16 = 0x8000;
+
+// Running totals, copied into the link telemetry
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArqStats {
+    // Downlinked packets sent again after a timeout or NACK
+    pub retransmitted: usize,
+    // Downlinked packets acknowledged by the ground
+    pub acked: usize,
+    // Downlinked packets given up on after max_retries
+    pub abandoned: usize,
+    // Uplinked packets received again after they had already been handed on
+    pub duplicates: usize,
+}
+
+// What came of a received packet
+#[derive(Debug, Default)]
+pub struct Received {
+    // Uplinked packets to hand on, in order
+    pub packets: Vec<Vec<u8>>,
+    // ACK, NACK, SKIP or RESET ACK packets to send back
+    pub replies: Vec<Vec<u8>>,
+}
+
+// A downlinked packet waiting for its ACK
+struct Slot {
+    seq: u16,
+    packet: Vec<u8>,
+    // None when it is due to be sent (again) straight away
+    sent: Option<Instant>,
+    attempts: u32,
+    acked: bool,
+    abandoned: bool,
+}
+
+pub struct Arq {
+    config: ArqConfig,
+    // Sender: packets waiting for room in the window, then the window itself, oldest
+    // first.  `next_seq` is the sequence number of the next packet into the window.
+    queue: VecDeque<Vec<u8>>,
+    window: VecDeque<Slot>,
+    next_seq: u16,
+    // Sender: whether our RESET is still waiting for its RESET ACK, and when it was
+    // last sent
+    reset_pending: bool,
+    reset_sent: Option<Instant>,
+    // Sender: whether the window has moved past an abandoned packet since the last SKIP
+    skip_pending: bool,
+    // Receiver: the next sequence number to hand on, and packets held until then,
+    // keyed by how far ahead of it they are
+    expected: u16,
+    held: BTreeMap<u16, Vec<u8>>,
+    stats: ArqStats,
+}
+
+impl Arq {
+
+    pub fn new(config: &ArqConfig) -> Arq {
+        Arq {
+            config: config.clone(),
+            queue: VecDeque::new(),
+            window: VecDeque::new(),
+            next_seq: 0,
+            reset_pending: true,
+            reset_sent: None,
+            skip_pending: false,
+            expected: 0,
+            held: BTreeMap::new(),
+            stats: ArqStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ArqStats {
+        self.stats
+    }
+
+    // Queue a packet for the downlink.  It goes out on the next call to `transmit`.
+    pub fn queue(&mut self, packet: Vec<u8>) -> Result<(), String> {
+        if self.queue.len() >= self.config.max_queue {
+            return Err(format!("ARQ queue is full ({} packets)", self.config.max_queue));
+        }
+        self.queue.push_back(packet);
+        Ok(())
+    }
+
+    // Packets to send now: queued packets that fit in the window, and anything whose
+    // timer has run out or that was NACKed.  Packets out of retries are abandoned.
+    pub fn transmit(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+
+        // Nothing goes out until the ground knows we've started again
+        if self.reset_pending {
+            let due = match self.reset_sent {
+                Some(sent) => now.duration_since(sent) >= self.config.timeout,
+                None => true,
+            };
+            if due {
+                debug!("Sending ARQ reset");
+                self.reset_sent = Some(now);
+                out.push(control(RESET, &[]));
+            }
+            return out;
+        }
+
+        for slot in self.window.iter_mut().filter(|slot| !slot.acked) {
+            let due = match slot.sent {
+                Some(sent) => now.duration_since(sent) >= self.config.timeout,
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+            if slot.attempts > self.config.max_retries {
+                warn!("Abandoning downlink packet {} after {} attempts", slot.seq, slot.attempts);
+                slot.acked = true;
+                slot.abandoned = true;
+                self.stats.abandoned += 1;
+                continue;
+            }
+            debug!("Retransmitting downlink packet {}", slot.seq);
+            self.stats.retransmitted += 1;
+            slot.sent = Some(now);
+            slot.attempts += 1;
+            out.push(data(slot.seq, &slot.packet));
+        }
+
+        self.slide();
+        if self.skip_pending {
+            debug!("Skipping the ground to downlink packet {}", self.base());
+            self.skip_pending = false;
+            out.push(control(SKIP, &[self.base()]));
+        }
+        while self.window.len() < self.config.window {
+            let packet = match self.queue.pop_front() {
+                Some(packet) => packet,
+                None => break,
+            };
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            out.push(data(seq, &packet));
+            self.window.push_back(Slot {
+                seq,
+                packet,
+                sent: Some(now),
+                attempts: 1,
+                acked: false,
+                abandoned: false,
+            });
+        }
+
+        out
+    }
+
+    // When `transmit` next needs to be called, if there is anything in flight or the
+    // reset hasn't been acknowledged
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.reset_pending {
+            return Some(match self.reset_sent {
+                Some(sent) => sent + self.config.timeout,
+                None => Instant::now(),
+            });
+        }
+
+        self.window
+            .iter()
+            .filter(|slot| !slot.acked)
+            .map(|slot| match slot.sent {
+                Some(sent) => sent + self.config.timeout,
+                None => Instant::now(),
+            })
+            .min()
+    }
+
+    // Handle a packet from the link
+    pub fn receive(&mut self, packet: &[u8]) -> Received {
+        let mut received = Received::default();
+        let (kind, body) = match packet.split_first() {
+            Some((kind, body)) => (*kind, body),
+            None => return received,
+        };
+
+        match kind {
+            DATA if body.len() > 2 => {
+                let seq = u16::from_be_bytes([body[0], body[1]]);
+                self.receive_data(seq, body[2..].to_vec(), &mut received);
+            }
+            ACK => {
+                for seq in sequence_numbers(body) {
+                    if let Some(slot) = self.slot(seq) {
+                        if !slot.acked {
+                            slot.acked = true;
+                            self.stats.acked += 1;
+                        }
+                    }
+                }
+                self.slide();
+            }
+            NACK => {
+                let base = self.base();
+                let mut skipped = false;
+                for seq in sequence_numbers(body) {
+                    if let Some(slot) = self.slot(seq) {
+                        if !slot.acked {
+                            slot.sent = None;
+                        }
+                    } else if (1..=MAX_SKIP).contains(&base.wrapping_sub(seq)) && !skipped {
+                        // Given up on already, so the ground needs telling again
+                        skipped = true;
+                        received.replies.push(control(SKIP, &[base]));
+                    }
+                }
+            }
+            RESET => {
+                info!("Ground reset the ARQ uplink sequence");
+                self.expected = 0;
+                self.held.clear();
+                received.replies.push(control(RESET_ACK, &[]));
+            }
+            SKIP if body.len() == 2 => {
+                let seq = u16::from_be_bytes([body[0], body[1]]);
+                self.skip_to(seq, &mut received);
+            }
+            RESET_ACK => {
+                if self.reset_pending {
+                    info!("Ground acknowledged the ARQ downlink reset");
+                    self.reset_pending = false;
+                }
+            }
+            _ => warn!("Dropping malformed ARQ packet ({} bytes, type {:#04x})", packet.len(), kind),
+        }
+
+        received
+    }
+
+    fn receive_data(&mut self, seq: u16, packet: Vec<u8>, received: &mut Received) {
+        // The window before ours was already handed on, and the ground didn't hear our
+        // ACK
+        let behind = self.expected.wrapping_sub(seq);
+        if behind > 0 && behind as usize <= self.config.window {
+            debug!("Dropping duplicate uplink packet {}", seq);
+            self.stats.duplicates += 1;
+            received.replies.push(control(ACK, &[seq]));
+            return;
+        }
+
+        // Beyond our window, so the ground has moved on without the packet we're
+        // waiting for.  Ask for it, which gets us a SKIP if it was abandoned.
+        let ahead = seq.wrapping_sub(self.expected);
+        if ahead as usize >= self.config.window {
+            debug!("Dropping uplink packet {} beyond the window at {}", seq, self.expected);
+            received.replies.push(control(NACK, &[self.expected]));
+            return;
+        }
+
+        received.replies.push(control(ACK, &[seq]));
+        if self.held.contains_key(&ahead) {
+            self.stats.duplicates += 1;
+            return;
+        }
+        self.held.insert(ahead, packet);
+
+        if ahead > 0 {
+            let missing: Vec<u16> = (0..ahead)
+                .filter(|offset| !self.held.contains_key(offset))
+                .take(MAX_NACKS)
+                .map(|offset| self.expected.wrapping_add(offset))
+                .collect();
+            if !missing.is_empty() {
+                received.replies.push(control(NACK, &missing));
+            }
+            return;
+        }
+
+        self.hand_on_in_order(received);
+    }
+
+    // The ground has given up on everything before `seq`: hand on what we were holding
+    // from before then, and carry on from there
+    fn skip_to(&mut self, seq: u16, received: &mut Received) {
+        let ahead = seq.wrapping_sub(self.expected);
+        if ahead == 0 || ahead > MAX_SKIP {
+            return;
+        }
+
+        warn!("Ground gave up on uplink packets {} to {}", self.expected, seq.wrapping_sub(1));
+        let later = self.held.split_off(&ahead);
+        for (_, packet) in std::mem::take(&mut self.held) {
+            received.packets.push(packet);
+        }
+        self.expected = seq;
+        self.held = later
+            .into_iter()
+            .map(|(offset, packet)| (offset - ahead, packet))
+            .collect();
+
+        self.hand_on_in_order(received);
+    }
+
+    // Hand on everything from `expected` that's now in order, and renumber what's left
+    fn hand_on_in_order(&mut self, received: &mut Received) {
+        let mut next = 0;
+        while let Some(packet) = self.held.remove(&next) {
+            received.packets.push(packet);
+            next += 1;
+        }
+        self.expected = self.expected.wrapping_add(next);
+        self.held = std::mem::take(&mut self.held)
+            .into_iter()
+            .map(|(offset, packet)| (offset - next, packet))
+            .collect();
+    }
+
+    fn slot(&mut self, seq: u16) -> Option<&mut Slot> {
+        let base = self.window.front()?.seq;
+        self.window.get_mut(seq.wrapping_sub(base) as usize)
+    }
+
+    // Sequence number at the start of the send window
+    fn base(&self) -> u16 {
+        self.window.front().map(|slot| slot.seq).unwrap_or(self.next_seq)
+    }
+
+    // Drop acknowledged packets off the front of the window
+    fn slide(&mut self) {
+        while self.window.front().map(|slot| slot.acked).unwrap_or(false) {
+            if let Some(slot) = self.window.pop_front() {
+                self.skip_pending |= slot.abandoned;
+            }
+        }
+    }
+}
+
+fn data(seq: u16, packet: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + packet.len());
+    data.push(DATA);
+    data.extend_from_slice(&seq.to_be_bytes());
+    data.extend_from_slice(packet);
+    data
+}
+
+fn control(kind: u8, seqs: &[u16]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    for seq in seqs {
+        packet.extend_from_slice(&seq.to_be_bytes());
+    }
+    packet
+}
+
+fn sequence_numbers(body: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    body.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // An ARQ end whose RESET has already been acknowledged
+    fn arq(window: usize, max_retries: u32) -> Arq {
+        let mut arq = Arq::new(&ArqConfig {
+            enabled: true,
+            window,
+            timeout: TIMEOUT,
+            max_retries,
+            max_queue: 64,
+        });
+        arq.reset_pending = false;
+        arq
+    }
+
+    fn packet(n: u8) -> Vec<u8> {
+        vec![n; 4]
+    }
+
+    fn ack(seqs: &[u16]) -> Vec<u8> {
+        control(ACK, seqs)
+    }
+
+    fn nack(seqs: &[u16]) -> Vec<u8> {
+        control(NACK, seqs)
+    }
+
+    #[test]
+    fn resets_until_acknowledged() {
+        let mut arq = Arq::new(&ArqConfig {
+            enabled: true,
+            window: 4,
+            timeout: TIMEOUT,
+            max_retries: 3,
+            max_queue: 64,
+        });
+        arq.queue(packet(0)).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(arq.transmit(start), vec![control(RESET, &[])]);
+        assert!(arq.transmit(start).is_empty());
+        assert_eq!(arq.transmit(start + TIMEOUT), vec![control(RESET, &[])]);
+
+        arq.receive(&[RESET_ACK]);
+        assert_eq!(arq.transmit(start + TIMEOUT), vec![data(0, &packet(0))]);
+
+        let received = arq.receive(&[RESET]);
+        assert_eq!(received.replies, vec![control(RESET_ACK, &[])]);
+    }
+
+    #[test]
+    fn hands_on_reordered_packets_in_order() {
+        let mut arq = arq(4, 3);
+
+        let received = arq.receive(&data(2, &packet(2)));
+        assert!(received.packets.is_empty());
+        assert_eq!(received.replies, vec![ack(&[2]), nack(&[0, 1])]);
+
+        let received = arq.receive(&data(1, &packet(1)));
+        assert!(received.packets.is_empty());
+        assert_eq!(received.replies, vec![ack(&[1]), nack(&[0])]);
+
+        let received = arq.receive(&data(0, &packet(0)));
+        assert_eq!(received.packets, vec![packet(0), packet(1), packet(2)]);
+        assert_eq!(received.replies, vec![ack(&[0])]);
+        assert_eq!(arq.expected, 3);
+        assert!(arq.held.is_empty());
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut arq = arq(4, 3);
+
+        assert_eq!(arq.receive(&data(0, &packet(0))).packets, vec![packet(0)]);
+        arq.receive(&data(2, &packet(2)));
+
+        // Already handed on, so acknowledged again but not handed on twice
+        let received = arq.receive(&data(0, &packet(0)));
+        assert!(received.packets.is_empty());
+        assert_eq!(received.replies, vec![ack(&[0])]);
+
+        // Already held
+        let received = arq.receive(&data(2, &packet(2)));
+        assert!(received.packets.is_empty());
+        assert_eq!(received.replies, vec![ack(&[2])]);
+
+        assert_eq!(arq.stats().duplicates, 2);
+    }
+
+    #[test]
+    fn never_acknowledges_beyond_the_window() {
+        let mut arq = arq(4, 3);
+
+        // The packet at `expected` must have been given up on, so ask for it
+        let received = arq.receive(&data(4, &packet(4)));
+        assert!(received.packets.is_empty());
+        assert_eq!(received.replies, vec![nack(&[0])]);
+
+        // Nor is anything from long ago taken for a duplicate
+        let received = arq.receive(&data(60000, &packet(0)));
+        assert_eq!(received.replies, vec![nack(&[0])]);
+        assert_eq!(arq.stats().duplicates, 0);
+        assert!(arq.held.is_empty());
+    }
+
+    #[test]
+    fn retransmits_and_resends_nacked() {
+        let mut arq = arq(4, 3);
+        let start = Instant::now();
+        arq.queue(packet(0)).unwrap();
+        arq.queue(packet(1)).unwrap();
+
+        assert_eq!(arq.transmit(start), vec![data(0, &packet(0)), data(1, &packet(1))]);
+        assert!(arq.transmit(start).is_empty());
+        assert_eq!(arq.next_deadline(), Some(start + TIMEOUT));
+
+        arq.receive(&nack(&[1]));
+        assert_eq!(arq.transmit(start), vec![data(1, &packet(1))]);
+
+        arq.receive(&ack(&[1]));
+        assert_eq!(arq.transmit(start + TIMEOUT), vec![data(0, &packet(0))]);
+        arq.receive(&ack(&[0]));
+        assert_eq!(arq.next_deadline(), None);
+
+        assert_eq!(arq.stats().acked, 2);
+        assert_eq!(arq.stats().retransmitted, 2);
+    }
+
+    #[test]
+    fn fills_window_then_queues() {
+        let mut arq = arq(2, 3);
+        let start = Instant::now();
+        for n in 0..3 {
+            arq.queue(packet(n)).unwrap();
+        }
+
+        assert_eq!(arq.transmit(start).len(), 2);
+        arq.receive(&ack(&[0]));
+        assert_eq!(arq.transmit(start), vec![data(2, &packet(2))]);
+    }
+
+    #[test]
+    fn skips_abandoned_packets() {
+        let mut sender = arq(4, 1);
+        let mut receiver = arq(4, 1);
+        let start = Instant::now();
+        for n in 0..3 {
+            sender.queue(packet(n)).unwrap();
+        }
+
+        // Packet 0 is lost every time; 1 and 2 get through and are held
+        let sent = sender.transmit(start);
+        for frame in &sent[1..] {
+            for reply in receiver.receive(frame).replies {
+                sender.receive(&reply);
+            }
+        }
+        assert_eq!(receiver.held.len(), 2);
+
+        assert_eq!(sender.transmit(start + TIMEOUT), vec![data(0, &packet(0))]);
+        let sent = sender.transmit(start + TIMEOUT * 2);
+        assert_eq!(sent, vec![control(SKIP, &[3])]);
+        assert_eq!(sender.stats().abandoned, 1);
+
+        let received = receiver.receive(&sent[0]);
+        assert_eq!(received.packets, vec![packet(1), packet(2)]);
+        assert_eq!(receiver.expected, 3);
+
+        // And the link carries on from there
+        sender.queue(packet(3)).unwrap();
+        let sent = sender.transmit(start + TIMEOUT * 2);
+        assert_eq!(receiver.receive(&sent[0]).packets, vec![packet(3)]);
+    }
+
+    #[test]
+    fn resends_lost_skip_when_nacked() {
+        let mut sender = arq(2, 0);
+        let mut receiver = arq(2, 0);
+        let start = Instant::now();
+        sender.queue(packet(0)).unwrap();
+        sender.queue(packet(1)).unwrap();
+
+        // Packet 0 is abandoned and its SKIP lost
+        sender.transmit(start);
+        sender.receive(&ack(&[1]));
+        assert_eq!(sender.transmit(start + TIMEOUT), vec![control(SKIP, &[2])]);
+
+        // Later packets are beyond the receiver's window, so draw a NACK, not an ACK
+        sender.queue(packet(2)).unwrap();
+        sender.queue(packet(3)).unwrap();
+        let sent = sender.transmit(start + TIMEOUT);
+        let received = receiver.receive(&sent[1]);
+        assert_eq!(received.replies, vec![nack(&[0])]);
+
+        let skip = sender.receive(&received.replies[0]).replies;
+        assert_eq!(skip, vec![control(SKIP, &[2])]);
+        assert!(receiver.receive(&skip[0]).packets.is_empty());
+        assert_eq!(receiver.expected, 2);
+
+        let received = receiver.receive(&sent[1]);
+        assert!(received.packets.is_empty());
+        assert_eq!(received.replies, vec![ack(&[3]), nack(&[2])]);
+        let received = receiver.receive(&sent[0]);
+        assert_eq!(received.packets, vec![packet(2), packet(3)]);
+    }
+
+    #[test]
+    fn ignores_stale_skips() {
+        let mut arq = arq(4, 3);
+        arq.expected = 10;
+
+        assert!(arq.receive(&control(SKIP, &[10])).packets.is_empty());
+        assert!(arq.receive(&control(SKIP, &[5])).packets.is_empty());
+        assert_eq!(arq.expected, 10);
+    }
+
+    #[test]
+    fn wraps_sequence_numbers() {
+        let mut sender = arq(4, 3);
+        let mut receiver = arq(4, 3);
+        sender.next_seq = 65534;
+        receiver.expected = 65534;
+        let start = Instant::now();
+        for n in 0..4 {
+            sender.queue(packet(n)).unwrap();
+        }
+
+        let sent = sender.transmit(start);
+        assert_eq!(sent[2], data(0, &packet(2)));
+
+        // Delivered in order across the wrap, whatever order they arrive in
+        let mut delivered = vec![];
+        for &i in &[3, 1, 2, 0] {
+            let received = receiver.receive(&sent[i]);
+            for reply in received.replies {
+                sender.receive(&reply);
+            }
+            delivered.extend(received.packets);
+        }
+        assert_eq!(delivered, (0..4).map(packet).collect::<Vec<_>>());
+        assert_eq!(receiver.expected, 2);
+        assert!(sender.window.is_empty());
+
+        // Packet 65535 is now in the window before ours, so it's a duplicate
+        let received = receiver.receive(&sent[1]);
+        assert_eq!(received.replies, vec![ack(&[65535])]);
+        assert!(received.packets.is_empty());
+
+        // And a skip across the wrap lands in the right place
+        receiver.expected = 65535;
+        receiver.receive(&data(1, &packet(9)));
+        assert!(receiver.receive(&control(SKIP, &[0])).packets.is_empty());
+        assert_eq!(receiver.receive(&control(SKIP, &[1])).packets, vec![packet(9)]);
+        assert_eq!(receiver.expected, 2);
+    }
+}
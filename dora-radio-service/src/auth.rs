@@ -0,0 +1,388 @@
+// Authentication of uplinked packets
+//
+// The fallback commands give root access to the OBC, so anything arriving over the
+// radio has to prove it came from the ground.  Every uplinked packet carries a
+// trailer after the space packet:
+//
+//     +--------+----------------+----------------------+
+//     | key ID | counter        | tag                  |
+//     | 1 byte | 8 bytes, BE    | 16 bytes             |
+//     +--------+----------------+----------------------+
+//
+// The tag is HMAC-SHA256, truncated to 128 bits, over the key ID, counter and space
+// packet, using the key with that ID.  The counter must be higher than that of any
+// packet accepted before, so a recorded packet can't be played back.  The ground is
+// free to skip counter values; a timestamp works well.
+//
+// So that this holds across restarts without writing to flash for every packet, the
+// counter file holds a reservation: a value some way above the highest counter
+// accepted.  It is only saved again once a packet's counter goes past it.  After a
+// restart everything up to the reservation is refused, so the ground has to jump its
+// counter ahead by up to counter_reserve.  A packet that needs a new reservation is
+// refused if it can't be saved, since accepting it would let it be replayed after a
+// restart.
+//
+// With ARQ on, the trailer goes after the whole ARQ packet instead, so the ARQ header
+// and sequence number are covered by the tag too.
+//
+// Packets that fail any of these checks are dropped before they reach the comms
+// service and counted in the link telemetry.
+
+use crate::checksum::from_hex;
+use crate::config::AuthConfig;
+use crate::ServiceResult;
+use failure::*;
+use hmac::{Hmac, Mac};
+use log::*;
+use nix::unistd::geteuid;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// Sizes of the trailer fields
+const KEY_ID_LEN: usize = 1;
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const TRAILER_LEN: usize = KEY_ID_LEN + COUNTER_LEN + TAG_LEN;
+
+pub struct Authenticator {
+    keys: HashMap<u8, Vec<u8>>,
+    counter_file: PathBuf,
+    counter_reserve: u64,
+    // Highest counter accepted so far
+    counter: u64,
+    // Counter saved to disk, which nothing at or below is accepted after a restart
+    reserved: u64,
+}
+
+impl Authenticator {
+
+    // Load the keys and the last accepted counter
+    pub fn new(config: &AuthConfig) -> ServiceResult<Authenticator> {
+        let keys = load_keys(&config.key_file)?;
+
+        if let Some(dir) = config.counter_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let counter = match fs::read_to_string(&config.counter_file) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|_| {
+                format_err!("Invalid uplink counter file {}", config.counter_file.display())
+            })?,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!("No saved uplink counter, accepting any counter above 0");
+                0
+            }
+            Err(err) => bail!(
+                "Failed to read uplink counter file {}: {}",
+                config.counter_file.display(),
+                err
+            ),
+        };
+
+        info!("Loaded {} uplink key(s), last counter {}", keys.len(), counter);
+
+        Ok(Authenticator {
+            keys,
+            counter_file: config.counter_file.clone(),
+            counter_reserve: config.counter_reserve,
+            counter,
+            reserved: counter,
+        })
+    }
+
+    // Check a packet's trailer and return the packet without it
+    pub fn verify(&mut self, mut packet: Vec<u8>) -> Result<Vec<u8>, String> {
+        if packet.len() <= TRAILER_LEN {
+            return Err(format!("Packet too short to authenticate ({} bytes)", packet.len()));
+        }
+
+        let trailer = packet.split_off(packet.len() - TRAILER_LEN);
+        let key_id = trailer[0];
+        let mut counter_bytes = [0; COUNTER_LEN];
+        counter_bytes.copy_from_slice(&trailer[KEY_ID_LEN..KEY_ID_LEN + COUNTER_LEN]);
+        let counter = u64::from_be_bytes(counter_bytes);
+        let tag = &trailer[KEY_ID_LEN + COUNTER_LEN..];
+
+        let key = self
+            .keys
+            .get(&key_id)
+            .ok_or_else(|| format!("Unknown key ID {}", key_id))?;
+
+        let expected = compute_tag(key, key_id, counter, &packet);
+        if !constant_time_eq(&expected, tag) {
+            return Err(format!("Bad authentication tag (key {}, counter {})", key_id, counter));
+        }
+
+        // Only checked once the tag is good, so a forged packet can't push the counter up
+        if counter <= self.counter {
+            return Err(format!(
+                "Replayed packet: counter {} is not above {}",
+                counter, self.counter
+            ));
+        }
+
+        if counter > self.reserved {
+            self.save_counter(counter.saturating_add(self.counter_reserve))?;
+        }
+        self.counter = counter;
+        Ok(packet)
+    }
+
+    // Save a new reservation through a temporary file so a reboot mid-write can't lose
+    // it.  If that fails the old one is kept.
+    fn save_counter(&mut self, reserved: u64) -> Result<(), String> {
+        let tmp = self.counter_file.with_extension("tmp");
+        let result = File::create(&tmp)
+            .and_then(|mut f| {
+                write!(f, "{}", reserved)?;
+                f.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &self.counter_file));
+
+        match result {
+            Ok(()) => {
+                self.reserved = reserved;
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    "Failed to save uplink counter to {}: {}",
+                    self.counter_file.display(),
+                    err
+                );
+                Err("Failed to save uplink counter".to_owned())
+            }
+        }
+    }
+}
+
+// Truncated HMAC-SHA256 over the key ID, counter and packet
+pub fn compute_tag(key: &[u8], key_id: u8, counter: u64, packet: &[u8]) -> Vec<u8> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+    mac.input(&[key_id]);
+    mac.input(&counter.to_be_bytes());
+    mac.input(packet);
+
+    let mut tag = mac.result().code().to_vec();
+    tag.truncate(TAG_LEN);
+    tag
+}
+
+// Compare two tags without giving away how many leading bytes matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Load the key file.  Each line holds a key ID (0-255) and a hex key, separated by
+// whitespace; blank lines and lines starting with '#' are ignored.  The file must be
+// owned by the user running the service and not accessible to anyone else.
+fn load_keys(path: &Path) -> ServiceResult<HashMap<u8, Vec<u8>>> {
+    let meta = fs::metadata(path)
+        .map_err(|err| format_err!("Failed to read uplink key file {}: {}", path.display(), err))?;
+    if meta.uid() != geteuid().as_raw() {
+        bail!("Uplink key file {} must be owned by the service user", path.display());
+    }
+    if meta.mode() & 0o077 != 0 {
+        bail!(
+            "Uplink key file {} must not be accessible to group or others (mode {:o})",
+            path.display(),
+            meta.mode() & 0o777
+        );
+    }
+
+    let text = fs::read_to_string(path)
+        .map_err(|err| format_err!("Failed to read uplink key file {}: {}", path.display(), err))?;
+
+    let mut keys = HashMap::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let id = fields.next().and_then(|id| id.parse::<u8>().ok());
+        let key = fields.next().and_then(from_hex);
+        match (id, key, fields.next()) {
+            (Some(id), Some(ref key), None) if !key.is_empty() => {
+                if keys.insert(id, key.clone()).is_some() {
+                    bail!("Uplink key file {}: duplicate key ID {}", path.display(), id);
+                }
+            }
+            _ => bail!(
+                "Uplink key file {}: line {} must be '<key ID> <hex key>'",
+                path.display(),
+                num + 1
+            ),
+        }
+    }
+
+    if keys.is_empty() {
+        bail!("Uplink key file {} contains no keys", path.display());
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const KEY: &[u8] = b"0123456789abcdef";
+
+    // A fresh directory for each test's key and counter files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("dora-auth-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_keys(dir: &Path, text: &str) -> PathBuf {
+        let path = dir.join("keys");
+        fs::write(&path, text).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        path
+    }
+
+    fn authenticator(dir: &Path) -> Authenticator {
+        let config = AuthConfig {
+            enabled: true,
+            key_file: write_keys(dir, "# ground key\n7 30313233343536373839616263646566\n"),
+            counter_file: dir.join("counter"),
+            counter_reserve: 100,
+        };
+        Authenticator::new(&config).unwrap()
+    }
+
+    fn signed(key_id: u8, counter: u64, packet: &[u8]) -> Vec<u8> {
+        let mut signed = packet.to_vec();
+        signed.push(key_id);
+        signed.extend_from_slice(&counter.to_be_bytes());
+        signed.extend(compute_tag(KEY, key_id, counter, packet));
+        signed
+    }
+
+    #[test]
+    fn strips_good_trailer() {
+        let dir = test_dir("good");
+        let mut auth = authenticator(&dir);
+
+        assert_eq!(auth.verify(signed(7, 1, b"packet")), Ok(b"packet".to_vec()));
+        assert_eq!(
+            auth.verify(signed(7, 2, b"")),
+            Err("Packet too short to authenticate (25 bytes)".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_tags() {
+        let dir = test_dir("tags");
+        let mut auth = authenticator(&dir);
+
+        let mut corrupted = signed(7, 1, b"packet");
+        corrupted[0] ^= 0x01;
+        assert!(auth.verify(corrupted).is_err());
+
+        let mut bad_tag = signed(7, 2, b"packet");
+        *bad_tag.last_mut().unwrap() ^= 0x80;
+        assert!(auth.verify(bad_tag).is_err());
+
+        // Changing the counter invalidates the tag too
+        let mut bumped = signed(7, 3, b"packet");
+        bumped[6 + KEY_ID_LEN + COUNTER_LEN - 1] = 4;
+        assert!(auth.verify(bumped).is_err());
+
+        assert_eq!(
+            auth.verify(signed(8, 5, b"packet")),
+            Err("Unknown key ID 8".to_owned())
+        );
+        assert!(auth.verify(b"short".to_vec()).is_err());
+
+        // Failures don't move the counter, so the next good packet still gets in
+        assert!(auth.verify(signed(7, 1, b"packet")).is_ok());
+    }
+
+    #[test]
+    fn rejects_replays_across_restarts() {
+        let dir = test_dir("replay");
+        let mut auth = authenticator(&dir);
+
+        assert!(auth.verify(signed(7, 10, b"a")).is_ok());
+        assert!(auth.verify(signed(7, 10, b"a")).is_err());
+        assert!(auth.verify(signed(7, 9, b"b")).is_err());
+        assert!(auth.verify(signed(7, 20, b"c")).is_ok());
+
+        // Everything up to the reservation is refused after a restart
+        let mut restarted = authenticator(&dir);
+        assert!(restarted.verify(signed(7, 20, b"c")).is_err());
+        assert!(restarted.verify(signed(7, 110, b"d")).is_err());
+        assert!(restarted.verify(signed(7, 111, b"d")).is_ok());
+    }
+
+    #[test]
+    fn saves_only_when_reservation_runs_out() {
+        let dir = test_dir("reserve");
+        let mut auth = authenticator(&dir);
+        let saved = || fs::read_to_string(dir.join("counter")).unwrap();
+
+        assert!(auth.verify(signed(7, 5, b"a")).is_ok());
+        assert_eq!(saved(), "105");
+
+        fs::remove_file(dir.join("counter")).unwrap();
+        for counter in 6..=105 {
+            assert!(auth.verify(signed(7, counter, b"b")).is_ok());
+        }
+        assert!(!dir.join("counter").exists());
+
+        assert!(auth.verify(signed(7, 106, b"c")).is_ok());
+        assert_eq!(saved(), "206");
+
+        assert!(auth.verify(signed(7, u64::MAX, b"d")).is_ok());
+        assert_eq!(saved(), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn rejects_packets_when_counter_cannot_be_saved() {
+        let dir = test_dir("unwritable");
+        let mut auth = authenticator(&dir);
+
+        // A directory where the temporary file goes makes the save fail, even as root
+        fs::create_dir(dir.join("counter.tmp")).unwrap();
+        assert_eq!(
+            auth.verify(signed(7, 5, b"a")),
+            Err("Failed to save uplink counter".to_owned())
+        );
+        assert!(!dir.join("counter").exists());
+
+        // Nothing was accepted, so the same packet gets in once the save works
+        fs::remove_dir(dir.join("counter.tmp")).unwrap();
+        assert!(auth.verify(signed(7, 5, b"a")).is_ok());
+        assert_eq!(fs::read_to_string(dir.join("counter")).unwrap(), "105");
+    }
+
+    #[test]
+    fn parses_key_file() {
+        let dir = test_dir("keys");
+
+        let keys = load_keys(&write_keys(&dir, "\n# comment\n1 00ff\n  2\tABCD  \n")).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[&1], vec![0x00, 0xFF]);
+        assert_eq!(keys[&2], vec![0xAB, 0xCD]);
+
+        for bad in &["1 00ff\n1 abcd\n", "1 0\n", "256 00ff\n", "1 00ff extra\n", "1\n", "# none\n"] {
+            assert!(load_keys(&write_keys(&dir, bad)).is_err(), "accepted {:?}", bad);
+        }
+
+        let path = write_keys(&dir, "1 00ff\n");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(load_keys(&path).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+// AX.25 UI frames over KISS, for amateur-band testing through a standard TNC
+//
+// In "ax25" framing mode each packet is sent as the information field of an AX.25
+// UI frame (control 0x03, PID 0xF0, no layer 3) from the configured source callsign
+// to the configured destination callsign.  The TNC on the other end of the serial
+// link does the HDLC flags, bit stuffing and FCS, so the frames are passed to it in
+// KISS data frames:
+//
+//     FEND | port << 4 | escaped(AX.25 address, control, PID, packet) | FEND
+//
+// FEND (0xC0) and FESC (0xDB) inside a frame are sent as FESC TFEND and FESC TFESC.
+// On receive, only UI frames addressed to our own (source) callsign are passed on;
+// anything else heard on the channel is someone else's traffic and is dropped.
+// Digipeater addresses in received frames are skipped over.
+
+use crate::config::Ax25Config;
+use log::*;
+use std::collections::VecDeque;
+use std::fmt;
+
+const FEND: u8 = 0xC0;
+const FESC: u8 = 0xDB;
+const TFEND: u8 = 0xDC;
+const TFESC: u8 = 0xDD;
+// Low nibble of the KISS command byte for a data frame
+const KISS_DATA: u8 = 0x00;
+
+const ADDRESS_LEN: usize = 7;
+const CONTROL_UI: u8 = 0x03;
+const PID_NONE: u8 = 0xF0;
+// Most addresses in a frame: destination, source and up to 8 digipeaters
+const MAX_ADDRESSES: usize = 10;
+
+// A callsign and SSID, e.g. "N0CALL-1"
+#[derive(Clone, Debug, PartialEq)]
+pub struct Address {
+    pub callsign: String,
+    pub ssid: u8,
+}
+
+impl Address {
+
+    pub fn parse(text: &str) -> Result<Address, String> {
+        let mut parts = text.splitn(2, '-');
+        let callsign = parts.next().unwrap_or("").to_uppercase();
+        let ssid = match parts.next() {
+            Some(ssid) => ssid.parse::<u8>().ok().filter(|ssid| *ssid <= 15),
+            None => Some(0),
+        };
+
+        match ssid {
+            Some(ssid)
+                if !callsign.is_empty()
+                    && callsign.len() <= 6
+                    && callsign.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                Ok(Address { callsign, ssid })
+            }
+            _ => Err(format!("'{}' is not a callsign with an optional SSID from 0 to 15", text)),
+        }
+    }
+
+    // The 7-byte address field.  `flag` is the C bit (or H bit for a digipeater),
+    // and `last` marks the end of the address list.
+    fn encode(&self, flag: bool, last: bool) -> [u8; ADDRESS_LEN] {
+        let mut field = [b' ' << 1; ADDRESS_LEN];
+        for (byte, c) in field.iter_mut().zip(self.callsign.bytes()) {
+            *byte = c << 1;
+        }
+        field[6] = 0x60 | (self.ssid << 1);
+        if flag {
+            field[6] |= 0x80;
+        }
+        if last {
+            field[6] |= 1;
+        }
+        field
+    }
+
+    fn decode(field: &[u8]) -> Address {
+        let callsign = field[..6]
+            .iter()
+            .map(|byte| (byte >> 1) as char)
+            .collect::<String>()
+            .trim_end()
+            .to_owned();
+        Address {
+            callsign,
+            ssid: (field[6] >> 1) & 0x0F,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.callsign, self.ssid)
+    }
+}
+
+// Transmit side: wraps packets in UI frames and KISS
+#[derive(Clone)]
+pub struct Framer {
+    // Address, control and PID fields, the same for every frame
+    header: Vec<u8>,
+    port: u8,
+}
+
+impl Framer {
+
+    pub fn new(config: &Ax25Config) -> Framer {
+        let mut header = Vec::with_capacity(2 * ADDRESS_LEN + 2);
+        // A command frame: C bit set in the destination, clear in the source
+        header.extend_from_slice(&config.destination.encode(true, false));
+        header.extend_from_slice(&config.source.encode(false, true));
+        header.push(CONTROL_UI);
+        header.push(PID_NONE);
+
+        Framer {
+            header,
+            port: config.kiss_port,
+        }
+    }
+
+    pub fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.header.len() + packet.len() + 8);
+        frame.push(FEND);
+        frame.push(self.port << 4 | KISS_DATA);
+        for byte in self.header.iter().chain(packet) {
+            match *byte {
+                FEND => frame.extend_from_slice(&[FESC, TFEND]),
+                FESC => frame.extend_from_slice(&[FESC, TFESC]),
+                byte => frame.push(byte),
+            }
+        }
+        frame.push(FEND);
+        frame
+    }
+}
+
+// Receive side: pulls UI frames for us out of the KISS stream
+//
+// Works like the HDLC deframer: bytes go in with `push`, and packets come out of
+// `next_frame`.  Malformed frames are counted as bad frames.
+pub struct Deframer {
+    address: Address,
+    port: u8,
+    max_len: usize,
+    buffer: Vec<u8>,
+    escaped: bool,
+    discarding: bool,
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Deframer {
+
+    // `max_len` is the largest packet that will be accepted
+    pub fn new(config: &Ax25Config, max_len: usize) -> Deframer {
+        Deframer {
+            address: config.source.clone(),
+            port: config.kiss_port,
+            max_len,
+            buffer: vec![],
+            escaped: false,
+            discarding: false,
+            frames: VecDeque::new(),
+        }
+    }
+
+    // Process newly received bytes.  Returns the number of bad frames found.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let mut bad = 0;
+
+        for &byte in data {
+            if byte == FEND {
+                if !self.discarding && !self.buffer.is_empty() && !self.check() {
+                    bad += 1;
+                }
+                self.buffer.clear();
+                self.escaped = false;
+                self.discarding = false;
+                continue;
+            }
+
+            if self.discarding {
+                continue;
+            }
+
+            let byte = if self.escaped {
+                self.escaped = false;
+                match byte {
+                    TFEND => FEND,
+                    TFESC => FESC,
+                    _ => {
+                        bad += 1;
+                        self.discarding = true;
+                        continue;
+                    }
+                }
+            } else if byte == FESC {
+                self.escaped = true;
+                continue;
+            } else {
+                byte
+            };
+
+            self.buffer.push(byte);
+            if self.buffer.len() > 1 + MAX_ADDRESSES * ADDRESS_LEN + 2 + self.max_len {
+                bad += 1;
+                self.buffer.clear();
+                self.discarding = true;
+            }
+        }
+
+        bad
+    }
+
+    // Take the next packet, if any
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    // Handle the KISS frame in the buffer.  Returns false if it was malformed.
+    fn check(&mut self) -> bool {
+        // Other KISS commands and other ports aren't ours to handle
+        let command = self.buffer[0];
+        if command & 0x0F != KISS_DATA || command >> 4 != self.port {
+            return true;
+        }
+
+        let frame = &self.buffer[1..];
+        let mut addresses = 0;
+        loop {
+            let end = (addresses + 1) * ADDRESS_LEN;
+            if end > frame.len() || addresses == MAX_ADDRESSES {
+                return false;
+            }
+            addresses += 1;
+            if frame[end - 1] & 1 != 0 {
+                break;
+            }
+        }
+        if addresses < 2 {
+            return false;
+        }
+
+        let fields = addresses * ADDRESS_LEN;
+        if frame.len() < fields + 2 {
+            return false;
+        }
+        if frame[fields] != CONTROL_UI || frame[fields + 1] != PID_NONE {
+            debug!("Ignoring AX.25 frame that isn't a UI frame with no layer 3");
+            return true;
+        }
+
+        let destination = Address::decode(&frame[..ADDRESS_LEN]);
+        if destination != self.address {
+            debug!("Ignoring AX.25 frame for {}", destination);
+            return true;
+        }
+
+        let packet = frame[fields + 2..].to_vec();
+        if !packet.is_empty() {
+            self.frames.push_back(packet);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(source: &str, destination: &str) -> Ax25Config {
+        Ax25Config {
+            source: Address::parse(source).unwrap(),
+            destination: Address::parse(destination).unwrap(),
+            kiss_port: 0,
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let address = Address::parse("n0call-7").unwrap();
+        assert_eq!(address, Address { callsign: "N0CALL".to_owned(), ssid: 7 });
+        assert_eq!(address.to_string(), "N0CALL-7");
+        assert_eq!(Address::parse("AB1").unwrap().ssid, 0);
+        for bad in &["", "-1", "TOOLONG", "AB-16", "AB-x", "A.B"] {
+            assert!(Address::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn encodes_addresses() {
+        let address = Address::parse("AB1-2").unwrap();
+        assert_eq!(address.encode(true, false), [0x82, 0x84, 0x62, 0x40, 0x40, 0x40, 0xE4]);
+        assert_eq!(address.encode(false, true), [0x82, 0x84, 0x62, 0x40, 0x40, 0x40, 0x65]);
+        assert_eq!(Address::decode(&address.encode(true, true)), address);
+    }
+
+    #[test]
+    fn kiss_round_trip() {
+        let packet = vec![0x01, FEND, 0x02, FESC, 0x03];
+        let frame = Framer::new(&config("GROUND", "SAT-1")).encode(&packet);
+        assert_eq!(frame[..2], [FEND, 0x00]);
+        assert_eq!(frame[frame.len() - 8..], [0x01, FESC, TFEND, 0x02, FESC, TFESC, 0x03, FEND]);
+
+        let mut deframer = Deframer::new(&config("SAT-1", "GROUND"), 256);
+        for byte in &frame {
+            assert_eq!(deframer.push(&[*byte]), 0);
+        }
+        assert_eq!(deframer.next_frame(), Some(packet));
+        assert_eq!(deframer.next_frame(), None);
+    }
+
+    #[test]
+    fn filters_frames() {
+        let ours = config("SAT-1", "GROUND");
+        let mut deframer = Deframer::new(&ours, 256);
+
+        // Someone else's traffic is ignored without counting as bad
+        let other = Framer::new(&config("GROUND", "OTHER")).encode(b"not ours");
+        assert_eq!(deframer.push(&other), 0);
+        assert_eq!(deframer.next_frame(), None);
+
+        // Through a digipeater
+        let mut frame = vec![FEND, 0x00];
+        frame.extend_from_slice(&ours.source.encode(true, false));
+        frame.extend_from_slice(&ours.destination.encode(false, false));
+        frame.extend_from_slice(&Address::parse("RELAY").unwrap().encode(true, true));
+        frame.extend_from_slice(&[CONTROL_UI, PID_NONE, b'h', b'i', FEND]);
+        assert_eq!(deframer.push(&frame), 0);
+        assert_eq!(deframer.next_frame(), Some(b"hi".to_vec()));
+
+        // A bad escape, and a frame with only one address
+        let mut bad = vec![FEND, 0x00, FESC, 0x01, FEND, FEND, 0x00];
+        bad.extend_from_slice(&ours.source.encode(true, true));
+        bad.extend_from_slice(&[CONTROL_UI, PID_NONE, b'x', FEND]);
+        assert_eq!(deframer.push(&bad), 2);
+        assert_eq!(deframer.next_frame(), None);
+    }
+}
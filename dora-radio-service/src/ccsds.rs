@@ -0,0 +1,647 @@
+// CCSDS transfer frames for the radio link
+//
+// In "ccsds" framing mode the link carries standard CCSDS frames instead of HDLC, so
+// off-the-shelf ground station software can talk to the service directly.
+//
+// Downlink: TM Transfer Frames (CCSDS 132.0-B), all of the configured fixed length,
+// each after an attached sync marker or in its own RS code block (see fec.rs):
+//
+//     +-----------------------------------------+---------------------+------+
+//     | primary header (6 bytes)                | data field          | FECF |
+//     | ver | SCID | VCID | OCF | MC# | VC# | status (FHP)          | (opt)|
+//     +-----------------------------------------+---------------------+------+
+//
+// Space Packets are multiplexed onto virtual channels 0-6 by APID.  Each virtual
+// channel is a continuous stream of packets cut into frame-sized pieces, so a packet
+// can be split across several frames, and the first header pointer says where the
+// first packet starting in a frame begins.  Rather than hold a part-filled frame back
+// until the next packet, it is padded out with an idle packet (APID 0x7FF) and sent.
+// When configured, frames with only idle data (VCID 7) are also sent whenever the
+// downlink has been quiet for a while, for radios that need a continuous stream.
+// Every frame carries the master channel frame count and the frame count of its own
+// virtual channel, both modulo 256.
+//
+// Uplink: TC Transfer Frames (CCSDS 232.0-B) in CLTUs (CCSDS 231.0-B):
+//
+//     EB90 | BCH codeblocks (7 data bytes + 1 check byte each) | C5C5C5C5C5C5C579
+//
+// Each codeblock is checked and single bit errors corrected.  The frames in the
+// codeblocks are checked against the spacecraft ID and the FECF, and the segment
+// header after the frame header is used to put packets split over several frames
+// back together (per virtual channel and MAP ID).  Frames carrying COP-1 control
+// commands are ignored, and the frame sequence number isn't checked, since there is
+// no COP-1 on board; every data frame is treated as a bypass (Type-B) frame.
+
+use crate::config::CcsdsConfig;
+use crate::fec::ASM;
+use log::*;
+use std::collections::{HashMap, VecDeque};
+
+const TM_HEADER_LEN: usize = 6;
+const TC_HEADER_LEN: usize = 5;
+const FECF_LEN: usize = 2;
+// First header pointer values with no packet start in the frame
+const FHP_NO_START: u16 = 0x7FF;
+const FHP_IDLE: u16 = 0x7FE;
+pub const IDLE_VCID: u8 = 7;
+const IDLE_APID: u16 = 0x7FF;
+// Smallest space packet: the header and one byte of data
+const MIN_PACKET: usize = 7;
+const IDLE_BYTE: u8 = 0x55;
+
+const CLTU_START: [u8; 2] = [0xEB, 0x90];
+const CLTU_TAIL: [u8; 8] = [0xC5, 0xC5, 0xC5, 0xC5, 0xC5, 0xC5, 0xC5, 0x79];
+const CODEBLOCK_LEN: usize = 8;
+const CODEBLOCK_DATA: usize = 7;
+// BCH(63,56) generator polynomial x^7 + x^6 + x^2 + 1, without the x^7 term
+const BCH_POLY: u8 = 0x45;
+
+// Segment header sequence flags
+const SEG_CONTINUE: u8 = 0;
+const SEG_FIRST: u8 = 1;
+const SEG_LAST: u8 = 2;
+const SEG_WHOLE: u8 = 3;
+
+// CRC-16-CCITT (0x1021 polynomial, initial value 0xFFFF), used for the FECF
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Total length of the space packet at the start of `data`, if its header is there
+fn packet_len(data: &[u8]) -> Option<usize> {
+    if data.len() < TM_HEADER_LEN {
+        return None;
+    }
+    Some(TM_HEADER_LEN + ((usize::from(data[4]) << 8) | usize::from(data[5])) + 1)
+}
+
+// An idle packet of the given total length (at least MIN_PACKET)
+fn idle_packet(len: usize) -> Vec<u8> {
+    let data_len = len - TM_HEADER_LEN - 1;
+    let mut packet = vec![
+        (IDLE_APID >> 8) as u8,
+        (IDLE_APID & 0xFF) as u8,
+        0xC0,
+        0x00,
+        (data_len >> 8) as u8,
+        (data_len & 0xFF) as u8,
+    ];
+    packet.resize(len, IDLE_BYTE);
+    packet
+}
+
+// Packet stream of one virtual channel
+#[derive(Default)]
+struct VirtualChannel {
+    count: u8,
+    // Bytes not yet sent, and where packets start in them
+    buffer: Vec<u8>,
+    starts: VecDeque<usize>,
+}
+
+// Downlink side: turns packets into TM frames
+pub struct TmMux {
+    spacecraft_id: u16,
+    frame_len: usize,
+    fecf: bool,
+    default_vc: u8,
+    vc_apids: HashMap<u16, u8>,
+    mc_count: u8,
+    channels: Vec<VirtualChannel>,
+}
+
+impl TmMux {
+
+    pub fn new(config: &CcsdsConfig) -> TmMux {
+        TmMux {
+            spacecraft_id: config.spacecraft_id,
+            frame_len: config.frame_len,
+            fecf: config.fecf,
+            default_vc: config.default_vc,
+            vc_apids: config.vc_apids.clone(),
+            mc_count: 0,
+            channels: (0..=IDLE_VCID).map(|_| VirtualChannel::default()).collect(),
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        self.frame_len - TM_HEADER_LEN - if self.fecf { FECF_LEN } else { 0 }
+    }
+
+    // The frames that carry a packet, on the virtual channel for its APID.  The last
+    // one is padded out with an idle packet, so nothing is left waiting.
+    pub fn frames(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        let apid = if packet.len() >= 2 {
+            (u16::from(packet[0] & 0x07) << 8) | u16::from(packet[1])
+        } else {
+            IDLE_APID
+        };
+        let vcid = self.vc_apids.get(&apid).cloned().unwrap_or(self.default_vc);
+        let data_len = self.data_len();
+
+        let channel = &mut self.channels[vcid as usize];
+        channel.starts.push_back(channel.buffer.len());
+        channel.buffer.extend_from_slice(packet);
+
+        let room = data_len - channel.buffer.len() % data_len;
+        if room != data_len {
+            // An idle packet can't be shorter than MIN_PACKET, so if there is less room
+            // than that its tail goes at the start of the channel's next frame
+            let idle = if room >= MIN_PACKET { room } else { room + MIN_PACKET };
+            channel.starts.push_back(channel.buffer.len());
+            channel.buffer.extend(idle_packet(idle));
+        }
+
+        let mut frames = vec![];
+        while self.channels[vcid as usize].buffer.len() >= data_len {
+            frames.push(self.frame(vcid));
+        }
+        frames
+    }
+
+    // A frame with nothing but idle data, on the idle virtual channel
+    pub fn idle_frame(&mut self) -> Vec<u8> {
+        let data_len = self.data_len();
+        let channel = &mut self.channels[IDLE_VCID as usize];
+        channel.buffer = vec![IDLE_BYTE; data_len];
+        channel.starts.clear();
+        self.frame(IDLE_VCID)
+    }
+
+    // Take the next frame's worth of data from a virtual channel and frame it
+    fn frame(&mut self, vcid: u8) -> Vec<u8> {
+        let data_len = self.data_len();
+        let channel = &mut self.channels[vcid as usize];
+
+        let pointer = if vcid == IDLE_VCID {
+            FHP_IDLE
+        } else {
+            match channel.starts.front() {
+                Some(&start) if start < data_len => start as u16,
+                _ => FHP_NO_START,
+            }
+        };
+
+        let mut frame = Vec::with_capacity(self.frame_len);
+        frame.push((self.spacecraft_id >> 4) as u8 & 0x3F);
+        frame.push(((self.spacecraft_id & 0x0F) as u8) << 4 | vcid << 1);
+        frame.push(self.mc_count);
+        frame.push(channel.count);
+        // No secondary header, packets in order, segment length ID 0b11
+        frame.push(0x18 | (pointer >> 8) as u8);
+        frame.push((pointer & 0xFF) as u8);
+        frame.extend(channel.buffer.drain(..data_len));
+        if self.fecf {
+            let crc = crc16_ccitt(&frame);
+            frame.push((crc >> 8) as u8);
+            frame.push((crc & 0xFF) as u8);
+        }
+
+        while channel.starts.front().map_or(false, |start| *start < data_len) {
+            channel.starts.pop_front();
+        }
+        for start in channel.starts.iter_mut() {
+            *start -= data_len;
+        }
+
+        channel.count = channel.count.wrapping_add(1);
+        self.mc_count = self.mc_count.wrapping_add(1);
+        frame
+    }
+}
+
+// A TM frame as sent when there is no RS coding: just the sync marker in front
+pub fn attach_sync(frame: Vec<u8>) -> Vec<u8> {
+    let mut out = ASM.to_vec();
+    out.extend(frame);
+    out
+}
+
+// Outcome of processing some received bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TcStats {
+    // BCH codeblocks with a bit error that was corrected
+    pub corrected: usize,
+    // BCH codeblocks with more errors than that, which end their CLTU
+    pub uncorrectable: usize,
+    // TC frames dropped for a bad header, length or FECF
+    pub bad_frames: usize,
+}
+
+// BCH check byte for 7 bytes of data: the 7 parity bits, complemented, and a zero
+// filler bit
+pub fn bch_check(data: &[u8]) -> u8 {
+    let mut reg: u8 = 0;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let feedback = ((reg >> 6) ^ (byte >> bit)) & 1;
+            reg = (reg << 1) & 0x7F;
+            if feedback != 0 {
+                reg ^= BCH_POLY;
+            }
+        }
+    }
+    (!reg & 0x7F) << 1
+}
+
+// Uplink side: pulls TC frames out of CLTUs and packets out of the frames
+pub struct TcDemux {
+    spacecraft_id: u16,
+    fecf: bool,
+    max_packet: usize,
+    // Bit to flip for each BCH syndrome with a single bit error: 0-55 in the data,
+    // 56-62 in the check bits
+    syndromes: HashMap<u8, usize>,
+    // Last two bytes seen while looking for a start sequence
+    window: u16,
+    // Codeblock being collected and the data of the CLTU so far, once started
+    codeblock: Vec<u8>,
+    cltu: Option<Vec<u8>>,
+    // Packets being put back together, by virtual channel and MAP ID
+    segments: HashMap<(u8, u8), Vec<u8>>,
+    packets: VecDeque<Vec<u8>>,
+}
+
+impl TcDemux {
+
+    pub fn new(config: &CcsdsConfig, max_packet: usize) -> TcDemux {
+        let mut syndromes = HashMap::new();
+        for bit in 0..CODEBLOCK_DATA * 8 {
+            let mut data = [0; CODEBLOCK_DATA];
+            data[bit / 8] = 0x80 >> (bit % 8);
+            syndromes.insert((bch_check(&data) ^ bch_check(&[0; CODEBLOCK_DATA])) >> 1, bit);
+        }
+        for bit in 0..7 {
+            syndromes.insert(0x40 >> bit, CODEBLOCK_DATA * 8 + bit);
+        }
+
+        TcDemux {
+            spacecraft_id: config.spacecraft_id,
+            fecf: config.fecf,
+            max_packet,
+            syndromes,
+            window: 0,
+            codeblock: Vec::with_capacity(CODEBLOCK_LEN),
+            cltu: None,
+            segments: HashMap::new(),
+            packets: VecDeque::new(),
+        }
+    }
+
+    // Process newly received bytes
+    pub fn push(&mut self, data: &[u8]) -> TcStats {
+        let mut stats = TcStats::default();
+
+        for &byte in data {
+            if self.cltu.is_none() {
+                self.window = (self.window << 8) | u16::from(byte);
+                if self.window == u16::from_be_bytes(CLTU_START) {
+                    self.cltu = Some(vec![]);
+                    self.codeblock.clear();
+                    self.window = 0;
+                }
+                continue;
+            }
+
+            self.codeblock.push(byte);
+            if self.codeblock.len() < CODEBLOCK_LEN {
+                continue;
+            }
+
+            let mut block = [0; CODEBLOCK_LEN];
+            block.copy_from_slice(&self.codeblock);
+            self.codeblock.clear();
+
+            let ended = if block == CLTU_TAIL {
+                true
+            } else {
+                match self.correct(&mut block) {
+                    Some(corrected) => {
+                        if corrected {
+                            stats.corrected += 1;
+                        }
+                        if let Some(ref mut cltu) = self.cltu {
+                            cltu.extend_from_slice(&block[..CODEBLOCK_DATA]);
+                        }
+                        false
+                    }
+                    None => {
+                        stats.uncorrectable += 1;
+                        true
+                    }
+                }
+            };
+
+            if ended {
+                let cltu = self.cltu.take().unwrap_or_default();
+                stats.bad_frames += self.frames(&cltu);
+            }
+        }
+
+        stats
+    }
+
+    // Take the next complete packet, if any
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        self.packets.pop_front()
+    }
+
+    // Check a codeblock, correcting a single bit error.  Returns whether there was one
+    // to correct, or None if there were more.
+    fn correct(&self, block: &mut [u8; CODEBLOCK_LEN]) -> Option<bool> {
+        let syndrome = (bch_check(&block[..CODEBLOCK_DATA]) ^ block[CODEBLOCK_DATA]) >> 1;
+        if syndrome == 0 {
+            return Some(false);
+        }
+
+        let bit = *self.syndromes.get(&syndrome)?;
+        if bit < CODEBLOCK_DATA * 8 {
+            block[bit / 8] ^= 0x80 >> (bit % 8);
+        } else {
+            block[CODEBLOCK_DATA] ^= 0x80 >> (bit - CODEBLOCK_DATA * 8);
+        }
+        Some(true)
+    }
+
+    // Handle the frames in the data of a CLTU.  Returns the number of bad frames.
+    fn frames(&mut self, mut data: &[u8]) -> usize {
+        let mut bad = 0;
+
+        while data.len() >= TC_HEADER_LEN {
+            // Fill after the last frame (0x55) has the wrong version number
+            if data[0] >> 6 != 0 {
+                break;
+            }
+
+            let len = ((usize::from(data[2]) & 0x03) << 8 | usize::from(data[3])) + 1;
+            if len > data.len() || len < TC_HEADER_LEN + 1 + if self.fecf { FECF_LEN } else { 0 } {
+                warn!("Dropped TC frame with bad length {}", len);
+                bad += 1;
+                break;
+            }
+
+            let (frame, rest) = data.split_at(len);
+            data = rest;
+            if !self.frame(frame) {
+                bad += 1;
+            }
+        }
+
+        bad
+    }
+
+    // Handle one TC frame.  Returns false if it had to be dropped.
+    fn frame(&mut self, frame: &[u8]) -> bool {
+        let spacecraft_id = (u16::from(frame[0]) & 0x03) << 8 | u16::from(frame[1]);
+        if spacecraft_id != self.spacecraft_id {
+            warn!("Dropped TC frame for spacecraft {}", spacecraft_id);
+            return false;
+        }
+
+        let mut body = &frame[TC_HEADER_LEN..];
+        if self.fecf {
+            let split = frame.len() - FECF_LEN;
+            let fecf = u16::from(frame[split]) << 8 | u16::from(frame[split + 1]);
+            if crc16_ccitt(&frame[..split]) != fecf {
+                warn!("Dropped TC frame with bad FECF");
+                return false;
+            }
+            body = &frame[TC_HEADER_LEN..split];
+        }
+
+        if frame[0] & 0x10 != 0 {
+            debug!("Ignoring TC control command frame");
+            return true;
+        }
+
+        let vcid = frame[2] >> 2;
+        let flags = body[0] >> 6;
+        let key = (vcid, body[0] & 0x3F);
+        let segment = &body[1..];
+
+        let unit = match flags {
+            SEG_WHOLE => Some(segment.to_vec()),
+            SEG_FIRST => {
+                if self.segments.insert(key, segment.to_vec()).is_some() {
+                    warn!("Dropped incomplete packet on TC virtual channel {}", vcid);
+                }
+                None
+            }
+            SEG_CONTINUE | SEG_LAST => match self.segments.get_mut(&key) {
+                Some(unit) => {
+                    unit.extend_from_slice(segment);
+                    if flags == SEG_LAST {
+                        self.segments.remove(&key)
+                    } else {
+                        None
+                    }
+                }
+                None => {
+                    warn!("Dropped TC segment with no start on virtual channel {}", vcid);
+                    return false;
+                }
+            },
+            _ => None,
+        };
+
+        if self.segments.get(&key).map_or(false, |unit| unit.len() > self.max_packet) {
+            warn!("Dropped oversized packet on TC virtual channel {}", vcid);
+            self.segments.remove(&key);
+            return false;
+        }
+
+        if let Some(unit) = unit {
+            self.unpack(unit);
+        }
+        true
+    }
+
+    // Queue the space packets in a reassembled segment, which may hold several
+    fn unpack(&mut self, unit: Vec<u8>) {
+        let mut packets = vec![];
+        let mut rest = &unit[..];
+        while let Some(len) = packet_len(rest).filter(|len| *len <= rest.len()) {
+            let (packet, tail) = rest.split_at(len);
+            packets.push(packet.to_vec());
+            rest = tail;
+        }
+
+        if packets.is_empty() || !rest.is_empty() {
+            // Not a sequence of whole packets, so pass it on as it came
+            packets = vec![unit];
+        }
+        self.packets.extend(packets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CcsdsConfig {
+        let mut vc_apids = HashMap::new();
+        vc_apids.insert(5, 2);
+        CcsdsConfig {
+            spacecraft_id: 0x2A5,
+            frame_len: 64,
+            fecf: true,
+            default_vc: 0,
+            vc_apids,
+            idle_interval: None,
+        }
+    }
+
+    fn packet(apid: u16, len: usize) -> Vec<u8> {
+        let data_len = len - TM_HEADER_LEN - 1;
+        let mut packet = vec![
+            0x10 | (apid >> 8) as u8,
+            apid as u8,
+            0xC0,
+            0x00,
+            (data_len >> 8) as u8,
+            data_len as u8,
+        ];
+        packet.extend((0..len - TM_HEADER_LEN).map(|i| i as u8));
+        packet
+    }
+
+    // A TC frame with one segment, and its FECF
+    fn tc_frame(spacecraft_id: u16, vcid: u8, flags: u8, segment: &[u8]) -> Vec<u8> {
+        let len = TC_HEADER_LEN + 1 + segment.len() + FECF_LEN;
+        let mut frame = vec![
+            0x20 | (spacecraft_id >> 8) as u8,
+            spacecraft_id as u8,
+            vcid << 2 | ((len - 1) >> 8) as u8,
+            (len - 1) as u8,
+            0x00,
+            flags << 6,
+        ];
+        frame.extend_from_slice(segment);
+        let crc = crc16_ccitt(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame
+    }
+
+    fn cltu(data: &[u8]) -> Vec<u8> {
+        let mut cltu = CLTU_START.to_vec();
+        for chunk in data.chunks(CODEBLOCK_DATA) {
+            let mut block = chunk.to_vec();
+            block.resize(CODEBLOCK_DATA, 0x55);
+            let check = bch_check(&block);
+            cltu.extend(block);
+            cltu.push(check);
+        }
+        cltu.extend_from_slice(&CLTU_TAIL);
+        cltu
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16_ccitt(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn bch_corrects_single_bits() {
+        assert_eq!(bch_check(&[0; CODEBLOCK_DATA]), 0xFE);
+
+        let demux = TcDemux::new(&config(), 1024);
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE];
+        let mut good = [0; CODEBLOCK_LEN];
+        good[..CODEBLOCK_DATA].copy_from_slice(&data);
+        good[CODEBLOCK_DATA] = bch_check(&data);
+        assert_eq!(demux.correct(&mut good.clone()), Some(false));
+
+        // Every bit but the filler bit at the very end
+        for bit in 0..CODEBLOCK_LEN * 8 - 1 {
+            let mut block = good;
+            block[bit / 8] ^= 0x80 >> (bit % 8);
+            assert_eq!(demux.correct(&mut block), Some(true), "bit {}", bit);
+            assert_eq!(block, good);
+        }
+
+        let mut block = good;
+        block[0] ^= 0x81;
+        assert_eq!(demux.correct(&mut block), None);
+    }
+
+    #[test]
+    fn tc_segments_reassembled() {
+        let config = config();
+        let whole = packet(9, 20);
+        let split = packet(3, 40);
+
+        let mut data = tc_frame(config.spacecraft_id, 1, SEG_FIRST, &split[..15]);
+        data.extend(tc_frame(config.spacecraft_id, 2, SEG_WHOLE, &whole));
+        data.extend(tc_frame(config.spacecraft_id, 1, SEG_CONTINUE, &split[15..30]));
+        data.extend(tc_frame(config.spacecraft_id, 1, SEG_LAST, &split[30..]));
+
+        let mut stream = vec![0x00, 0xEB];
+        stream.extend(cltu(&data));
+        // One bit error along the way
+        stream[20] ^= 0x04;
+
+        let mut demux = TcDemux::new(&config, 1024);
+        let stats = demux.push(&stream);
+        assert_eq!(stats, TcStats { corrected: 1, uncorrectable: 0, bad_frames: 0 });
+        assert_eq!(demux.next_packet(), Some(whole));
+        assert_eq!(demux.next_packet(), Some(split));
+        assert_eq!(demux.next_packet(), None);
+    }
+
+    #[test]
+    fn tc_bad_frames_dropped() {
+        let config = config();
+        let mut data = tc_frame(config.spacecraft_id + 1, 0, SEG_WHOLE, &packet(1, 10));
+        let mut bad_fecf = tc_frame(config.spacecraft_id, 0, SEG_WHOLE, &packet(1, 10));
+        bad_fecf[8] ^= 0xFF;
+        data.extend(bad_fecf);
+        data.extend(tc_frame(config.spacecraft_id, 0, SEG_LAST, &packet(1, 10)));
+
+        let mut demux = TcDemux::new(&config, 1024);
+        assert_eq!(demux.push(&cltu(&data)).bad_frames, 3);
+        assert_eq!(demux.next_packet(), None);
+    }
+
+    #[test]
+    fn tm_frames() {
+        let config = config();
+        let mut mux = TmMux::new(&config);
+        let data_len = config.frame_len - TM_HEADER_LEN - FECF_LEN;
+
+        // Spans three frames.  No packet starts in the middle one, and the last is
+        // filled up with an idle packet.
+        let frames = mux.frames(&packet(5, 2 * data_len + 10));
+        assert_eq!(frames.len(), 3);
+        for (count, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.len(), config.frame_len);
+            let spacecraft_id = u16::from(frame[0] & 0x3F) << 4 | u16::from(frame[1] >> 4);
+            assert_eq!(spacecraft_id, config.spacecraft_id);
+            assert_eq!((frame[1] >> 1) & 0x07, 2);
+            assert_eq!(frame[2] as usize, count);
+            let split = frame.len() - FECF_LEN;
+            assert_eq!(crc16_ccitt(&frame[..split]).to_be_bytes(), frame[split..]);
+        }
+        assert_eq!(u16::from(frames[0][4] & 0x07) << 8 | u16::from(frames[0][5]), 0);
+        assert_eq!(u16::from(frames[1][4] & 0x07) << 8 | u16::from(frames[1][5]), FHP_NO_START);
+        assert_eq!(u16::from(frames[2][4] & 0x07) << 8 | u16::from(frames[2][5]), 10);
+        let idle = &frames[2][TM_HEADER_LEN + 10..];
+        assert_eq!(u16::from(idle[0] & 0x07) << 8 | u16::from(idle[1]), IDLE_APID);
+        assert_eq!(packet_len(idle), Some(data_len - 10));
+
+        let idle = mux.idle_frame();
+        assert_eq!((idle[1] >> 1) & 0x07, IDLE_VCID);
+        assert_eq!(idle[2], 3);
+        assert_eq!(u16::from(idle[4] & 0x07) << 8 | u16::from(idle[5]), FHP_IDLE);
+    }
+}
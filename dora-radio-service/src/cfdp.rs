@@ -0,0 +1,1485 @@
+// CCSDS File Delivery Protocol entity (CCSDS 727.0-B-5), classes 1 and 2
+//
+// CFDP PDUs travel in space packets with their own APID, over the same radio link as
+// the comms service.  The radio I/O thread hands uplinked packets with that APID to
+// the entity thread (see `start`) rather than to the comms service, and the PDUs the
+// entity sends go out in space packets of their own.
+//
+// The ground sends files to us with unacknowledged (class 1) or acknowledged
+// (class 2) transactions, and `put` sends a file the other way.  The Metadata, File
+// Data, EOF, ACK, NAK and Finished PDUs are handled.  NAKs are deferred: a receiver
+// asks for missing data once it has the EOF.  Files are sent with the modular
+// checksum, and the modular or null checksum is accepted.  Segment metadata,
+// filestore requests, prompts, keep-alives and large files aren't supported.
+//
+// Each transaction is saved to a manifest in the staging area, with incoming data
+// kept in a partial file beside it, so transactions carry on across passes and
+// reboots.  When a timer runs out of retries the transaction is suspended rather
+// than abandoned, and resumed as soon as anything is heard from the other entity.
+// Only the inactivity timeout ends a transaction that the other side has gone quiet
+// on.
+
+use crate::ccsds::crc16_ccitt;
+use crate::config::CfdpConfig;
+use crate::radio::RadioHandle;
+use crate::transfer::{add_range, gaps, load_manifests, now, remove_range, save_manifest};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often timers are checked and file data sent
+const TICK: Duration = Duration::from_secs(1);
+
+const VERSION: u8 = 1;
+// Shortest entity ID and sequence number fields sent; longer values use more bytes
+const ENTITY_ID_LEN: usize = 2;
+const SEQUENCE_LEN: usize = 4;
+// Most room a PDU header can take, with 8-byte IDs and sequence number
+pub const MAX_HEADER_LEN: usize = 4 + 3 * 8;
+// Space packet primary header
+const PACKET_HEADER_LEN: usize = 6;
+
+// File directive codes
+const EOF: u8 = 0x04;
+const FINISHED: u8 = 0x05;
+const ACK: u8 = 0x06;
+const METADATA: u8 = 0x07;
+const NAK: u8 = 0x08;
+
+const CHECKSUM_MODULAR: u8 = 0;
+const CHECKSUM_NULL: u8 = 15;
+const TLV_ENTITY_ID: u8 = 0x06;
+
+// File status in a Finished PDU
+const FILE_DISCARDED: u8 = 0;
+const FILE_REJECTED: u8 = 1;
+const FILE_RETAINED: u8 = 2;
+// Transaction status in an ACK PDU
+const STATUS_ACTIVE: u8 = 1;
+const STATUS_TERMINATED: u8 = 2;
+const STATUS_UNRECOGNIZED: u8 = 3;
+
+// Most segment requests put in one NAK
+const MAX_NAK_SEGMENTS: usize = 64;
+// NAK directive code and scope, then 8 bytes per segment request
+const NAK_FIXED_LEN: usize = 9;
+const NAK_SEGMENT_LEN: usize = 8;
+// Metadata directive code, flags, file size and the two name lengths
+const METADATA_FIXED_LEN: usize = 8;
+// Shortest directive body a frame must have room for: a NAK asking for the metadata
+// and one gap, which is longer than any EOF, Finished or ACK we send
+pub const MIN_DIRECTIVE_LEN: usize = NAK_FIXED_LEN + 2 * NAK_SEGMENT_LEN;
+
+// Condition codes
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    NoError,
+    AckLimitReached,
+    KeepAliveLimitReached,
+    InvalidTransmissionMode,
+    FilestoreRejection,
+    FileChecksumFailure,
+    FileSizeError,
+    NakLimitReached,
+    InactivityDetected,
+    InvalidFileStructure,
+    CheckLimitReached,
+    UnsupportedChecksumType,
+    SuspendRequestReceived,
+    CancelRequestReceived,
+}
+
+impl Condition {
+
+    fn code(self) -> u8 {
+        match self {
+            Condition::NoError => 0,
+            Condition::AckLimitReached => 1,
+            Condition::KeepAliveLimitReached => 2,
+            Condition::InvalidTransmissionMode => 3,
+            Condition::FilestoreRejection => 4,
+            Condition::FileChecksumFailure => 5,
+            Condition::FileSizeError => 6,
+            Condition::NakLimitReached => 7,
+            Condition::InactivityDetected => 8,
+            Condition::InvalidFileStructure => 9,
+            Condition::CheckLimitReached => 10,
+            Condition::UnsupportedChecksumType => 11,
+            Condition::SuspendRequestReceived => 14,
+            Condition::CancelRequestReceived => 15,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Condition> {
+        Some(match code {
+            0 => Condition::NoError,
+            1 => Condition::AckLimitReached,
+            2 => Condition::KeepAliveLimitReached,
+            3 => Condition::InvalidTransmissionMode,
+            4 => Condition::FilestoreRejection,
+            5 => Condition::FileChecksumFailure,
+            6 => Condition::FileSizeError,
+            7 => Condition::NakLimitReached,
+            8 => Condition::InactivityDetected,
+            9 => Condition::InvalidFileStructure,
+            10 => Condition::CheckLimitReached,
+            11 => Condition::UnsupportedChecksumType,
+            14 => Condition::SuspendRequestReceived,
+            15 => Condition::CancelRequestReceived,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum State {
+    Active,
+    // Out of retries, waiting to hear from the other entity again
+    Suspended,
+    // Completed with no error
+    Finished,
+    // Completed with a fault
+    Failed,
+    Cancelled,
+}
+
+// Where an active transaction has got to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Phase {
+    // Sender: sending the metadata and file data, then the EOF
+    Sending,
+    // Sender: waiting for the EOF to be acknowledged (class 2)
+    EofSent,
+    // Sender: waiting for the receiver's Finished (class 2)
+    AwaitingFinished,
+    // Receiver: collecting the metadata, file data and EOF
+    Receiving,
+    // Receiver: waiting for the Finished to be acknowledged (class 2)
+    FinishedSent,
+    Done,
+}
+
+// One transaction, as saved in its manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transaction {
+    // "<source entity ID>-<sequence number>"
+    pub id: String,
+    pub source_entity: u64,
+    pub sequence: u64,
+    // The entity at the other end
+    pub peer: u64,
+    pub direction: Direction,
+    // Class 2 rather than class 1
+    pub acknowledged: bool,
+    pub state: State,
+    pub condition: Condition,
+    pub source_file: String,
+    pub destination_file: String,
+    // Unknown to a receiver until the metadata or EOF arrives
+    pub file_size: Option<u64>,
+    pub checksum_type: u8,
+    // Sender: checksum of the file when the transaction started.  Receiver: checksum
+    // from the EOF.
+    pub checksum: Option<u32>,
+    // Receiver: byte ranges received.  Sender: byte ranges NAKed and not yet resent.
+    pub ranges: Vec<(u64, u64)>,
+    // Sender: bytes sent for the first time.  Receiver: bytes received.
+    pub progress: u64,
+    // Whether the metadata and EOF have been sent or received
+    pub metadata: bool,
+    pub eof: bool,
+    phase: Phase,
+    // When the current ACK or NAK timer started, and how many times it has run out
+    timer: u64,
+    attempts: u32,
+    pub started: u64,
+    // Last time anything was heard from the other entity
+    pub updated: u64,
+    pub finished: Option<u64>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Transaction {
+
+    fn is_open(&self) -> bool {
+        self.state == State::Active || self.state == State::Suspended
+    }
+}
+
+// The fixed PDU header
+#[derive(Clone, Copy)]
+struct Header {
+    file_data: bool,
+    toward_sender: bool,
+    acknowledged: bool,
+    source: u64,
+    sequence: u64,
+    destination: u64,
+}
+
+pub struct Entity {
+    config: CfdpConfig,
+    dir: PathBuf,
+    transactions: HashMap<String, Transaction>,
+    next_sequence: u64,
+    // PDUs waiting to be sent
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Entity {
+
+    // Pick up the transactions that were open before a restart
+    pub fn new(config: &CfdpConfig, staging_dir: &Path) -> Entity {
+        let dir = staging_dir.join("cfdp");
+        if let Err(err) = fs::create_dir_all(&dir) {
+            error!("Failed to create CFDP directory {}: {}", dir.display(), err);
+        }
+
+        // The sequence number is kept in its own file so numbers aren't reused once
+        // old transactions are deleted
+        let next_sequence = fs::read_to_string(dir.join("sequence"))
+            .ok()
+            .and_then(|text| text.trim().parse::<u64>().ok())
+            .unwrap_or(1);
+
+        let transactions = load_manifests::<Transaction>(&dir)
+            .into_iter()
+            .map(|transaction| (transaction.id.clone(), transaction))
+            .collect();
+
+        Entity {
+            config: config.clone(),
+            dir,
+            transactions,
+            next_sequence,
+            outgoing: vec![],
+        }
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn save(&self, transaction: &Transaction) {
+        if let Err(err) = save_manifest(&self.manifest_path(&transaction.id), transaction) {
+            error!("Failed to save CFDP transaction {}: {}", transaction.id, err);
+        }
+    }
+
+    // Take the PDUs to be sent
+    pub fn take_outgoing(&mut self) -> Vec<Vec<u8>> {
+        self.outgoing.split_off(0)
+    }
+
+    // Start sending a file to the ground
+    pub fn put(
+        &mut self,
+        source: &str,
+        destination: &str,
+        acknowledged: bool,
+    ) -> Result<Transaction, String> {
+        if destination.is_empty() || destination.len() > 255 {
+            return Err("Destination file name must be 1 to 255 bytes".to_owned());
+        }
+        let source_path = fs::canonicalize(source).map_err(|err| format!("{}: {}", source, err))?;
+        let source_file = source_path.to_string_lossy().into_owned();
+        if source_file.len() > 255 {
+            return Err("Source file name must be at most 255 bytes".to_owned());
+        }
+        if METADATA_FIXED_LEN + source_file.len() + destination.len() > self.config.max_directive {
+            return Err(format!(
+                "File names must be at most {} bytes together to fit in one frame",
+                self.config.max_directive - METADATA_FIXED_LEN
+            ));
+        }
+        let info = fs::metadata(&source_path).map_err(|err| format!("{}: {}", source, err))?;
+        if !info.is_file() {
+            return Err(format!("{} is not a regular file", source));
+        }
+        if info.len() > u64::from(u32::max_value()) {
+            return Err("Files over 4 GiB can't be sent".to_owned());
+        }
+
+        let checksum = modular_checksum(&source_path, info.len())
+            .map_err(|err| format!("Failed to read {}: {}", source, err))?;
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        if let Err(err) = fs::write(self.dir.join("sequence"), self.next_sequence.to_string()) {
+            error!("Failed to save CFDP sequence number: {}", err);
+        }
+
+        let time = now();
+        let transaction = Transaction {
+            id: format!("{}-{}", self.config.entity_id, sequence),
+            source_entity: self.config.entity_id,
+            sequence,
+            peer: self.config.ground_id,
+            direction: Direction::Send,
+            acknowledged,
+            state: State::Active,
+            condition: Condition::NoError,
+            source_file,
+            destination_file: destination.to_owned(),
+            file_size: Some(info.len()),
+            checksum_type: CHECKSUM_MODULAR,
+            checksum: Some(checksum),
+            ranges: vec![],
+            progress: 0,
+            metadata: false,
+            eof: false,
+            phase: Phase::Sending,
+            timer: time,
+            attempts: 0,
+            started: time,
+            updated: time,
+            finished: None,
+            dirty: false,
+        };
+
+        save_manifest(&self.manifest_path(&transaction.id), &transaction)?;
+        info!(
+            "Started CFDP transaction {}: {} to {}",
+            transaction.id, transaction.source_file, transaction.destination_file
+        );
+        self.transactions.insert(transaction.id.clone(), transaction.clone());
+        Ok(transaction)
+    }
+
+    // Stop an open transaction, telling the other entity
+    pub fn cancel(&mut self, id: &str) -> Result<Transaction, String> {
+        let mut transaction = self.status(id)?;
+        if !transaction.is_open() {
+            return Err("Transaction has already ended".to_owned());
+        }
+
+        transaction.condition = Condition::CancelRequestReceived;
+        match transaction.direction {
+            Direction::Send => {
+                let pdu = self.eof_pdu(&transaction);
+                self.outgoing.push(pdu);
+            }
+            Direction::Receive => {
+                if transaction.acknowledged {
+                    let pdu = self.finished_pdu(&transaction);
+                    self.outgoing.push(pdu);
+                }
+                let _ = fs::remove_file(self.part_path(id));
+            }
+        }
+
+        self.finish(&mut transaction, State::Cancelled, Condition::CancelRequestReceived);
+        self.save(&transaction);
+        self.transactions.insert(transaction.id.clone(), transaction.clone());
+        Ok(transaction)
+    }
+
+    // Forget a transaction that has ended
+    pub fn delete(&mut self, id: &str) -> Result<(), String> {
+        if self.status(id)?.is_open() {
+            return Err("Transaction is still open, cancel it first".to_owned());
+        }
+
+        self.transactions.remove(id);
+        let _ = fs::remove_file(self.manifest_path(id));
+        let _ = fs::remove_file(self.part_path(id));
+        Ok(())
+    }
+
+    pub fn status(&self, id: &str) -> Result<Transaction, String> {
+        self.transactions
+            .get(id)
+            .cloned()
+            .ok_or_else(|| "Unknown CFDP transaction ID".to_owned())
+    }
+
+    // Every transaction, oldest first
+    pub fn list(&self) -> Vec<Transaction> {
+        let mut list: Vec<Transaction> = self.transactions.values().cloned().collect();
+        list.sort_by_key(|transaction| (transaction.started, transaction.sequence));
+        list
+    }
+
+    // Handle a PDU from the link
+    pub fn receive(&mut self, pdu: &[u8], time: u64) {
+        let (header, body) = match parse_header(pdu) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                warn!("Dropping CFDP PDU: {}", err);
+                return;
+            }
+        };
+
+        if header.source == self.config.entity_id && header.toward_sender {
+            self.resume(header.destination, time);
+            self.receive_as_sender(&header, body, time);
+        } else if header.destination == self.config.entity_id && !header.toward_sender {
+            self.resume(header.source, time);
+            self.receive_as_receiver(&header, body, time);
+        } else {
+            debug!("Ignoring CFDP PDU from {} for {}", header.source, header.destination);
+        }
+    }
+
+    // Any PDU shows the link to an entity is back, so carry on with whatever was
+    // suspended waiting for it
+    fn resume(&mut self, peer: u64, time: u64) {
+        for transaction in self.transactions.values_mut() {
+            if transaction.peer != peer || !transaction.is_open() {
+                continue;
+            }
+            if transaction.state == State::Suspended {
+                info!("Resuming CFDP transaction {}", transaction.id);
+                transaction.state = State::Active;
+                transaction.attempts = 0;
+                // Have the timer run out on the next tick, so the retry goes straight away
+                transaction.timer = 0;
+            }
+            transaction.updated = time;
+            transaction.dirty = true;
+        }
+    }
+
+    // ACK, NAK or Finished for a file we are sending
+    fn receive_as_sender(&mut self, header: &Header, body: &[u8], time: u64) {
+        if header.file_data || body.is_empty() {
+            return;
+        }
+        let id = format!("{}-{}", header.source, header.sequence);
+        let mut transaction = match self.transactions.get(&id) {
+            Some(transaction) => transaction.clone(),
+            None => {
+                // Let the receiver finish up even though we've forgotten the transaction
+                if body[0] == FINISHED && body.len() >= 2 {
+                    let condition = Condition::from_code(body[1] >> 4).unwrap_or(Condition::NoError);
+                    let ack = ack_body(FINISHED, condition, STATUS_UNRECOGNIZED);
+                    self.outgoing.push(encode(&reply_header(header), &ack));
+                }
+                return;
+            }
+        };
+
+        match body[0] {
+            ACK if body.len() >= 3 && body[1] >> 4 == EOF => {
+                if transaction.phase == Phase::EofSent {
+                    transaction.phase = Phase::AwaitingFinished;
+                    transaction.attempts = 0;
+                }
+            }
+            NAK if transaction.is_open() && body.len() >= NAK_FIXED_LEN => {
+                let size = transaction.file_size.unwrap_or(0);
+                for request in body[NAK_FIXED_LEN..].chunks_exact(NAK_SEGMENT_LEN) {
+                    let start = u64::from(read_u32(&request[..4]));
+                    let end = u64::from(read_u32(&request[4..]));
+                    if start == 0 && end == 0 {
+                        transaction.metadata = false;
+                    } else if start < end.min(size) {
+                        add_range(&mut transaction.ranges, start, end.min(size));
+                    }
+                }
+            }
+            FINISHED if body.len() >= 2 => {
+                let condition = Condition::from_code(body[1] >> 4).unwrap_or(Condition::InvalidFileStructure);
+                let ack = ack_body(FINISHED, condition, STATUS_TERMINATED);
+                self.outgoing.push(encode(&reply_header(header), &ack));
+                if transaction.is_open() {
+                    let state = if condition == Condition::NoError && body[1] & 0x03 == FILE_RETAINED {
+                        State::Finished
+                    } else {
+                        State::Failed
+                    };
+                    self.finish(&mut transaction, state, condition);
+                }
+            }
+            _ => debug!("Ignoring CFDP directive {:#04x} for {}", body[0], id),
+        }
+
+        transaction.updated = time;
+        self.save(&transaction);
+        self.transactions.insert(id, transaction);
+    }
+
+    // Metadata, file data, EOF or ACK for a file we are receiving
+    fn receive_as_receiver(&mut self, header: &Header, body: &[u8], time: u64) {
+        let id = format!("{}-{}", header.source, header.sequence);
+        let mut transaction = match self.transactions.get(&id) {
+            Some(transaction) => transaction.clone(),
+            None => {
+                // An ACK or a stray PDU for a transaction that ended long ago
+                if !header.file_data && body.first() == Some(&ACK) {
+                    return;
+                }
+                info!("Started CFDP transaction {} from entity {}", id, header.source);
+                Transaction {
+                    id: id.clone(),
+                    source_entity: header.source,
+                    sequence: header.sequence,
+                    peer: header.source,
+                    direction: Direction::Receive,
+                    acknowledged: header.acknowledged,
+                    state: State::Active,
+                    condition: Condition::NoError,
+                    source_file: String::new(),
+                    destination_file: String::new(),
+                    file_size: None,
+                    checksum_type: CHECKSUM_NULL,
+                    checksum: None,
+                    ranges: vec![],
+                    progress: 0,
+                    metadata: false,
+                    eof: false,
+                    phase: Phase::Receiving,
+                    timer: time,
+                    attempts: 0,
+                    started: time,
+                    updated: time,
+                    finished: None,
+                    dirty: true,
+                }
+            }
+        };
+        transaction.updated = time;
+
+        if header.file_data {
+            if transaction.phase == Phase::Receiving && transaction.is_open() {
+                self.file_data(&mut transaction, body);
+            }
+        } else {
+            match body.first().cloned() {
+                Some(METADATA) => {
+                    if transaction.phase == Phase::Receiving && transaction.is_open() {
+                        self.metadata(&mut transaction, body);
+                    }
+                }
+                Some(EOF) => self.eof(&mut transaction, body, time),
+                Some(ACK) => {
+                    let finished = body.len() >= 2 && body[1] >> 4 == FINISHED;
+                    if finished && transaction.phase == Phase::FinishedSent {
+                        let state = if transaction.condition == Condition::NoError {
+                            State::Finished
+                        } else {
+                            State::Failed
+                        };
+                        let condition = transaction.condition;
+                        self.finish(&mut transaction, state, condition);
+                    }
+                }
+                _ => debug!("Ignoring CFDP PDU for {}", id),
+            }
+        }
+
+        // Once the EOF is in, deliver the file as soon as the last missing piece
+        // arrives, but don't NAK again for every file data PDU
+        if transaction.is_open() && transaction.phase == Phase::Receiving && transaction.eof {
+            let complete = transaction.metadata
+                && gaps(&transaction.ranges, transaction.file_size.unwrap_or(0)).is_empty();
+            if complete || !header.file_data {
+                self.check_complete(&mut transaction, time);
+            }
+        }
+
+        if transaction.dirty && !header.file_data {
+            self.save(&transaction);
+            transaction.dirty = false;
+        }
+        self.transactions.insert(id, transaction);
+    }
+
+    fn metadata(&mut self, transaction: &mut Transaction, body: &[u8]) {
+        let parsed = (|| {
+            let flags = *body.get(1)?;
+            let size = read_u32(body.get(2..6)?);
+            let (source, rest) = read_lv(&body[6..])?;
+            let (destination, _) = read_lv(rest)?;
+            Some((flags & 0x0F, u64::from(size), source, destination))
+        })();
+        let (checksum_type, size, source, destination) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                warn!("Dropping malformed CFDP metadata for {}", transaction.id);
+                return;
+            }
+        };
+
+        transaction.checksum_type = checksum_type;
+        transaction.source_file = source;
+        transaction.destination_file = destination;
+        if transaction.file_size.is_none() {
+            transaction.file_size = Some(size);
+        }
+        transaction.metadata = true;
+        transaction.dirty = true;
+    }
+
+    fn file_data(&mut self, transaction: &mut Transaction, body: &[u8]) {
+        if body.len() < 4 {
+            return;
+        }
+        let offset = u64::from(read_u32(&body[..4]));
+        let data = &body[4..];
+        let end = offset + data.len() as u64;
+        if data.is_empty() {
+            return;
+        }
+
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.part_path(&transaction.id))
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)
+            });
+        if let Err(err) = result {
+            error!("Failed to store CFDP file data for {}: {}", transaction.id, err);
+            return;
+        }
+
+        add_range(&mut transaction.ranges, offset, end);
+        transaction.progress = transaction.ranges.iter().map(|(start, end)| end - start).sum();
+        transaction.dirty = true;
+    }
+
+    fn eof(&mut self, transaction: &mut Transaction, body: &[u8], time: u64) {
+        if body.len() < 10 {
+            return;
+        }
+        let condition = Condition::from_code(body[1] >> 4).unwrap_or(Condition::InvalidFileStructure);
+
+        if transaction.acknowledged {
+            let status = if transaction.is_open() { STATUS_ACTIVE } else { STATUS_TERMINATED };
+            let ack = ack_body(EOF, condition, status);
+            self.outgoing.push(self.pdu(transaction, false, &ack));
+        }
+
+        // A repeated EOF means the sender never heard our Finished
+        if transaction.phase == Phase::FinishedSent {
+            transaction.timer = 0;
+            return;
+        }
+        if transaction.eof || !transaction.is_open() {
+            return;
+        }
+
+        if condition != Condition::NoError {
+            info!("CFDP transaction {} was cancelled by the sender: {:?}", transaction.id, condition);
+            let _ = fs::remove_file(self.part_path(&transaction.id));
+            self.finish(transaction, State::Cancelled, condition);
+            return;
+        }
+
+        let size = u64::from(read_u32(&body[6..10]));
+        if transaction.file_size.map_or(false, |known| known != size) {
+            warn!("CFDP transaction {}: EOF file size differs from metadata", transaction.id);
+        }
+        transaction.file_size = Some(size);
+        transaction.checksum = Some(read_u32(&body[2..6]));
+        transaction.eof = true;
+        transaction.timer = time;
+        transaction.attempts = 0;
+        transaction.dirty = true;
+    }
+
+    // Once the EOF is in: deliver the file if it's all there, otherwise ask for what's
+    // missing (class 2) or give up on it (class 1)
+    fn check_complete(&mut self, transaction: &mut Transaction, time: u64) {
+        let size = transaction.file_size.unwrap_or(0);
+        let missing = gaps(&transaction.ranges, size);
+
+        if !transaction.metadata || !missing.is_empty() {
+            if transaction.acknowledged {
+                let pdu = self.nak_pdu(transaction, &missing);
+                self.outgoing.push(pdu);
+                transaction.timer = time;
+            } else {
+                warn!("CFDP transaction {} ended with data missing", transaction.id);
+                let _ = fs::remove_file(self.part_path(&transaction.id));
+                self.finish(transaction, State::Failed, Condition::FileSizeError);
+            }
+            return;
+        }
+
+        let condition = self.deliver(transaction, size);
+        if transaction.acknowledged {
+            transaction.condition = condition;
+            transaction.phase = Phase::FinishedSent;
+            transaction.timer = time;
+            transaction.attempts = 0;
+            transaction.dirty = true;
+            let pdu = self.finished_pdu(transaction);
+            self.outgoing.push(pdu);
+        } else {
+            let state = if condition == Condition::NoError { State::Finished } else { State::Failed };
+            self.finish(transaction, state, condition);
+        }
+    }
+
+    // Check the received file and move it into place
+    fn deliver(&self, transaction: &Transaction, size: u64) -> Condition {
+        let part = self.part_path(&transaction.id);
+        if size == 0 {
+            if let Err(err) = File::create(&part) {
+                error!("Failed to create {}: {}", part.display(), err);
+                return Condition::FilestoreRejection;
+            }
+        }
+
+        let condition = match transaction.checksum_type {
+            CHECKSUM_NULL => Condition::NoError,
+            CHECKSUM_MODULAR => match modular_checksum(&part, size) {
+                Ok(sum) if Some(sum) == transaction.checksum => Condition::NoError,
+                Ok(_) => Condition::FileChecksumFailure,
+                Err(_) => Condition::FilestoreRejection,
+            },
+            _ => Condition::UnsupportedChecksumType,
+        };
+
+        let condition = if condition != Condition::NoError {
+            condition
+        } else {
+            let result = OpenOptions::new()
+                .write(true)
+                .open(&part)
+                .and_then(|file| file.set_len(size))
+                .and_then(|_| move_file(&part, Path::new(&transaction.destination_file)));
+            match result {
+                Ok(()) => Condition::NoError,
+                Err(err) => {
+                    error!("Failed to deliver {}: {}", transaction.destination_file, err);
+                    Condition::FilestoreRejection
+                }
+            }
+        };
+
+        if condition == Condition::NoError {
+            info!("CFDP transaction {} delivered {}", transaction.id, transaction.destination_file);
+        } else {
+            warn!("CFDP transaction {} failed: {:?}", transaction.id, condition);
+            let _ = fs::remove_file(&part);
+        }
+        condition
+    }
+
+    fn finish(&self, transaction: &mut Transaction, state: State, condition: Condition) {
+        info!("CFDP transaction {} ended: {:?} ({:?})", transaction.id, state, condition);
+        transaction.state = state;
+        transaction.condition = condition;
+        transaction.phase = Phase::Done;
+        transaction.finished = Some(now());
+        transaction.dirty = true;
+    }
+
+    // Send file data and run the timers
+    pub fn tick(&mut self, time: u64) {
+        let mut budget = self.config.max_burst;
+        let mut ids: Vec<String> = self
+            .transactions
+            .values()
+            .filter(|transaction| transaction.is_open() || transaction.dirty)
+            .map(|transaction| transaction.id.clone())
+            .collect();
+        ids.sort();
+
+        for id in ids {
+            let mut transaction = self.transactions[&id].clone();
+
+            let idle = time.saturating_sub(transaction.updated);
+            if transaction.is_open() && idle > self.config.inactivity_timeout.as_secs() {
+                warn!("CFDP transaction {}: nothing heard from entity {}", id, transaction.peer);
+                let _ = fs::remove_file(self.part_path(&id));
+                self.finish(&mut transaction, State::Failed, Condition::InactivityDetected);
+            }
+
+            if transaction.state == State::Active {
+                match transaction.direction {
+                    Direction::Send => self.tick_sender(&mut transaction, &mut budget, time),
+                    Direction::Receive => self.tick_receiver(&mut transaction, time),
+                }
+            }
+
+            if transaction.dirty {
+                transaction.dirty = false;
+                self.save(&transaction);
+            }
+            self.transactions.insert(id, transaction);
+        }
+    }
+
+    fn tick_sender(&mut self, transaction: &mut Transaction, budget: &mut usize, time: u64) {
+        if !transaction.metadata {
+            let pdu = self.metadata_pdu(transaction);
+            self.outgoing.push(pdu);
+            transaction.metadata = true;
+            transaction.dirty = true;
+        }
+
+        let size = transaction.file_size.unwrap_or(0);
+        while *budget > 0 {
+            // Anything NAKed first, then the rest of the file
+            let (start, end) = match transaction.ranges.first() {
+                Some(&(start, end)) => (start, end.min(start + self.config.segment_len as u64)),
+                None if transaction.progress < size => (
+                    transaction.progress,
+                    size.min(transaction.progress + self.config.segment_len as u64),
+                ),
+                None => break,
+            };
+
+            let data = match read_segment(&transaction.source_file, start, end) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!(
+                        "CFDP transaction {}: failed to read {}: {}",
+                        transaction.id, transaction.source_file, err
+                    );
+                    transaction.condition = Condition::FilestoreRejection;
+                    let pdu = self.eof_pdu(transaction);
+                    self.outgoing.push(pdu);
+                    self.finish(transaction, State::Failed, Condition::FilestoreRejection);
+                    return;
+                }
+            };
+
+            let mut body = Vec::with_capacity(4 + data.len());
+            body.extend_from_slice(&(start as u32).to_be_bytes());
+            body.extend_from_slice(&data);
+            self.outgoing.push(self.pdu(transaction, true, &body));
+            *budget -= 1;
+
+            if transaction.ranges.first().map_or(false, |&(first, _)| first == start) {
+                remove_range(&mut transaction.ranges, start, end);
+            } else {
+                transaction.progress = end;
+            }
+            transaction.dirty = true;
+        }
+
+        let waited = time.saturating_sub(transaction.timer);
+        match transaction.phase {
+            Phase::Sending if transaction.progress >= size && transaction.ranges.is_empty() => {
+                let pdu = self.eof_pdu(transaction);
+                self.outgoing.push(pdu);
+                transaction.eof = true;
+                transaction.dirty = true;
+                if transaction.acknowledged {
+                    transaction.phase = Phase::EofSent;
+                    transaction.timer = time;
+                    transaction.attempts = 0;
+                } else {
+                    self.finish(transaction, State::Finished, Condition::NoError);
+                }
+            }
+            Phase::EofSent if waited >= self.config.ack_timeout.as_secs() => {
+                if self.retry(transaction, self.config.ack_limit, time) {
+                    let pdu = self.eof_pdu(transaction);
+                    self.outgoing.push(pdu);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn tick_receiver(&mut self, transaction: &mut Transaction, time: u64) {
+        let waited = time.saturating_sub(transaction.timer);
+        match transaction.phase {
+            Phase::Receiving if transaction.eof && transaction.acknowledged => {
+                if waited >= self.config.nak_timeout.as_secs()
+                    && self.retry(transaction, self.config.nak_limit, time)
+                {
+                    self.check_complete(transaction, time);
+                }
+            }
+            Phase::FinishedSent if waited >= self.config.ack_timeout.as_secs() => {
+                if self.retry(transaction, self.config.ack_limit, time) {
+                    let pdu = self.finished_pdu(transaction);
+                    self.outgoing.push(pdu);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Count a timer running out.  Returns false, and suspends the transaction, once
+    // there have been too many.
+    fn retry(&self, transaction: &mut Transaction, limit: u32, time: u64) -> bool {
+        transaction.attempts += 1;
+        transaction.timer = time;
+        transaction.dirty = true;
+        if transaction.attempts > limit {
+            info!(
+                "Suspending CFDP transaction {} until entity {} is heard from",
+                transaction.id, transaction.peer
+            );
+            transaction.state = State::Suspended;
+            return false;
+        }
+        true
+    }
+
+    // A PDU for a transaction
+    fn pdu(&self, transaction: &Transaction, file_data: bool, body: &[u8]) -> Vec<u8> {
+        let (destination, toward_sender) = match transaction.direction {
+            Direction::Send => (transaction.peer, false),
+            Direction::Receive => (self.config.entity_id, true),
+        };
+        let header = Header {
+            file_data,
+            toward_sender,
+            acknowledged: transaction.acknowledged,
+            source: transaction.source_entity,
+            sequence: transaction.sequence,
+            destination,
+        };
+        encode(&header, body)
+    }
+
+    fn metadata_pdu(&self, transaction: &Transaction) -> Vec<u8> {
+        let size = transaction.file_size.unwrap_or(0) as u32;
+        let mut body = vec![METADATA, transaction.checksum_type & 0x0F];
+        body.extend_from_slice(&size.to_be_bytes());
+        for name in &[&transaction.source_file, &transaction.destination_file] {
+            body.push(name.len() as u8);
+            body.extend_from_slice(name.as_bytes());
+        }
+        self.pdu(transaction, false, &body)
+    }
+
+    fn eof_pdu(&self, transaction: &Transaction) -> Vec<u8> {
+        let (checksum, size) = if transaction.condition == Condition::NoError {
+            (transaction.checksum.unwrap_or(0), transaction.file_size.unwrap_or(0))
+        } else {
+            (0, transaction.progress)
+        };
+        let mut body = vec![EOF, transaction.condition.code() << 4];
+        body.extend_from_slice(&checksum.to_be_bytes());
+        body.extend_from_slice(&(size as u32).to_be_bytes());
+        if transaction.condition != Condition::NoError {
+            body.extend_from_slice(&entity_tlv(self.config.entity_id));
+        }
+        self.pdu(transaction, false, &body)
+    }
+
+    fn finished_pdu(&self, transaction: &Transaction) -> Vec<u8> {
+        let complete = transaction.metadata
+            && transaction.eof
+            && gaps(&transaction.ranges, transaction.file_size.unwrap_or(0)).is_empty();
+        let status = match transaction.condition {
+            Condition::NoError => FILE_RETAINED,
+            Condition::FilestoreRejection => FILE_REJECTED,
+            _ => FILE_DISCARDED,
+        };
+        let mut body = vec![
+            FINISHED,
+            transaction.condition.code() << 4 | (!complete as u8) << 2 | status,
+        ];
+        if transaction.condition != Condition::NoError {
+            body.extend_from_slice(&entity_tlv(self.config.entity_id));
+        }
+        self.pdu(transaction, false, &body)
+    }
+
+    // Only as many segment requests as fit in a frame go in; the scope then ends where
+    // the last one does so the sender doesn't take the rest as received
+    fn nak_pdu(&self, transaction: &Transaction, missing: &[(u64, u64)]) -> Vec<u8> {
+        let limit = MAX_NAK_SEGMENTS.min((self.config.max_directive - NAK_FIXED_LEN) / NAK_SEGMENT_LEN);
+        let metadata = if transaction.metadata { None } else { Some((0, 0)) };
+        let requests: Vec<(u64, u64)> = metadata.into_iter().chain(missing.iter().cloned()).collect();
+        let segments = &requests[..requests.len().min(limit)];
+        let scope_end = if segments.len() < requests.len() {
+            segments.last().map_or(0, |&(_, end)| end)
+        } else {
+            transaction.file_size.unwrap_or(0)
+        };
+
+        let mut body = Vec::with_capacity(NAK_FIXED_LEN + segments.len() * NAK_SEGMENT_LEN);
+        body.push(NAK);
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&(scope_end as u32).to_be_bytes());
+        for &(start, end) in segments {
+            body.extend_from_slice(&(start as u32).to_be_bytes());
+            body.extend_from_slice(&(end as u32).to_be_bytes());
+        }
+        self.pdu(transaction, false, &body)
+    }
+}
+
+// Start the entity and the thread that passes PDUs between it and the radio
+pub fn start(config: &CfdpConfig, staging_dir: &Path, radio: RadioHandle) -> Arc<Mutex<Entity>> {
+    let entity = Arc::new(Mutex::new(Entity::new(config, staging_dir)));
+    let runner = entity.clone();
+    let apid = config.apid;
+
+    let _ = thread::Builder::new()
+        .name("cfdp".to_owned())
+        .spawn(move || {
+            let mut count: u16 = 0;
+            let mut last_tick = Instant::now();
+            loop {
+                let packet = match radio.read_pdu(TICK) {
+                    Ok(packet) => packet,
+                    Err(err) => {
+                        error!("CFDP entity failed to read from radio: {}", err);
+                        continue;
+                    }
+                };
+
+                let outgoing = match runner.lock() {
+                    Ok(mut entity) => {
+                        if let Some(pdu) = packet.as_ref().and_then(|packet| packet_data(packet)) {
+                            entity.receive(pdu, now());
+                        }
+                        if last_tick.elapsed() >= TICK {
+                            last_tick = Instant::now();
+                            entity.tick(now());
+                        }
+                        entity.take_outgoing()
+                    }
+                    Err(_) => continue,
+                };
+
+                // Sent without the entity locked, since each write waits for the link
+                for pdu in outgoing {
+                    if let Err(err) = radio.write(&space_packet(apid, count, &pdu)) {
+                        error!("Failed to send CFDP PDU: {}", err);
+                    }
+                    count = (count + 1) & 0x3FFF;
+                }
+            }
+        });
+
+    entity
+}
+
+// The data field of a space packet
+fn packet_data(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < PACKET_HEADER_LEN + 1 {
+        return None;
+    }
+    let len = ((usize::from(packet[4]) << 8) | usize::from(packet[5])) + 1;
+    packet.get(PACKET_HEADER_LEN..PACKET_HEADER_LEN + len)
+}
+
+// A telemetry space packet with no secondary header
+fn space_packet(apid: u16, count: u16, data: &[u8]) -> Vec<u8> {
+    let len = data.len() - 1;
+    let mut packet = Vec::with_capacity(PACKET_HEADER_LEN + data.len());
+    packet.push((apid >> 8) as u8 & 0x07);
+    packet.push(apid as u8);
+    packet.push(0xC0 | (count >> 8) as u8);
+    packet.push(count as u8);
+    packet.push((len >> 8) as u8);
+    packet.push(len as u8);
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn parse_header(pdu: &[u8]) -> Result<(Header, &[u8]), String> {
+    if pdu.len() < 4 {
+        return Err("too short".to_owned());
+    }
+    if pdu[0] >> 5 != VERSION {
+        return Err(format!("unsupported version {}", pdu[0] >> 5));
+    }
+    if pdu[0] & 0x01 != 0 {
+        return Err("large files aren't supported".to_owned());
+    }
+    if pdu[3] & 0x08 != 0 {
+        return Err("segment metadata isn't supported".to_owned());
+    }
+
+    let id_len = usize::from((pdu[3] >> 4) & 0x07) + 1;
+    let sequence_len = usize::from(pdu[3] & 0x07) + 1;
+    let header_len = 4 + 2 * id_len + sequence_len;
+    let data_len = (usize::from(pdu[1]) << 8) | usize::from(pdu[2]);
+    if pdu.len() < header_len + data_len {
+        return Err("truncated".to_owned());
+    }
+
+    let mut data = &pdu[header_len..header_len + data_len];
+    if pdu[0] & 0x02 != 0 {
+        if data.len() < 2 {
+            return Err("truncated".to_owned());
+        }
+        let crc = read_u16(&data[data.len() - 2..]);
+        if crc16_ccitt(&pdu[..header_len + data_len - 2]) != crc {
+            return Err("bad CRC".to_owned());
+        }
+        data = &data[..data.len() - 2];
+    }
+
+    let header = Header {
+        file_data: pdu[0] & 0x10 != 0,
+        toward_sender: pdu[0] & 0x08 != 0,
+        acknowledged: pdu[0] & 0x04 == 0,
+        source: read_uint(&pdu[4..4 + id_len]),
+        sequence: read_uint(&pdu[4 + id_len..4 + id_len + sequence_len]),
+        destination: read_uint(&pdu[4 + id_len + sequence_len..header_len]),
+    };
+    Ok((header, data))
+}
+
+// The header for an ACK of a PDU from the receiver, going back the other way
+fn reply_header(header: &Header) -> Header {
+    Header {
+        file_data: false,
+        toward_sender: false,
+        ..*header
+    }
+}
+
+fn encode(header: &Header, body: &[u8]) -> Vec<u8> {
+    let id_len = ENTITY_ID_LEN.max(uint_len(header.source)).max(uint_len(header.destination));
+    let sequence_len = SEQUENCE_LEN.max(uint_len(header.sequence));
+
+    let mut pdu = Vec::with_capacity(4 + 2 * id_len + sequence_len + body.len());
+    pdu.push(
+        VERSION << 5
+            | (header.file_data as u8) << 4
+            | (header.toward_sender as u8) << 3
+            | (!header.acknowledged as u8) << 2,
+    );
+    pdu.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    pdu.push(((id_len - 1) as u8) << 4 | (sequence_len - 1) as u8);
+    pdu.extend_from_slice(&header.source.to_be_bytes()[8 - id_len..]);
+    pdu.extend_from_slice(&header.sequence.to_be_bytes()[8 - sequence_len..]);
+    pdu.extend_from_slice(&header.destination.to_be_bytes()[8 - id_len..]);
+    pdu.extend_from_slice(body);
+    pdu
+}
+
+fn ack_body(directive: u8, condition: Condition, status: u8) -> Vec<u8> {
+    let subtype = if directive == FINISHED { 1 } else { 0 };
+    vec![ACK, directive << 4 | subtype, condition.code() << 4 | status]
+}
+
+// Fault location TLV naming this entity
+fn entity_tlv(id: u64) -> Vec<u8> {
+    let len = uint_len(id).max(ENTITY_ID_LEN);
+    let mut tlv = vec![TLV_ENTITY_ID, len as u8];
+    tlv.extend_from_slice(&id.to_be_bytes()[8 - len..]);
+    tlv
+}
+
+// Bytes needed to hold a value
+fn uint_len(value: u64) -> usize {
+    ((64 - value.leading_zeros() as usize) + 7) / 8
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, byte| value << 8 | u64::from(*byte))
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    read_uint(&data[..2]) as u16
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    read_uint(&data[..4]) as u32
+}
+
+// A length-prefixed string, and what follows it
+fn read_lv(data: &[u8]) -> Option<(String, &[u8])> {
+    let len = usize::from(*data.first()?);
+    let value = data.get(1..1 + len)?;
+    Some((String::from_utf8_lossy(value).into_owned(), &data[1 + len..]))
+}
+
+fn read_segment(path: &str, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = vec![0; (end - start) as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// The CFDP modular checksum: the sum of the file as big-endian 32-bit words, with the
+// last word padded with zeroes
+fn modular_checksum(path: &Path, size: u64) -> io::Result<u32> {
+    let mut file = File::open(path)?.take(size);
+    let mut buffer = [0u8; 4096];
+    let mut word = [0u8; 4];
+    let mut used = 0;
+    let mut sum: u32 = 0;
+
+    loop {
+        let num = file.read(&mut buffer)?;
+        if num == 0 {
+            break;
+        }
+        for &byte in &buffer[..num] {
+            word[used] = byte;
+            used += 1;
+            if used == 4 {
+                sum = sum.wrapping_add(u32::from_be_bytes(word));
+                used = 0;
+            }
+        }
+    }
+    if used > 0 {
+        word[used..].iter_mut().for_each(|byte| *byte = 0);
+        sum = sum.wrapping_add(u32::from_be_bytes(word));
+    }
+    Ok(sum)
+}
+
+// Rename, or copy if the destination is on another filesystem
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory for each test's staging area and files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dora-cfdp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(entity_id: u64, ground_id: u64, max_directive: usize) -> CfdpConfig {
+        CfdpConfig {
+            enabled: true,
+            apid: 100,
+            entity_id,
+            ground_id,
+            segment_len: 16,
+            max_directive,
+            max_burst: 16,
+            ack_timeout: Duration::from_secs(10),
+            ack_limit: 5,
+            nak_timeout: Duration::from_secs(10),
+            nak_limit: 5,
+            inactivity_timeout: Duration::from_secs(600),
+        }
+    }
+
+    // Pass every PDU waiting at one entity to the other, except the ones dropped
+    fn pass(from: &mut Entity, to: &mut Entity, time: u64, drop: &[usize]) -> usize {
+        let pdus = from.take_outgoing();
+        for (index, pdu) in pdus.iter().enumerate() {
+            if !drop.contains(&index) {
+                to.receive(pdu, time);
+            }
+        }
+        pdus.len()
+    }
+
+    fn nak_segments(body: &[u8]) -> Vec<(u64, u64)> {
+        body[NAK_FIXED_LEN..]
+            .chunks_exact(NAK_SEGMENT_LEN)
+            .map(|request| (u64::from(read_u32(&request[..4])), u64::from(read_u32(&request[4..]))))
+            .collect()
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        for &(source, sequence, destination) in
+            &[(1, 1, 0), (0x1234, 0xDEAD_BEEF, 7), (u64::max_value(), 1 << 40, 0x0102_0304_0506)]
+        {
+            for &flags in &[(false, false, true), (true, true, false), (true, false, true)] {
+                let header = Header {
+                    file_data: flags.0,
+                    toward_sender: flags.1,
+                    acknowledged: flags.2,
+                    source,
+                    sequence,
+                    destination,
+                };
+                let pdu = encode(&header, b"body");
+                assert!(pdu.len() <= MAX_HEADER_LEN + 4);
+
+                let (parsed, body) = parse_header(&pdu).unwrap();
+                assert_eq!(body, b"body");
+                assert_eq!(parsed.file_data, flags.0);
+                assert_eq!(parsed.toward_sender, flags.1);
+                assert_eq!(parsed.acknowledged, flags.2);
+                assert_eq!(parsed.source, source);
+                assert_eq!(parsed.sequence, sequence);
+                assert_eq!(parsed.destination, destination);
+            }
+        }
+    }
+
+    #[test]
+    fn checks_pdu_crc() {
+        let header = Header {
+            file_data: false,
+            toward_sender: false,
+            acknowledged: true,
+            source: 1,
+            sequence: 2,
+            destination: 0,
+        };
+        let mut pdu = encode(&header, b"body\0\0");
+        pdu[0] |= 0x02;
+        let len = pdu.len();
+        let crc = crc16_ccitt(&pdu[..len - 2]);
+        pdu[len - 2..].copy_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(parse_header(&pdu).unwrap().1, b"body");
+        pdu[len - 3] ^= 0x01;
+        assert_eq!(parse_header(&pdu).err(), Some("bad CRC".to_owned()));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let header = Header {
+            file_data: true,
+            toward_sender: false,
+            acknowledged: false,
+            source: 1,
+            sequence: 2,
+            destination: 0,
+        };
+        let pdu = encode(&header, b"body");
+        assert!(parse_header(&pdu[..pdu.len() - 1]).is_err());
+        assert!(parse_header(&pdu[..3]).is_err());
+
+        let mut large = pdu.clone();
+        large[0] |= 0x01;
+        assert!(parse_header(&large).is_err());
+
+        let mut version = pdu;
+        version[0] = (version[0] & 0x1F) | 2 << 5;
+        assert!(parse_header(&version).is_err());
+    }
+
+    #[test]
+    fn sends_a_file_unacknowledged() {
+        let dir = test_dir("class1");
+        let source = dir.join("source");
+        let destination = dir.join("destination");
+        let contents: Vec<u8> = (0..40).collect();
+        fs::write(&source, &contents).unwrap();
+
+        let mut sender = Entity::new(&config(1, 0, 100), &dir.join("sender"));
+        let mut receiver = Entity::new(&config(0, 1, 100), &dir.join("receiver"));
+        let transaction = sender
+            .put(source.to_str().unwrap(), destination.to_str().unwrap(), false)
+            .unwrap();
+
+        sender.tick(100);
+        // Metadata, three file data PDUs and the EOF
+        assert_eq!(pass(&mut sender, &mut receiver, 100, &[]), 5);
+        assert_eq!(sender.status(&transaction.id).unwrap().state, State::Finished);
+        assert_eq!(receiver.status(&transaction.id).unwrap().state, State::Finished);
+        assert_eq!(fs::read(&destination).unwrap(), contents);
+        assert!(receiver.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn naks_lost_data_and_metadata() {
+        let dir = test_dir("class2");
+        let source = dir.join("source");
+        let destination = dir.join("destination");
+        let contents: Vec<u8> = (0..40).collect();
+        fs::write(&source, &contents).unwrap();
+
+        let mut sender = Entity::new(&config(1, 0, 100), &dir.join("sender"));
+        let mut receiver = Entity::new(&config(0, 1, 100), &dir.join("receiver"));
+        let transaction = sender
+            .put(source.to_str().unwrap(), destination.to_str().unwrap(), true)
+            .unwrap();
+
+        // Lose the metadata and the second segment
+        sender.tick(100);
+        assert_eq!(pass(&mut sender, &mut receiver, 100, &[0, 2]), 5);
+
+        let replies = receiver.take_outgoing();
+        assert_eq!(replies.len(), 2);
+        let (header, body) = parse_header(&replies[1]).unwrap();
+        assert!(header.toward_sender);
+        assert_eq!(body[0], NAK);
+        assert_eq!(read_u32(&body[5..9]), 40);
+        assert_eq!(nak_segments(body), vec![(0, 0), (16, 32)]);
+        for pdu in &replies {
+            sender.receive(pdu, 101);
+        }
+
+        // The sender resends both, and the receiver finishes
+        sender.tick(101);
+        assert_eq!(pass(&mut sender, &mut receiver, 101, &[]), 2);
+        assert_eq!(fs::read(&destination).unwrap(), contents);
+        let replies = receiver.take_outgoing();
+        let (_, body) = parse_header(replies.last().unwrap()).unwrap();
+        assert_eq!(body[0], FINISHED);
+        for pdu in &replies {
+            sender.receive(pdu, 101);
+        }
+        let acks = sender.take_outgoing();
+        let (_, body) = parse_header(&acks[0]).unwrap();
+        assert_eq!(body[..2], [ACK, FINISHED << 4 | 1]);
+        receiver.receive(&acks[0], 101);
+        assert_eq!(sender.status(&transaction.id).unwrap().state, State::Finished);
+        assert_eq!(receiver.status(&transaction.id).unwrap().state, State::Finished);
+    }
+
+    #[test]
+    fn fits_naks_in_a_frame() {
+        let dir = test_dir("nak");
+        let source = dir.join("source");
+        fs::write(&source, vec![0; 1000]).unwrap();
+
+        let mut entity = Entity::new(&config(1, 0, 1000), &dir.join("large"));
+        let transaction = entity.put(source.to_str().unwrap(), "file", true).unwrap();
+        let missing: Vec<(u64, u64)> = (0..10).map(|index| (index * 100, index * 100 + 50)).collect();
+
+        let pdu = entity.nak_pdu(&transaction, &missing);
+        let (_, body) = parse_header(&pdu).unwrap();
+        assert_eq!(nak_segments(body).len(), 11);
+        assert_eq!(read_u32(&body[5..9]), 1000);
+
+        let entity = Entity::new(&config(1, 0, MIN_DIRECTIVE_LEN), &dir.join("small"));
+        let pdu = entity.nak_pdu(&transaction, &missing);
+        let (_, body) = parse_header(&pdu).unwrap();
+        assert_eq!(body.len(), MIN_DIRECTIVE_LEN);
+        assert_eq!(nak_segments(body), vec![(0, 0), (0, 50)]);
+        // The scope stops at the last request so the rest isn't taken as received
+        assert_eq!(read_u32(&body[1..5]), 0);
+        assert_eq!(read_u32(&body[5..9]), 50);
+    }
+
+    #[test]
+    fn rejects_names_too_long_for_a_frame() {
+        let dir = test_dir("names");
+        let source = dir.join("source");
+        fs::write(&source, b"data").unwrap();
+        let source = source.to_str().unwrap();
+
+        let mut entity = Entity::new(&config(1, 0, METADATA_FIXED_LEN + source.len() + 4), &dir);
+        assert!(entity.put(source, "/abcd", false).is_err());
+        let transaction = entity.put(source, "/abc", false).unwrap();
+
+        let pdu = entity.metadata_pdu(&transaction);
+        let (_, body) = parse_header(&pdu).unwrap();
+        assert_eq!(body.len(), METADATA_FIXED_LEN + source.len() + 4);
+        let (name, rest) = read_lv(&body[6..]).unwrap();
+        assert_eq!(name, source);
+        assert_eq!(read_lv(rest).unwrap().0, "/abc");
+    }
+}
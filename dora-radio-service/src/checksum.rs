@@ -0,0 +1,137 @@
+// Checksums used to verify files moved over the radio link
+
+use crc::crc32::{self as crc32_alg, Hasher32};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Size of the pieces files are read in while hashing
+const HASH_BLOCK: usize = 64 * 1024;
+
+// Checksums the ground can use to verify a file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HashType {
+    Sha256,
+    Crc32,
+}
+
+impl HashType {
+    // Number of hex digits in a checksum of this type
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashType::Sha256 => 64,
+            HashType::Crc32 => 8,
+        }
+    }
+}
+
+// Lower-case hex encoding, as used for hashes in GraphQL responses
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Bytes from a hex string, or None if it isn't valid hex
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+// CRC-32 (IEEE 802.3) of a block of data
+pub fn crc32(data: &[u8]) -> u32 {
+    crc::crc32::checksum_ieee(data)
+}
+
+// SHA-256 of a block of data, as a hex string
+pub fn sha256(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    to_hex(&hasher.result())
+}
+
+// SHA-256 of a whole file, as a hex string.  The file is read in pieces so large files
+// don't have to fit in memory.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    read_blocks(path, 0, None, |block| hasher.input(block))?;
+    Ok(to_hex(&hasher.result()))
+}
+
+// SHA-256 of everything left in a reader, such as a file that is already open
+pub fn sha256_reader<R: Read>(reader: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    feed_blocks(reader, |block| hasher.input(block))?;
+    Ok(to_hex(&hasher.result()))
+}
+
+// CRC-32 of a whole file, as a hex string
+pub fn crc32_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut digest = crc32_alg::Digest::new(crc32_alg::IEEE);
+    read_blocks(path, 0, None, |block| digest.write(block))?;
+    Ok(format!("{:08x}", digest.sum32()))
+}
+
+// Checksum of a whole file, as a hex string
+pub fn file_checksum<P: AsRef<Path>>(path: P, hash_type: HashType) -> io::Result<String> {
+    match hash_type {
+        HashType::Sha256 => sha256_file(path),
+        HashType::Crc32 => crc32_file(path),
+    }
+}
+
+// Checksum of `length` bytes of a file from `offset` on (or to the end of the file if
+// no length is given), as a hex string.  Also returns the number of bytes hashed, which
+// is less than asked for if the range runs past the end of the file.
+pub fn range_checksum<P: AsRef<Path>>(
+    path: P,
+    hash_type: HashType,
+    offset: u64,
+    length: Option<u64>,
+) -> io::Result<(String, u64)> {
+    match hash_type {
+        HashType::Sha256 => {
+            let mut hasher = Sha256::new();
+            let num = read_blocks(path, offset, length, |block| hasher.input(block))?;
+            Ok((to_hex(&hasher.result()), num))
+        }
+        HashType::Crc32 => {
+            let mut digest = crc32_alg::Digest::new(crc32_alg::IEEE);
+            let num = read_blocks(path, offset, length, |block| digest.write(block))?;
+            Ok((format!("{:08x}", digest.sum32()), num))
+        }
+    }
+}
+
+// Feed part of a file through `f` one block at a time.  Returns the number of bytes read.
+fn read_blocks<P: AsRef<Path>, F: FnMut(&[u8])>(
+    path: P,
+    offset: u64,
+    length: Option<u64>,
+    f: F,
+) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    feed_blocks(file.take(length.unwrap_or(u64::max_value())), f)
+}
+
+// Feed everything left in a reader through `f` one block at a time
+fn feed_blocks<R: Read, F: FnMut(&[u8])>(mut reader: R, mut f: F) -> io::Result<u64> {
+    let mut buffer = vec![0; HASH_BLOCK];
+    let mut total = 0;
+
+    loop {
+        let num = reader.read(&mut buffer)?;
+        if num == 0 {
+            return Ok(total);
+        }
+        f(&buffer[0..num]);
+        total += num as u64;
+    }
+}
@@ -0,0 +1,391 @@
+// Restricted command execution for run_command
+//
+// Only commands in the allowlist in the config file can be run, and every argument
+// has to match one of the patterns listed for the command.  Patterns are matched as
+// paths (see glob.rs), so '*' stays within one directory, and arguments with a '..'
+// component are refused outright so they can't climb out of an allowed directory.
+// Each command runs in its
+// own process group with a memory limit, and the whole group is killed if it is still
+// running when its timeout expires, so a hung command can't tie up the GraphQL
+// handler.  Captured output is capped, with anything beyond the cap thrown away.
+// Output can instead be redirected to files, but only new or existing files in the
+// configured output directory, since the command runs as root.
+
+use crate::config::{AllowedCommand, CommandConfig};
+use crate::glob;
+use log::*;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Component, Path};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often a running command is checked for exit
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Where commands given without a directory are looked for if PATH isn't set
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// How a command finished
+pub struct CommandStatus {
+    // Exit code, if the command exited normally
+    pub exit_code: Option<i32>,
+    // Signal that killed the command, if any
+    pub signal: Option<i32>,
+    // Whether the command was killed for running past its timeout
+    pub timed_out: bool,
+}
+
+// Captured output of a command run by run_command
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub stdout_truncated: bool,
+    pub stderr_truncated: bool,
+    pub status: CommandStatus,
+}
+
+// Run an allowed command and wait for it to finish or time out.  stdout and stderr go
+// to the given files in the output directory if there are any, otherwise they are
+// captured.
+pub fn run(
+    config: &CommandConfig,
+    path: &str,
+    args: &[String],
+    stdout: Option<String>,
+    stderr: Option<String>,
+) -> Result<CommandOutput, String> {
+    let path = resolve(path)?;
+    let allowed = check_allowed(config, &path, args)?;
+    let timeout = allowed.timeout.unwrap_or(config.timeout);
+
+    execute(config, &path, args, timeout, stdout, stderr)
+}
+
+// Run a command that needs no allowlist check, such as one from the config file, with
+// the same limits as run_command apart from the timeout
+pub fn execute(
+    config: &CommandConfig,
+    path: &str,
+    args: &[String],
+    timeout: Duration,
+    stdout: Option<String>,
+    stderr: Option<String>,
+) -> Result<CommandOutput, String> {
+    let mut command = Command::new(path);
+    command.args(args).stdin(Stdio::null());
+    command.stdout(output_to(config, stdout, "stdout")?);
+    command.stderr(output_to(config, stderr, "stderr")?);
+
+    isolate(&mut command, config.max_memory);
+
+    let mut child = command.spawn().map_err(|_| "Could not execute command".to_owned())?;
+    let group = Pid::from_raw(child.id() as i32);
+    info!("Running {} {:?} (pid {}, timeout {}s)", path, args, child.id(), timeout.as_secs());
+
+    let stdout_reader = child.stdout.take().map(|out| capture(out, config.max_output));
+    let stderr_reader = child.stderr.take().map(|err| capture(err, config.max_output));
+
+    let deadline = Instant::now() + timeout;
+    let mut timed_out = false;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) => (),
+            Err(err) => {
+                error!("Failed to wait for command {}: {}", path, err);
+                break None;
+            }
+        }
+
+        if Instant::now() >= deadline {
+            warn!("Command {} timed out after {}s, killing it", path, timeout.as_secs());
+            timed_out = true;
+            let _ = killpg(group, Signal::SIGKILL);
+            break child.wait().ok();
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    // Anything the command left running in the background would keep the output pipes
+    // open and hold up the readers, so take the rest of the group down too
+    let _ = killpg(group, Signal::SIGKILL);
+
+    let (stdout, stdout_truncated) = finish_capture(stdout_reader);
+    let (stderr, stderr_truncated) = finish_capture(stderr_reader);
+
+    Ok(CommandOutput {
+        stdout,
+        stderr,
+        stdout_truncated,
+        stderr_truncated,
+        status: command_status(status, timed_out),
+    })
+}
+
+// Run a command that starts or checks a long-running service, such as an update
+// target's restart or health check, and wait for it to finish or time out.  None of
+// the run_command limits apply: an init script has to be able to leave a daemon
+// running, and the daemon mustn't inherit a memory limit meant for ad-hoc commands.
+// The command gets a session of its own only so it can be killed along with anything
+// it started if it times out; whatever it leaves behind once it exits is left alone.
+// Its output isn't captured, since a daemon would hold the pipes open.
+pub fn execute_service(path: &str, args: &[String], timeout: Duration) -> Result<CommandStatus, String> {
+    let mut command = Command::new(path);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn().map_err(|_| "Could not execute command".to_owned())?;
+    let group = Pid::from_raw(child.id() as i32);
+    info!("Running {} {:?} (pid {}, timeout {}s)", path, args, child.id(), timeout.as_secs());
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(command_status(Some(status), false)),
+            Ok(None) => (),
+            Err(err) => {
+                error!("Failed to wait for command {}: {}", path, err);
+                return Ok(command_status(None, false));
+            }
+        }
+
+        if Instant::now() >= deadline {
+            warn!("Command {} timed out after {}s, killing it", path, timeout.as_secs());
+            let _ = killpg(group, Signal::SIGKILL);
+            return Ok(command_status(child.wait().ok(), true));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Put a command in a process group of its own so it can be killed along with anything
+// it starts, and limit its address space
+pub fn isolate(command: &mut Command, max_memory: u64) {
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if max_memory > 0 {
+                let limit = libc::rlimit {
+                    rlim_cur: max_memory as libc::rlim_t,
+                    rlim_max: max_memory as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+// Find the full path of a command given by name, the same way a shell would
+pub fn resolve(path: &str) -> Result<String, String> {
+    if path.contains('/') {
+        return Ok(path.to_owned());
+    }
+
+    let search = env::var("PATH").unwrap_or_else(|_| DEFAULT_PATH.to_owned());
+    search
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(path))
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| candidate.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("Command '{}' not found", path))
+}
+
+// Find the allowlist entry that permits this command line
+pub fn check_allowed<'a>(
+    config: &'a CommandConfig,
+    path: &str,
+    args: &[String],
+) -> Result<&'a AllowedCommand, String> {
+    let mut entries = config.allow.iter().filter(|entry| entry.path == path).peekable();
+    if entries.peek().is_none() {
+        return Err(format!("Command {} is not allowed", path));
+    }
+
+    // Also split at '=' to catch options like --file=../x
+    if let Some(arg) = args
+        .iter()
+        .find(|arg| arg.split(&['/', '='][..]).any(|part| part == ".."))
+    {
+        return Err(format!("Argument {} must not contain '..'", arg));
+    }
+
+    entries
+        .find(|entry| {
+            args.iter()
+                .all(|arg| entry.args.iter().any(|pattern| glob::matches_path(pattern, arg)))
+        })
+        .ok_or_else(|| format!("Arguments not allowed for command {}", path))
+}
+
+// Where to send one of the command's output streams.  A file has to be in the output
+// directory, and a symlink there isn't followed.
+fn output_to(config: &CommandConfig, file: Option<String>, name: &str) -> Result<Stdio, String> {
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(Stdio::piped()),
+    };
+
+    let path = Path::new(&file);
+    if !path.starts_with(&config.output_dir)
+        || path == config.output_dir
+        || path.components().any(|part| part == Component::ParentDir)
+    {
+        return Err(format!("The {} file must be in {}", name, config.output_dir.display()));
+    }
+
+    fs::create_dir_all(&config.output_dir)
+        .and_then(|_| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(path)
+        })
+        .map(Stdio::from)
+        .map_err(|_| format!("Failed to open {} file", name))
+}
+
+// Read an output stream on its own thread, keeping up to `max` bytes.  The rest is
+// read and discarded so the command doesn't block on a full pipe.
+fn capture<R: Read + Send + 'static>(mut stream: R, max: usize) -> JoinHandle<(Vec<u8>, bool)> {
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut truncated = false;
+        let mut buffer = [0; 4096];
+
+        loop {
+            let num = match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(num) => num,
+            };
+            let room = max.saturating_sub(kept.len());
+            if num > room {
+                truncated = true;
+            }
+            kept.extend_from_slice(&buffer[0..num.min(room)]);
+        }
+
+        (kept, truncated)
+    })
+}
+
+fn finish_capture(reader: Option<JoinHandle<(Vec<u8>, bool)>>) -> (String, bool) {
+    match reader.and_then(|reader| reader.join().ok()) {
+        Some((data, truncated)) => (String::from_utf8_lossy(&data).to_string(), truncated),
+        None => (String::new(), false),
+    }
+}
+
+fn command_status(status: Option<ExitStatus>, timed_out: bool) -> CommandStatus {
+    CommandStatus {
+        exit_code: status.and_then(|status| status.code()),
+        signal: status.and_then(|status| status.signal()),
+        timed_out,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn config(allow: &[(&str, &[&str])]) -> CommandConfig {
+        CommandConfig {
+            allow: allow
+                .iter()
+                .map(|&(path, args)| AllowedCommand {
+                    path: path.to_owned(),
+                    args: args.iter().map(|arg| arg.to_string()).collect(),
+                    timeout: None,
+                })
+                .collect(),
+            timeout: Duration::from_secs(30),
+            max_output: 1024,
+            max_memory: 0,
+            job_timeout: Duration::from_secs(60),
+            max_job_output: 1024,
+            max_jobs: 1,
+            output_dir: PathBuf::from("/tmp/output"),
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_commands_without_args() {
+        let config = config(&[("/bin/ps", &[])]);
+        assert!(check_allowed(&config, "/bin/ps", &[]).is_ok());
+        assert!(check_allowed(&config, "/bin/ps", &args(&["aux"])).is_err());
+        assert!(check_allowed(&config, "/bin/sh", &[]).is_err());
+    }
+
+    #[test]
+    fn picks_the_entry_that_allows_every_arg() {
+        let config = config(&[
+            ("/bin/ls", &["-*"]),
+            ("/bin/ls", &["/home/*"]),
+            ("/bin/cat", &["/var/log/**"]),
+        ]);
+        assert!(check_allowed(&config, "/bin/ls", &args(&["-l", "-a"])).is_ok());
+        assert!(check_allowed(&config, "/bin/ls", &args(&["/home/user"])).is_ok());
+        // Each entry allows one of these but neither allows both
+        assert!(check_allowed(&config, "/bin/ls", &args(&["-l", "/home/user"])).is_err());
+        assert!(check_allowed(&config, "/bin/ls", &args(&["/etc"])).is_err());
+
+        let allowed = check_allowed(&config, "/bin/cat", &args(&["/var/log/dora/radio.log"])).unwrap();
+        assert_eq!(allowed.path, "/bin/cat");
+    }
+
+    #[test]
+    fn redirects_output_only_to_the_output_dir() {
+        let config = config(&[]);
+        let file = |path: &str| Some(path.to_owned());
+        assert!(output_to(&config, None, "stdout").is_ok());
+        assert!(output_to(&config, file("/etc/passwd"), "stdout").is_err());
+        assert!(output_to(&config, file("/tmp/output"), "stdout").is_err());
+        assert!(output_to(&config, file("/tmp/outputs/x"), "stdout").is_err());
+        assert!(output_to(&config, file("/tmp/output/../x"), "stdout").is_err());
+        assert!(output_to(&config, file("output/x"), "stdout").is_err());
+    }
+
+    #[test]
+    fn refuses_path_traversal() {
+        let config = config(&[("/bin/cat", &["/home/*", "/var/log/**", "--file=*"])]);
+        assert!(check_allowed(&config, "/bin/cat", &args(&["/home/notes"])).is_ok());
+        assert!(check_allowed(&config, "/bin/cat", &args(&["/home/user/notes"])).is_err());
+        assert!(check_allowed(&config, "/bin/cat", &args(&["/home/../etc/shadow"])).is_err());
+        assert!(check_allowed(&config, "/bin/cat", &args(&["/var/log/../../etc/shadow"])).is_err());
+        assert!(check_allowed(&config, "/bin/cat", &args(&["/home/.."])).is_err());
+        assert!(check_allowed(&config, "/bin/cat", &args(&["--file=.."])).is_err());
+        // Only whole '..' components count
+        assert!(check_allowed(&config, "/bin/cat", &args(&["/home/notes..txt"])).is_ok());
+    }
+}
@@ -0,0 +1,1333 @@
+use crate::ax25::Address;
+use crate::ccsds;
+use crate::cfdp;
+use crate::fec;
+use crate::ServiceResult;
+use failure::*;
+use log::*;
+use serial;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Defaults used for any setting missing from the [dora-radio-service.serial]
+// section of the config file.  These match the flatsat radio UART.
+const DEFAULT_BUS: &str = "/dev/ttyS2";
+const DEFAULT_BAUD: usize = 115200;
+const DEFAULT_CHAR_SIZE: i64 = 8;
+const DEFAULT_PARITY: &str = "none";
+const DEFAULT_STOP_BITS: i64 = 1;
+const DEFAULT_FLOW_CONTROL: &str = "none";
+const DEFAULT_TIMEOUT_MS: i64 = 100;
+const DEFAULT_MAX_READ: i64 = 48;
+
+const DEFAULT_LINK: &str = "serial";
+const DEFAULT_UDP_LOCAL: &str = "0.0.0.0:8160";
+const DEFAULT_UDP_REMOTE: &str = "127.0.0.1:8161";
+// Read sizes used by the UDP and pseudo-terminal links.  UDP reads have to be big
+// enough for a whole datagram.
+const UDP_READ: usize = 65507;
+const PTY_READ: usize = 1024;
+
+const DEFAULT_FRAMING: &str = "hdlc";
+const DEFAULT_MAX_FRAME: i64 = 4096;
+const DEFAULT_FEC: &str = "none";
+const DEFAULT_INTERLEAVE: i64 = 1;
+const DEFAULT_RANDOMIZE: bool = true;
+
+const DEFAULT_SPACECRAFT_ID: i64 = 0;
+const DEFAULT_FECF: bool = true;
+const DEFAULT_VC: i64 = 0;
+const DEFAULT_IDLE_INTERVAL_MS: i64 = 0;
+const DEFAULT_AX25_SOURCE: &str = "N0CALL-1";
+const DEFAULT_AX25_DESTINATION: &str = "N0CALL";
+const DEFAULT_KISS_PORT: i64 = 0;
+
+// TM frames have to fit a header, an idle packet and a FECF, and the first header
+// pointer only goes up to 2047
+const MIN_TM_FRAME: i64 = 16;
+const MAX_TM_FRAME: i64 = 2048;
+
+const DEFAULT_STAGING_DIR: &str = "/home/system/var/dora-radio-service";
+// Uploads with no new chunks for this long are deleted (two days, so a transfer can
+// survive several missed passes)
+const DEFAULT_UPLOAD_TIMEOUT_S: i64 = 48 * 60 * 60;
+const DEFAULT_MAX_UPLOAD_SIZE: i64 = 256 * 1024 * 1024;
+
+// Off unless turned on in the config file, so a development machine running the
+// service doesn't get rebooted after a week
+const DEFAULT_WATCHDOG_ENABLED: bool = false;
+const DEFAULT_KEEP_ALIVE_TIMEOUT_S: i64 = 7 * 24 * 60 * 60;
+const DEFAULT_WATCHDOG_STATE: &str = "/home/system/var/dora-radio-service/keep-alive.json";
+const DEFAULT_REBOOT_LOG: &str = "/home/system/var/dora-radio-service/reboot.log";
+const DEFAULT_REBOOT_COMMAND: &str = "reboot";
+
+const DEFAULT_AUTH_ENABLED: bool = false;
+const DEFAULT_KEY_FILE: &str = "/home/system/etc/dora-radio-service/uplink.keys";
+const DEFAULT_COUNTER_FILE: &str = "/home/system/var/dora-radio-service/uplink-counter";
+const DEFAULT_COUNTER_RESERVE: i64 = 1000;
+
+const DEFAULT_ARQ_ENABLED: bool = false;
+const DEFAULT_ARQ_WINDOW: i64 = 16;
+// Long enough for a packet and its ACK to cross the link and the ground station software
+const DEFAULT_ARQ_TIMEOUT_MS: i64 = 3000;
+const DEFAULT_ARQ_RETRIES: i64 = 5;
+const DEFAULT_ARQ_QUEUE: i64 = 64;
+
+const DEFAULT_CFDP_ENABLED: bool = false;
+const DEFAULT_CFDP_APID: i64 = 100;
+const DEFAULT_ENTITY_ID: i64 = 1;
+const DEFAULT_GROUND_ID: i64 = 0;
+const DEFAULT_SEGMENT_LEN: i64 = 1024;
+const DEFAULT_MAX_BURST: i64 = 16;
+const DEFAULT_CFDP_ACK_TIMEOUT_S: i64 = 10;
+const DEFAULT_CFDP_NAK_TIMEOUT_S: i64 = 10;
+const DEFAULT_CFDP_LIMIT: i64 = 5;
+// Long enough for a transaction to wait out several missed passes
+const DEFAULT_INACTIVITY_TIMEOUT_S: i64 = 48 * 60 * 60;
+
+const DEFAULT_COMMAND_TIMEOUT_S: i64 = 30;
+const DEFAULT_MAX_OUTPUT: i64 = 64 * 1024;
+const DEFAULT_MAX_MEMORY: i64 = 128 * 1024 * 1024;
+const DEFAULT_JOB_TIMEOUT_S: i64 = 4 * 60 * 60;
+const DEFAULT_MAX_JOB_OUTPUT: i64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_JOBS: i64 = 4;
+const DEFAULT_OUTPUT_DIR: &str = "/home/system/var/dora-radio-service/output";
+
+const DEFAULT_QUEUE_ENTRIES: i64 = 256;
+const DEFAULT_QUEUE_RESULT: i64 = 16 * 1024;
+// Entries more than this late, e.g. because the OBC was off when they were due, are
+// skipped rather than run out of their intended order or context
+const DEFAULT_QUEUE_MAX_LATE_S: i64 = 60 * 60;
+const DEFAULT_QUEUE_TIMEOUT_S: i64 = 60;
+
+const DEFAULT_UPDATE_ENABLED: bool = false;
+const DEFAULT_UPDATE_KEY: &str = "/home/system/etc/dora-radio-service/update.pub";
+const DEFAULT_INSTALL_DIR: &str = "/home/system/usr/local/lib/dora";
+const DEFAULT_KEEP_VERSIONS: i64 = 3;
+const DEFAULT_HEALTH_DELAY_S: i64 = 10;
+const DEFAULT_HEALTH_TIMEOUT_S: i64 = 60;
+
+const DEFAULT_SHELL_ENABLED: bool = false;
+const DEFAULT_SHELL: &str = "/bin/sh";
+const DEFAULT_MAX_SESSIONS: i64 = 2;
+const DEFAULT_IDLE_TIMEOUT_S: i64 = 30 * 60;
+const DEFAULT_SHELL_BUFFER: i64 = 64 * 1024;
+
+// Room left in each frame for the space packet and file data PDU headers around a
+// CFDP file segment
+const SEGMENT_OVERHEAD: usize = 6 + cfdp::MAX_HEADER_LEN + 4;
+// Room left in each frame for the space packet and PDU headers around a CFDP file
+// directive
+const DIRECTIVE_OVERHEAD: usize = 6 + cfdp::MAX_HEADER_LEN;
+
+// Room left in each frame for the space packet header and the GraphQL response wrapped
+// around a base64-encoded file chunk
+const CHUNK_OVERHEAD: usize = 512;
+
+// Upper limit on the read buffer so a typo in the config can't make us allocate
+// something silly for every read
+const MAX_READ_LIMIT: i64 = 4096;
+
+// Baud rates supported by the UART driver
+const BAUD_RATES: [usize; 15] = [
+    110, 300, 600, 1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400, 460800, 500000,
+    921600,
+];
+
+
+// Serial link settings for the radio UART
+//
+// Read from the [dora-radio-service.serial] section of the system config file, e.g.:
+//
+//     [dora-radio-service.serial]
+//     bus = "/dev/ttyS2"
+//     baud_rate = 115200
+//     char_size = 8
+//     parity = "none"         # "none", "odd" or "even"
+//     stop_bits = 1
+//     flow_control = "none"   # "none", "software" or "hardware"
+//     timeout = 100           # milliseconds
+//     max_read = 48           # bytes
+#[derive(Clone, Debug)]
+pub struct SerialConfig {
+    pub bus: String,
+    pub settings: serial::PortSettings,
+    pub timeout: Duration,
+    pub max_read: usize,
+}
+
+impl SerialConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<SerialConfig> {
+
+        let get_str = |key: &str, default: &str| get_str(config, "serial", key, default);
+        let get_int = |key: &str, default: i64| get_int(config, "serial", key, default);
+
+        let bus = get_str("bus", DEFAULT_BUS)?;
+        if bus.is_empty() {
+            bail!("Invalid serial config: 'bus' must not be empty");
+        }
+
+        let baud = get_int("baud_rate", DEFAULT_BAUD as i64)?;
+        let baud_rate = match BAUD_RATES.iter().find(|&&rate| rate as i64 == baud) {
+            Some(&rate) => serial::BaudRate::from_speed(rate),
+            None => bail!("Invalid serial config: unsupported baud_rate {}", baud),
+        };
+
+        let char_size = match get_int("char_size", DEFAULT_CHAR_SIZE)? {
+            5 => serial::Bits5,
+            6 => serial::Bits6,
+            7 => serial::Bits7,
+            8 => serial::Bits8,
+            other => bail!("Invalid serial config: char_size must be 5-8, not {}", other),
+        };
+
+        let parity = match get_str("parity", DEFAULT_PARITY)?.to_lowercase().as_str() {
+            "none" => serial::ParityNone,
+            "odd" => serial::ParityOdd,
+            "even" => serial::ParityEven,
+            other => bail!("Invalid serial config: unknown parity '{}'", other),
+        };
+
+        let stop_bits = match get_int("stop_bits", DEFAULT_STOP_BITS)? {
+            1 => serial::Stop1,
+            2 => serial::Stop2,
+            other => bail!("Invalid serial config: stop_bits must be 1 or 2, not {}", other),
+        };
+
+        let flow_control = match get_str("flow_control", DEFAULT_FLOW_CONTROL)?
+            .to_lowercase()
+            .as_str()
+        {
+            "none" => serial::FlowNone,
+            "software" => serial::FlowSoftware,
+            "hardware" => serial::FlowHardware,
+            other => bail!("Invalid serial config: unknown flow_control '{}'", other),
+        };
+
+        let timeout = get_int("timeout", DEFAULT_TIMEOUT_MS)?;
+        if timeout <= 0 {
+            bail!("Invalid serial config: timeout must be a positive number of milliseconds");
+        }
+
+        let max_read = get_int("max_read", DEFAULT_MAX_READ)?;
+        if max_read <= 0 || max_read > MAX_READ_LIMIT {
+            bail!(
+                "Invalid serial config: max_read must be between 1 and {} bytes",
+                MAX_READ_LIMIT
+            );
+        }
+
+        Ok(SerialConfig {
+            bus,
+            settings: serial::PortSettings {
+                baud_rate,
+                char_size,
+                parity,
+                stop_bits,
+                flow_control,
+            },
+            timeout: Duration::from_millis(timeout as u64),
+            max_read: max_read as usize,
+        })
+    }
+}
+
+// Which transport carries the radio byte stream (see link.rs)
+#[derive(Clone, Debug)]
+pub enum LinkType {
+    Serial(SerialConfig),
+    Udp { local: SocketAddr, remote: SocketAddr },
+    Pty { link: Option<String> },
+}
+
+// Radio link settings
+//
+// The transport is picked by the [dora-radio-service.link] section, and its own
+// settings come from the section of the same name, e.g.:
+//
+//     [dora-radio-service.link]
+//     type = "udp"            # "serial", "udp" or "pty"
+//
+//     [dora-radio-service.udp]
+//     local = "0.0.0.0:8160"  # uplink bytes are received here
+//     remote = "127.0.0.1:8161" # downlink bytes are sent here
+//     timeout = 100           # milliseconds
+//
+//     [dora-radio-service.pty]
+//     link = "/tmp/dora-radio" # optional symlink to the slave device
+//     timeout = 100           # milliseconds
+//
+// The serial link keeps its settings in [dora-radio-service.serial] (see SerialConfig).
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    pub link_type: LinkType,
+    pub timeout: Duration,
+    pub max_read: usize,
+}
+
+impl LinkConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<LinkConfig> {
+
+        match get_str(config, "link", "type", DEFAULT_LINK)?.to_lowercase().as_str() {
+            "serial" => {
+                let serial = SerialConfig::new(config)?;
+                Ok(LinkConfig {
+                    timeout: serial.timeout,
+                    max_read: serial.max_read,
+                    link_type: LinkType::Serial(serial),
+                })
+            }
+            "udp" => {
+                let local = get_addr(config, "udp", "local", DEFAULT_UDP_LOCAL)?;
+                let remote = get_addr(config, "udp", "remote", DEFAULT_UDP_REMOTE)?;
+                Ok(LinkConfig {
+                    link_type: LinkType::Udp { local, remote },
+                    timeout: get_timeout(config, "udp")?,
+                    max_read: UDP_READ,
+                })
+            }
+            "pty" => {
+                let link = get_str(config, "pty", "link", "")?;
+                Ok(LinkConfig {
+                    link_type: LinkType::Pty {
+                        link: if link.is_empty() { None } else { Some(link) },
+                    },
+                    timeout: get_timeout(config, "pty")?,
+                    max_read: PTY_READ,
+                })
+            }
+            other => bail!("Invalid link config: unknown type '{}'", other),
+        }
+    }
+}
+
+// How packets are delimited on the serial link
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FramingMode {
+    // No framing: a packet ends when a read comes up short or times out.  Only kept
+    // for talking to older ground software.
+    None,
+    // HDLC-style flags, byte stuffing and a 16-bit FCS (see framing.rs)
+    Hdlc,
+    // CCSDS TC frames in CLTUs up, TM frames down (see ccsds.rs)
+    Ccsds,
+    // AX.25 UI frames to and from a KISS TNC (see ax25.rs)
+    Ax25,
+}
+
+// Error correction applied to the framed byte stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FecMode {
+    None,
+    // CCSDS RS(255,223) code blocks with sync markers (see fec.rs)
+    ReedSolomon,
+}
+
+// Framing layer settings
+//
+// Read from the [dora-radio-service.framing] section of the system config file, e.g.:
+//
+//     [dora-radio-service.framing]
+//     mode = "hdlc"           # "hdlc", "ccsds", "ax25" or "none"
+//     max_frame = 4096        # largest packet accepted, in bytes
+//     fec = "rs"              # "rs" or "none"
+//     interleave = 4          # RS interleaving depth: 1, 2, 3, 4, 5 or 8
+//     randomize = true        # apply the CCSDS pseudo-random sequence to RS blocks
+//
+// Error correction needs HDLC or CCSDS framing, since packets are found in the
+// decoded stream by their flags or frame headers.  It can't be used with AX.25, where
+// the TNC does the coding.  In CCSDS mode it only applies to
+// the downlink, with each TM frame filling one code block; the uplink CLTUs have
+// their own BCH code.  Convolutional coding is left to the radio or modem.
+#[derive(Clone, Debug)]
+pub struct FramingConfig {
+    pub mode: FramingMode,
+    pub max_frame: usize,
+    pub fec: FecMode,
+    pub interleave: usize,
+    pub randomize: bool,
+    pub ccsds: CcsdsConfig,
+    pub ax25: Ax25Config,
+}
+
+impl FramingConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<FramingConfig> {
+
+        let mode = match get_str(config, "framing", "mode", DEFAULT_FRAMING)?
+            .to_lowercase()
+            .as_str()
+        {
+            "none" => FramingMode::None,
+            "hdlc" => FramingMode::Hdlc,
+            "ccsds" => FramingMode::Ccsds,
+            "ax25" => FramingMode::Ax25,
+            other => bail!("Invalid framing config: unknown mode '{}'", other),
+        };
+
+        let max_frame = get_int(config, "framing", "max_frame", DEFAULT_MAX_FRAME)?;
+        if max_frame <= 0 || max_frame > 65535 {
+            bail!("Invalid framing config: max_frame must be between 1 and 65535 bytes");
+        }
+
+        let fec = match get_str(config, "framing", "fec", DEFAULT_FEC)?
+            .to_lowercase()
+            .as_str()
+        {
+            "none" => FecMode::None,
+            "rs" => FecMode::ReedSolomon,
+            other => bail!("Invalid framing config: unknown fec '{}'", other),
+        };
+        if fec != FecMode::None && (mode == FramingMode::None || mode == FramingMode::Ax25) {
+            bail!("Invalid framing config: fec needs 'hdlc' or 'ccsds' framing");
+        }
+
+        let interleave = get_int(config, "framing", "interleave", DEFAULT_INTERLEAVE)?;
+        if !fec::DEPTHS.contains(&(interleave as usize)) {
+            bail!("Invalid framing config: interleave must be one of {:?}", fec::DEPTHS);
+        }
+
+        let randomize = get_bool(config, "framing", "randomize", DEFAULT_RANDOMIZE)?;
+
+        let ccsds = CcsdsConfig::new(config, fec, interleave as usize)?;
+        let ax25 = Ax25Config::new(config)?;
+
+        Ok(FramingConfig {
+            mode,
+            max_frame: max_frame as usize,
+            fec,
+            interleave: interleave as usize,
+            randomize,
+            ccsds,
+            ax25,
+        })
+    }
+}
+
+// CCSDS frame settings, used in "ccsds" framing mode
+//
+// Read from the [dora-radio-service.ccsds] section of the system config file, with a
+// [[dora-radio-service.ccsds.vc]] entry for each virtual channel that packets are
+// sent on by APID, e.g.:
+//
+//     [dora-radio-service.ccsds]
+//     spacecraft_id = 42
+//     frame_len = 892         # TM frame length; must be 223 * interleave with RS coding
+//     fecf = true             # CRC on the end of TM and TC frames
+//     default_vc = 0          # virtual channel for APIDs not listed below
+//     idle_interval = 0       # ms of downlink silence before an idle frame is sent
+//                             # (0 for no idle frames)
+//
+//     [[dora-radio-service.ccsds.vc]]
+//     id = 1
+//     apids = [2]             # the comms service uses APID 2 for GraphQL
+#[derive(Clone, Debug)]
+pub struct CcsdsConfig {
+    pub spacecraft_id: u16,
+    pub frame_len: usize,
+    pub fecf: bool,
+    pub default_vc: u8,
+    pub vc_apids: HashMap<u16, u8>,
+    pub idle_interval: Option<Duration>,
+}
+
+impl CcsdsConfig {
+
+    pub fn new(config: &kubos_system::Config, fec: FecMode, interleave: usize) -> ServiceResult<CcsdsConfig> {
+
+        let spacecraft_id = get_int(config, "ccsds", "spacecraft_id", DEFAULT_SPACECRAFT_ID)?;
+        if spacecraft_id < 0 || spacecraft_id > 1023 {
+            bail!("Invalid ccsds config: spacecraft_id must be between 0 and 1023");
+        }
+
+        let block_data = (fec::DATA_LEN * interleave) as i64;
+        let frame_len = get_int(config, "ccsds", "frame_len", block_data)?;
+        if fec == FecMode::ReedSolomon && frame_len != block_data {
+            bail!("Invalid ccsds config: frame_len must be {} to fill an RS block with interleave {}", block_data, interleave);
+        }
+        if frame_len < MIN_TM_FRAME || frame_len > MAX_TM_FRAME {
+            bail!("Invalid ccsds config: frame_len must be between {} and {} bytes", MIN_TM_FRAME, MAX_TM_FRAME);
+        }
+
+        let fecf = get_bool(config, "ccsds", "fecf", DEFAULT_FECF)?;
+
+        let default_vc = get_int(config, "ccsds", "default_vc", DEFAULT_VC)?;
+        if default_vc < 0 || default_vc >= i64::from(ccsds::IDLE_VCID) {
+            bail!("Invalid ccsds config: default_vc must be between 0 and {}", ccsds::IDLE_VCID - 1);
+        }
+
+        let idle_interval = get_int(config, "ccsds", "idle_interval", DEFAULT_IDLE_INTERVAL_MS)?;
+        if idle_interval < 0 {
+            bail!("Invalid ccsds config: idle_interval must not be negative");
+        }
+
+        let entries = match config.get("ccsds").and_then(|table| table.get("vc").cloned()) {
+            None => vec![],
+            Some(val) => match val.as_array() {
+                Some(entries) => entries.clone(),
+                None => bail!("Invalid ccsds config: 'vc' must be an array of tables"),
+            },
+        };
+
+        let mut vc_apids = HashMap::new();
+        for (num, entry) in entries.iter().enumerate() {
+            let id = match entry.get("id").and_then(|id| id.as_integer()) {
+                Some(id) if id >= 0 && id < i64::from(ccsds::IDLE_VCID) => id as u8,
+                _ => bail!("Invalid ccsds config: vc {} needs an 'id' between 0 and {}", num + 1, ccsds::IDLE_VCID - 1),
+            };
+
+            let apids = entry
+                .get("apids")
+                .and_then(|apids| apids.as_array())
+                .and_then(|apids| apids.iter().map(|apid| apid.as_integer()).collect::<Option<Vec<i64>>>())
+                .filter(|apids| apids.iter().all(|apid| *apid >= 0 && *apid < 0x7FF))
+                .ok_or_else(|| format_err!("Invalid ccsds config: 'apids' for vc {} must be a list of APIDs below 2047", id))?;
+            for apid in apids {
+                if vc_apids.insert(apid as u16, id).is_some() {
+                    bail!("Invalid ccsds config: APID {} is on more than one virtual channel", apid);
+                }
+            }
+        }
+
+        Ok(CcsdsConfig {
+            spacecraft_id: spacecraft_id as u16,
+            frame_len: frame_len as usize,
+            fecf,
+            default_vc: default_vc as u8,
+            vc_apids,
+            idle_interval: if idle_interval > 0 {
+                Some(Duration::from_millis(idle_interval as u64))
+            } else {
+                None
+            },
+        })
+    }
+}
+
+// File transfer settings
+//
+// Read from the [dora-radio-service.transfer] section of the system config file, e.g.:
+//
+//     [dora-radio-service.transfer]
+//     staging_dir = "/home/system/var/dora-radio-service"
+//     chunk_size = 2688       # bytes; defaults to the most that fits in one frame
+//     upload_timeout = 172800 # seconds without a new chunk before an upload is dropped
+//     max_upload_size = 268435456 # bytes; larger uploads and patched files are refused
+#[derive(Clone, Debug)]
+pub struct TransferConfig {
+    pub staging_dir: PathBuf,
+    pub chunk_size: usize,
+    pub upload_timeout: Duration,
+    pub max_upload_size: u64,
+}
+
+impl TransferConfig {
+
+    pub fn new(config: &kubos_system::Config, framing: &FramingConfig) -> ServiceResult<TransferConfig> {
+
+        // Largest chunk whose base64 encoding still fits in a single frame, rounded
+        // down to a multiple of 3 bytes so the encoding needs no padding
+        let max_chunk = framing.max_frame.saturating_sub(CHUNK_OVERHEAD) / 4 * 3;
+        if max_chunk == 0 {
+            bail!(
+                "Invalid framing config: max_frame must be more than {} bytes to carry file chunks",
+                CHUNK_OVERHEAD
+            );
+        }
+
+        let staging_dir = get_str(config, "transfer", "staging_dir", DEFAULT_STAGING_DIR)?;
+        if staging_dir.is_empty() {
+            bail!("Invalid transfer config: 'staging_dir' must not be empty");
+        }
+
+        let chunk_size = get_int(config, "transfer", "chunk_size", max_chunk as i64)?;
+        if chunk_size <= 0 || chunk_size as usize > max_chunk {
+            bail!(
+                "Invalid transfer config: chunk_size must be between 1 and {} bytes for a max_frame of {}",
+                max_chunk,
+                framing.max_frame
+            );
+        }
+
+        let upload_timeout = get_int(config, "transfer", "upload_timeout", DEFAULT_UPLOAD_TIMEOUT_S)?;
+        if upload_timeout <= 0 {
+            bail!("Invalid transfer config: upload_timeout must be a positive number of seconds");
+        }
+
+        let max_upload_size = get_int(config, "transfer", "max_upload_size", DEFAULT_MAX_UPLOAD_SIZE)?;
+        if max_upload_size <= 0 {
+            bail!("Invalid transfer config: max_upload_size must be a positive number of bytes");
+        }
+
+        Ok(TransferConfig {
+            staging_dir: PathBuf::from(staging_dir),
+            chunk_size: chunk_size as usize,
+            upload_timeout: Duration::from_secs(upload_timeout as u64),
+            max_upload_size: max_upload_size as u64,
+        })
+    }
+}
+
+// Keep-alive watchdog settings
+//
+// Read from the [dora-radio-service.watchdog] section of the system config file, e.g.:
+//
+//     [dora-radio-service.watchdog]
+//     enabled = true
+//     timeout = 604800        # seconds without a keepAlive before rebooting
+//     state_file = "/home/system/var/dora-radio-service/keep-alive.json"
+//     reboot_log = "/home/system/var/dora-radio-service/reboot.log"
+//     reboot_command = "reboot"
+//
+// The state file and reboot log must be on persistent storage.
+#[derive(Clone, Debug)]
+pub struct WatchdogConfig {
+    pub enabled: bool,
+    pub timeout: Duration,
+    pub state_file: PathBuf,
+    pub reboot_log: PathBuf,
+    pub reboot_command: String,
+}
+
+impl WatchdogConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<WatchdogConfig> {
+
+        let enabled = get_bool(config, "watchdog", "enabled", DEFAULT_WATCHDOG_ENABLED)?;
+
+        let timeout = get_int(config, "watchdog", "timeout", DEFAULT_KEEP_ALIVE_TIMEOUT_S)?;
+        if timeout <= 0 {
+            bail!("Invalid watchdog config: timeout must be a positive number of seconds");
+        }
+
+        let state_file = get_str(config, "watchdog", "state_file", DEFAULT_WATCHDOG_STATE)?;
+        let reboot_log = get_str(config, "watchdog", "reboot_log", DEFAULT_REBOOT_LOG)?;
+        let reboot_command = get_str(config, "watchdog", "reboot_command", DEFAULT_REBOOT_COMMAND)?;
+        if state_file.is_empty() || reboot_log.is_empty() || reboot_command.is_empty() {
+            bail!("Invalid watchdog config: state_file, reboot_log and reboot_command must not be empty");
+        }
+
+        Ok(WatchdogConfig {
+            enabled,
+            timeout: Duration::from_secs(timeout as u64),
+            state_file: PathBuf::from(state_file),
+            reboot_log: PathBuf::from(reboot_log),
+            reboot_command,
+        })
+    }
+}
+
+// Uplink authentication settings (see auth.rs)
+//
+// Read from the [dora-radio-service.auth] section of the system config file, e.g.:
+//
+//     [dora-radio-service.auth]
+//     enabled = true
+//     key_file = "/home/system/etc/dora-radio-service/uplink.keys"
+//     counter_file = "/home/system/var/dora-radio-service/uplink-counter"
+//     counter_reserve = 1000  # counter values reserved with each save
+//
+// The key file must only be readable by the service user.  The counter file must be
+// on persistent storage, or replays become possible after a reboot.  After a restart
+// the ground has to jump its counter ahead by up to counter_reserve.
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    pub key_file: PathBuf,
+    pub counter_file: PathBuf,
+    pub counter_reserve: u64,
+}
+
+impl AuthConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<AuthConfig> {
+
+        let enabled = get_bool(config, "auth", "enabled", DEFAULT_AUTH_ENABLED)?;
+        let key_file = get_str(config, "auth", "key_file", DEFAULT_KEY_FILE)?;
+        let counter_file = get_str(config, "auth", "counter_file", DEFAULT_COUNTER_FILE)?;
+        if key_file.is_empty() || counter_file.is_empty() {
+            bail!("Invalid auth config: key_file and counter_file must not be empty");
+        }
+        let counter_reserve =
+            get_int(config, "auth", "counter_reserve", DEFAULT_COUNTER_RESERVE)?;
+        if counter_reserve <= 0 {
+            bail!("Invalid auth config: counter_reserve must be positive");
+        }
+
+        Ok(AuthConfig {
+            enabled,
+            key_file: PathBuf::from(key_file),
+            counter_file: PathBuf::from(counter_file),
+            counter_reserve: counter_reserve as u64,
+        })
+    }
+}
+
+// Selective-repeat ARQ settings (see arq.rs)
+//
+// Read from the [dora-radio-service.arq] section of the system config file, e.g.:
+//
+//     [dora-radio-service.arq]
+//     enabled = true
+//     window = 16             # packets sent but not yet acknowledged
+//     timeout = 3000          # ms to wait for an ACK before sending again
+//     max_retries = 5         # retransmissions before a packet is given up on
+//     max_queue = 64          # packets waiting for room in the window
+//
+// ARQ can't be used with CCSDS framing, whose TM frames have to carry plain space
+// packets.
+#[derive(Clone, Debug)]
+pub struct ArqConfig {
+    pub enabled: bool,
+    pub window: usize,
+    pub timeout: Duration,
+    pub max_retries: u32,
+    pub max_queue: usize,
+}
+
+impl ArqConfig {
+
+    pub fn new(config: &kubos_system::Config, framing: &FramingConfig) -> ServiceResult<ArqConfig> {
+
+        let enabled = get_bool(config, "arq", "enabled", DEFAULT_ARQ_ENABLED)?;
+        if enabled && framing.mode == FramingMode::Ccsds {
+            bail!("Invalid arq config: ARQ needs 'hdlc', 'ax25' or 'none' framing");
+        }
+
+        // Half the sequence number space at most, so old and new packets can't be confused
+        let window = get_int(config, "arq", "window", DEFAULT_ARQ_WINDOW)?;
+        if window < 1 || window > 32768 {
+            bail!("Invalid arq config: window must be between 1 and 32768 packets");
+        }
+
+        let timeout = get_int(config, "arq", "timeout", DEFAULT_ARQ_TIMEOUT_MS)?;
+        if timeout <= 0 {
+            bail!("Invalid arq config: timeout must be a positive number of milliseconds");
+        }
+
+        let max_retries = get_int(config, "arq", "max_retries", DEFAULT_ARQ_RETRIES)?;
+        if max_retries < 0 || max_retries > 255 {
+            bail!("Invalid arq config: max_retries must be between 0 and 255");
+        }
+
+        let max_queue = get_int(config, "arq", "max_queue", DEFAULT_ARQ_QUEUE)?;
+        if max_queue < 1 {
+            bail!("Invalid arq config: max_queue must be at least 1");
+        }
+
+        Ok(ArqConfig {
+            enabled,
+            window: window as usize,
+            timeout: Duration::from_millis(timeout as u64),
+            max_retries: max_retries as u32,
+            max_queue: max_queue as usize,
+        })
+    }
+}
+
+// CFDP entity settings (see cfdp.rs)
+//
+// Read from the [dora-radio-service.cfdp] section of the system config file, e.g.:
+//
+//     [dora-radio-service.cfdp]
+//     enabled = true
+//     apid = 100              # APID of the space packets that carry CFDP PDUs
+//     entity_id = 1           # our CFDP entity ID
+//     ground_id = 0           # entity ID that cfdpPut sends files to
+//     segment_len = 1024      # bytes of file data per PDU
+//     max_burst = 16          # file data PDUs sent per second
+//     ack_timeout = 10        # seconds before an unacknowledged EOF or Finished is resent
+//     ack_limit = 5           # resends before the transaction is suspended
+//     nak_timeout = 10        # seconds before missing file data is asked for again
+//     nak_limit = 5           # NAKs before the transaction is suspended
+//     inactivity_timeout = 172800 # seconds without a PDU from the other entity
+//                             # before a transaction is abandoned
+//
+// The APID must not be one the comms service uses (1 for UDP, 2 for GraphQL), since
+// packets with it never reach the comms service.
+//
+// max_directive isn't read from the file: it's the longest directive PDU body that fits
+// in a frame, which limits the segment requests in a NAK and the file names in a
+// metadata PDU.
+#[derive(Clone, Debug)]
+pub struct CfdpConfig {
+    pub enabled: bool,
+    pub apid: u16,
+    pub entity_id: u64,
+    pub ground_id: u64,
+    pub segment_len: usize,
+    pub max_directive: usize,
+    pub max_burst: usize,
+    pub ack_timeout: Duration,
+    pub ack_limit: u32,
+    pub nak_timeout: Duration,
+    pub nak_limit: u32,
+    pub inactivity_timeout: Duration,
+}
+
+impl CfdpConfig {
+
+    pub fn new(config: &kubos_system::Config, framing: &FramingConfig) -> ServiceResult<CfdpConfig> {
+
+        let enabled = get_bool(config, "cfdp", "enabled", DEFAULT_CFDP_ENABLED)?;
+
+        let apid = get_int(config, "cfdp", "apid", DEFAULT_CFDP_APID)?;
+        if apid < 3 || apid > 2046 {
+            bail!("Invalid cfdp config: apid must be between 3 and 2046");
+        }
+
+        let entity_id = get_int(config, "cfdp", "entity_id", DEFAULT_ENTITY_ID)?;
+        let ground_id = get_int(config, "cfdp", "ground_id", DEFAULT_GROUND_ID)?;
+        if entity_id < 0 || ground_id < 0 || entity_id == ground_id {
+            bail!("Invalid cfdp config: entity_id and ground_id must be different and not negative");
+        }
+
+        let max_directive = framing.max_frame.saturating_sub(DIRECTIVE_OVERHEAD);
+        if max_directive < cfdp::MIN_DIRECTIVE_LEN {
+            bail!(
+                "Invalid cfdp config: max_frame must be at least {} bytes to carry CFDP PDUs",
+                DIRECTIVE_OVERHEAD + cfdp::MIN_DIRECTIVE_LEN
+            );
+        }
+
+        let max_segment = framing.max_frame.saturating_sub(SEGMENT_OVERHEAD);
+        let segment_len = get_int(config, "cfdp", "segment_len", DEFAULT_SEGMENT_LEN.min(max_segment as i64))?;
+        if segment_len <= 0 || segment_len as usize > max_segment {
+            bail!(
+                "Invalid cfdp config: segment_len must be between 1 and {} bytes for a max_frame of {}",
+                max_segment,
+                framing.max_frame
+            );
+        }
+
+        let max_burst = get_int(config, "cfdp", "max_burst", DEFAULT_MAX_BURST)?;
+        if max_burst <= 0 {
+            bail!("Invalid cfdp config: max_burst must be at least 1");
+        }
+
+        let ack_timeout = get_int(config, "cfdp", "ack_timeout", DEFAULT_CFDP_ACK_TIMEOUT_S)?;
+        let nak_timeout = get_int(config, "cfdp", "nak_timeout", DEFAULT_CFDP_NAK_TIMEOUT_S)?;
+        let inactivity_timeout = get_int(config, "cfdp", "inactivity_timeout", DEFAULT_INACTIVITY_TIMEOUT_S)?;
+        if ack_timeout <= 0 || nak_timeout <= 0 || inactivity_timeout <= 0 {
+            bail!("Invalid cfdp config: timeouts must be a positive number of seconds");
+        }
+
+        let ack_limit = get_int(config, "cfdp", "ack_limit", DEFAULT_CFDP_LIMIT)?;
+        let nak_limit = get_int(config, "cfdp", "nak_limit", DEFAULT_CFDP_LIMIT)?;
+        if ack_limit < 0 || nak_limit < 0 {
+            bail!("Invalid cfdp config: ack_limit and nak_limit must not be negative");
+        }
+
+        Ok(CfdpConfig {
+            enabled,
+            apid: apid as u16,
+            entity_id: entity_id as u64,
+            ground_id: ground_id as u64,
+            segment_len: segment_len as usize,
+            max_directive,
+            max_burst: max_burst as usize,
+            ack_timeout: Duration::from_secs(ack_timeout as u64),
+            ack_limit: ack_limit as u32,
+            nak_timeout: Duration::from_secs(nak_timeout as u64),
+            nak_limit: nak_limit as u32,
+            inactivity_timeout: Duration::from_secs(inactivity_timeout as u64),
+        })
+    }
+}
+
+// A command run_command is allowed to run
+#[derive(Clone, Debug)]
+pub struct AllowedCommand {
+    // Full path of the executable
+    pub path: String,
+    // Wildcard patterns matched as paths (see glob.rs), one of which every argument
+    // must match
+    pub args: Vec<String>,
+    // Overrides the default timeout for this command
+    pub timeout: Option<Duration>,
+}
+
+// run_command settings (see command.rs)
+//
+// Read from the [dora-radio-service.command] section of the system config file, with
+// one [[dora-radio-service.command.allow]] entry per allowed command, e.g.:
+//
+//     [dora-radio-service.command]
+//     timeout = 30            # seconds before the command's process group is killed
+//     max_output = 65536      # bytes of stdout and of stderr kept
+//     max_memory = 134217728  # bytes of address space; 0 for no limit
+//     job_timeout = 14400     # seconds before a background job is killed
+//     max_job_output = 16777216 # bytes a background job may write to stdout or stderr
+//     max_jobs = 4            # background jobs running at once
+//     output_dir = "/home/system/var/dora-radio-service/output"
+//                             # where run_command may redirect stdout and stderr
+//
+//     [[dora-radio-service.command.allow]]
+//     path = "/bin/ls"
+//     args = ["-*", "/home/*"]
+//
+//     [[dora-radio-service.command.allow]]
+//     path = "/usr/bin/fw_update"
+//     args = ["/home/system/firmware/**"]
+//     timeout = 600
+//
+// A command with no `args` can only be run without arguments.  In `args`, '*' doesn't
+// match '/', so "/home/*" allows files directly in /home, while '**' matches across
+// directories.  Arguments with a '..' component are always refused.  With no allow
+// entries at all, run_command refuses everything.
+#[derive(Clone, Debug)]
+pub struct CommandConfig {
+    pub allow: Vec<AllowedCommand>,
+    pub timeout: Duration,
+    pub max_output: usize,
+    pub max_memory: u64,
+    pub job_timeout: Duration,
+    pub max_job_output: u64,
+    pub max_jobs: usize,
+    pub output_dir: PathBuf,
+}
+
+impl CommandConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<CommandConfig> {
+
+        let timeout = get_int(config, "command", "timeout", DEFAULT_COMMAND_TIMEOUT_S)?;
+        if timeout <= 0 {
+            bail!("Invalid command config: timeout must be a positive number of seconds");
+        }
+
+        let max_output = get_int(config, "command", "max_output", DEFAULT_MAX_OUTPUT)?;
+        if max_output < 0 {
+            bail!("Invalid command config: max_output must not be negative");
+        }
+
+        let max_memory = get_int(config, "command", "max_memory", DEFAULT_MAX_MEMORY)?;
+        if max_memory < 0 {
+            bail!("Invalid command config: max_memory must not be negative");
+        }
+
+        let job_timeout = get_int(config, "command", "job_timeout", DEFAULT_JOB_TIMEOUT_S)?;
+        if job_timeout <= 0 {
+            bail!("Invalid command config: job_timeout must be a positive number of seconds");
+        }
+
+        let max_job_output = get_int(config, "command", "max_job_output", DEFAULT_MAX_JOB_OUTPUT)?;
+        if max_job_output <= 0 {
+            bail!("Invalid command config: max_job_output must be positive");
+        }
+
+        let max_jobs = get_int(config, "command", "max_jobs", DEFAULT_MAX_JOBS)?;
+        if max_jobs <= 0 {
+            bail!("Invalid command config: max_jobs must be positive");
+        }
+
+        let output_dir = get_str(config, "command", "output_dir", DEFAULT_OUTPUT_DIR)?;
+        if !output_dir.starts_with('/') || output_dir.split('/').any(|part| part == "..") {
+            bail!("Invalid command config: 'output_dir' must be an absolute path without '..'");
+        }
+
+        let entries = match config.get("command").and_then(|table| table.get("allow").cloned()) {
+            None => vec![],
+            Some(val) => match val.as_array() {
+                Some(entries) => entries.clone(),
+                None => bail!("Invalid command config: 'allow' must be an array of tables"),
+            },
+        };
+
+        let mut allow = vec![];
+        for (num, entry) in entries.iter().enumerate() {
+            let path = match entry.get("path").and_then(|path| path.as_str()) {
+                Some(path) if path.starts_with('/') => path.to_owned(),
+                _ => bail!("Invalid command config: allow entry {} needs an absolute 'path'", num + 1),
+            };
+
+            let args = match entry.get("args") {
+                None => vec![],
+                Some(args) => args
+                    .as_array()
+                    .and_then(|args| args.iter().map(|arg| arg.as_str().map(str::to_owned)).collect())
+                    .ok_or_else(|| format_err!("Invalid command config: 'args' for {} must be a list of strings", path))?,
+            };
+
+            let timeout = match entry.get("timeout") {
+                None => None,
+                Some(timeout) => match timeout.as_integer() {
+                    Some(timeout) if timeout > 0 => Some(Duration::from_secs(timeout as u64)),
+                    _ => bail!("Invalid command config: 'timeout' for {} must be a positive number of seconds", path),
+                },
+            };
+
+            allow.push(AllowedCommand { path, args, timeout });
+        }
+
+        if allow.is_empty() {
+            warn!("No commands allowed for run_command");
+        }
+
+        Ok(CommandConfig {
+            allow,
+            timeout: Duration::from_secs(timeout as u64),
+            max_output: max_output as usize,
+            max_memory: max_memory as u64,
+            job_timeout: Duration::from_secs(job_timeout as u64),
+            max_job_output: max_job_output as u64,
+            max_jobs: max_jobs as usize,
+            output_dir: PathBuf::from(output_dir),
+        })
+    }
+}
+
+// Shell session settings (see shell.rs)
+//
+// Read from the [dora-radio-service.shell] section of the system config file, e.g.:
+//
+//     [dora-radio-service.shell]
+//     enabled = true
+//     shell = "/bin/sh"
+//     max_sessions = 2
+//     idle_timeout = 1800     # seconds before an unused session is closed
+//     buffer_size = 65536     # bytes of output kept per session
+#[derive(Clone, Debug)]
+pub struct ShellConfig {
+    pub enabled: bool,
+    pub shell: String,
+    pub max_sessions: usize,
+    pub idle_timeout: Duration,
+    pub buffer_size: usize,
+}
+
+impl ShellConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<ShellConfig> {
+
+        let enabled = get_bool(config, "shell", "enabled", DEFAULT_SHELL_ENABLED)?;
+
+        let shell = get_str(config, "shell", "shell", DEFAULT_SHELL)?;
+        if !shell.starts_with('/') {
+            bail!("Invalid shell config: shell must be an absolute path");
+        }
+
+        let max_sessions = get_int(config, "shell", "max_sessions", DEFAULT_MAX_SESSIONS)?;
+        if max_sessions <= 0 {
+            bail!("Invalid shell config: max_sessions must be positive");
+        }
+
+        let idle_timeout = get_int(config, "shell", "idle_timeout", DEFAULT_IDLE_TIMEOUT_S)?;
+        if idle_timeout <= 0 {
+            bail!("Invalid shell config: idle_timeout must be a positive number of seconds");
+        }
+
+        let buffer_size = get_int(config, "shell", "buffer_size", DEFAULT_SHELL_BUFFER)?;
+        if buffer_size <= 0 {
+            bail!("Invalid shell config: buffer_size must be positive");
+        }
+
+        Ok(ShellConfig {
+            enabled,
+            shell,
+            max_sessions: max_sessions as usize,
+            idle_timeout: Duration::from_secs(idle_timeout as u64),
+            buffer_size: buffer_size as usize,
+        })
+    }
+}
+
+// Time-tagged command queue settings (see queue.rs)
+//
+// Read from the [dora-radio-service.queue] section of the system config file, e.g.:
+//
+//     [dora-radio-service.queue]
+//     max_entries = 256       # entries kept, finished ones included
+//     max_result = 16384      # bytes of each entry's result kept
+//     max_late = 3600         # seconds past its time an entry may still run
+//     timeout = 60            # seconds a queued GraphQL request may take
+//
+// Queued commands are subject to the run_command allowlist and limits.  Queued GraphQL
+// requests go to this service's own address unless they name another service.
+#[derive(Clone, Debug)]
+pub struct QueueConfig {
+    pub max_entries: usize,
+    pub max_result: usize,
+    pub max_late: u64,
+    pub timeout: Duration,
+    // "ip:port" of this service's GraphQL endpoint
+    pub service_url: Option<String>,
+}
+
+impl QueueConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<QueueConfig> {
+
+        let max_entries = get_int(config, "queue", "max_entries", DEFAULT_QUEUE_ENTRIES)?;
+        if max_entries <= 0 {
+            bail!("Invalid queue config: max_entries must be positive");
+        }
+
+        let max_result = get_int(config, "queue", "max_result", DEFAULT_QUEUE_RESULT)?;
+        if max_result < 0 {
+            bail!("Invalid queue config: max_result must not be negative");
+        }
+
+        let max_late = get_int(config, "queue", "max_late", DEFAULT_QUEUE_MAX_LATE_S)?;
+        if max_late < 0 {
+            bail!("Invalid queue config: max_late must not be negative");
+        }
+
+        let timeout = get_int(config, "queue", "timeout", DEFAULT_QUEUE_TIMEOUT_S)?;
+        if timeout <= 0 {
+            bail!("Invalid queue config: timeout must be a positive number of seconds");
+        }
+
+        Ok(QueueConfig {
+            max_entries: max_entries as usize,
+            max_result: max_result as usize,
+            max_late: max_late as u64,
+            timeout: Duration::from_secs(timeout as u64),
+            service_url: config.hosturl(),
+        })
+    }
+}
+
+// Something the update subsystem can install
+#[derive(Clone, Debug)]
+pub struct UpdateTarget {
+    pub name: String,
+    // Symlink switched to the directory of the running version
+    pub link: PathBuf,
+    // Command line that restarts the target
+    pub restart: Vec<String>,
+    // Command line that exits with 0 if the target is healthy
+    pub health_check: Vec<String>,
+}
+
+// Software update settings (see update.rs)
+//
+// Read from the [dora-radio-service.update] section of the system config file, with
+// one [[dora-radio-service.update.target]] entry per app or service that can be
+// updated, e.g.:
+//
+//     [dora-radio-service.update]
+//     enabled = true
+//     public_key_file = "/home/system/etc/dora-radio-service/update.pub"
+//     install_dir = "/home/system/usr/local/lib/dora"
+//     keep_versions = 3       # installed versions kept per target
+//     health_delay = 10       # seconds between restarting and checking a target
+//     health_timeout = 60     # seconds the restart and health commands may take
+//
+//     [[dora-radio-service.update.target]]
+//     name = "dora-health-app"
+//     restart = ["/etc/init.d/S90dora-health-app", "restart"]
+//     health_check = ["/bin/pidof", "dora-health-app"]
+//
+// Each version of a target is installed to <install_dir>/<name>/<version>, and the
+// symlink <install_dir>/<name>/current (or `link`, if given) points to the one in use.
+#[derive(Clone, Debug)]
+pub struct UpdateConfig {
+    pub enabled: bool,
+    pub public_key_file: PathBuf,
+    pub install_dir: PathBuf,
+    pub keep_versions: usize,
+    pub health_delay: Duration,
+    pub health_timeout: Duration,
+    pub targets: Vec<UpdateTarget>,
+}
+
+impl UpdateConfig {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<UpdateConfig> {
+
+        let enabled = get_bool(config, "update", "enabled", DEFAULT_UPDATE_ENABLED)?;
+
+        let public_key_file = get_str(config, "update", "public_key_file", DEFAULT_UPDATE_KEY)?;
+        let install_dir = get_str(config, "update", "install_dir", DEFAULT_INSTALL_DIR)?;
+        if !install_dir.starts_with('/') || public_key_file.is_empty() {
+            bail!("Invalid update config: install_dir must be an absolute path and public_key_file must not be empty");
+        }
+        let install_dir = PathBuf::from(install_dir);
+
+        let keep_versions = get_int(config, "update", "keep_versions", DEFAULT_KEEP_VERSIONS)?;
+        if keep_versions < 2 {
+            bail!("Invalid update config: keep_versions must be at least 2, so there is a version to roll back to");
+        }
+
+        let health_delay = get_int(config, "update", "health_delay", DEFAULT_HEALTH_DELAY_S)?;
+        if health_delay < 0 {
+            bail!("Invalid update config: health_delay must not be negative");
+        }
+
+        let health_timeout = get_int(config, "update", "health_timeout", DEFAULT_HEALTH_TIMEOUT_S)?;
+        if health_timeout <= 0 {
+            bail!("Invalid update config: health_timeout must be a positive number of seconds");
+        }
+
+        let entries = match config.get("update").and_then(|table| table.get("target").cloned()) {
+            None => vec![],
+            Some(val) => match val.as_array() {
+                Some(entries) => entries.clone(),
+                None => bail!("Invalid update config: 'target' must be an array of tables"),
+            },
+        };
+
+        let mut targets: Vec<UpdateTarget> = vec![];
+        for (num, entry) in entries.iter().enumerate() {
+            let name = match entry.get("name").and_then(|name| name.as_str()) {
+                Some(name) if !name.is_empty() && !name.contains('/') && !name.starts_with('.') => name.to_owned(),
+                _ => bail!("Invalid update config: target {} needs a 'name' that can be used as a directory name", num + 1),
+            };
+            if targets.iter().any(|target| target.name == name) {
+                bail!("Invalid update config: duplicate target {}", name);
+            }
+
+            let link = match entry.get("link") {
+                None => install_dir.join(&name).join("current"),
+                Some(link) => match link.as_str() {
+                    Some(link) if link.starts_with('/') => PathBuf::from(link),
+                    _ => bail!("Invalid update config: 'link' for {} must be an absolute path", name),
+                },
+            };
+
+            let command = |key: &str| -> ServiceResult<Vec<String>> {
+                entry
+                    .get(key)
+                    .and_then(|command| command.as_array())
+                    .and_then(|command| command.iter().map(|arg| arg.as_str().map(str::to_owned)).collect::<Option<Vec<String>>>())
+                    .filter(|command| command.first().map_or(false, |path| path.starts_with('/')))
+                    .ok_or_else(|| format_err!("Invalid update config: '{}' for {} must be a command line starting with an absolute path", key, name))
+            };
+            let restart = command("restart")?;
+            let health_check = command("health_check")?;
+
+            targets.push(UpdateTarget { name, link, restart, health_check });
+        }
+
+        if enabled && targets.is_empty() {
+            warn!("Software updates are enabled but no update targets are configured");
+        }
+
+        Ok(UpdateConfig {
+            enabled,
+            public_key_file: PathBuf::from(public_key_file),
+            install_dir,
+            keep_versions: keep_versions as usize,
+            health_delay: Duration::from_secs(health_delay as u64),
+            health_timeout: Duration::from_secs(health_timeout as u64),
+            targets,
+        })
+    }
+}
+
+// AX.25 settings, used in "ax25" framing mode
+//
+// Read from the [dora-radio-service.ax25] section of the system config file, e.g.:
+//
+//     [dora-radio-service.ax25]
+//     source = "N0CALL-1"     # our callsign; frames to it are received
+//     destination = "N0CALL"  # the ground station's callsign
+//     kiss_port = 0           # TNC port, for multi-port TNCs
+#[derive(Clone, Debug)]
+pub struct Ax25Config {
+    pub source: Address,
+    pub destination: Address,
+    pub kiss_port: u8,
+}
+
+impl Ax25Config {
+
+    pub fn new(config: &kubos_system::Config) -> ServiceResult<Ax25Config> {
+
+        let source = Address::parse(&get_str(config, "ax25", "source", DEFAULT_AX25_SOURCE)?)
+            .map_err(|err| format_err!("Invalid ax25 config: source {}", err))?;
+        let destination = Address::parse(&get_str(config, "ax25", "destination", DEFAULT_AX25_DESTINATION)?)
+            .map_err(|err| format_err!("Invalid ax25 config: destination {}", err))?;
+
+        let kiss_port = get_int(config, "ax25", "kiss_port", DEFAULT_KISS_PORT)?;
+        if kiss_port < 0 || kiss_port > 15 {
+            bail!("Invalid ax25 config: kiss_port must be between 0 and 15");
+        }
+
+        Ok(Ax25Config {
+            source,
+            destination,
+            kiss_port: kiss_port as u8,
+        })
+    }
+}
+
+// Fetch a string setting from a subsection of the service config, failing if it is
+// present but not a string
+fn get_str(
+    config: &kubos_system::Config,
+    section: &str,
+    key: &str,
+    default: &str,
+) -> ServiceResult<String> {
+    match config.get(section).and_then(|table| table.get(key).cloned()) {
+        None => Ok(default.to_owned()),
+        Some(val) => match val.as_str() {
+            Some(s) => Ok(s.to_owned()),
+            None => bail!("Invalid {} config: '{}' must be a string", section, key),
+        },
+    }
+}
+
+// Fetch a socket address setting ("ip:port") from a subsection of the service config
+fn get_addr(
+    config: &kubos_system::Config,
+    section: &str,
+    key: &str,
+    default: &str,
+) -> ServiceResult<SocketAddr> {
+    let addr = get_str(config, section, key, default)?;
+    addr.parse::<SocketAddr>()
+        .map_err(|_| format_err!("Invalid {} config: '{}' is not an ip:port address", section, addr))
+}
+
+// Fetch the read timeout for a link, in milliseconds
+fn get_timeout(config: &kubos_system::Config, section: &str) -> ServiceResult<Duration> {
+    let timeout = get_int(config, section, "timeout", DEFAULT_TIMEOUT_MS)?;
+    if timeout <= 0 {
+        bail!(
+            "Invalid {} config: timeout must be a positive number of milliseconds",
+            section
+        );
+    }
+    Ok(Duration::from_millis(timeout as u64))
+}
+
+// Fetch an integer setting from a subsection of the service config, failing if it is
+// present but not an integer
+fn get_int(
+    config: &kubos_system::Config,
+    section: &str,
+    key: &str,
+    default: i64,
+) -> ServiceResult<i64> {
+    match config.get(section).and_then(|table| table.get(key).cloned()) {
+        None => Ok(default),
+        Some(val) => match val.as_integer() {
+            Some(i) => Ok(i),
+            None => bail!("Invalid {} config: '{}' must be an integer", section, key),
+        },
+    }
+}
+
+// Fetch a boolean setting from a subsection of the service config, failing if it is
+// present but not a boolean
+fn get_bool(
+    config: &kubos_system::Config,
+    section: &str,
+    key: &str,
+    default: bool,
+) -> ServiceResult<bool> {
+    match config.get(section).and_then(|table| table.get(key).cloned()) {
+        None => Ok(default),
+        Some(val) => match val.as_bool() {
+            Some(b) => Ok(b),
+            None => bail!("Invalid {} config: '{}' must be true or false", section, key),
+        },
+    }
+}
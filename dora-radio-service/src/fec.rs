@@ -0,0 +1,547 @@
+// CCSDS Reed-Solomon coding for the radio link
+//
+// When forward error correction is on, the framed byte stream is cut into code blocks
+// as described in CCSDS 131.0-B (TM Synchronization and Channel Coding):
+//
+//     ASM | randomize(interleave(RS(255,223) codewords))
+//
+// - Each RS(255,223) codeword carries 223 data bytes and 32 check bytes, and can
+//   correct up to 16 bad bytes.  Symbols are in the dual basis, with the CCSDS field
+//   and generator polynomials, so the blocks match other CCSDS equipment.
+// - With interleaving depth I, a block holds I codewords, byte-interleaved so a burst
+//   of up to 16 * I bad bytes is spread over all of them.
+// - The 4-byte attached sync marker (0x1ACFFC1D) marks the start of each block.
+//   Since the UART is byte oriented, markers are only looked for on byte boundaries,
+//   and a few bit errors in a marker are tolerated.
+// - Randomization XORs each block (not the marker) with the CCSDS pseudo-random
+//   sequence, so long runs of identical bytes don't upset the radio's clock recovery.
+//
+// A packet always starts a new block, and the unused end of its last block is padded
+// with a fill byte the framing layer ignores (an HDLC flag).
+//
+// Only the Reed-Solomon code is done here.  The convolutional (inner) code of the
+// concatenated CCSDS scheme works on the bit stream, so it is left to the radio or
+// modem, which is where soft-decision Viterbi decoding can be done anyway.
+
+use std::collections::VecDeque;
+
+pub const ASM: [u8; 4] = [0x1A, 0xCF, 0xFC, 0x1D];
+// Most bit errors allowed in a sync marker before it stops counting as one
+const ASM_TOLERANCE: u32 = 3;
+
+pub const DATA_LEN: usize = 223;
+pub const CODE_LEN: usize = 255;
+const PARITY_LEN: usize = CODE_LEN - DATA_LEN;
+// Interleaving depths allowed by the standard
+pub const DEPTHS: &[usize] = &[1, 2, 3, 4, 5, 8];
+
+// Field generator polynomial x^8 + x^7 + x^2 + x + 1
+const FIELD_POLY: u16 = 0x187;
+// Code generator roots are alpha^(PRIM * (FCR + i)) for i in 0..32
+const FCR: usize = 112;
+const PRIM: usize = 11;
+// Rows of the matrix taking a symbol from the conventional to the dual basis
+const TAL: [u8; 8] = [0x8d, 0xef, 0xec, 0x86, 0xfa, 0x99, 0xaf, 0x7b];
+// Stands for log(0) in the log tables
+const LOG_ZERO: u8 = 255;
+
+// Outcome of decoding some received bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FecStats {
+    // Codewords that had errors, all of which were corrected
+    pub corrected: usize,
+    // Codewords with too many errors to correct
+    pub uncorrectable: usize,
+}
+
+// RS(255,223) over GF(256), as used by CCSDS
+struct ReedSolomon {
+    exp: [u8; 255],
+    log: [u8; 256],
+    // Generator polynomial, constant term first
+    generator: [u8; PARITY_LEN + 1],
+    to_dual: [u8; 256],
+    from_dual: [u8; 256],
+}
+
+impl ReedSolomon {
+
+    fn new() -> ReedSolomon {
+        let mut exp = [0; 255];
+        let mut log = [LOG_ZERO; 256];
+        let mut value: u16 = 1;
+        for (power, entry) in exp.iter_mut().enumerate() {
+            *entry = value as u8;
+            log[value as usize] = power as u8;
+            value <<= 1;
+            if value & 0x100 != 0 {
+                value ^= FIELD_POLY;
+            }
+        }
+
+        let mut rs = ReedSolomon {
+            exp,
+            log,
+            generator: [0; PARITY_LEN + 1],
+            to_dual: [0; 256],
+            from_dual: [0; 256],
+        };
+
+        // Multiply out (x - root) for each root
+        rs.generator[0] = 1;
+        for i in 0..PARITY_LEN {
+            let root = rs.power(PRIM * (FCR + i));
+            for j in (1..=i + 1).rev() {
+                rs.generator[j] = rs.generator[j - 1] ^ rs.mul(rs.generator[j], root);
+            }
+            rs.generator[0] = rs.mul(rs.generator[0], root);
+        }
+
+        for symbol in 0..256 {
+            let mut dual = 0;
+            for (bit, row) in TAL.iter().rev().enumerate() {
+                if symbol & (1 << bit) != 0 {
+                    dual ^= row;
+                }
+            }
+            rs.to_dual[symbol] = dual;
+            rs.from_dual[dual as usize] = symbol as u8;
+        }
+
+        rs
+    }
+
+    // alpha^n
+    fn power(&self, n: usize) -> u8 {
+        self.exp[n % 255]
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[(self.log[a as usize] as usize + self.log[b as usize] as usize) % 255]
+    }
+
+    fn div(&self, a: u8, b: u8) -> u8 {
+        if a == 0 {
+            return 0;
+        }
+        self.exp[(self.log[a as usize] as usize + 255 - self.log[b as usize] as usize) % 255]
+    }
+
+    // Fill in the check bytes at the end of a codeword from the data before them
+    fn encode(&self, codeword: &mut [u8]) {
+        let mut parity = [0; PARITY_LEN];
+        for &byte in &codeword[..DATA_LEN] {
+            let feedback = self.from_dual[byte as usize] ^ parity[0];
+            for j in 1..PARITY_LEN {
+                parity[j - 1] = parity[j] ^ self.mul(feedback, self.generator[PARITY_LEN - j]);
+            }
+            parity[PARITY_LEN - 1] = self.mul(feedback, self.generator[0]);
+        }
+
+        for (out, check) in codeword[DATA_LEN..].iter_mut().zip(parity.iter()) {
+            *out = self.to_dual[*check as usize];
+        }
+    }
+
+    // Correct a received codeword in place.  Returns the number of bytes corrected, or
+    // None if there were too many errors, in which case the codeword is left alone.
+    fn decode(&self, codeword: &mut [u8]) -> Option<usize> {
+        let symbols: Vec<u8> = codeword.iter().map(|byte| self.from_dual[*byte as usize]).collect();
+
+        // Syndromes: the received polynomial at each root of the generator
+        let mut syndromes = [0; PARITY_LEN];
+        for (i, syndrome) in syndromes.iter_mut().enumerate() {
+            let root = self.power(PRIM * (FCR + i));
+            *syndrome = symbols.iter().fold(0, |sum, symbol| self.mul(sum, root) ^ symbol);
+        }
+        if syndromes.iter().all(|syndrome| *syndrome == 0) {
+            return Some(0);
+        }
+
+        // Berlekamp-Massey for the error locator polynomial
+        let mut locator = [0; PARITY_LEN + 1];
+        let mut previous = [0; PARITY_LEN + 1];
+        locator[0] = 1;
+        previous[0] = 1;
+        let mut errors = 0;
+        let mut shift = 1;
+        let mut last_discrepancy = 1;
+        for n in 0..PARITY_LEN {
+            let discrepancy = (1..=errors).fold(syndromes[n], |sum, i| {
+                sum ^ self.mul(locator[i], syndromes[n - i])
+            });
+            if discrepancy == 0 {
+                shift += 1;
+                continue;
+            }
+
+            let scale = self.div(discrepancy, last_discrepancy);
+            let saved = locator;
+            for i in 0..=PARITY_LEN - shift {
+                locator[i + shift] ^= self.mul(scale, previous[i]);
+            }
+            if 2 * errors <= n {
+                errors = n + 1 - errors;
+                previous = saved;
+                last_discrepancy = discrepancy;
+                shift = 1;
+            } else {
+                shift += 1;
+            }
+        }
+        if errors > PARITY_LEN / 2 || locator[errors + 1..].iter().any(|coef| *coef != 0) {
+            return None;
+        }
+
+        // Error evaluator: syndromes times locator, mod x^32
+        let mut evaluator = [0; PARITY_LEN];
+        for (i, term) in evaluator.iter_mut().enumerate() {
+            for j in 0..=i.min(errors) {
+                *term ^= self.mul(syndromes[i - j], locator[j]);
+            }
+        }
+
+        // Chien search for the error positions, then Forney for the values.  The byte at
+        // index k is the coefficient of x^(254 - k), with locator alpha^(PRIM * (254 - k)).
+        let evaluate = |poly: &[u8], x: u8| poly.iter().rev().fold(0, |sum, coef| self.mul(sum, x) ^ coef);
+        let mut corrections = vec![];
+        for k in 0..CODE_LEN {
+            let exponent = (PRIM * (CODE_LEN - 1 - k)) % 255;
+            let inverse = self.power(255 - exponent);
+            if evaluate(&locator[..=errors], inverse) != 0 {
+                continue;
+            }
+
+            let mut derivative = 0;
+            for j in (1..=errors).step_by(2) {
+                derivative ^= self.mul(locator[j], self.power(((255 - exponent) * (j - 1)) % 255));
+            }
+            if derivative == 0 {
+                return None;
+            }
+
+            // Value = X^(1 - FCR) * evaluator(1/X) / locator'(1/X), for locator X
+            let scale = self.power((exponent * (255 - (FCR - 1) % 255)) % 255);
+            let value = self.mul(scale, self.div(evaluate(&evaluator, inverse), derivative));
+            corrections.push((k, value));
+        }
+        if corrections.len() != errors {
+            return None;
+        }
+
+        for (k, value) in corrections {
+            codeword[k] = self.to_dual[(symbols[k] ^ value) as usize];
+        }
+        Some(errors)
+    }
+}
+
+// The CCSDS pseudo-random sequence (h(x) = x^8 + x^7 + x^5 + x^3 + 1, all ones to
+// start), one period of 255 bytes
+fn randomizer() -> Vec<u8> {
+    let mut bits: Vec<u8> = vec![1; 8];
+    while bits.len() < 255 * 8 {
+        let n = bits.len() - 8;
+        bits.push(bits[n + 7] ^ bits[n + 5] ^ bits[n + 3] ^ bits[n]);
+    }
+    bits.chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | bit))
+        .collect()
+}
+
+// Transmit side of the coding layer
+pub struct Encoder {
+    rs: ReedSolomon,
+    depth: usize,
+    randomizer: Option<Vec<u8>>,
+    fill: u8,
+}
+
+impl Encoder {
+
+    pub fn new(depth: usize, randomize: bool, fill: u8) -> Encoder {
+        Encoder {
+            rs: ReedSolomon::new(),
+            depth,
+            randomizer: if randomize { Some(randomizer()) } else { None },
+            fill,
+        }
+    }
+
+    // Bytes of the framed stream each block carries
+    pub fn block_data(&self) -> usize {
+        DATA_LEN * self.depth
+    }
+
+    // Code a framed packet into as many complete blocks as it takes, each with its
+    // sync marker
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let block_len = CODE_LEN * self.depth;
+        let mut out = Vec::with_capacity((data.len() / self.block_data() + 1) * (block_len + ASM.len()));
+
+        for chunk in data.chunks(self.block_data()) {
+            let mut codewords = vec![vec![self.fill; CODE_LEN]; self.depth];
+            for (i, byte) in chunk.iter().enumerate() {
+                codewords[i % self.depth][i / self.depth] = *byte;
+            }
+            for codeword in codewords.iter_mut() {
+                self.rs.encode(codeword);
+            }
+
+            out.extend_from_slice(&ASM);
+            for i in 0..block_len {
+                let mut byte = codewords[i % self.depth][i / self.depth];
+                if let Some(ref sequence) = self.randomizer {
+                    byte ^= sequence[i % CODE_LEN];
+                }
+                out.push(byte);
+            }
+        }
+
+        out
+    }
+}
+
+// Receive side of the coding layer
+//
+// Bytes are fed in with `push` as they arrive, like the deframer.  Each block found
+// after a sync marker is decoded, and the data of blocks that decode are queued and
+// handed out by `next_block`.
+pub struct Decoder {
+    rs: ReedSolomon,
+    depth: usize,
+    randomizer: Option<Vec<u8>>,
+    // Last few bytes seen while looking for a sync marker
+    window: u32,
+    seen: usize,
+    // Block being collected after a sync marker, if any
+    block: Option<Vec<u8>>,
+    blocks: VecDeque<Option<Vec<u8>>>,
+}
+
+impl Decoder {
+
+    pub fn new(depth: usize, randomize: bool) -> Decoder {
+        Decoder {
+            rs: ReedSolomon::new(),
+            depth,
+            randomizer: if randomize { Some(randomizer()) } else { None },
+            window: 0,
+            seen: 0,
+            block: None,
+            blocks: VecDeque::new(),
+        }
+    }
+
+    // Process newly received bytes, returning what decoding them found
+    pub fn push(&mut self, data: &[u8]) -> FecStats {
+        let mut stats = FecStats::default();
+        let block_len = CODE_LEN * self.depth;
+        let asm = u32::from_be_bytes(ASM);
+
+        for &byte in data {
+            match self.block {
+                Some(ref mut block) => {
+                    block.push(byte);
+                    if block.len() == block_len {
+                        let block = self.block.take().unwrap_or_default();
+                        let decoded = self.decode(block, &mut stats);
+                        self.blocks.push_back(decoded);
+                    }
+                }
+                None => {
+                    self.window = (self.window << 8) | u32::from(byte);
+                    self.seen += 1;
+                    if self.seen >= ASM.len() && (self.window ^ asm).count_ones() <= ASM_TOLERANCE {
+                        self.block = Some(Vec::with_capacity(block_len));
+                        self.seen = 0;
+                    }
+                }
+            }
+        }
+
+        stats
+    }
+
+    // Take the data of the next block, or None for a block that could not be corrected
+    pub fn next_block(&mut self) -> Option<Option<Vec<u8>>> {
+        self.blocks.pop_front()
+    }
+
+    fn decode(&self, mut block: Vec<u8>, stats: &mut FecStats) -> Option<Vec<u8>> {
+        if let Some(ref sequence) = self.randomizer {
+            for (i, byte) in block.iter_mut().enumerate() {
+                *byte ^= sequence[i % CODE_LEN];
+            }
+        }
+
+        let mut good = true;
+        let mut codewords = vec![vec![0; CODE_LEN]; self.depth];
+        for (i, byte) in block.iter().enumerate() {
+            codewords[i % self.depth][i / self.depth] = *byte;
+        }
+        for codeword in codewords.iter_mut() {
+            match self.rs.decode(codeword) {
+                Some(0) => (),
+                Some(_) => stats.corrected += 1,
+                None => {
+                    stats.uncorrectable += 1;
+                    good = false;
+                }
+            }
+        }
+        if !good {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(DATA_LEN * self.depth);
+        for i in 0..DATA_LEN * self.depth {
+            data.push(codewords[i % self.depth][i / self.depth]);
+        }
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Codeword with data bytes 0..=222 (dual basis), check bytes worked out
+    // separately from the generator polynomial and dual-basis matrix in CCSDS 131.0-B
+    const KNOWN_CHECK: [u8; PARITY_LEN] = [
+        0x4f, 0xfb, 0x92, 0xdd, 0x55, 0x7e, 0xc6, 0x7f, 0x27, 0xfb, 0x89, 0x82, 0xcf, 0x58,
+        0xf8, 0xfd, 0x02, 0x8a, 0xd1, 0x17, 0xfc, 0xef, 0x6b, 0x27, 0x93, 0xd0, 0x41, 0x88,
+        0x26, 0x57, 0x86, 0x51,
+    ];
+
+    // Flip `count` bytes of every codeword in a coded block (after its sync marker)
+    fn corrupt(coded: &mut [u8], depth: usize, count: usize) {
+        for codeword in 0..depth {
+            for n in 0..count {
+                let position = (n * 37 + codeword * 5) % CODE_LEN;
+                coded[ASM.len() + position * depth + codeword] ^= 0x5A;
+            }
+        }
+    }
+
+    fn block(depth: usize) -> Vec<u8> {
+        (0..DATA_LEN * depth).map(|i| (i * 13 + 7) as u8).collect()
+    }
+
+    #[test]
+    fn generator_matches_spec() {
+        // Powers of alpha for each coefficient, from the standard
+        let powers = [
+            0, 249, 59, 66, 4, 43, 126, 251, 97, 30, 3, 213, 50, 66, 170, 5, 24, 5, 170, 66, 50,
+            213, 3, 30, 97, 251, 126, 43, 4, 66, 59, 249, 0,
+        ];
+        let rs = ReedSolomon::new();
+        for (coef, power) in rs.generator.iter().zip(powers.iter()) {
+            assert_eq!(*coef, rs.power(*power));
+        }
+    }
+
+    #[test]
+    fn dual_basis_table() {
+        let rs = ReedSolomon::new();
+        assert_eq!(rs.to_dual[..8], [0x00, 0x7b, 0xaf, 0xd4, 0x99, 0xe2, 0x36, 0x4d]);
+        for symbol in 0..=255u8 {
+            assert_eq!(rs.from_dual[rs.to_dual[symbol as usize] as usize], symbol);
+        }
+    }
+
+    #[test]
+    fn randomizer_sequence() {
+        let sequence = randomizer();
+        assert_eq!(sequence.len(), CODE_LEN);
+        assert_eq!(
+            sequence[..16],
+            [
+                0xFF, 0x48, 0x0E, 0xC0, 0x9A, 0x0D, 0x70, 0xBC, 0x8E, 0x2C, 0x93, 0xAD, 0xA7,
+                0xB7, 0x46, 0xCE
+            ]
+        );
+    }
+
+    #[test]
+    fn known_codeword() {
+        let rs = ReedSolomon::new();
+        let mut codeword: Vec<u8> = (0..CODE_LEN).map(|i| i as u8).collect();
+        rs.encode(&mut codeword);
+        assert_eq!(codeword[DATA_LEN..], KNOWN_CHECK);
+        assert_eq!(rs.decode(&mut codeword), Some(0));
+    }
+
+    #[test]
+    fn corrects_up_to_16_errors() {
+        for &depth in DEPTHS {
+            for &randomize in &[false, true] {
+                let data = block(depth);
+                let mut coded = Encoder::new(depth, randomize, 0x7E).encode(&data);
+                assert_eq!(coded.len(), ASM.len() + CODE_LEN * depth);
+                corrupt(&mut coded, depth, 16);
+
+                let mut decoder = Decoder::new(depth, randomize);
+                let stats = decoder.push(&coded);
+                assert_eq!(stats, FecStats { corrected: depth, uncorrectable: 0 });
+                assert_eq!(decoder.next_block(), Some(Some(data)));
+                assert_eq!(decoder.next_block(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn flags_17_errors() {
+        for &depth in DEPTHS {
+            let mut coded = Encoder::new(depth, true, 0x7E).encode(&block(depth));
+            corrupt(&mut coded, depth, 17);
+
+            let mut decoder = Decoder::new(depth, true);
+            let stats = decoder.push(&coded);
+            assert_eq!(stats, FecStats { corrected: 0, uncorrectable: depth });
+            assert_eq!(decoder.next_block(), Some(None));
+        }
+    }
+
+    #[test]
+    fn pads_last_block() {
+        let data = vec![1; DATA_LEN * 2 + 1];
+        let coded = Encoder::new(2, true, 0x7E).encode(&data);
+        assert_eq!(coded.len(), 2 * (ASM.len() + CODE_LEN * 2));
+
+        let mut decoder = Decoder::new(2, true);
+        decoder.push(&coded);
+        assert_eq!(decoder.next_block(), Some(Some(data[..DATA_LEN * 2].to_vec())));
+        let mut last = vec![0x7E; DATA_LEN * 2];
+        last[0] = 1;
+        assert_eq!(decoder.next_block(), Some(Some(last)));
+    }
+
+    #[test]
+    fn finds_sync_marker() {
+        let data = block(1);
+        let coded = Encoder::new(1, true, 0x7E).encode(&data);
+
+        // Noise before the marker, and a marker with as many bit errors as allowed,
+        // fed in a byte at a time
+        let mut stream = vec![0x00, 0x55, 0x1A, 0xCF, 0x00];
+        let mut damaged = coded.clone();
+        damaged[0] ^= 0x01;
+        damaged[2] ^= 0x81;
+        stream.extend_from_slice(&damaged);
+        let mut decoder = Decoder::new(1, true);
+        for byte in &stream {
+            decoder.push(&[*byte]);
+        }
+        assert_eq!(decoder.next_block(), Some(Some(data)));
+
+        // One bit error too many and it is just noise
+        damaged[3] ^= 0x10;
+        let mut decoder = Decoder::new(1, true);
+        decoder.push(&damaged);
+        assert_eq!(decoder.next_block(), None);
+    }
+}
@@ -0,0 +1,447 @@
+// Helpers for looking at and putting files in place on the OBC
+//
+// Anything written on behalf of the ground goes to a temporary file in the target's
+// directory first and is then renamed over the target.  The rename is atomic, so the
+// target is always either the old file or the complete new one, never a truncated
+// mixture, even if the service dies or the board reboots part way through.
+//
+// Directory listings and file metadata are reported without following symlinks, so a
+// link is shown as a link and a recursive listing can't loop.  Removing a symlink
+// likewise removes the link, never what it points to.
+
+use nix::unistd::{chown, Gid, Uid};
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Most entries a directory listing returns, however deep it goes
+pub const MAX_LISTING: usize = 1000;
+// Most bytes read_range returns in one go
+pub const MAX_READ: u64 = 1024 * 1024;
+// Block size used when searching backwards for the start of the last lines
+const TAIL_BLOCK: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+// Metadata of one file or directory
+pub struct FileInfo {
+    pub path: String,
+    pub file_type: FileType,
+    pub size: u64,
+    // Seconds since the epoch
+    pub mtime: i64,
+    // Permission bits, including setuid, setgid and sticky
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Part of a file read by read_range
+pub struct FileRange {
+    pub offset: u64,
+    pub data: Vec<u8>,
+    // Size of the whole file at the time it was read
+    pub size: u64,
+}
+
+// Outcome of a file operation on one path
+pub struct PathResult {
+    pub path: String,
+    pub error: Option<String>,
+}
+
+impl PathResult {
+    pub fn new(path: &str, result: Result<(), String>) -> PathResult {
+        PathResult {
+            path: path.to_owned(),
+            error: result.err(),
+        }
+    }
+}
+
+// Ownership to apply to an installed file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+// Parse an octal permission string such as "0755" or "644"
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+        .ok_or_else(|| format!("Invalid file mode '{}'", mode))
+}
+
+// Resolve an owner given as "user", "user:group" or ":group".  Names and numeric IDs
+// are both accepted.
+pub fn parse_owner(owner: &str) -> Result<Owner, String> {
+    let mut parts = owner.splitn(2, ':');
+    let user = parts.next().unwrap_or("");
+    let group = parts.next().unwrap_or("");
+
+    let uid = if user.is_empty() {
+        None
+    } else {
+        Some(user.parse::<u32>().or_else(|_| lookup_user(user))?)
+    };
+    let gid = if group.is_empty() {
+        None
+    } else {
+        Some(group.parse::<u32>().or_else(|_| lookup_group(group))?)
+    };
+
+    if uid.is_none() && gid.is_none() {
+        return Err(format!("Invalid owner '{}'", owner));
+    }
+
+    Ok(Owner { uid, gid })
+}
+
+fn lookup_user(name: &str) -> Result<u32, String> {
+    let cname = CString::new(name).map_err(|_| format!("Invalid user name '{}'", name))?;
+    let entry = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("Unknown user '{}'", name));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+fn lookup_group(name: &str) -> Result<u32, String> {
+    let cname = CString::new(name).map_err(|_| format!("Invalid group name '{}'", name))?;
+    let entry = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("Unknown group '{}'", name));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+// Apply the requested mode and owner to a file
+pub fn set_attributes(path: &Path, mode: Option<u32>, owner: Option<Owner>) -> Result<(), String> {
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|err| format!("Failed to set mode of {}: {}", path.display(), err))?;
+    }
+
+    if let Some(owner) = owner {
+        chown(path, owner.uid.map(Uid::from_raw), owner.gid.map(Gid::from_raw))
+            .map_err(|err| format!("Failed to set owner of {}: {}", path.display(), err))?;
+    }
+
+    Ok(())
+}
+
+// Temporary file next to `target`, on the same filesystem so it can be renamed over it
+fn temp_path(target: &Path) -> Result<PathBuf, String> {
+    let name = target
+        .file_name()
+        .ok_or_else(|| format!("Invalid file path {}", target.display()))?;
+
+    Ok(target.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        process::id()
+    )))
+}
+
+// Finish off a temporary file and rename it over the target
+fn commit(tmp: &Path, target: &Path, mode: Option<u32>, owner: Option<Owner>) -> Result<(), String> {
+    let result = set_attributes(tmp, mode, owner)
+        .and_then(|_| {
+            File::open(tmp)
+                .and_then(|f| f.sync_all())
+                .map_err(|err| format!("Failed to sync {}: {}", tmp.display(), err))
+        })
+        .and_then(|_| {
+            fs::rename(tmp, target)
+                .map_err(|err| format!("Failed to move file into place at {}: {}", target.display(), err))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(tmp);
+    }
+    result
+}
+
+// Atomically replace `target` with `data`
+pub fn write_atomic(target: &Path, data: &[u8], mode: Option<u32>, owner: Option<Owner>) -> Result<(), String> {
+    let tmp = temp_path(target)?;
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(data));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("Failed to write {}: {}", tmp.display(), err));
+    }
+
+    commit(&tmp, target, mode, owner)
+}
+
+// Atomically replace `target` with a copy of `source`.  The source is typically in the
+// staging area, which may be on a different filesystem, so it can't just be renamed.
+pub fn install_file(source: &Path, target: &Path, mode: Option<u32>, owner: Option<Owner>) -> Result<(), String> {
+    let tmp = temp_path(target)?;
+
+    if let Err(err) = fs::copy(source, &tmp) {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("Failed to copy file to {}: {}", tmp.display(), err));
+    }
+
+    commit(&tmp, target, mode, owner)
+}
+
+// Whether a path looks usable as a file target: it must name a file in an existing
+// directory
+pub fn check_target(target: &Path) -> io::Result<()> {
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    if target.file_name().is_none() || !parent.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Target directory does not exist",
+        ));
+    }
+
+    Ok(())
+}
+
+// Metadata of a single path
+pub fn stat(path: &Path) -> Result<FileInfo, String> {
+    let meta = fs::symlink_metadata(path).map_err(|_| "Failed to stat file".to_owned())?;
+    Ok(file_info(path, &meta))
+}
+
+fn file_info(path: &Path, meta: &fs::Metadata) -> FileInfo {
+    let file_type = meta.file_type();
+    FileInfo {
+        path: path.to_string_lossy().into_owned(),
+        file_type: if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Directory
+        } else if file_type.is_file() {
+            FileType::File
+        } else {
+            FileType::Other
+        },
+        size: meta.len(),
+        mtime: meta.mtime(),
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+    }
+}
+
+// List a directory, descending `depth` levels into subdirectories (0 for just the
+// directory itself).  Entries come out sorted by path.  Returns true as well if the
+// listing was cut short at MAX_LISTING entries.
+pub fn list_dir(path: &Path, depth: usize) -> Result<(Vec<FileInfo>, bool), String> {
+    let meta = fs::metadata(path).map_err(|_| "Failed to open directory".to_owned())?;
+    if !meta.is_dir() {
+        return Err("Path is not a directory".to_owned());
+    }
+
+    let mut entries = vec![];
+    let truncated = walk(path, depth, &mut entries)?;
+    Ok((entries, truncated))
+}
+
+fn walk(dir: &Path, depth: usize, entries: &mut Vec<FileInfo>) -> Result<bool, String> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|_| format!("Failed to read directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        if entries.len() >= MAX_LISTING {
+            return Ok(true);
+        }
+
+        // Entries can disappear while we're listing them
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        entries.push(file_info(&path, &meta));
+
+        // Unreadable subdirectories are still listed, just not descended into
+        if depth > 0 && meta.is_dir() {
+            if let Ok(true) = walk(&path, depth - 1, entries) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+// Remove a file, symlink or directory.  Directories that aren't empty are only removed
+// if `recursive` is set.
+pub fn remove(path: &Path, recursive: bool) -> Result<(), String> {
+    if path.parent().is_none() {
+        return Err(format!("Refusing to remove {}", path.display()));
+    }
+
+    let meta = fs::symlink_metadata(path)
+        .map_err(|err| format!("Failed to remove {}: {}", path.display(), err))?;
+    let result = if !meta.is_dir() {
+        fs::remove_file(path)
+    } else if recursive {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_dir(path)
+    };
+
+    result.map_err(|err| format!("Failed to remove {}: {}", path.display(), err))
+}
+
+// Create a directory, and any missing parents if `parents` is set
+pub fn make_dir(path: &Path, parents: bool, mode: Option<u32>) -> Result<(), String> {
+    let result = if parents {
+        fs::create_dir_all(path)
+    } else {
+        fs::create_dir(path)
+    };
+    result.map_err(|err| format!("Failed to create directory {}: {}", path.display(), err))?;
+
+    set_attributes(path, mode, None)
+}
+
+// Where a file moved or copied to `target` ends up: inside it if it is a directory,
+// otherwise at `target` itself
+fn destination(source: &Path, target: &Path, overwrite: bool) -> Result<PathBuf, String> {
+    let dest = if target.is_dir() {
+        let name = source
+            .file_name()
+            .ok_or_else(|| format!("Invalid file path {}", source.display()))?;
+        target.join(name)
+    } else {
+        target.to_path_buf()
+    };
+
+    if !overwrite && fs::symlink_metadata(&dest).is_ok() {
+        return Err(format!("{} already exists", dest.display()));
+    }
+    Ok(dest)
+}
+
+// Copy a regular file, keeping its mode.  The copy replaces the destination atomically.
+pub fn copy(source: &Path, target: &Path, overwrite: bool) -> Result<(), String> {
+    let meta = fs::metadata(source)
+        .map_err(|err| format!("Failed to copy {}: {}", source.display(), err))?;
+    if !meta.is_file() {
+        return Err(format!("Failed to copy {}: not a regular file", source.display()));
+    }
+
+    let dest = destination(source, target, overwrite)?;
+    install_file(source, &dest, Some(meta.mode() & 0o7777), None)
+}
+
+// Move a file or directory.  Files are copied and then removed if the destination is
+// on another filesystem; directories can only be moved within a filesystem.
+pub fn rename(source: &Path, target: &Path, overwrite: bool) -> Result<(), String> {
+    let meta = fs::symlink_metadata(source)
+        .map_err(|err| format!("Failed to move {}: {}", source.display(), err))?;
+    let dest = destination(source, target, overwrite)?;
+
+    match fs::rename(source, &dest) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.raw_os_error() == Some(libc::EXDEV) && meta.is_file() => {
+            install_file(source, &dest, Some(meta.mode() & 0o7777), None)?;
+            fs::remove_file(source).map_err(|err| {
+                format!("Copied {} to {} but failed to remove it: {}", source.display(), dest.display(), err)
+            })
+        }
+        Err(err) => Err(format!(
+            "Failed to move {} to {}: {}",
+            source.display(),
+            dest.display(),
+            err
+        )),
+    }
+}
+
+// Where to read from and how much
+pub enum ReadFrom {
+    // Up to `length` bytes from `offset` on, or to the end of the file
+    Offset(u64, Option<u64>),
+    // The last `lines` lines of the file, keeping only the final `length` bytes of
+    // them if they are longer than that
+    Tail(usize, Option<u64>),
+}
+
+// Read part of a file, never more than MAX_READ bytes
+pub fn read_range(path: &Path, from: ReadFrom) -> Result<FileRange, String> {
+    let mut file = File::open(path).map_err(|_| "Failed to open file".to_owned())?;
+    let size = file.metadata().map_err(|_| "Failed to open file".to_owned())?.len();
+
+    let (offset, length) = match from {
+        ReadFrom::Offset(offset, length) => (offset, length),
+        ReadFrom::Tail(lines, length) => {
+            let start = tail_offset(&mut file, size, lines).map_err(|_| "Failed to read file".to_owned())?;
+            match length {
+                Some(length) if size - start > length => (size - length, Some(length)),
+                _ => (start, None),
+            }
+        }
+    };
+    let length = length.unwrap_or(MAX_READ).min(MAX_READ);
+
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.take(length).read_to_end(&mut data))
+        .map_err(|_| "Failed to read file".to_owned())?;
+
+    Ok(FileRange { offset, data, size })
+}
+
+// Offset of the start of the last `lines` lines of a file.  A newline at the very end
+// of the file doesn't start another line.
+fn tail_offset(file: &mut File, size: u64, lines: usize) -> io::Result<u64> {
+    if lines == 0 {
+        return Ok(size);
+    }
+
+    let mut end = size;
+    let mut found = 0;
+    let mut block = vec![0; TAIL_BLOCK as usize];
+
+    while end > 0 {
+        let start = end.saturating_sub(TAIL_BLOCK);
+        let block = &mut block[0..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+
+        for (i, &byte) in block.iter().enumerate().rev() {
+            let pos = start + i as u64;
+            if byte != b'\n' || pos == size - 1 {
+                continue;
+            }
+            found += 1;
+            if found == lines {
+                return Ok(pos + 1);
+            }
+        }
+        end = start;
+    }
+
+    Ok(0)
+}
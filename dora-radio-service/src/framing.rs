@@ -0,0 +1,207 @@
+// HDLC-style asynchronous framing for the radio serial link
+//
+// Each packet is sent over the UART as:
+//
+//     FLAG | stuffed(packet | FCS) | FLAG
+//
+// FLAG is 0x7E and the FCS is the 16-bit CRC used by HDLC and X.25, transmitted
+// least significant byte first.  Any FLAG or ESCAPE (0x7D) byte inside the frame is
+// replaced by ESCAPE followed by the original byte XOR 0x20, so a flag can never
+// appear inside a frame.  That lets the receiver find packet boundaries no matter
+// how the bytes are split across reads, and resynchronize on the next flag after
+// line noise.
+
+use std::collections::VecDeque;
+
+pub const FLAG: u8 = 0x7E;
+pub const ESCAPE: u8 = 0x7D;
+const ESCAPE_XOR: u8 = 0x20;
+
+// Number of FCS bytes at the end of each frame
+const FCS_LEN: usize = 2;
+
+// CRC-16/X-25 (reflected 0x1021 polynomial, initial value and final XOR of 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Append a byte to an outgoing frame, escaping it if necessary
+fn stuff(frame: &mut Vec<u8>, byte: u8) {
+    if byte == FLAG || byte == ESCAPE {
+        frame.push(ESCAPE);
+        frame.push(byte ^ ESCAPE_XOR);
+    } else {
+        frame.push(byte);
+    }
+}
+
+// Wrap a packet in a complete frame, ready to be written to the UART
+pub fn encode(packet: &[u8]) -> Vec<u8> {
+    let fcs = crc16(packet);
+
+    let mut frame = Vec::with_capacity(packet.len() + 8);
+    frame.push(FLAG);
+    for byte in packet {
+        stuff(&mut frame, *byte);
+    }
+    stuff(&mut frame, (fcs & 0xFF) as u8);
+    stuff(&mut frame, (fcs >> 8) as u8);
+    frame.push(FLAG);
+
+    frame
+}
+
+// Receive side of the framing layer
+//
+// Bytes are fed in with `push` as they arrive from the UART, in whatever chunks the
+// reads happen to return.  Complete frames with a good FCS are queued and handed out
+// by `next_frame`.  Anything else between two flags (FCS mismatch, aborted escape
+// sequence, runt or oversized frame) is dropped and counted as a bad frame.
+pub struct Deframer {
+    buffer: Vec<u8>,
+    escaped: bool,
+    discarding: bool,
+    max_len: usize,
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Deframer {
+
+    // `max_len` is the largest packet (excluding FCS) that will be accepted
+    pub fn new(max_len: usize) -> Deframer {
+        Deframer {
+            buffer: Vec::with_capacity(max_len + FCS_LEN),
+            escaped: false,
+            discarding: false,
+            max_len,
+            frames: VecDeque::new(),
+        }
+    }
+
+    // Process newly received bytes.  Returns the number of bad frames found.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let mut bad = 0;
+
+        for &byte in data {
+            if byte == FLAG {
+                if self.discarding {
+                    // End of an oversized frame, which was already counted
+                    self.discarding = false;
+                } else if !self.buffer.is_empty() || self.escaped {
+                    match self.check() {
+                        Some(packet) => self.frames.push_back(packet),
+                        None => bad += 1,
+                    }
+                }
+                // Back-to-back flags are just idle fill between frames
+                self.buffer.clear();
+                self.escaped = false;
+                continue;
+            }
+
+            if self.discarding {
+                continue;
+            }
+
+            if byte == ESCAPE {
+                self.escaped = true;
+                continue;
+            }
+
+            let byte = if self.escaped { byte ^ ESCAPE_XOR } else { byte };
+            self.escaped = false;
+            self.buffer.push(byte);
+
+            if self.buffer.len() > self.max_len + FCS_LEN {
+                // Either a runaway frame or we lost a flag.  Drop everything until the
+                // next flag so we resynchronize on a real frame boundary.
+                bad += 1;
+                self.buffer.clear();
+                self.discarding = true;
+            }
+        }
+
+        bad
+    }
+
+    // Take the next complete packet, if any
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    // Validate the frame currently in the buffer, returning its packet if it is good
+    fn check(&mut self) -> Option<Vec<u8>> {
+        if self.escaped || self.buffer.len() <= FCS_LEN {
+            return None;
+        }
+
+        let split = self.buffer.len() - FCS_LEN;
+        let fcs = u16::from(self.buffer[split]) | (u16::from(self.buffer[split + 1]) << 8);
+        if crc16(&self.buffer[..split]) != fcs {
+            return None;
+        }
+
+        Some(self.buffer[..split].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x906E);
+    }
+
+    #[test]
+    fn escapes_flags() {
+        let frame = encode(&[0x01, FLAG, ESCAPE]);
+        assert_eq!(frame[0], FLAG);
+        assert_eq!(frame[1..6], [0x01, ESCAPE, 0x5E, ESCAPE, 0x5D]);
+        assert_eq!(*frame.last().unwrap(), FLAG);
+        assert!(!frame[1..frame.len() - 1].contains(&FLAG));
+    }
+
+    #[test]
+    fn round_trip_in_pieces() {
+        let packets = vec![vec![FLAG; 20], (0..=255).collect::<Vec<u8>>(), vec![ESCAPE, 0x00]];
+        let stream: Vec<u8> = packets.iter().flat_map(|packet| encode(packet)).collect();
+
+        let mut deframer = Deframer::new(256);
+        for piece in stream.chunks(3) {
+            assert_eq!(deframer.push(piece), 0);
+        }
+        for packet in packets {
+            assert_eq!(deframer.next_frame(), Some(packet));
+        }
+        assert_eq!(deframer.next_frame(), None);
+    }
+
+    #[test]
+    fn drops_bad_frames() {
+        let mut corrupted = encode(b"hello");
+        corrupted[2] ^= 0x01;
+        let mut stream = corrupted;
+        // Oversized, then a runt, then a good frame
+        stream.extend(encode(&[0x42; 20]));
+        stream.extend(&[FLAG, 0x01, FLAG]);
+        stream.extend(encode(b"ok"));
+
+        let mut deframer = Deframer::new(16);
+        assert_eq!(deframer.push(&stream), 3);
+        assert_eq!(deframer.next_frame(), Some(b"ok".to_vec()));
+        assert_eq!(deframer.next_frame(), None);
+    }
+}
@@ -0,0 +1,98 @@
+// Shell-style wildcard matching, used for the patterns in the config file
+//
+// '*' matches any run of characters (including none) and '?' matches any single
+// character.  Everything else matches itself.  There are no character classes or
+// escapes, which keeps patterns in the config easy to read and to audit.
+//
+// Patterns that guard paths, such as the run_command allowlist, use matches_path
+// instead, where '*' and '?' stop at a '/' and only '**' matches across directories.
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    wildcard(pattern, text, false)
+}
+
+pub fn matches_path(pattern: &str, text: &str) -> bool {
+    wildcard(pattern, text, true)
+}
+
+enum Token {
+    // '**', or '*' when not matching paths
+    AnyRun,
+    // '*' within one path component
+    Run,
+    One,
+    Literal(char),
+}
+
+fn wildcard(pattern: &str, text: &str, path: bool) -> bool {
+    let text: Vec<char> = text.chars().collect();
+
+    let mut tokens = vec![];
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                Token::AnyRun
+            }
+            '*' if path => Token::Run,
+            '*' => Token::AnyRun,
+            '?' => Token::One,
+            c => Token::Literal(c),
+        });
+    }
+
+    // Which prefixes of the text the pattern so far can match
+    let mut reached = vec![false; text.len() + 1];
+    reached[0] = true;
+    for token in &tokens {
+        let mut next = vec![false; text.len() + 1];
+        for i in 0..=text.len() {
+            next[i] = match *token {
+                Token::AnyRun => reached[i] || (i > 0 && next[i - 1]),
+                Token::Run => reached[i] || (i > 0 && next[i - 1] && text[i - 1] != '/'),
+                Token::One => i > 0 && reached[i - 1] && !(path && text[i - 1] == '/'),
+                Token::Literal(c) => i > 0 && reached[i - 1] && text[i - 1] == c,
+            };
+        }
+        reached = next;
+    }
+
+    reached[text.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        assert!(matches("abc", "abc"));
+        assert!(!matches("abc", "abd"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("*", ""));
+        assert!(matches("*.log", "radio.log"));
+        assert!(matches("*.log", "logs/radio.log"));
+        assert!(!matches("*.log", "radio.log.1"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(matches("a*b*c", "abcbc"));
+        assert!(!matches("a*b*c", "acb"));
+        assert!(matches("**", "a/b/c"));
+    }
+
+    #[test]
+    fn keeps_path_wildcards_in_one_directory() {
+        assert!(matches_path("/home/*", "/home/file"));
+        assert!(!matches_path("/home/*", "/home/user/file"));
+        assert!(!matches_path("/home/?", "/home//"));
+        assert!(matches_path("/home/*/file", "/home/user/file"));
+        assert!(matches_path("/home/**", "/home/user/file"));
+        assert!(!matches_path("/home/**", "/etc/shadow"));
+        assert!(matches_path("-*", "-la"));
+        assert!(!matches_path("-*", "-/x"));
+        assert!(matches_path("**", "/any/path"));
+    }
+}
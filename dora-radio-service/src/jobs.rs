@@ -0,0 +1,337 @@
+// Background jobs for commands that outlast a ground pass
+//
+// run_command can start a command as a job instead of waiting for it.  The job gets
+// an ID straight away, and its stdout and stderr go to files in the staging area that
+// the ground can page through on later passes.  The same allowlist and memory limit
+// apply as for ordinary commands, but jobs get their own (longer) timeout and a cap on
+// how much output they can write.
+//
+// Each job's record is kept in a manifest next to its output.  The command is started
+// through a small shell wrapper that writes its exit code to a status file, so a job
+// that finishes while this service is restarting still gets its exit code recorded.
+// On startup, jobs that were running are picked up again if their process is still
+// alive; otherwise they are marked as lost.
+
+use crate::command;
+use crate::config::CommandConfig;
+use crate::transfer::{load_manifests, new_id, now, save_manifest};
+use log::*;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+// Runs the command given as the wrapper's arguments, then records its exit code
+const WRAPPER: &str = "\"$0\" \"$@\"; echo $? > \"$DORA_JOB_STATUS\"";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum JobState {
+    Running,
+    // The command exited by itself
+    Exited,
+    Cancelled,
+    TimedOut,
+    // Killed for writing more output than allowed
+    OutputLimit,
+    // The process disappeared without recording an exit code, e.g. across a reboot
+    Lost,
+}
+
+// State of one job, as saved in its manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub path: String,
+    pub args: Vec<String>,
+    pub state: JobState,
+    pub exit_code: Option<i32>,
+    pub pid: i32,
+    // Kernel start time of the process, so a reused PID isn't mistaken for the job
+    pub start_ticks: u64,
+    pub started: u64,
+    pub finished: Option<u64>,
+    pub timeout: u64,
+}
+
+// Which output stream of a job to read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+// One page of a job's output
+pub struct OutputPage {
+    pub offset: u64,
+    pub data: Vec<u8>,
+    // Bytes written to the stream so far
+    pub size: u64,
+}
+
+pub struct Jobs {
+    dir: PathBuf,
+    config: CommandConfig,
+    jobs: HashMap<String, Job>,
+    // Processes started by this instance of the service, kept so they can be reaped
+    children: HashMap<String, Child>,
+}
+
+impl Jobs {
+
+    // Pick up the records of jobs started before a restart
+    pub fn new(staging_dir: &Path, config: &CommandConfig) -> Jobs {
+        let dir = staging_dir.join("jobs");
+        if let Err(err) = fs::create_dir_all(&dir) {
+            error!("Failed to create job directory {}: {}", dir.display(), err);
+        }
+
+        let jobs = load_manifests::<Job>(&dir)
+            .into_iter()
+            .map(|job| (job.id.clone(), job))
+            .collect();
+
+        let mut jobs = Jobs {
+            dir,
+            config: config.clone(),
+            jobs,
+            children: HashMap::new(),
+        };
+        jobs.poll();
+        jobs
+    }
+
+    fn file(&self, id: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, ext))
+    }
+
+    fn save(&self, job: &Job) {
+        if let Err(err) = save_manifest(&self.file(&job.id, "json"), job) {
+            error!("Failed to save job {}: {}", job.id, err);
+        }
+    }
+
+    // Start an allowed command in the background
+    pub fn start(&mut self, path: &str, args: &[String]) -> Result<Job, String> {
+        self.poll();
+
+        let running = self.jobs.values().filter(|job| job.state == JobState::Running).count();
+        if running >= self.config.max_jobs {
+            return Err(format!("Too many jobs running (limit {})", self.config.max_jobs));
+        }
+
+        let path = command::resolve(path)?;
+        command::check_allowed(&self.config, &path, args)?;
+
+        let id = new_id(&path);
+        let stdout = File::create(self.file(&id, "stdout"))
+            .map_err(|_| "Failed to create job output file".to_owned())?;
+        let stderr = File::create(self.file(&id, "stderr"))
+            .map_err(|_| "Failed to create job output file".to_owned())?;
+
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg(WRAPPER)
+            .arg(&path)
+            .args(args)
+            .env("DORA_JOB_STATUS", self.file(&id, "status"))
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        command::isolate(&mut command, self.config.max_memory);
+
+        let child = match command.spawn() {
+            Ok(child) => child,
+            Err(_) => {
+                self.remove_files(&id);
+                return Err("Could not execute command".to_owned());
+            }
+        };
+
+        let pid = child.id() as i32;
+        let job = Job {
+            id: id.clone(),
+            path,
+            args: args.to_vec(),
+            state: JobState::Running,
+            exit_code: None,
+            pid,
+            start_ticks: running_since(pid).unwrap_or(0),
+            started: now(),
+            finished: None,
+            timeout: self.config.job_timeout.as_secs(),
+        };
+
+        info!("Started job {}: {} {:?} (pid {})", id, job.path, job.args, pid);
+        self.save(&job);
+        self.jobs.insert(id.clone(), job.clone());
+        self.children.insert(id, child);
+        Ok(job)
+    }
+
+    // Check on every running job: record the ones that have finished and kill the ones
+    // over their time or output limits
+    pub fn poll(&mut self) {
+        let running: Vec<String> = self
+            .jobs
+            .values()
+            .filter(|job| job.state == JobState::Running)
+            .map(|job| job.id.clone())
+            .collect();
+
+        for id in running {
+            if !self.is_alive(&id) {
+                self.finish(&id, None);
+                continue;
+            }
+
+            let job = &self.jobs[&id];
+            if now() >= job.started + job.timeout {
+                warn!("Job {} timed out after {}s, killing it", id, job.timeout);
+                self.kill(&id, JobState::TimedOut);
+            } else if self.output_size(&id) > self.config.max_job_output {
+                warn!("Job {} exceeded its output limit, killing it", id);
+                self.kill(&id, JobState::OutputLimit);
+            }
+        }
+    }
+
+    // Whether a running job's process is still there.  Our own children are reaped
+    // here; jobs picked up after a restart are checked by PID and start time.
+    fn is_alive(&mut self, id: &str) -> bool {
+        if let Some(child) = self.children.get_mut(id) {
+            return match child.try_wait() {
+                Ok(None) => true,
+                _ => {
+                    self.children.remove(id);
+                    false
+                }
+            };
+        }
+
+        let job = &self.jobs[id];
+        running_since(job.pid) == Some(job.start_ticks)
+    }
+
+    fn output_size(&self, id: &str) -> u64 {
+        ["stdout", "stderr"]
+            .iter()
+            .filter_map(|ext| fs::metadata(self.file(id, ext)).ok())
+            .map(|meta| meta.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Kill a job's whole process group and record why
+    fn kill(&mut self, id: &str, state: JobState) {
+        let pid = self.jobs[id].pid;
+        let _ = killpg(Pid::from_raw(pid), Signal::SIGKILL);
+        if let Some(mut child) = self.children.remove(id) {
+            let _ = child.wait();
+        }
+        self.finish(id, Some(state));
+    }
+
+    // Record the end of a job.  Without a state of its own, a job that left an exit
+    // code behind has exited and one that didn't has been lost.
+    fn finish(&mut self, id: &str, state: Option<JobState>) {
+        let exit_code = fs::read_to_string(self.file(id, "status"))
+            .ok()
+            .and_then(|status| status.trim().parse::<i32>().ok());
+
+        let job = match self.jobs.get_mut(id) {
+            Some(job) => job,
+            None => return,
+        };
+        job.state = state.unwrap_or(if exit_code.is_some() {
+            JobState::Exited
+        } else {
+            JobState::Lost
+        });
+        job.exit_code = exit_code;
+        job.finished = Some(now());
+        info!("Job {} finished: {:?}, exit code {:?}", id, job.state, job.exit_code);
+
+        let job = job.clone();
+        self.save(&job);
+    }
+
+    pub fn cancel(&mut self, id: &str) -> Result<Job, String> {
+        self.poll();
+        let job = self.status(id)?;
+        if job.state != JobState::Running {
+            return Err("Job is not running".to_owned());
+        }
+
+        self.kill(id, JobState::Cancelled);
+        self.status(id)
+    }
+
+    // Delete a finished job's record and output
+    pub fn cleanup(&mut self, id: &str) -> Result<(), String> {
+        self.poll();
+        let job = self.status(id)?;
+        if job.state == JobState::Running {
+            return Err("Job is still running".to_owned());
+        }
+
+        self.jobs.remove(id);
+        self.remove_files(id);
+        Ok(())
+    }
+
+    fn remove_files(&self, id: &str) {
+        for ext in &["json", "stdout", "stderr", "status"] {
+            let _ = fs::remove_file(self.file(id, ext));
+        }
+    }
+
+    pub fn status(&self, id: &str) -> Result<Job, String> {
+        self.jobs
+            .get(id)
+            .cloned()
+            .ok_or_else(|| "Unknown job ID".to_owned())
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        let mut list: Vec<Job> = self.jobs.values().cloned().collect();
+        list.sort_by_key(|job| job.started);
+        list
+    }
+
+    // Read up to `length` bytes of a job's output from `offset`
+    pub fn output(&self, id: &str, stream: Stream, offset: u64, length: usize) -> Result<OutputPage, String> {
+        self.status(id)?;
+        let ext = match stream {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+
+        let mut file = File::open(self.file(id, ext)).map_err(|_| "Failed to open job output".to_owned())?;
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+
+        let mut data = vec![];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.take(length as u64).read_to_end(&mut data))
+            .map_err(|_| "Failed to read job output".to_owned())?;
+
+        Ok(OutputPage { offset, data, size })
+    }
+}
+
+// Start time of a running process in clock ticks since boot, from /proc/<pid>/stat.
+// None if there is no such process or it has exited and is waiting to be reaped.
+fn running_since(pid: i32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name can contain spaces, so count fields from the end of it.  The
+    // state is field 3, the first after the name, and starttime is field 22.
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+    if fields.first() == Some(&"Z") {
+        return None;
+    }
+    fields.get(19)?.parse().ok()
+}
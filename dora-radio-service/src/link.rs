@@ -0,0 +1,296 @@
+// Transports that can carry the radio byte stream
+//
+// The flatsat talks to the radio over a UART, but the same service binary also needs
+// to run in the Vagrant box and in CI where there is no radio.  Each backend here
+// implements `RadioLink`, and the rest of the service (framing, the comms service
+// read/write functions) only ever sees the trait.
+//
+// - serial: the radio UART (see SerialConfig)
+// - udp:    a pair of UDP sockets, one per direction, for talking to a simulated
+//           ground station on the same or another machine
+// - pty:    a pseudo-terminal, so ground software expecting a serial device can be
+//           pointed at the slave side (optionally through a fixed symlink)
+//
+// Writes on every backend give up after the link timeout rather than blocking the
+// radio I/O thread indefinitely.  Nothing may be reading the far end of a
+// pseudo-terminal, so it is non-blocking and a frame that doesn't fit in time is
+// dropped with an error, to be counted as a failed downlink.  A frame is only ever
+// dropped whole: not every framing mode can find the start of the next frame after
+// a cut-off one.
+
+use crate::config::{LinkConfig, LinkType, SerialConfig};
+use crate::ServiceResult;
+use failure::*;
+use log::*;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use serial::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+// A bidirectional byte stream to the radio (or something pretending to be one).  The
+// file descriptor is what the radio I/O thread polls for incoming data.
+pub trait RadioLink: AsRawFd + Send {
+    // Read whatever bytes are available into `buf`, waiting no longer than the link's
+    // timeout.  Returns Ok(0) if nothing arrived in time.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    // Write all of `data` to the link, failing if it can't be written within the
+    // link's timeout
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+
+    // Human-readable description of the link for log messages
+    fn describe(&self) -> String;
+}
+
+// Open the link selected in the config file
+pub fn open(config: &LinkConfig) -> ServiceResult<Box<dyn RadioLink>> {
+    let link: Box<dyn RadioLink> = match config.link_type {
+        LinkType::Serial(ref serial) => Box::new(SerialLink::open(serial)?),
+        LinkType::Udp { local, remote } => Box::new(UdpLink::open(local, remote, config.timeout)?),
+        LinkType::Pty { ref link } => Box::new(PtyLink::open(link.as_ref(), config.timeout)?),
+    };
+
+    info!("Radio link opened on {}", link.describe());
+    Ok(link)
+}
+
+// The radio UART
+pub struct SerialLink {
+    bus: String,
+    port: serial::SystemPort,
+}
+
+impl SerialLink {
+
+    pub fn open(config: &SerialConfig) -> ServiceResult<SerialLink> {
+
+        // Open a connection to the serial port
+        let mut port = serial::open(&config.bus)
+            .map_err(|err| format_err!("Failed to open radio bus {}: {}", config.bus, err))?;
+
+        // Save our settings
+        port.configure(&config.settings)
+            .map_err(|err| format_err!("Failed to configure radio bus {}: {}", config.bus, err))?;
+        port.set_timeout(config.timeout)?;
+
+        Ok(SerialLink {
+            bus: config.bus.clone(),
+            port,
+        })
+    }
+}
+
+impl AsRawFd for SerialLink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.port.as_raw_fd()
+    }
+}
+
+impl RadioLink for SerialLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.port.read(buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => Ok(0),
+            other => other,
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut self.port, data)
+    }
+
+    fn describe(&self) -> String {
+        format!("serial port {}", self.bus)
+    }
+}
+
+// A UDP socket pair.  Uplink datagrams are received on `local` and downlink bytes are
+// sent to `remote`.  Reads need a buffer big enough for a whole datagram, since the
+// kernel discards whatever doesn't fit.
+pub struct UdpLink {
+    socket: UdpSocket,
+    remote: SocketAddr,
+}
+
+impl UdpLink {
+
+    pub fn open(local: SocketAddr, remote: SocketAddr, timeout: Duration) -> ServiceResult<UdpLink> {
+        let socket = UdpSocket::bind(local)
+            .map_err(|err| format_err!("Failed to bind radio UDP socket {}: {}", local, err))?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        Ok(UdpLink { socket, remote })
+    }
+}
+
+impl AsRawFd for UdpLink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl RadioLink for UdpLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.socket.recv_from(buf) {
+            Ok((num, _)) => Ok(num),
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(0)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.socket.send_to(data, self.remote).map(|_| ())
+    }
+
+    fn describe(&self) -> String {
+        match self.socket.local_addr() {
+            Ok(local) => format!("UDP {} -> {}", local, self.remote),
+            Err(_) => format!("UDP -> {}", self.remote),
+        }
+    }
+}
+
+// A pseudo-terminal.  The service holds the master side and ground software opens the
+// slave side as if it were the radio's serial device.
+pub struct PtyLink {
+    master: File,
+    // Kept open so reads on the master don't fail with EIO before the ground side connects
+    _slave: File,
+    slave_name: String,
+    timeout: Duration,
+    // The rest of a frame the terminal had no room for in time, which has to go out
+    // before anything else
+    pending: Vec<u8>,
+}
+
+impl PtyLink {
+
+    pub fn open(link: Option<&String>, timeout: Duration) -> ServiceResult<PtyLink> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        grantpt(&master)?;
+        unlockpt(&master)?;
+        let slave_name = ptsname_r(&master)?;
+
+        // Put the slave in raw mode so the line discipline leaves our bytes alone
+        let slave = OpenOptions::new().read(true).write(true).open(&slave_name)?;
+        let mut termios = tcgetattr(slave.as_raw_fd())?;
+        cfmakeraw(&mut termios);
+        tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+
+        // Give the ground side a stable path to connect to, replacing the link left by
+        // an earlier run but nothing else
+        if let Some(path) = link {
+            match fs::symlink_metadata(path) {
+                Ok(ref info) if info.file_type().is_symlink() => fs::remove_file(path)
+                    .map_err(|err| format_err!("Failed to remove old link {}: {}", path, err))?,
+                Ok(_) => bail!(
+                    "Failed to link {} to {}: path exists and isn't a symlink",
+                    path,
+                    slave_name
+                ),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => bail!("Failed to check {}: {}", path, err),
+            }
+            std::os::unix::fs::symlink(&slave_name, path)
+                .map_err(|err| format_err!("Failed to link {} to {}: {}", path, slave_name, err))?;
+        }
+
+        let master = unsafe { File::from_raw_fd(master.into_raw_fd()) };
+
+        Ok(PtyLink {
+            master,
+            _slave: slave,
+            slave_name,
+            timeout,
+            pending: vec![],
+        })
+    }
+
+    // Write as much of `data` as the terminal takes, waiting until `deadline` for room
+    fn write_before(&self, data: &[u8], deadline: Instant) -> io::Result<usize> {
+        loop {
+            match (&self.master).write(data) {
+                Ok(num) => return Ok(num),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "pseudo-terminal is full, nothing is reading it",
+                        ));
+                    }
+                    let mut fds = [PollFd::new(self.master.as_raw_fd(), PollFlags::POLLOUT)];
+                    poll(&mut fds, (deadline - now).as_millis() as i32)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl AsRawFd for PtyLink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
+
+impl RadioLink for PtyLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fds = [PollFd::new(self.master.as_raw_fd(), PollFlags::POLLIN)];
+        let ready = poll(&mut fds, self.timeout.as_millis() as i32)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        if ready == 0 {
+            return Ok(0);
+        }
+
+        match self.master.read(buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            other => other,
+        }
+    }
+
+    // With no ground software reading the slave side, the terminal fills up and stays
+    // full, so wait no longer than the timeout for room.  A frame that has started going
+    // out is never cut short, since the ground couldn't tell where the next one starts:
+    // whatever is left is kept and finished ahead of the next frame, and it's the next
+    // frame that is dropped if there still isn't room.
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        while !self.pending.is_empty() {
+            let num = self.write_before(&self.pending, deadline)?;
+            self.pending.drain(..num);
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            match self.write_before(&data[written..], deadline) {
+                Ok(num) => written += num,
+                Err(err) => {
+                    if written == 0 {
+                        return Err(err);
+                    }
+                    self.pending = data[written..].to_vec();
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("pseudo-terminal {}", self.slave_name)
+    }
+}
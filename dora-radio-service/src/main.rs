@@ -1,47 +1,95 @@
+#[macro_use]
+extern crate juniper;
+
+mod archive;
+mod arq;
+mod auth;
+mod ax25;
+mod ccsds;
+mod cfdp;
+mod checksum;
+mod command;
+mod config;
+mod fec;
+mod files;
+mod framing;
+mod glob;
+mod jobs;
+mod link;
+mod model;
+mod objects;
+mod patch;
+mod queue;
+mod radio;
+mod schema;
+mod shell;
+mod telemetry;
+mod transfer;
+mod update;
+mod watchdog;
+
 // Return type for this service.
 type ServiceResult<T> = Result<T, Error>;
 
+use crate::arq::Arq;
+use crate::auth::Authenticator;
+use crate::config::{
+    ArqConfig, AuthConfig, CfdpConfig, CommandConfig, FramingConfig, LinkConfig, QueueConfig,
+    ShellConfig, TransferConfig, UpdateConfig, WatchdogConfig,
+};
+use crate::model::Subsystem;
+use crate::radio::RadioHandle;
+use crate::schema::{MutationRoot, QueryRoot};
+use crate::telemetry::LinkTelemetry;
+use crate::watchdog::Watchdog;
 use comms_service::*;
 use failure::*;
-use kubos_service::Logger;
+use kubos_service::{Logger, Service};
 use log::*;
-use serial;
-use serial::prelude::*;
-use std::cell::RefCell;
-use std::io::prelude::*;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-
-const BUS: &str = "/dev/ttyS2";
-const TIMEOUT: Duration = Duration::from_millis(100);
-// Maximum number of bytes to attempt to read at one time
-const MAX_READ: usize = 48;
-
 
-// Initialize the serial bus connection for reading and writing from/to the "radio"
-pub fn serial_init() -> ServiceResult<Arc<Mutex<RefCell<serial::SystemPort>>>> {
-
-    // Define our serial settings
-    let settings = serial::PortSettings {
-        baud_rate: serial::Baud115200,
-        char_size: serial::Bits8,
-        parity: serial::ParityNone,
-        stop_bits: serial::Stop1,
-        flow_control: serial::FlowNone,
+// Open the configured radio link (UART, UDP or pseudo-terminal) and hand it to the
+// radio I/O thread, along with the uplink authenticator if authentication is on, the
+// ARQ state if ARQ is on, and the APID to pass to the CFDP entity if CFDP is on
+pub fn radio_init(
+    link_config: &LinkConfig,
+    framing_config: &FramingConfig,
+    arq_config: &ArqConfig,
+    auth_config: &AuthConfig,
+    cfdp_config: &CfdpConfig,
+    telem: &Arc<Mutex<CommsTelemetry>>,
+    link_telem: &Arc<Mutex<LinkTelemetry>>,
+) -> ServiceResult<RadioHandle> {
+
+    let auth = if auth_config.enabled {
+        Some(Authenticator::new(auth_config)?)
+    } else {
+        warn!("Uplink authentication is disabled");
+        None
     };
 
-    // Open a connection to the serial port
-    let mut port = serial::open(BUS)?;
-
-    // Save our settings
-    port.configure(&settings)?;
-    port.set_timeout(TIMEOUT)?;
-
-    // Wrap the port in a mutex so that multiple threads can access it
-    let conn = Arc::new(Mutex::new(RefCell::new(port)));
+    let arq = if arq_config.enabled {
+        Some(Arq::new(arq_config))
+    } else {
+        None
+    };
 
-    Ok(conn)
+    let link = link::open(link_config)?;
+
+    RadioHandle::spawn(
+        link,
+        link_config,
+        framing_config,
+        arq,
+        auth,
+        if cfdp_config.enabled {
+            Some(cfdp_config.apid)
+        } else {
+            None
+        },
+        telem.clone(),
+        link_telem.clone(),
+    )
 }
 
 
@@ -49,19 +97,8 @@ pub fn serial_init() -> ServiceResult<Arc<Mutex<RefCell<serial::SystemPort>>>> {
 // The write function that the comms service will use to write messages to the "radio"
 //
 // This function may be called from either a message handler thread or from a downlink endpoint
-pub fn write(conn: &Arc<Mutex<RefCell<serial::SystemPort>>>, msg: &[u8]) -> ServiceResult<()> {
-    let conn = match conn.lock() {
-        Ok(val) => val,
-        Err(e) => bail!("Failed to take mutex: {:?}", e),
-    };
-    let mut conn = conn.try_borrow_mut()?;
-
-    conn.write(msg).and_then(|num| {
-        debug!("Wrote {} bytes to radio", num);
-        Ok(())
-    })?;
-
-    Ok(())
+pub fn write(conn: &RadioHandle, msg: &[u8]) -> ServiceResult<()> {
+    conn.write(msg)
 }
 
 
@@ -70,53 +107,8 @@ pub fn write(conn: &Arc<Mutex<RefCell<serial::SystemPort>>>, msg: &[u8]) -> Serv
 // "radio"
 //
 // Returns once a message has been received
-pub fn read(conn: &Arc<Mutex<RefCell<serial::SystemPort>>>) -> ServiceResult<Vec<u8>> {
-    loop {
-        // Note: These brackets force the program to release the serial port's mutex so that any
-        // threads waiting on it in order to perform a write may do so
-        {
-            // Take ownership of the serial port
-            let conn = match conn.lock() {
-                Ok(val) => val,
-                Err(e) => {
-                    error!("Failed to take mutex: {:?}", e);
-                    panic!();
-                }
-            };
-            let mut conn = conn.try_borrow_mut()?;
-
-            // Loop until either a full message has been received or a non-timeout error has occured
-            let mut packet = vec![];
-            loop {
-                let mut buffer: Vec<u8> = vec![0; MAX_READ];
-                match conn.read(buffer.as_mut_slice()) {
-                    Ok(num) => {
-                        buffer.resize(num, 0);
-                        packet.append(&mut buffer);
-
-                        debug!("Read {} bytes from radio", packet.len());
-
-                        if num < MAX_READ {
-                            return Ok(packet);
-                        }
-                    }
-                    Err(ref err) => match err.kind() {
-                        ::std::io::ErrorKind::TimedOut => {
-                            if packet.len() > 0 {
-                                return Ok(packet);
-                            } else {
-                                break;
-                            }
-                        }
-                        other => bail!("Radio read failed: {:?}", other),
-                    },
-                };
-            }
-        }
-
-        // Sleep for a moment so that other threads have the chance to grab the serial port mutex
-        thread::sleep(Duration::from_millis(10));
-    }
+pub fn read(conn: &RadioHandle) -> ServiceResult<Vec<u8>> {
+    conn.read()
 }
 
 
@@ -132,33 +124,159 @@ fn main() -> ServiceResult<()> {
     let service_config = kubos_system::Config::new("dora-radio-service")?;
 
     // Pull out our communication settings
-    let config = CommsConfig::new(service_config)?;
-
-    // Initialize the serial port
-    let conn = serial_init()?;
-
-    // In this instance, reading and writing are done over the same connection,
-    // so we'll just clone the UART port connection
-    let read_conn = conn.clone();
-    let write_conn = conn;
-
-    // Tie everything together in our final control block
-    let control = CommsControlBlock::new(
-        Some(Arc::new(read)),
-        vec![Arc::new(write)],
-        read_conn,
-        write_conn,
-        config,
-    )?;
-
-    // Set up our communications telemetry structure
-    let telemetry = Arc::new(Mutex::new(CommsTelemetry::default()));
+    let config = CommsConfig::new(service_config.clone())?;
+
+    // Pull out the radio link and framing settings
+    let link_config = LinkConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load link config: {}", err);
+        err
+    })?;
+    let framing_config = FramingConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load framing config: {}", err);
+        err
+    })?;
+
+    // Pull out the ARQ settings, which depend on the framing
+    let arq_config = ArqConfig::new(&service_config, &framing_config).map_err(|err| {
+        error!("Failed to load arq config: {}", err);
+        err
+    })?;
+
+    // Pull out the uplink authentication settings
+    let auth_config = AuthConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load auth config: {}", err);
+        err
+    })?;
+
+    // Pull out the file transfer settings, which depend on the frame size
+    let transfer_config = TransferConfig::new(&service_config, &framing_config).map_err(|err| {
+        error!("Failed to load transfer config: {}", err);
+        err
+    })?;
+
+    // Pull out the CFDP entity settings, which also depend on the frame size
+    let cfdp_config = CfdpConfig::new(&service_config, &framing_config).map_err(|err| {
+        error!("Failed to load cfdp config: {}", err);
+        err
+    })?;
+
+    // Pull out the run_command allowlist and limits
+    let command_config = CommandConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load command config: {}", err);
+        err
+    })?;
+
+    // Pull out the shell session settings
+    let shell_config = ShellConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load shell config: {}", err);
+        err
+    })?;
+
+    // Pull out the software update settings
+    let update_config = UpdateConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load update config: {}", err);
+        err
+    })?;
 
-    // Start the comms service thread
-    CommsService::start::<Arc<Mutex<RefCell<serial::SystemPort>>>, SpacePacket>(control, &telemetry)?;
+    // Pull out the time-tagged command queue settings
+    let queue_config = QueueConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load queue config: {}", err);
+        err
+    })?;
 
-    // TODO: Start the GraphQL service
-    loop {}
+    // Start the keep-alive watchdog before bringing up the link, so the OBC still gets
+    // rebooted if the ground can't reach us because the link won't come up
+    let watchdog_config = WatchdogConfig::new(&service_config).map_err(|err| {
+        error!("Failed to load watchdog config: {}", err);
+        err
+    })?;
+    let watchdog = if watchdog_config.enabled {
+        Some(Watchdog::start(&watchdog_config).map_err(|err| {
+            error!("Failed to start keep-alive watchdog: {}", err);
+            err
+        })?)
+    } else {
+        warn!("Keep-alive watchdog is disabled");
+        None
+    };
 
+    // Set up our communications telemetry structures
+    let telemetry = Arc::new(Mutex::new(CommsTelemetry::default()));
+    let link_telemetry = Arc::new(Mutex::new(LinkTelemetry::default()));
+
+    let start_link = || -> ServiceResult<_> {
+        // Open the link and start the radio I/O thread
+        let conn = radio_init(
+            &link_config,
+            &framing_config,
+            &arq_config,
+            &auth_config,
+            &cfdp_config,
+            &telemetry,
+            &link_telemetry,
+        )?;
+
+        // Start the CFDP entity, which shares the radio with the comms service
+        let cfdp = if cfdp_config.enabled {
+            Some(cfdp::start(&cfdp_config, &transfer_config.staging_dir, conn.clone()))
+        } else {
+            None
+        };
+
+        // In this instance, reading and writing are done over the same connection,
+        // so we'll just clone the radio handle
+        let read_conn = conn.clone();
+        let write_conn = conn;
+
+        // Tie everything together in our final control block
+        let control = CommsControlBlock::new(
+            Some(Arc::new(read)),
+            vec![Arc::new(write)],
+            read_conn,
+            write_conn,
+            config,
+        )?;
+
+        // Start the comms service thread
+        CommsService::start::<RadioHandle, SpacePacket>(control, &telemetry)?;
+
+        Ok(cfdp)
+    };
 
+    // If the link can't be brought up, exiting would take the watchdog down with us.
+    // Carry on without it instead, so the watchdog can still reboot the OBC and the
+    // GraphQL service can still be reached by other means.  The error goes in the
+    // telemetry.
+    let cfdp = match start_link() {
+        Ok(cfdp) => cfdp,
+        Err(err) => {
+            error!("Failed to start the radio link: {}", err);
+            if let Ok(mut data) = telemetry.lock() {
+                data.errors.push(format!("Failed to start the radio link: {}", err));
+            }
+            None
+        }
+    };
+
+    // Start the GraphQL service so the ground can reach the link telemetry and the
+    // fallback commands through the radio (this call blocks while the service runs)
+    Service::new(
+        service_config,
+        Subsystem::new(
+            telemetry,
+            link_telemetry,
+            &transfer_config,
+            command_config,
+            &shell_config,
+            &update_config,
+            &queue_config,
+            watchdog,
+            cfdp,
+        ),
+        QueryRoot,
+        MutationRoot,
+    )
+    .start();
+
+    Ok(())
 }
\ No newline at end of file
@@ -1,20 +1,147 @@
+use crate::archive::{self, Filter};
+use crate::cfdp::{Entity, Transaction};
+use crate::checksum::{self, HashType};
+use crate::command::{self, CommandOutput};
+use crate::config::{CommandConfig, QueueConfig, ShellConfig, TransferConfig, UpdateConfig};
+use crate::files::{self, FileRange, PathResult, ReadFrom};
+use crate::jobs::{Job, Jobs, OutputPage, Stream};
+use crate::patch;
+use crate::queue::{self, Action, Entry, Queue};
+use crate::shell::{SessionInfo, Sessions, ShellOutput};
+use crate::telemetry::LinkTelemetry;
+use crate::transfer::{free_space, new_id, Chunk, Download, Downloads, Upload, UploadRequest, Uploads};
+use crate::update::{TargetStatus, Update, Updates};
+use crate::watchdog::Watchdog;
 use comms_service::CommsTelemetry;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::sync::{Arc, Mutex};
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use base64::{encode, decode};
-use std::process::Command;
-use log::*;
+
+// How often abandoned uploads are looked for
+const UPLOAD_SWEEP: Duration = Duration::from_secs(10 * 60);
+// How often background jobs are checked for exit and limits
+const JOB_POLL: Duration = Duration::from_secs(1);
+// How often idle shell sessions are looked for
+const SHELL_SWEEP: Duration = Duration::from_secs(60);
+// Largest float below which every whole number is exact (2^53)
+const MAX_EXACT_FLOAT: f64 = 9_007_199_254_740_992.0;
 
 #[derive(Clone)]
 pub struct Subsystem {
     telem: Arc<Mutex<CommsTelemetry>>,
+    link_telem: Arc<Mutex<LinkTelemetry>>,
+    downloads: Arc<Mutex<Downloads>>,
+    uploads: Arc<Mutex<Uploads>>,
+    commands: Arc<CommandConfig>,
+    jobs: Arc<Mutex<Jobs>>,
+    // Largest page of job or shell output returned at once, so it fits in one radio frame
+    page_size: usize,
+    shells: Arc<Mutex<Sessions>>,
+    // Where patched files are rebuilt before they are installed
+    patch_dir: PathBuf,
+    // Largest file a patch may build
+    max_patch_size: u64,
+    updates: Arc<Mutex<Updates>>,
+    queue: Arc<Mutex<Queue>>,
+    watchdog: Option<Arc<Watchdog>>,
+    cfdp: Option<Arc<Mutex<Entity>>>,
 }
 
 impl Subsystem {
 
-    pub fn new(telem: Arc<Mutex<CommsTelemetry>>) -> Subsystem {
-        Subsystem { telem }
+    pub fn new(telem: Arc<Mutex<CommsTelemetry>>,
+               link_telem: Arc<Mutex<LinkTelemetry>>,
+               transfer: &TransferConfig,
+               commands: CommandConfig,
+               shell: &ShellConfig,
+               update: &UpdateConfig,
+               queue_config: &QueueConfig,
+               watchdog: Option<Arc<Watchdog>>,
+               cfdp: Option<Arc<Mutex<Entity>>>) -> Subsystem {
+        let downloads = Downloads::new(&transfer.staging_dir, transfer.chunk_size);
+        let uploads = Arc::new(Mutex::new(Uploads::new(
+            &transfer.staging_dir,
+            transfer.chunk_size,
+            transfer.max_upload_size,
+            transfer.upload_timeout,
+        )));
+
+        // Clear out abandoned uploads even if the ground never starts another one
+        let sweep = uploads.clone();
+        let _ = thread::Builder::new()
+            .name("upload-sweep".to_owned())
+            .spawn(move || loop {
+                thread::sleep(UPLOAD_SWEEP);
+                if let Ok(mut uploads) = sweep.lock() {
+                    uploads.expire();
+                }
+            });
+
+        // Reap finished jobs and enforce their limits whether or not anyone is asking
+        let jobs = Arc::new(Mutex::new(Jobs::new(&transfer.staging_dir, &commands)));
+        let monitor = jobs.clone();
+        let _ = thread::Builder::new()
+            .name("job-monitor".to_owned())
+            .spawn(move || loop {
+                thread::sleep(JOB_POLL);
+                if let Ok(mut jobs) = monitor.lock() {
+                    jobs.poll();
+                }
+            });
+
+        let shells = Arc::new(Mutex::new(Sessions::new(shell)));
+        let sweep = shells.clone();
+        let _ = thread::Builder::new()
+            .name("shell-sweep".to_owned())
+            .spawn(move || loop {
+                thread::sleep(SHELL_SWEEP);
+                if let Ok(mut shells) = sweep.lock() {
+                    shells.expire();
+                }
+            });
+
+        let updates = Arc::new(Mutex::new(Updates::new(update)));
+
+        // Run time-tagged commands as they come due.  The queue is only locked while
+        // an entry is picked and its outcome recorded, not while it runs.
+        let queue = Arc::new(Mutex::new(Queue::new(&transfer.staging_dir, queue_config, &commands)));
+        let runner = queue.clone();
+        let runner_config = queue_config.clone();
+        let runner_commands = commands.clone();
+        let _ = thread::Builder::new()
+            .name("queue-runner".to_owned())
+            .spawn(move || loop {
+                thread::sleep(queue::POLL_INTERVAL);
+                while let Some(entry) = runner.lock().ok().and_then(|mut queue| queue.next_due()) {
+                    let entry = queue::execute(&runner_config, &runner_commands, entry);
+                    if let Ok(mut queue) = runner.lock() {
+                        queue.complete(entry);
+                    }
+                }
+            });
+
+        Subsystem {
+            telem,
+            link_telem,
+            downloads: Arc::new(Mutex::new(downloads)),
+            uploads,
+            commands: Arc::new(commands),
+            jobs,
+            page_size: transfer.chunk_size,
+            shells,
+            patch_dir: transfer.staging_dir.join("patches"),
+            max_patch_size: transfer.max_upload_size,
+            updates,
+            queue,
+            watchdog,
+            cfdp,
+        }
     }
 
 
@@ -27,9 +154,9 @@ impl Subsystem {
     // - downlink_file
     // - uplink_file
     //
-    // In the future, we may also want to include a keep_alive command that must be
-    // received from the ground once per ~week or else the computer is made to 
-    // reboot by the radio service.
+    // There is also a keep_alive command that must be received from the ground once
+    // per ~week or else the computer is made to reboot by the radio service (see
+    // watchdog.rs).
     //
     //
     // run_command
@@ -37,40 +164,118 @@ impl Subsystem {
     // Runs a system command specified by a path and optional command line arguments.
     // The path may be absolute or may be a command name that can be found through the 
     // user PATH environment for the root user.  The option stdout and stderr arguments
-    // can be used to pipe the output to specified files paths, otherwise, the output
-    // will be returned in the resulting JSON file from the service.
+    // can be used to pipe the output to specified files paths in the configured output
+    // directory, otherwise, the output will be returned in the resulting JSON file from
+    // the service.  Only commands and arguments in the allowlist can be run, and each
+    // runs under a timeout and memory and output limits (see command.rs).
     pub fn run_command(&self,   path: Option<String>, 
                                 args: Option<Vec<String>>,
                                 stdout: Option<String>,
-                                stderr: Option<String>) -> Result<String, String> {
+                                stderr: Option<String>) -> Result<CommandOutput, String> {
 
-        // Build the command 
         let p = path.ok_or("No command path specified".to_owned())?;
-        let mut command = Command::new(p);
+        command::run(&self.commands, &p, &args.unwrap_or_default(), stdout, stderr)
+    }
 
-        // Pipe the stdout output to a file if one is provided
-        stdout.map(|s| { 
-            File::create(s)
-                .and_then(|f| Ok(command.stdout(f)))
-                .or_else(|_| Err(info!("Failed to open stdout file")))
-        });
+    // Background jobs
+    //
+    // For commands that take longer than a pass (see jobs.rs).  start_job returns as
+    // soon as the command is running, and job_status, job_output, cancel_job and
+    // cleanup_job follow it up on later passes.
+    pub fn start_job(&self, path: Option<String>, args: Option<Vec<String>>) -> Result<Job, String> {
+        let p = path.ok_or("No command path specified".to_owned())?;
+        self.jobs.lock()
+            .map_err(|_| "Failed to lock jobs".to_owned())?
+            .start(&p, &args.unwrap_or_default())
+    }
 
-        // Pipe the stderr output to a file if one is provided         
-        stderr.map(|s| { 
-            File::create(s)
-                .and_then(|f| Ok(command.stderr(f)))
-                .or_else(|_| Err(info!("Failed to open stderr file")))
-        });
+    pub fn job_status(&self, id: Option<String>) -> Result<Vec<Job>, String> {
+        let jobs = self.jobs.lock().map_err(|_| "Failed to lock jobs".to_owned())?;
+        match id {
+            Some(id) => Ok(vec![jobs.status(&id)?]),
+            None => Ok(jobs.list()),
+        }
+    }
+
+    pub fn job_output(&self, id: String, stream: Stream, offset: Option<f64>, length: Option<i32>) -> Result<OutputPage, String> {
+        let offset = byte_count(offset.unwrap_or(0.0), "Offset")?;
+        let length = match length {
+            Some(length) if length <= 0 => return Err("Length must be positive".to_owned()),
+            Some(length) => (length as usize).min(self.page_size),
+            None => self.page_size,
+        };
+
+        self.jobs.lock()
+            .map_err(|_| "Failed to lock jobs".to_owned())?
+            .output(&id, stream, offset, length)
+    }
+
+    pub fn cancel_job(&self, id: String) -> Result<Job, String> {
+        self.jobs.lock()
+            .map_err(|_| "Failed to lock jobs".to_owned())?
+            .cancel(&id)
+    }
 
-        // Set the command arguments if any are provided
-        args.map(|a| command.args(a));
+    pub fn cleanup_job(&self, id: String) -> Result<(), String> {
+        self.jobs.lock()
+            .map_err(|_| "Failed to lock jobs".to_owned())?
+            .cleanup(&id)
+    }
 
-        // Execute the command
-        command.output()
-            .map(|s| format!("stdout: {}\n\nstderr: {}", 
-                String::from_utf8_lossy(&s.stdout),
-                String::from_utf8_lossy(&s.stderr)))
-            .map_err(|_| "Could not execute command".to_owned())       
+    // Shell sessions
+    //
+    // An interactive shell on a pseudo-terminal for when run_command isn't enough (see
+    // shell.rs).  open_shell starts one, shell_write sends it input, shell_read returns
+    // the output since a cursor, and resize_shell and close_shell do what they say.
+    pub fn open_shell(&self, rows: Option<i32>, cols: Option<i32>) -> Result<SessionInfo, String> {
+        let (rows, cols) = terminal_size(rows.unwrap_or(24), cols.unwrap_or(80))?;
+        self.shells.lock()
+            .map_err(|_| "Failed to lock shell sessions".to_owned())?
+            .open(rows, cols)
+    }
+
+    pub fn shell_write(
+        &self,
+        id: String,
+        data: String,
+        dec: Option<bool>,
+    ) -> Result<(usize, SessionInfo), String> {
+        let data = if dec.unwrap_or(false) {
+            decode(data).map_err(|_| "Failed to decode data".to_owned())?
+        } else {
+            data.into_bytes()
+        };
+
+        self.shells.lock()
+            .map_err(|_| "Failed to lock shell sessions".to_owned())?
+            .write(&id, &data)
+    }
+
+    pub fn shell_read(&self, id: String, cursor: Option<f64>) -> Result<ShellOutput, String> {
+        let cursor = byte_count(cursor.unwrap_or(0.0), "Cursor")?;
+
+        self.shells.lock()
+            .map_err(|_| "Failed to lock shell sessions".to_owned())?
+            .read(&id, cursor, self.page_size)
+    }
+
+    pub fn resize_shell(&self, id: String, rows: i32, cols: i32) -> Result<SessionInfo, String> {
+        let (rows, cols) = terminal_size(rows, cols)?;
+        self.shells.lock()
+            .map_err(|_| "Failed to lock shell sessions".to_owned())?
+            .resize(&id, rows, cols)
+    }
+
+    pub fn close_shell(&self, id: String) -> Result<(), String> {
+        self.shells.lock()
+            .map_err(|_| "Failed to lock shell sessions".to_owned())?
+            .close(&id)
+    }
+
+    pub fn shell_sessions(&self) -> Result<Vec<SessionInfo>, String> {
+        Ok(self.shells.lock()
+            .map_err(|_| "Failed to lock shell sessions".to_owned())?
+            .list())
     }
 
     // download_file
@@ -99,13 +304,245 @@ impl Subsystem {
     }
 
 
+    // read_file
+    //
+    // Read part of a file instead of the whole thing: a window given by offset and
+    // length, or the last few lines, which is usually all that's wanted of a log.  The
+    // returned offset and file size let the ground pick up where it left off as the
+    // file grows.  Optionally the data is gzipped (and then always base64 encoded) to
+    // save link time.
+    pub fn read_file(&self,  path: String,
+                             offset: Option<f64>,
+                             length: Option<i32>,
+                             tail_lines: Option<i32>,
+                             compress: bool) -> Result<(FileRange, Vec<u8>), String> {
+        let length = match length {
+            Some(length) if length <= 0 => return Err("Length must be positive".to_owned()),
+            Some(length) => Some(length as u64),
+            None => None,
+        };
+        let from = match (tail_lines, offset) {
+            (Some(_), Some(_)) => return Err("Offset can't be used with tailLines".to_owned()),
+            (Some(lines), None) if lines < 0 => return Err("tailLines must not be negative".to_owned()),
+            (Some(lines), None) => ReadFrom::Tail(lines as usize, length),
+            (None, offset) => ReadFrom::Offset(byte_count(offset.unwrap_or(0.0), "Offset")?, length),
+        };
+
+        let range = files::read_range(Path::new(&path), from)?;
+        let data = if compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&range.data)
+                .and_then(|_| encoder.finish())
+                .map_err(|_| "Failed to compress file data".to_owned())?
+        } else {
+            range.data.clone()
+        };
+
+        Ok((range, data))
+    }
+
+    // Browsing the file system
+    //
+    // list_directory lists a directory, going `depth` levels into subdirectories if
+    // `recursive` is set (all the way down if no depth is given), up to a limit on the
+    // number of entries.  stat describes a single path and file_checksum hashes a file
+    // or part of one, so the ground can check a file without downloading it.
+    pub fn list_directory(&self, path: String, recursive: Option<bool>, depth: Option<i32>) -> Result<(Vec<files::FileInfo>, bool), String> {
+        let depth = match (recursive.unwrap_or(false), depth) {
+            (false, _) => 0,
+            (true, Some(depth)) if depth < 0 => return Err("Depth must not be negative".to_owned()),
+            (true, Some(depth)) => depth as usize,
+            (true, None) => usize::max_value(),
+        };
+
+        files::list_dir(Path::new(&path), depth)
+    }
+
+    pub fn stat(&self, path: String) -> Result<files::FileInfo, String> {
+        files::stat(Path::new(&path))
+    }
+
+    pub fn file_checksum(&self,  path: String,
+                                 hash_type: HashType,
+                                 offset: Option<f64>,
+                                 length: Option<f64>) -> Result<(String, u64), String> {
+        let offset = byte_count(offset.unwrap_or(0.0), "Offset")?;
+        let length = match length {
+            Some(length) => Some(byte_count(length, "Length")?),
+            None => None,
+        };
+
+        let meta = std::fs::metadata(&path).map_err(|_| "Failed to open file".to_owned())?;
+        if !meta.is_file() {
+            return Err("Path is not a regular file".to_owned());
+        }
+
+        checksum::range_checksum(&path, hash_type, offset, length)
+            .map_err(|_| "Failed to read file".to_owned())
+    }
+
+    // File management
+    //
+    // Typed replacements for rm, mv, cp, mkdir and chmod, so the ground can clean up
+    // without going through run_command.  Each takes a list of paths and carries on
+    // past failures, reporting what happened to every path separately.  Moving or
+    // copying several sources at once needs an existing directory as the target.
+    pub fn delete_files(&self, paths: Vec<String>, recursive: Option<bool>) -> Result<Vec<PathResult>, String> {
+        let recursive = recursive.unwrap_or(false);
+        Ok(paths.iter()
+            .map(|path| PathResult::new(path, files::remove(Path::new(path), recursive)))
+            .collect())
+    }
+
+    pub fn move_files(&self, sources: Vec<String>, target: String, overwrite: Option<bool>) -> Result<Vec<PathResult>, String> {
+        check_targets(&sources, &target)?;
+        let overwrite = overwrite.unwrap_or(false);
+        Ok(sources.iter()
+            .map(|source| PathResult::new(source, files::rename(Path::new(source), Path::new(&target), overwrite)))
+            .collect())
+    }
+
+    pub fn copy_files(&self, sources: Vec<String>, target: String, overwrite: Option<bool>) -> Result<Vec<PathResult>, String> {
+        check_targets(&sources, &target)?;
+        let overwrite = overwrite.unwrap_or(false);
+        Ok(sources.iter()
+            .map(|source| PathResult::new(source, files::copy(Path::new(source), Path::new(&target), overwrite)))
+            .collect())
+    }
+
+    pub fn make_directories(&self, paths: Vec<String>, parents: Option<bool>, mode: Option<String>) -> Result<Vec<PathResult>, String> {
+        let mode = match mode {
+            Some(mode) => Some(files::parse_mode(&mode)?),
+            None => None,
+        };
+        let parents = parents.unwrap_or(false);
+        Ok(paths.iter()
+            .map(|path| PathResult::new(path, files::make_dir(Path::new(path), parents, mode)))
+            .collect())
+    }
+
+    pub fn change_mode(&self, paths: Vec<String>, mode: String) -> Result<Vec<PathResult>, String> {
+        let mode = files::parse_mode(&mode)?;
+        Ok(paths.iter()
+            .map(|path| PathResult::new(path, files::set_attributes(Path::new(path), Some(mode), None)))
+            .collect())
+    }
+
+
+    // Chunked downloads
+    //
+    // download_file only suits files that fit in a single radio frame.  Anything bigger
+    // is downloaded in chunks instead (see transfer.rs): start_download snapshots the
+    // file and reports its size, hash and chunk count, download_chunk returns one chunk,
+    // and ack_download_chunks records which chunks the ground has.  download_status
+    // lists the chunks still missing so a transfer can resume on the next pass, and
+    // finish_download removes the snapshot once the ground is done with it.
+    pub fn start_download(&self, path: String, chunk_size: Option<i32>) -> Result<Download, String> {
+        let chunk_size = match chunk_size {
+            Some(size) if size <= 0 => return Err("Chunk size must be positive".to_owned()),
+            Some(size) => Some(size as usize),
+            None => None,
+        };
+
+        let mut staging = self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .prepare(&path, chunk_size)?;
+        staging.write(|data_path| {
+            std::fs::copy(&path, data_path)
+                .map(|_| ())
+                .map_err(|_| "Failed to copy file to staging area".to_owned())
+        })?;
+
+        self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .register(staging)
+    }
+
+    pub fn download_chunk(&self, id: String, index: i32) -> Result<Chunk, String> {
+        if index < 0 {
+            return Err("Chunk index must not be negative".to_owned());
+        }
+
+        self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .chunk(&id, index as u64)
+    }
+
+    // Pack a directory into a gzipped tar archive and start a chunked download of it,
+    // so the archive's size and hash are known before the first chunk is fetched.
+    // Files are chosen by glob patterns on their path within the directory and by
+    // modification time (see archive.rs).
+    pub fn archive_directory(&self,  path: String,
+                                     include: Option<Vec<String>>,
+                                     exclude: Option<Vec<String>>,
+                                     modified_after: Option<f64>,
+                                     modified_before: Option<f64>,
+                                     chunk_size: Option<i32>) -> Result<(Download, usize), String> {
+        let chunk_size = match chunk_size {
+            Some(size) if size <= 0 => return Err("Chunk size must be positive".to_owned()),
+            Some(size) => Some(size as usize),
+            None => None,
+        };
+        let filter = Filter {
+            include: include.unwrap_or_default(),
+            exclude: exclude.unwrap_or_default(),
+            modified_after: modified_after.map(file_time).transpose()?,
+            modified_before: modified_before.map(file_time).transpose()?,
+        };
+
+        // The walk, compression and hashing can take minutes, so they happen with the
+        // downloads unlocked
+        let mut count = 0;
+        let name = format!("{}.tar.gz", path.trim_end_matches('/'));
+        let mut staging = self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .prepare(&name, chunk_size)?;
+        staging.write(|data_path| {
+            count = archive::create(Path::new(&path), data_path, &filter)?;
+            Ok(())
+        })?;
+
+        let download = self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .register(staging)?;
+
+        Ok((download, count))
+    }
+
+    pub fn ack_download_chunks(&self, id: String, chunks: Vec<i32>) -> Result<Download, String> {
+        if chunks.iter().any(|&index| index < 0) {
+            return Err("Chunk index must not be negative".to_owned());
+        }
+        let chunks: Vec<u64> = chunks.iter().map(|&index| index as u64).collect();
+
+        self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .ack(&id, &chunks)
+    }
+
+    pub fn download_status(&self, id: Option<String>) -> Result<Vec<Download>, String> {
+        let downloads = self.downloads.lock().map_err(|_| "Failed to lock downloads".to_owned())?;
+        match id {
+            Some(id) => Ok(vec![downloads.status(&id)?]),
+            None => Ok(downloads.list()),
+        }
+    }
+
+    pub fn finish_download(&self, id: String) -> Result<(), String> {
+        self.downloads.lock()
+            .map_err(|_| "Failed to lock downloads".to_owned())?
+            .finish(&id)
+    }
+
+
     // upload_file
     //
     // Upload a file to the computer running the radio service.  The file data
     // is included in the GraphQL query and will be written in the file path 
     // specified.  Optionally, the data can be decoded from base64 before it is
-    // written to the file (this is useful for binary files).
-    pub fn upload_file(&self, path: Option<String>, dec: Option<bool>, data: Option<String>) -> Result<String, String> {
+    // written to the file (this is useful for binary files).  The file is replaced
+    // atomically, so an interrupted write never leaves a truncated file behind.
+    pub fn upload_file(&self, path: Option<String>, dec: Option<bool>, data: Option<String>) -> Result<usize, String> {
         
         // Decode the uploaded data if necessary
         let d = data.ok_or("No data for file".to_owned())?;
@@ -115,16 +552,257 @@ impl Subsystem {
             d.as_bytes().to_vec()
         };
 
-        // Open the new file for writing
+        // Write data to the file
         let p = path.ok_or("No file path specified".to_owned())?;
-        let mut f = File::create(p).map_err(|_| "Failed to create new file".to_owned())?;
+        files::write_atomic(Path::new(&p), &data_vec, None, None)?;
+        Ok(data_vec.len())
+    }
 
-        // Write data to the file
-        f.write_all(&data_vec)
-            .and_then(|_| Ok("Wrote data to file".to_owned()))
-            .map_err(|_| "Failed to write data to file".to_owned())            
+
+    // Chunked uploads
+    //
+    // The reverse of a chunked download.  start_upload gives the target path, size and
+    // hash of the file, plus an optional mode and owner, and gets back an ID and chunk
+    // count.  upload_chunk writes one chunk into the staging area, optionally checking
+    // its CRC-32 on the way.  finish_upload checks that every chunk has arrived and the
+    // whole file matches the hash before atomically moving it into place.
+    // upload_status lists the missing chunks so an upload can resume on a later pass.
+    pub fn start_upload(&self,  path: String,
+                                size: f64,
+                                hash: String,
+                                hash_type: HashType,
+                                chunk_size: Option<i32>,
+                                mode: Option<String>,
+                                owner: Option<String>) -> Result<Upload, String> {
+        let size = byte_count(size, "File size")?;
+        let chunk_size = match chunk_size {
+            Some(size) if size <= 0 => return Err("Chunk size must be positive".to_owned()),
+            Some(size) => Some(size as usize),
+            None => None,
+        };
+        let mode = match mode {
+            Some(mode) => Some(files::parse_mode(&mode)?),
+            None => None,
+        };
+
+        self.uploads.lock()
+            .map_err(|_| "Failed to lock uploads".to_owned())?
+            .start(UploadRequest {
+                path,
+                size,
+                hash,
+                hash_type,
+                chunk_size,
+                mode,
+                owner,
+            })
+    }
+
+    // apply_patch
+    //
+    // Rebuild a file from an existing one and a bsdiff patch (see patch.rs), usually
+    // uploaded with start_upload, so a small change to a big file costs a small
+    // upload.  The new file is built in the staging area, as long as it is no bigger
+    // than an upload could be and fits there, and is only installed at the target
+    // (the original file if no target is given) once it matches the hash.  It keeps
+    // the original file's mode unless another is given.
+    pub fn apply_patch(&self,  base: String,
+                               patch: String,
+                               target: Option<String>,
+                               hash: String,
+                               hash_type: HashType,
+                               mode: Option<String>,
+                               owner: Option<String>) -> Result<(String, u64), String> {
+        let hash = hash.to_lowercase();
+        if hash.len() != hash_type.hex_len() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Hash must be {} hex digits", hash_type.hex_len()));
+        }
+        let mode = match mode {
+            Some(mode) => files::parse_mode(&mode)?,
+            None => files::stat(Path::new(&base))
+                .map_err(|_| "Failed to open file to patch".to_owned())?
+                .mode,
+        };
+        let owner = match owner {
+            Some(owner) => Some(files::parse_owner(&owner)?),
+            None => None,
+        };
+        let target = target.unwrap_or_else(|| base.clone());
+        files::check_target(Path::new(&target)).map_err(|_| "Target directory does not exist".to_owned())?;
+
+        std::fs::create_dir_all(&self.patch_dir).map_err(|_| "Failed to create patch staging area".to_owned())?;
+        let staged = self.patch_dir.join(format!("{}.new", new_id(&target)));
+        let max_size = self.max_patch_size.min(free_space(&self.patch_dir)?);
+
+        let result = patch::apply(Path::new(&base), Path::new(&patch), &staged, max_size).and_then(|size| {
+            let actual = checksum::file_checksum(&staged, hash_type).map_err(|_| "Failed to hash patched file".to_owned())?;
+            if actual != hash {
+                return Err(format!("Patched file hash mismatch: expected {}, got {}", hash, actual));
+            }
+            files::install_file(&staged, Path::new(&target), Some(mode), owner)?;
+            Ok(size)
+        });
+        let _ = std::fs::remove_file(&staged);
+
+        Ok((target, result?))
+    }
+
+    pub fn upload_chunk(&self, id: String, index: i32, data: String, crc32: Option<String>) -> Result<Upload, String> {
+        if index < 0 {
+            return Err("Chunk index must not be negative".to_owned());
+        }
+        let data = decode(data).map_err(|_| "Failed to decode chunk data".to_owned())?;
+        let crc32 = match crc32 {
+            Some(crc) => Some(u32::from_str_radix(&crc, 16).map_err(|_| "Invalid chunk CRC".to_owned())?),
+            None => None,
+        };
+
+        self.uploads.lock()
+            .map_err(|_| "Failed to lock uploads".to_owned())?
+            .chunk(&id, index as u64, &data, crc32)
+    }
+
+    pub fn finish_upload(&self, id: String) -> Result<Upload, String> {
+        self.uploads.lock()
+            .map_err(|_| "Failed to lock uploads".to_owned())?
+            .finish(&id)
+    }
+
+    pub fn cancel_upload(&self, id: String) -> Result<(), String> {
+        self.uploads.lock()
+            .map_err(|_| "Failed to lock uploads".to_owned())?
+            .cancel(&id)
+    }
+
+    pub fn upload_status(&self, id: Option<String>) -> Result<Vec<Upload>, String> {
+        let uploads = self.uploads.lock().map_err(|_| "Failed to lock uploads".to_owned())?;
+        match id {
+            Some(id) => Ok(vec![uploads.status(&id)?]),
+            None => Ok(uploads.list()),
+        }
+    }
+
+
+
+    // Software updates
+    //
+    // install_update checks an uploaded package against its signature, installs it as
+    // a new version of a target and switches the target over to it.  The target is
+    // then restarted and health checked in the background, and rolled back if the
+    // check fails; update_status follows this.  rollback_update puts the previous
+    // version back by hand.  See update.rs.
+    pub fn install_update(&self,  target: String,
+                                  version: String,
+                                  package: String,
+                                  signature: String) -> Result<Update, String> {
+        self.updates.lock()
+            .map_err(|_| "Failed to lock updates".to_owned())?
+            .install(&target, &version, Path::new(&package), &signature)
+    }
+
+    pub fn rollback_update(&self, target: String) -> Result<Update, String> {
+        self.updates.lock()
+            .map_err(|_| "Failed to lock updates".to_owned())?
+            .rollback(&target)
+    }
+
+    pub fn update_status(&self, target: Option<String>) -> Result<Vec<TargetStatus>, String> {
+        let updates = self.updates.lock().map_err(|_| "Failed to lock updates".to_owned())?;
+        match target {
+            Some(target) => Ok(vec![updates.status(&target)?]),
+            None => Ok(updates.list()),
+        }
+    }
+
+
+    // Time-tagged commands
+    //
+    // queue_command and queue_graphql store a command line or a GraphQL request to be
+    // run at a given time, even across reboots.  The outcome is kept with the entry
+    // until it is deleted, so queued_commands can fetch it at the next pass.  See
+    // queue.rs.
+    pub fn queue_command(&self, execute_at: f64, command: String, args: Option<Vec<String>>) -> Result<Entry, String> {
+        self.queue.lock()
+            .map_err(|_| "Failed to lock command queue".to_owned())?
+            .add(execute_time(execute_at)?, Action::Command { path: command, args: args.unwrap_or_default() })
+    }
+
+    pub fn queue_graphql(&self, execute_at: f64, query: String, service: Option<String>) -> Result<Entry, String> {
+        self.queue.lock()
+            .map_err(|_| "Failed to lock command queue".to_owned())?
+            .add(execute_time(execute_at)?, Action::Graphql { service, query })
+    }
+
+    pub fn queued_commands(&self, id: Option<String>) -> Result<Vec<Entry>, String> {
+        let queue = self.queue.lock().map_err(|_| "Failed to lock command queue".to_owned())?;
+        match id {
+            Some(id) => Ok(vec![queue.status(&id)?]),
+            None => Ok(queue.list()),
+        }
+    }
+
+    pub fn delete_queued(&self, id: String) -> Result<(), String> {
+        self.queue.lock()
+            .map_err(|_| "Failed to lock command queue".to_owned())?
+            .remove(&id)
+    }
+
+    pub fn clear_queue(&self, finished_only: Option<bool>) -> Result<usize, String> {
+        Ok(self.queue.lock()
+            .map_err(|_| "Failed to lock command queue".to_owned())?
+            .clear(finished_only.unwrap_or(false)))
+    }
+
+
+    // CFDP transactions
+    //
+    // cfdp_put sends a file to the ground entity with a class 1 or class 2 transaction.
+    // Transactions the ground starts need nothing from GraphQL; all of them can be
+    // looked at with cfdp_transactions and stopped with cfdp_cancel.  See cfdp.rs.
+    fn cfdp(&self) -> Result<&Arc<Mutex<Entity>>, String> {
+        self.cfdp.as_ref().ok_or_else(|| "CFDP is disabled".to_owned())
+    }
+
+    pub fn cfdp_put(&self, source: String, destination: String, acknowledged: Option<bool>) -> Result<Transaction, String> {
+        self.cfdp()?.lock()
+            .map_err(|_| "Failed to lock CFDP entity".to_owned())?
+            .put(&source, &destination, acknowledged.unwrap_or(true))
+    }
+
+    pub fn cfdp_transactions(&self, id: Option<String>) -> Result<Vec<Transaction>, String> {
+        let entity = self.cfdp()?.lock().map_err(|_| "Failed to lock CFDP entity".to_owned())?;
+        match id {
+            Some(id) => Ok(vec![entity.status(&id)?]),
+            None => Ok(entity.list()),
+        }
+    }
+
+    pub fn cfdp_cancel(&self, id: String) -> Result<Transaction, String> {
+        self.cfdp()?.lock()
+            .map_err(|_| "Failed to lock CFDP entity".to_owned())?
+            .cancel(&id)
     }
 
+    pub fn cfdp_delete(&self, id: String) -> Result<(), String> {
+        self.cfdp()?.lock()
+            .map_err(|_| "Failed to lock CFDP entity".to_owned())?
+            .delete(&id)
+    }
+
+
+    // keep_alive
+    //
+    // Tells the watchdog the ground can still reach us, restarting the countdown to a
+    // reboot.  Returns the seconds until the new deadline.
+    pub fn keep_alive(&self) -> Result<f64, String> {
+        let watchdog = self.watchdog.as_ref().ok_or("Keep-alive watchdog is disabled".to_owned())?;
+        watchdog.keep_alive().map(|remaining| remaining as f64)
+    }
+
+    pub fn keep_alive_remaining(&self) -> Result<f64, String> {
+        let watchdog = self.watchdog.as_ref().ok_or("Keep-alive watchdog is disabled".to_owned())?;
+        Ok(watchdog.remaining() as f64)
+    }
 
 
     // The following functions are copied from the KubOS radio service tutorial
@@ -157,6 +835,62 @@ impl Subsystem {
         }
     }
 
+    pub fn bad_frames(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.bad_frames),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn auth_failures(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.auth_failures),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn corrected_codewords(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.corrected_codewords),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn uncorrectable_codewords(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.uncorrectable_codewords),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn retransmitted_packets(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.retransmitted_packets),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn acked_packets(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.acked_packets),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn abandoned_packets(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.abandoned_packets),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
+    pub fn duplicate_packets(&self) -> Result<i32, String> {
+        match self.link_telem.lock() {
+            Ok(data) => Ok(data.duplicate_packets),
+            Err(_) => Err("Failed to lock telemetry".to_owned()),
+        }
+    }
+
     pub fn errors(&self) -> Result<Vec<String>, String> {
         match self.telem.lock() {
             Ok(data) => {
@@ -165,4 +899,49 @@ impl Subsystem {
             Err(_) => Err("Failed to lock telemetry".to_owned()),
         }
     }
-}
\ No newline at end of file
+}
+
+// Several sources can only go into a directory
+fn check_targets(sources: &[String], target: &str) -> Result<(), String> {
+    if sources.len() > 1 && !Path::new(target).is_dir() {
+        return Err(format!("Target {} must be a directory when there are several sources", target));
+    }
+    Ok(())
+}
+
+// Check an execution time from the ground, in seconds since the epoch.  GraphQL ints
+// are only 32 bits, which runs out in 2038, so times come in as floats; any fraction
+// of a second is dropped.
+fn execute_time(execute_at: f64) -> Result<u64, String> {
+    if execute_at.is_nan() || execute_at < 0.0 || execute_at > MAX_EXACT_FLOAT {
+        return Err("Execution time must be a number of seconds since the epoch".to_owned());
+    }
+    Ok(execute_at as u64)
+}
+
+// Check a file modification time from the ground, in seconds since the epoch.  Like
+// execution times, these come in as floats to reach past 2038, and any fraction of a
+// second is dropped.
+fn file_time(time: f64) -> Result<i64, String> {
+    if time.is_nan() || time.abs() > MAX_EXACT_FLOAT {
+        return Err("Modification time must be a number of seconds since the epoch".to_owned());
+    }
+    Ok(time as i64)
+}
+
+// Check a file size or offset from the ground.  GraphQL ints are only 32 bits, which
+// stops short of 2 GiB, so these come in as floats too.
+fn byte_count(value: f64, what: &str) -> Result<u64, String> {
+    if value.is_nan() || value < 0.0 || value > MAX_EXACT_FLOAT || value.fract() != 0.0 {
+        return Err(format!("{} must be a whole number of bytes", what));
+    }
+    Ok(value as u64)
+}
+
+// Check a terminal size from the ground
+fn terminal_size(rows: i32, cols: i32) -> Result<(u16, u16), String> {
+    if rows <= 0 || cols <= 0 || rows > 1000 || cols > 1000 {
+        return Err("Terminal rows and columns must be between 1 and 1000".to_owned());
+    }
+    Ok((rows as u16, cols as u16))
+}
@@ -0,0 +1,928 @@
+// GraphQL types returned by the radio service.  Mutation responses follow the usual
+// KubOS convention of a `success` flag and an `errors` string alongside any result
+// fields.
+
+use crate::cfdp::{Direction, State as TransactionState, Transaction};
+use crate::checksum::HashType;
+use crate::command;
+use crate::files::{FileInfo, FileRange, FileType as Kind, PathResult};
+use crate::jobs::{Job, JobState as State, OutputPage, Stream};
+use crate::queue::{Action, Entry, EntryState};
+use crate::shell::{SessionInfo, ShellOutput as Output};
+use crate::transfer::{Chunk, Download, Upload};
+use crate::update::{TargetStatus, Update, UpdateState as Stage};
+use base64::encode;
+
+/// Common response fields structure for requests
+/// which don't return any specific data
+#[derive(GraphQLObject)]
+pub struct GenericResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+}
+
+/// How a command run by runCommand finished
+#[derive(GraphQLObject)]
+pub struct CommandStatus {
+    /// Exit code, if the command exited normally
+    pub exit_code: Option<i32>,
+    /// Signal that killed the command, if any
+    pub signal: Option<i32>,
+    /// Whether the command was killed for running past its timeout
+    pub timed_out: bool,
+}
+
+impl From<command::CommandStatus> for CommandStatus {
+    fn from(status: command::CommandStatus) -> CommandStatus {
+        CommandStatus {
+            exit_code: status.exit_code,
+            signal: status.signal,
+            timed_out: status.timed_out,
+        }
+    }
+}
+
+/// Response for the runCommand mutation
+#[derive(GraphQLObject)]
+pub struct RunCommandResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// Standard output of the command (empty if redirected to a file)
+    pub stdout: String,
+    /// Standard error of the command (empty if redirected to a file)
+    pub stderr: String,
+    /// Whether stdout was cut short at the output limit
+    pub stdout_truncated: bool,
+    /// Whether stderr was cut short at the output limit
+    pub stderr_truncated: bool,
+    /// How the command finished (absent if it could not be run or is running as a job)
+    pub status: Option<CommandStatus>,
+    /// ID of the background job, if the command was started as one
+    pub job_id: Option<String>,
+}
+
+/// State of a background job
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum JobState {
+    /// Still running
+    Running,
+    /// Exited by itself
+    Exited,
+    /// Killed by cancelJob
+    Cancelled,
+    /// Killed for running past the job timeout
+    TimedOut,
+    /// Killed for writing more output than allowed
+    OutputLimit,
+    /// Disappeared without recording an exit code
+    Lost,
+}
+
+impl From<State> for JobState {
+    fn from(state: State) -> JobState {
+        match state {
+            State::Running => JobState::Running,
+            State::Exited => JobState::Exited,
+            State::Cancelled => JobState::Cancelled,
+            State::TimedOut => JobState::TimedOut,
+            State::OutputLimit => JobState::OutputLimit,
+            State::Lost => JobState::Lost,
+        }
+    }
+}
+
+/// A background job started by runCommand
+#[derive(GraphQLObject)]
+pub struct JobInfo {
+    /// ID used to refer to this job
+    pub job_id: String,
+    /// Command being run
+    pub path: String,
+    /// Command arguments
+    pub args: Vec<String>,
+    /// Current state of the job
+    pub state: JobState,
+    /// Exit code, once the job has exited
+    pub exit_code: Option<i32>,
+    /// When the job started, in seconds since the epoch
+    pub started: f64,
+    /// When the job finished, in seconds since the epoch
+    pub finished: Option<f64>,
+}
+
+impl From<Job> for JobInfo {
+    fn from(job: Job) -> JobInfo {
+        JobInfo {
+            job_id: job.id,
+            path: job.path,
+            args: job.args,
+            state: job.state.into(),
+            exit_code: job.exit_code,
+            started: job.started as f64,
+            finished: job.finished.map(|time| time as f64),
+        }
+    }
+}
+
+/// Response for the cancelJob mutation
+#[derive(GraphQLObject)]
+pub struct JobResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// State of the job after the request
+    pub job: Option<JobInfo>,
+}
+
+/// Output stream of a background job
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum JobStream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl From<JobStream> for Stream {
+    fn from(stream: JobStream) -> Stream {
+        match stream {
+            JobStream::Stdout => Stream::Stdout,
+            JobStream::Stderr => Stream::Stderr,
+        }
+    }
+}
+
+/// One page of a background job's output
+#[derive(GraphQLObject)]
+pub struct JobOutput {
+    /// Offset of this page in the stream, in bytes
+    pub offset: f64,
+    /// Offset to ask for to get the next page
+    pub next_offset: f64,
+    /// Total bytes written to the stream so far
+    pub size: f64,
+    /// Page contents, base64 encoded if requested
+    pub data: String,
+}
+
+impl JobOutput {
+    pub fn new(page: OutputPage, base64: bool) -> JobOutput {
+        JobOutput {
+            offset: page.offset as f64,
+            next_offset: (page.offset + page.data.len() as u64) as f64,
+            size: page.size as f64,
+            data: if base64 {
+                encode(&page.data)
+            } else {
+                String::from_utf8_lossy(&page.data).to_string()
+            },
+        }
+    }
+}
+
+/// Part of a file returned by readFile
+#[derive(GraphQLObject)]
+pub struct FileContents {
+    /// Offset in the file of the first byte returned
+    pub offset: f64,
+    /// Number of bytes of the file returned, before any compression
+    pub length: i32,
+    /// Offset to ask for to get the rest of the file
+    pub next_offset: f64,
+    /// Size of the whole file in bytes
+    pub size: f64,
+    /// Whether the data is gzip compressed
+    pub compressed: bool,
+    /// File data, base64 encoded if requested or compressed
+    pub data: String,
+}
+
+impl FileContents {
+    pub fn new(range: FileRange, data: Vec<u8>, compressed: bool, base64: bool) -> FileContents {
+        FileContents {
+            offset: range.offset as f64,
+            length: range.data.len() as i32,
+            next_offset: (range.offset + range.data.len() as u64) as f64,
+            size: range.size as f64,
+            compressed,
+            data: if base64 || compressed {
+                encode(&data)
+            } else {
+                String::from_utf8_lossy(&data).to_string()
+            },
+        }
+    }
+}
+
+/// Response for the keepAlive mutation
+#[derive(GraphQLObject)]
+pub struct KeepAliveResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// Seconds until the OBC is rebooted if no further keep-alive arrives
+    pub remaining: f64,
+}
+
+/// Response for the uploadFile mutation
+#[derive(GraphQLObject)]
+pub struct UploadFileResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// Path of the file that was written
+    pub path: String,
+    /// Number of bytes written to the file
+    pub bytes_written: i32,
+}
+
+/// Response for the applyPatch mutation
+#[derive(GraphQLObject)]
+pub struct PatchResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// Path of the file that was written
+    pub path: String,
+    /// Size of the patched file in bytes
+    pub size: f64,
+}
+
+/// State of a chunked download
+#[derive(GraphQLObject)]
+pub struct DownloadInfo {
+    /// ID used to refer to this transfer
+    pub file_id: String,
+    /// File the download was started from
+    pub path: String,
+    /// Total size of the file in bytes
+    pub size: f64,
+    /// SHA-256 of the whole file, in hex
+    pub hash: String,
+    /// Size of each chunk in bytes (the last one may be shorter)
+    pub chunk_size: i32,
+    /// Number of chunks in the file
+    pub num_chunks: i32,
+    /// Chunks the ground has not yet acknowledged
+    pub missing: Vec<i32>,
+}
+
+impl From<Download> for DownloadInfo {
+    fn from(download: Download) -> DownloadInfo {
+        DownloadInfo {
+            missing: download.missing().iter().map(|&index| index as i32).collect(),
+            num_chunks: download.num_chunks as i32,
+            file_id: download.id,
+            path: download.path,
+            size: download.size as f64,
+            hash: download.hash,
+            chunk_size: download.chunk_size as i32,
+        }
+    }
+}
+
+/// Response for the startDownload and ackDownloadChunks mutations
+#[derive(GraphQLObject)]
+pub struct DownloadResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// State of the download after the request
+    pub download: Option<DownloadInfo>,
+}
+
+/// Response for the archiveDirectory mutation
+#[derive(GraphQLObject)]
+pub struct ArchiveResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// Number of files in the archive
+    pub files: i32,
+    /// Download of the archive, ready for its chunks to be fetched
+    pub download: Option<DownloadInfo>,
+}
+
+/// One chunk of a download
+#[derive(GraphQLObject)]
+pub struct DownloadChunk {
+    /// Index of the chunk
+    pub index: i32,
+    /// Offset of the chunk in the file, in bytes
+    pub offset: f64,
+    /// Number of bytes in the chunk
+    pub size: i32,
+    /// CRC-32 of the chunk data, in hex
+    pub crc32: String,
+    /// Chunk data, base64 encoded
+    pub data: String,
+}
+
+impl From<Chunk> for DownloadChunk {
+    fn from(chunk: Chunk) -> DownloadChunk {
+        DownloadChunk {
+            index: chunk.index as i32,
+            offset: chunk.offset as f64,
+            size: chunk.data.len() as i32,
+            crc32: format!("{:08x}", chunk.crc32),
+            data: encode(&chunk.data),
+        }
+    }
+}
+
+/// Checksum algorithm used to verify or describe a file
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum ChecksumType {
+    /// SHA-256
+    Sha256,
+    /// CRC-32 (IEEE 802.3)
+    Crc32,
+}
+
+impl From<ChecksumType> for HashType {
+    fn from(checksum: ChecksumType) -> HashType {
+        match checksum {
+            ChecksumType::Sha256 => HashType::Sha256,
+            ChecksumType::Crc32 => HashType::Crc32,
+        }
+    }
+}
+
+/// State of a chunked upload
+#[derive(GraphQLObject)]
+pub struct UploadInfo {
+    /// ID used to refer to this transfer
+    pub file_id: String,
+    /// Where the file will be installed once complete
+    pub path: String,
+    /// Total size of the file in bytes
+    pub size: f64,
+    /// Expected checksum of the whole file, in hex
+    pub hash: String,
+    /// Size of each chunk in bytes (the last one may be shorter)
+    pub chunk_size: i32,
+    /// Number of chunks in the file
+    pub num_chunks: i32,
+    /// Chunks that have not arrived yet
+    pub missing: Vec<i32>,
+}
+
+impl From<Upload> for UploadInfo {
+    fn from(upload: Upload) -> UploadInfo {
+        UploadInfo {
+            missing: upload.missing().iter().map(|&index| index as i32).collect(),
+            num_chunks: upload.num_chunks as i32,
+            file_id: upload.id,
+            path: upload.path,
+            size: upload.size as f64,
+            hash: upload.hash,
+            chunk_size: upload.chunk_size as i32,
+        }
+    }
+}
+
+/// Response for the startUpload, uploadChunk and finishUpload mutations
+#[derive(GraphQLObject)]
+pub struct UploadResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// State of the upload after the request
+    pub upload: Option<UploadInfo>,
+}
+
+/// An interactive shell session
+#[derive(GraphQLObject)]
+pub struct ShellSession {
+    /// ID used to refer to this session
+    pub session_id: String,
+    /// Process ID of the shell
+    pub pid: i32,
+    /// Terminal height in rows
+    pub rows: i32,
+    /// Terminal width in columns
+    pub cols: i32,
+    /// Whether the shell is still running
+    pub running: bool,
+    /// Exit code, once the shell has exited
+    pub exit_code: Option<i32>,
+    /// Signal that killed the shell, if any
+    pub signal: Option<i32>,
+    /// When the session was opened, in seconds since the epoch
+    pub opened: f64,
+    /// Seconds since the session was last used
+    pub idle: i32,
+}
+
+impl From<SessionInfo> for ShellSession {
+    fn from(info: SessionInfo) -> ShellSession {
+        ShellSession {
+            session_id: info.id,
+            pid: info.pid as i32,
+            rows: i32::from(info.rows),
+            cols: i32::from(info.cols),
+            running: info.running,
+            exit_code: info.exit_code,
+            signal: info.signal,
+            opened: info.opened as f64,
+            idle: info.idle as i32,
+        }
+    }
+}
+
+/// Response for the shell session mutations
+#[derive(GraphQLObject)]
+pub struct ShellResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// State of the session after the request
+    pub session: Option<ShellSession>,
+    /// Bytes of input the shell took, for shellWrite.  Any less than were sent should
+    /// be sent again.
+    pub written: Option<i32>,
+}
+
+/// Output read from a shell session
+#[derive(GraphQLObject)]
+pub struct ShellOutput {
+    /// Cursor of the first byte of data.  Later than the cursor asked for if output
+    /// in between was dropped.
+    pub cursor: f64,
+    /// Cursor to ask for next time
+    pub next_cursor: f64,
+    /// Whether output was dropped because it wasn't read in time
+    pub dropped: bool,
+    /// Terminal output, base64 encoded if requested
+    pub data: String,
+}
+
+impl ShellOutput {
+    pub fn new(output: Output, base64: bool) -> ShellOutput {
+        ShellOutput {
+            cursor: output.cursor as f64,
+            next_cursor: output.next_cursor as f64,
+            dropped: output.dropped,
+            data: if base64 {
+                encode(&output.data)
+            } else {
+                String::from_utf8_lossy(&output.data).to_string()
+            },
+        }
+    }
+}
+
+/// Type of a directory entry
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum FileType {
+    /// Regular file
+    File,
+    /// Directory
+    Directory,
+    /// Symbolic link (never followed)
+    Symlink,
+    /// Device, socket or pipe
+    Other,
+}
+
+impl From<Kind> for FileType {
+    fn from(kind: Kind) -> FileType {
+        match kind {
+            Kind::File => FileType::File,
+            Kind::Directory => FileType::Directory,
+            Kind::Symlink => FileType::Symlink,
+            Kind::Other => FileType::Other,
+        }
+    }
+}
+
+/// Metadata of a file or directory
+#[derive(GraphQLObject)]
+pub struct FileEntry {
+    /// Full path of the entry
+    pub path: String,
+    /// Type of the entry
+    pub file_type: FileType,
+    /// Size in bytes
+    pub size: f64,
+    /// Last modification time, in seconds since the epoch
+    pub mtime: f64,
+    /// Permission bits in octal, e.g. "0644"
+    pub mode: String,
+    /// Owning user ID
+    pub uid: i32,
+    /// Owning group ID
+    pub gid: i32,
+}
+
+impl From<FileInfo> for FileEntry {
+    fn from(info: FileInfo) -> FileEntry {
+        FileEntry {
+            path: info.path,
+            file_type: info.file_type.into(),
+            size: info.size as f64,
+            mtime: info.mtime as f64,
+            mode: format!("{:04o}", info.mode),
+            uid: info.uid as i32,
+            gid: info.gid as i32,
+        }
+    }
+}
+
+/// Contents of a directory
+#[derive(GraphQLObject)]
+pub struct DirectoryListing {
+    /// Entries, sorted by path
+    pub entries: Vec<FileEntry>,
+    /// Whether the listing was cut short at the entry limit
+    pub truncated: bool,
+}
+
+impl From<(Vec<FileInfo>, bool)> for DirectoryListing {
+    fn from((entries, truncated): (Vec<FileInfo>, bool)) -> DirectoryListing {
+        DirectoryListing {
+            entries: entries.into_iter().map(FileEntry::from).collect(),
+            truncated,
+        }
+    }
+}
+
+/// Checksum of a file or part of one
+#[derive(GraphQLObject)]
+pub struct FileChecksum {
+    /// Checksum in hex
+    pub hash: String,
+    /// Number of bytes hashed, less than asked for if the range ran past the end of
+    /// the file
+    pub length: f64,
+}
+
+impl From<(String, u64)> for FileChecksum {
+    fn from((hash, length): (String, u64)) -> FileChecksum {
+        FileChecksum {
+            hash,
+            length: length as f64,
+        }
+    }
+}
+
+/// Outcome of a file operation on one path
+#[derive(GraphQLObject)]
+pub struct FileResult {
+    /// Path the operation was applied to
+    pub path: String,
+    /// What went wrong, if anything
+    pub errors: String,
+    /// Whether the operation succeeded for this path
+    pub success: bool,
+}
+
+impl From<PathResult> for FileResult {
+    fn from(result: PathResult) -> FileResult {
+        FileResult {
+            path: result.path,
+            success: result.error.is_none(),
+            errors: result.error.unwrap_or_default(),
+        }
+    }
+}
+
+/// Response for the file management mutations
+#[derive(GraphQLObject)]
+pub struct FileOpResponse {
+    /// Any errors encountered by the request, for all paths together
+    pub errors: String,
+    /// Whether the operation succeeded for every path
+    pub success: bool,
+    /// Outcome for each path
+    pub results: Vec<FileResult>,
+}
+
+impl From<Result<Vec<PathResult>, String>> for FileOpResponse {
+    fn from(result: Result<Vec<PathResult>, String>) -> FileOpResponse {
+        match result {
+            Ok(results) => {
+                let errors: Vec<String> = results.iter().filter_map(|result| result.error.clone()).collect();
+                FileOpResponse {
+                    errors: errors.join("; "),
+                    success: errors.is_empty(),
+                    results: results.into_iter().map(FileResult::from).collect(),
+                }
+            }
+            Err(err) => FileOpResponse {
+                errors: err,
+                success: false,
+                results: vec![],
+            },
+        }
+    }
+}
+
+/// State of a software update
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum UpdateState {
+    /// Installed and restarted, waiting for the health check
+    Checking,
+    /// Passed the health check
+    Active,
+    /// The previous version was put back
+    RolledBack,
+    /// Failed the health check with nothing to roll back to
+    Failed,
+}
+
+impl From<Stage> for UpdateState {
+    fn from(state: Stage) -> UpdateState {
+        match state {
+            Stage::Checking => UpdateState::Checking,
+            Stage::Active => UpdateState::Active,
+            Stage::RolledBack => UpdateState::RolledBack,
+            Stage::Failed => UpdateState::Failed,
+        }
+    }
+}
+
+/// The last update of a target
+#[derive(GraphQLObject)]
+pub struct UpdateInfo {
+    /// Name of the app or service updated
+    pub target: String,
+    /// Version installed
+    pub version: String,
+    /// Version running before the update, if any
+    pub previous: Option<String>,
+    /// SHA-256 of the package, in hex
+    pub hash: String,
+    /// Where the update has got to
+    pub state: UpdateState,
+    /// What happened, including why a health check failed
+    pub message: String,
+    /// When the update was installed, in seconds since the epoch
+    pub started: f64,
+    /// When the health check or rollback finished
+    pub finished: Option<f64>,
+}
+
+impl From<Update> for UpdateInfo {
+    fn from(update: Update) -> UpdateInfo {
+        UpdateInfo {
+            target: update.target,
+            version: update.version,
+            previous: update.previous,
+            hash: update.hash,
+            state: update.state.into(),
+            message: update.message,
+            started: update.started as f64,
+            finished: update.finished.map(|time| time as f64),
+        }
+    }
+}
+
+/// Installed versions of an app or service
+#[derive(GraphQLObject)]
+pub struct UpdateTargetInfo {
+    /// Name of the app or service
+    pub target: String,
+    /// Version in use
+    pub current_version: Option<String>,
+    /// Every installed version, oldest first
+    pub versions: Vec<String>,
+    /// The last update, if there has been one
+    pub last_update: Option<UpdateInfo>,
+}
+
+impl From<TargetStatus> for UpdateTargetInfo {
+    fn from(status: TargetStatus) -> UpdateTargetInfo {
+        UpdateTargetInfo {
+            target: status.target,
+            current_version: status.current,
+            versions: status.versions,
+            last_update: status.last_update.map(UpdateInfo::from),
+        }
+    }
+}
+
+/// Response for the installUpdate and rollbackUpdate mutations
+#[derive(GraphQLObject)]
+pub struct UpdateResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// State of the update after the request
+    pub update: Option<UpdateInfo>,
+}
+
+/// Where a queued command has got to
+#[derive(GraphQLEnum)]
+pub enum QueueState {
+    /// Waiting for its execution time
+    Pending,
+    /// Running now
+    Running,
+    /// Ran successfully
+    Done,
+    /// Ran and failed, or could not be run
+    Failed,
+    /// Not run because the service could not get to it in time
+    Missed,
+}
+
+impl From<EntryState> for QueueState {
+    fn from(state: EntryState) -> QueueState {
+        match state {
+            EntryState::Pending => QueueState::Pending,
+            EntryState::Running => QueueState::Running,
+            EntryState::Done => QueueState::Done,
+            EntryState::Failed => QueueState::Failed,
+            EntryState::Missed => QueueState::Missed,
+        }
+    }
+}
+
+/// A time-tagged command or GraphQL request
+#[derive(GraphQLObject)]
+pub struct QueuedCommand {
+    /// ID used to refer to this entry
+    pub id: String,
+    /// When the entry is to run, in seconds since the epoch
+    pub execute_at: f64,
+    /// Command to run, for a queued command
+    pub command: Option<String>,
+    /// Command arguments
+    pub args: Vec<String>,
+    /// GraphQL request to send, for a queued request
+    pub query: Option<String>,
+    /// Service the request goes to (absent for this service)
+    pub service: Option<String>,
+    /// Where the entry has got to
+    pub state: QueueState,
+    /// When the entry was queued, in seconds since the epoch
+    pub queued: f64,
+    /// When the entry started running
+    pub started: Option<f64>,
+    /// When the entry finished, or was marked as missed
+    pub finished: Option<f64>,
+    /// Exit code of a command
+    pub exit_code: Option<i32>,
+    /// stdout of a command, or the GraphQL response
+    pub result: String,
+    /// Whether the result was cut short
+    pub result_truncated: bool,
+    /// stderr of a command, or why the entry failed or was missed
+    pub error: String,
+}
+
+impl From<Entry> for QueuedCommand {
+    fn from(entry: Entry) -> QueuedCommand {
+        let (command, args, query, service) = match entry.action {
+            Action::Command { path, args } => (Some(path), args, None, None),
+            Action::Graphql { service, query } => (None, vec![], Some(query), service),
+        };
+        QueuedCommand {
+            id: entry.id,
+            execute_at: entry.execute_at as f64,
+            command,
+            args,
+            query,
+            service,
+            state: entry.state.into(),
+            queued: entry.queued as f64,
+            started: entry.started.map(|time| time as f64),
+            finished: entry.finished.map(|time| time as f64),
+            exit_code: entry.exit_code,
+            result: entry.result,
+            result_truncated: entry.result_truncated,
+            error: entry.error,
+        }
+    }
+}
+
+/// Response for the queueCommand and queueGraphql mutations
+#[derive(GraphQLObject)]
+pub struct QueueResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// The new queue entry
+    pub entry: Option<QueuedCommand>,
+}
+
+/// Response for the clearQueue mutation
+#[derive(GraphQLObject)]
+pub struct ClearQueueResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// Number of entries deleted
+    pub deleted: i32,
+}
+
+/// Which way a CFDP transaction is sending a file
+#[derive(GraphQLEnum)]
+pub enum CfdpDirection {
+    /// From us to the ground
+    Send,
+    /// From the ground to us
+    Receive,
+}
+
+/// Where a CFDP transaction has got to
+#[derive(GraphQLEnum)]
+pub enum CfdpState {
+    /// Sending or receiving
+    Active,
+    /// Out of retries, waiting to hear from the other entity again
+    Suspended,
+    /// Completed and the file delivered
+    Finished,
+    /// Ended with a fault, given by the condition
+    Failed,
+    /// Cancelled by either end
+    Cancelled,
+}
+
+/// A CFDP file transfer
+#[derive(GraphQLObject)]
+pub struct CfdpTransaction {
+    /// ID used to refer to this transaction: source entity ID and sequence number
+    pub id: String,
+    /// The entity at the other end
+    pub peer: i32,
+    /// Which way the file is going
+    pub direction: CfdpDirection,
+    /// Class 2 (acknowledged) rather than class 1
+    pub acknowledged: bool,
+    /// Where the transaction has got to
+    pub state: CfdpState,
+    /// CFDP condition code, e.g. NoError or FileChecksumFailure
+    pub condition: String,
+    /// File being sent, on the sending entity
+    pub source_file: String,
+    /// Where the file is going, on the receiving entity
+    pub destination_file: String,
+    /// Size of the file, once known
+    pub file_size: Option<f64>,
+    /// Bytes sent for the first time, or received
+    pub progress: f64,
+    /// When the transaction started, in seconds since the epoch
+    pub started: f64,
+    /// When the other entity was last heard from
+    pub updated: f64,
+    /// When the transaction ended
+    pub finished: Option<f64>,
+}
+
+impl From<Transaction> for CfdpTransaction {
+    fn from(transaction: Transaction) -> CfdpTransaction {
+        CfdpTransaction {
+            id: transaction.id,
+            peer: transaction.peer as i32,
+            direction: match transaction.direction {
+                Direction::Send => CfdpDirection::Send,
+                Direction::Receive => CfdpDirection::Receive,
+            },
+            acknowledged: transaction.acknowledged,
+            state: match transaction.state {
+                TransactionState::Active => CfdpState::Active,
+                TransactionState::Suspended => CfdpState::Suspended,
+                TransactionState::Finished => CfdpState::Finished,
+                TransactionState::Failed => CfdpState::Failed,
+                TransactionState::Cancelled => CfdpState::Cancelled,
+            },
+            condition: format!("{:?}", transaction.condition),
+            source_file: transaction.source_file,
+            destination_file: transaction.destination_file,
+            file_size: transaction.file_size.map(|size| size as f64),
+            progress: transaction.progress as f64,
+            started: transaction.started as f64,
+            updated: transaction.updated as f64,
+            finished: transaction.finished.map(|time| time as f64),
+        }
+    }
+}
+
+/// Response for the cfdpPut and cfdpCancel mutations
+#[derive(GraphQLObject)]
+pub struct CfdpResponse {
+    /// Any errors encountered by the request
+    pub errors: String,
+    /// Request completion success or failure
+    pub success: bool,
+    /// State of the transaction after the request
+    pub transaction: Option<CfdpTransaction>,
+}
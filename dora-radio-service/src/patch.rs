@@ -0,0 +1,285 @@
+// Binary delta patches
+//
+// Patches are in the format made by the standard bsdiff tool (BSDIFF40), so the ground
+// can make them with `bsdiff old new patch`.  A patch is a 32-byte header followed by
+// three bzip2-compressed blocks:
+//
+//     +----------+-----------+-----------+----------+-------+------+-------+
+//     | BSDIFF40 | ctrl size | diff size | new size | ctrl  | diff | extra |
+//     | 8 bytes  | 8 bytes   | 8 bytes   | 8 bytes  |       |      |       |
+//     +----------+-----------+-----------+----------+-------+------+-------+
+//
+// The control block is a list of triples (x, y, z): add the next x bytes of the diff
+// block to the next x bytes of the old file, copy the next y bytes of the extra block,
+// then skip z bytes (possibly negative) in the old file.  The old file is read and the
+// new one written a block at a time, so neither has to fit in memory.
+
+use bzip2::read::BzDecoder;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"BSDIFF40";
+const HEADER_LEN: u64 = 32;
+// Size of the pieces the old file is read in
+const PATCH_BLOCK: usize = 64 * 1024;
+
+// Build `output` by applying a patch to `old`, refusing patches for files bigger than
+// `max_size`.  Returns the size of the new file.
+pub fn apply(old: &Path, patch: &Path, output: &Path, max_size: u64) -> Result<u64, String> {
+    let mut old = File::open(old).map_err(|_| "Failed to open file to patch".to_owned())?;
+    let old_size = old.metadata().map_err(|_| "Failed to open file to patch".to_owned())?.len();
+
+    let mut header = [0; HEADER_LEN as usize];
+    let mut patch_file = File::open(patch).map_err(|_| "Failed to open patch".to_owned())?;
+    patch_file
+        .read_exact(&mut header)
+        .map_err(|_| "Patch is too short".to_owned())?;
+    if &header[0..8] != MAGIC {
+        return Err("Patch is not in BSDIFF40 format".to_owned());
+    }
+    let ctrl_len = offtin(&header[8..16]);
+    let diff_len = offtin(&header[16..24]);
+    let new_size = offtin(&header[24..32]);
+    if ctrl_len < 0 || diff_len < 0 || new_size < 0 {
+        return Err("Patch header is corrupt".to_owned());
+    }
+    if new_size as u64 > max_size {
+        return Err(format!(
+            "Patched file would be {} bytes, more than the {} allowed",
+            new_size, max_size
+        ));
+    }
+
+    // Each block is read through its own handle on the patch file
+    let block = |offset: u64, len: u64| -> Result<BzDecoder<io::Take<File>>, String> {
+        let mut file = File::open(patch).map_err(|_| "Failed to open patch".to_owned())?;
+        file.seek(SeekFrom::Start(HEADER_LEN.saturating_add(offset)))
+            .map_err(|_| "Failed to read patch".to_owned())?;
+        Ok(BzDecoder::new(file.take(len)))
+    };
+    let mut ctrl = block(0, ctrl_len as u64)?;
+    let mut diff = block(ctrl_len as u64, diff_len as u64)?;
+    let mut extra = block(ctrl_len as u64 + diff_len as u64, u64::max_value())?;
+
+    let mut new = BufWriter::new(File::create(output).map_err(|_| "Failed to create patched file".to_owned())?);
+    let corrupt = |_| "Patch is corrupt".to_owned();
+
+    let new_size = new_size as u64;
+    let mut new_pos: u64 = 0;
+    let mut old_pos: i64 = 0;
+    let mut diff_buf = vec![0; PATCH_BLOCK];
+    let mut old_buf = vec![0; PATCH_BLOCK];
+
+    while new_pos < new_size {
+        let mut triple = [0; 24];
+        ctrl.read_exact(&mut triple).map_err(corrupt)?;
+        let add = offtin(&triple[0..8]);
+        let copy = offtin(&triple[8..16]);
+        let seek = offtin(&triple[16..24]);
+        if add < 0 || copy < 0 || add as u64 > new_size - new_pos || copy as u64 > new_size - new_pos - add as u64 {
+            return Err("Patch is corrupt".to_owned());
+        }
+
+        // Diff bytes added to the old file, which counts as zeros outside its bounds
+        let mut remaining = add as u64;
+        while remaining > 0 {
+            let len = remaining.min(PATCH_BLOCK as u64) as usize;
+            diff.read_exact(&mut diff_buf[0..len]).map_err(corrupt)?;
+            read_old(&mut old, old_size, old_pos, &mut old_buf[0..len])
+                .map_err(|_| "Failed to read file to patch".to_owned())?;
+            for (byte, old_byte) in diff_buf[0..len].iter_mut().zip(&old_buf[0..len]) {
+                *byte = byte.wrapping_add(*old_byte);
+            }
+            new.write_all(&diff_buf[0..len])
+                .map_err(|_| "Failed to write patched file".to_owned())?;
+            old_pos = old_pos.saturating_add(len as i64);
+            remaining -= len as u64;
+        }
+
+        // Bytes with no counterpart in the old file
+        let copied = io::copy(&mut (&mut extra).take(copy as u64), &mut new)
+            .map_err(|_| "Failed to write patched file".to_owned())?;
+        if copied != copy as u64 {
+            return Err("Patch is corrupt".to_owned());
+        }
+
+        new_pos += (add + copy) as u64;
+        old_pos = old_pos.checked_add(seek).ok_or_else(|| "Patch is corrupt".to_owned())?;
+    }
+
+    new.into_inner()
+        .map_err(|_| "Failed to write patched file".to_owned())?
+        .sync_all()
+        .map_err(|_| "Failed to write patched file".to_owned())?;
+    Ok(new_size)
+}
+
+// Fill `buf` from the old file at `pos`, with zeros for anything outside the file
+fn read_old(old: &mut File, size: u64, pos: i64, buf: &mut [u8]) -> io::Result<()> {
+    for byte in buf.iter_mut() {
+        *byte = 0;
+    }
+
+    let end = pos.saturating_add(buf.len() as i64);
+    let start = pos.max(0);
+    let stop = end.min(size as i64);
+    if start >= stop {
+        return Ok(());
+    }
+
+    old.seek(SeekFrom::Start(start as u64))?;
+    let from = (start - pos) as usize;
+    old.read_exact(&mut buf[from..from + (stop - start) as usize])
+}
+
+// bsdiff's 64-bit integers: little-endian magnitude with the sign in the top bit
+fn offtin(bytes: &[u8]) -> i64 {
+    let mut magnitude = [0; 8];
+    magnitude.copy_from_slice(&bytes[0..8]);
+    let negative = magnitude[7] & 0x80 != 0;
+    magnitude[7] &= 0x7f;
+
+    let value = i64::from_le_bytes(magnitude);
+    if negative {
+        -value
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use bzip2::Compression;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // testdata/sensors.patch was made with `bsdiff sensors.old sensors.new sensors.patch`.
+    // The new file moves a block from the end of the old one to the front, so the
+    // control block seeks backwards as well as forwards.
+    const OLD: &[u8] = include_bytes!("testdata/sensors.old");
+    const NEW: &[u8] = include_bytes!("testdata/sensors.new");
+    const PATCH: &[u8] = include_bytes!("testdata/sensors.patch");
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dora-patch-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Apply a patch to an old file, returning the new file's contents
+    fn run(name: &str, old: &[u8], patch: &[u8], max_size: u64) -> Result<Vec<u8>, String> {
+        let dir = test_dir(name);
+        fs::write(dir.join("old"), old).unwrap();
+        fs::write(dir.join("patch"), patch).unwrap();
+        let result = apply(&dir.join("old"), &dir.join("patch"), &dir.join("new"), max_size).map(|size| {
+            let new = fs::read(dir.join("new")).unwrap();
+            assert_eq!(size, new.len() as u64);
+            new
+        });
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+
+    fn offtout(value: i64) -> [u8; 8] {
+        let mut bytes = value.abs().to_le_bytes();
+        if value < 0 {
+            bytes[7] |= 0x80;
+        }
+        bytes
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Build a patch the way bsdiff lays one out
+    fn build(ctrl: &[(i64, i64, i64)], diff: &[u8], extra: &[u8], new_size: i64) -> Vec<u8> {
+        let mut triples = vec![];
+        for &(add, copy, seek) in ctrl {
+            triples.extend_from_slice(&offtout(add));
+            triples.extend_from_slice(&offtout(copy));
+            triples.extend_from_slice(&offtout(seek));
+        }
+        let ctrl = compress(&triples);
+        let diff = compress(diff);
+
+        let mut patch = MAGIC.to_vec();
+        patch.extend_from_slice(&offtout(ctrl.len() as i64));
+        patch.extend_from_slice(&offtout(diff.len() as i64));
+        patch.extend_from_slice(&offtout(new_size));
+        patch.extend(ctrl);
+        patch.extend(diff);
+        patch.extend(compress(extra));
+        patch
+    }
+
+    #[test]
+    fn applies_bsdiff_patches() {
+        assert_eq!(run("bsdiff", OLD, PATCH, 1 << 20).unwrap(), NEW);
+    }
+
+    #[test]
+    fn refuses_corrupt_headers() {
+        assert!(run("short", OLD, &PATCH[0..20], 1 << 20).is_err());
+
+        let mut patch = PATCH.to_vec();
+        patch[0..8].copy_from_slice(b"BSDIFF41");
+        assert_eq!(run("magic", OLD, &patch, 1 << 20).unwrap_err(), "Patch is not in BSDIFF40 format");
+
+        let mut patch = PATCH.to_vec();
+        patch[8..16].copy_from_slice(&offtout(-1));
+        assert_eq!(run("negative", OLD, &patch, 1 << 20).unwrap_err(), "Patch header is corrupt");
+
+        let mut patch = PATCH.to_vec();
+        patch[16..24].copy_from_slice(&offtout(3));
+        assert!(run("lengths", OLD, &patch, 1 << 20).is_err());
+    }
+
+    #[test]
+    fn refuses_files_bigger_than_allowed() {
+        assert!(run("limit", OLD, PATCH, NEW.len() as u64 - 1).is_err());
+        assert!(run("huge", OLD, &build(&[], &[], &[], i64::max_value()), 1 << 20).is_err());
+    }
+
+    #[test]
+    fn reads_zeros_outside_the_old_file() {
+        // Seeking before the start of the old file is allowed, as in bspatch
+        let patch = build(&[(0, 0, -2), (4, 0, 0)], &[1, 2, 3, 4], &[], 4);
+        assert_eq!(run("before", b"abcd", &patch, 1 << 20).unwrap(), vec![1, 2, b'a' + 3, b'b' + 4]);
+
+        let patch = build(&[(2, 0, 0)], &[1, 1], &[], 2);
+        assert_eq!(run("after", b"a", &patch, 1 << 20).unwrap(), vec![b'b', 1]);
+    }
+
+    #[test]
+    fn refuses_seeks_that_overflow() {
+        let patch = build(&[(0, 0, -i64::max_value()), (0, 0, -i64::max_value()), (1, 0, 0)], &[0], &[], 1);
+        assert_eq!(run("overflow", OLD, &patch, 1 << 20).unwrap_err(), "Patch is corrupt");
+    }
+
+    #[test]
+    fn refuses_truncated_control_blocks() {
+        // The control block runs out before the new file is complete
+        let patch = build(&[(4, 0, 0)], &[0; 8], &[], 8);
+        assert_eq!(run("ctrl", OLD, &patch, 1 << 20).unwrap_err(), "Patch is corrupt");
+
+        // The control block itself is cut short
+        let ctrl_len = offtin(&PATCH[8..16]) as usize;
+        let mut patch = PATCH[0..HEADER_LEN as usize + ctrl_len - 10].to_vec();
+        patch[8..16].copy_from_slice(&offtout(ctrl_len as i64 - 10));
+        patch[16..24].copy_from_slice(&offtout(0));
+        assert_eq!(run("cut", OLD, &patch, 1 << 20).unwrap_err(), "Patch is corrupt");
+    }
+
+    #[test]
+    fn refuses_steps_past_the_new_size() {
+        let patch = build(&[(4, 5, 0)], &[0; 4], &[0; 5], 8);
+        assert_eq!(run("past", OLD, &patch, 1 << 20).unwrap_err(), "Patch is corrupt");
+    }
+}
@@ -0,0 +1,413 @@
+// Time-tagged command queue
+//
+// The ground can queue commands and GraphQL requests to run at a given time (UTC, in
+// seconds since the epoch), so things can happen while the OBC is out of contact.
+// Each entry is saved to its own manifest in the staging area as soon as it is queued,
+// and again as it runs, so the queue and the results survive reboots.  Results are
+// kept until the ground deletes them.
+//
+// Queued commands are checked against the run_command allowlist when they are queued
+// and again when they run.  Queued GraphQL requests are sent over HTTP to this
+// service, or to another KubOS service named in the entry, just as the ground would
+// send them.
+//
+// If the OBC is off or busy when an entry is due, the entry runs as soon as possible
+// afterwards, unless it is more than `max_late` seconds late, in which case it is
+// marked as missed instead.  An entry interrupted by a restart is marked as failed
+// rather than run a second time.
+
+use crate::command;
+use crate::config::{CommandConfig, QueueConfig};
+use crate::transfer::{load_manifests, new_id, now, save_manifest};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// How often the scheduler looks for entries that are due
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Room allowed for the HTTP headers of a GraphQL response, on top of the result
+const MAX_HEADERS: u64 = 16 * 1024;
+
+// What a queue entry does
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Action {
+    // A command line, as for run_command
+    Command { path: String, args: Vec<String> },
+    // A GraphQL request for the named KubOS service, or for this one
+    Graphql { service: Option<String>, query: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EntryState {
+    Pending,
+    Running,
+    // Ran and succeeded: the command exited with 0, or the request got a response
+    // without errors
+    Done,
+    Failed,
+    // Not run because it was too late by the time it could be
+    Missed,
+}
+
+// One queued command, as saved in its manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: String,
+    pub execute_at: u64,
+    pub action: Action,
+    pub state: EntryState,
+    pub queued: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    // stdout of a command, or the GraphQL response
+    pub result: String,
+    pub result_truncated: bool,
+    // stderr of a command, or why the entry failed or was missed
+    pub error: String,
+    pub exit_code: Option<i32>,
+}
+
+pub struct Queue {
+    dir: PathBuf,
+    config: QueueConfig,
+    commands: CommandConfig,
+    entries: HashMap<String, Entry>,
+}
+
+impl Queue {
+
+    // Pick up the queue as it was before a restart
+    pub fn new(staging_dir: &Path, config: &QueueConfig, commands: &CommandConfig) -> Queue {
+        let dir = staging_dir.join("queue");
+        if let Err(err) = fs::create_dir_all(&dir) {
+            error!("Failed to create command queue directory {}: {}", dir.display(), err);
+        }
+
+        let mut queue = Queue {
+            dir,
+            config: config.clone(),
+            commands: commands.clone(),
+            entries: HashMap::new(),
+        };
+
+        for mut entry in load_manifests::<Entry>(&queue.dir) {
+            if entry.state == EntryState::Running {
+                warn!("Queued command {} was interrupted by a restart", entry.id);
+                entry.state = EntryState::Failed;
+                entry.error = "Interrupted by a restart".to_owned();
+                entry.finished = Some(now());
+                queue.save(&entry);
+            }
+            queue.entries.insert(entry.id.clone(), entry);
+        }
+
+        queue
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn save(&self, entry: &Entry) {
+        if let Err(err) = save_manifest(&self.manifest_path(&entry.id), entry) {
+            error!("Failed to save queued command {}: {}", entry.id, err);
+        }
+    }
+
+    // Queue an action to run at `execute_at`
+    pub fn add(&mut self, execute_at: u64, action: Action) -> Result<Entry, String> {
+        if self.entries.len() >= self.config.max_entries {
+            return Err(format!(
+                "Queue is full (limit {}), delete some entries first",
+                self.config.max_entries
+            ));
+        }
+        if execute_at + self.config.max_late < now() {
+            return Err("Execution time is in the past".to_owned());
+        }
+
+        // Catch mistakes now, while the ground is still listening
+        let action = match action {
+            Action::Command { path, args } => {
+                let path = command::resolve(&path)?;
+                command::check_allowed(&self.commands, &path, &args)?;
+                Action::Command { path, args }
+            }
+            Action::Graphql { service, query } => {
+                if query.trim().is_empty() {
+                    return Err("No GraphQL query given".to_owned());
+                }
+                Action::Graphql { service, query }
+            }
+        };
+
+        let id = new_id(&format!("{:?}", action));
+        let entry = Entry {
+            id: id.clone(),
+            execute_at,
+            action,
+            state: EntryState::Pending,
+            queued: now(),
+            started: None,
+            finished: None,
+            result: String::new(),
+            result_truncated: false,
+            error: String::new(),
+            exit_code: None,
+        };
+
+        save_manifest(&self.manifest_path(&id), &entry)?;
+        info!("Queued {} for {}: {:?}", id, execute_at, entry.action);
+        self.entries.insert(id, entry.clone());
+        Ok(entry)
+    }
+
+    // Delete an entry, whether it has run or not
+    pub fn remove(&mut self, id: &str) -> Result<(), String> {
+        let entry = self.status(id)?;
+        if entry.state == EntryState::Running {
+            return Err("Queued command is running".to_owned());
+        }
+
+        self.entries.remove(id);
+        let _ = fs::remove_file(self.manifest_path(id));
+        info!("Deleted queued command {}", id);
+        Ok(())
+    }
+
+    // Delete every entry that isn't running, or only the ones that have finished.
+    // Returns the number deleted.
+    pub fn clear(&mut self, finished_only: bool) -> usize {
+        let ids: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| match entry.state {
+                EntryState::Running => false,
+                EntryState::Pending => !finished_only,
+                _ => true,
+            })
+            .map(|entry| entry.id.clone())
+            .collect();
+
+        for id in &ids {
+            let _ = self.remove(id);
+        }
+        ids.len()
+    }
+
+    pub fn status(&self, id: &str) -> Result<Entry, String> {
+        self.entries
+            .get(id)
+            .cloned()
+            .ok_or_else(|| "Unknown queued command ID".to_owned())
+    }
+
+    // Every entry, soonest first
+    pub fn list(&self) -> Vec<Entry> {
+        let mut list: Vec<Entry> = self.entries.values().cloned().collect();
+        list.sort_by_key(|entry| (entry.execute_at, entry.queued));
+        list
+    }
+
+    // Take the next entry that is due and mark it as running.  Entries that are too
+    // late to run are marked as missed on the way.
+    pub fn next_due(&mut self) -> Option<Entry> {
+        let time = now();
+        let due: Vec<String> = self
+            .list()
+            .into_iter()
+            .filter(|entry| entry.state == EntryState::Pending && entry.execute_at <= time)
+            .map(|entry| entry.id)
+            .collect();
+
+        for id in due {
+            let mut entry = self.entries[&id].clone();
+            if entry.execute_at + self.config.max_late < time {
+                warn!("Queued command {} missed its time ({}s late)", id, time - entry.execute_at);
+                entry.state = EntryState::Missed;
+                entry.error = format!("Not run: {}s late", time - entry.execute_at);
+                entry.finished = Some(time);
+            } else {
+                entry.state = EntryState::Running;
+                entry.started = Some(time);
+            }
+
+            self.save(&entry);
+            self.entries.insert(id, entry.clone());
+            if entry.state == EntryState::Running {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+
+    // Record the outcome of an entry taken with next_due
+    pub fn complete(&mut self, entry: Entry) {
+        self.save(&entry);
+        self.entries.insert(entry.id.clone(), entry);
+    }
+}
+
+// Run an entry's action and fill in its outcome.  Called without the queue locked,
+// since commands can take a while.
+pub fn execute(config: &QueueConfig, commands: &CommandConfig, mut entry: Entry) -> Entry {
+    info!("Running queued command {}", entry.id);
+
+    let outcome = match entry.action.clone() {
+        Action::Command { path, args } => command::run(commands, &path, &args, None, None).map(|output| {
+            entry.exit_code = output.status.exit_code;
+            entry.error = output.stderr;
+            (output.stdout, output.status.exit_code == Some(0))
+        }),
+        Action::Graphql { service, query } => graphql(config, service, &query).map(|response| {
+            let ok = serde_json::from_str::<serde_json::Value>(&response)
+                .ok()
+                .map(|response| response["errors"].is_null())
+                .unwrap_or(true);
+            (response, ok)
+        }),
+    };
+
+    match outcome {
+        Ok((mut result, ok)) => {
+            if result.len() > config.max_result {
+                let mut end = config.max_result;
+                while !result.is_char_boundary(end) {
+                    end -= 1;
+                }
+                result.truncate(end);
+                entry.result_truncated = true;
+            }
+            entry.result = result;
+            entry.state = if ok { EntryState::Done } else { EntryState::Failed };
+        }
+        Err(err) => {
+            entry.error = err;
+            entry.state = EntryState::Failed;
+        }
+    }
+
+    entry.finished = Some(now());
+    info!("Queued command {} finished: {:?}", entry.id, entry.state);
+    entry
+}
+
+// POST a GraphQL request to a KubOS service and return the response body
+fn graphql(config: &QueueConfig, service: Option<String>, query: &str) -> Result<String, String> {
+    let url = match service {
+        Some(service) => kubos_system::Config::new(&service)
+            .ok()
+            .and_then(|service_config| service_config.hosturl())
+            .ok_or_else(|| format!("No address configured for service {}", service))?,
+        None => config
+            .service_url
+            .clone()
+            .ok_or_else(|| "No address configured for this service".to_owned())?,
+    };
+
+    let addr = url
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("Invalid service address {}", url))?;
+
+    let body = serde_json::json!({ "query": query }).to_string();
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url,
+        body.len(),
+        body
+    );
+
+    let mut response = vec![];
+    let limit = config.max_result as u64 + MAX_HEADERS;
+    TcpStream::connect_timeout(&addr, config.timeout)
+        .and_then(|mut stream| {
+            stream.set_read_timeout(Some(config.timeout))?;
+            stream.set_write_timeout(Some(config.timeout))?;
+            stream.write_all(request.as_bytes())?;
+            stream.take(limit).read_to_end(&mut response)
+        })
+        .map_err(|err| format!("GraphQL request to {} failed: {}", url, err))?;
+
+    parse_response(&response)
+}
+
+// Body of an HTTP response, failing unless the status is 200.  The body is only turned
+// into text once it has been put back together, since chunks are counted in bytes and
+// can split a character.
+fn parse_response(response: &[u8]) -> Result<String, String> {
+    let (head, body) = match find(response, b"\r\n\r\n") {
+        Some(end) => (String::from_utf8_lossy(&response[..end]), &response[end + 4..]),
+        None => return Err("Malformed HTTP response".to_owned()),
+    };
+
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("GraphQL request failed: {}", status));
+    }
+
+    let chunked = head.lines().any(|line| {
+        let line = line.to_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    if chunked {
+        Ok(String::from_utf8_lossy(&dechunk(body)).into_owned())
+    } else {
+        Ok(String::from_utf8_lossy(body).into_owned())
+    }
+}
+
+// Undo chunked transfer encoding
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    while let Some(end) = find(body, b"\r\n") {
+        let line = String::from_utf8_lossy(&body[..end]);
+        let size = usize::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16).unwrap_or(0);
+        body = &body[end + 2..];
+        if size == 0 || size > body.len() {
+            data.extend_from_slice(&body[..size.min(body.len())]);
+            break;
+        }
+        data.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or(&[]);
+    }
+    data
+}
+
+// Position of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dechunks_characters_split_across_chunks() {
+        assert_eq!(dechunk(b"1\r\n\xc3\r\n1\r\n\xa9\r\n0\r\n\r\n"), "\u{e9}".as_bytes());
+        assert_eq!(dechunk(b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n"), b"Wikipedia");
+        // Cut short by the size limit
+        assert_eq!(dechunk(b"a\r\nabc"), b"abc");
+    }
+
+    #[test]
+    fn parses_responses() {
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            1\r\n\xc3\r\n1\r\n\xa9\r\n0\r\n\r\n";
+        assert_eq!(parse_response(chunked), Ok("\u{e9}".to_owned()));
+
+        let plain = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(plain), Ok("{}".to_owned()));
+
+        assert!(parse_response(b"HTTP/1.1 500 Internal Server Error\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+}
@@ -0,0 +1,704 @@
+// Dedicated I/O thread for the radio link
+//
+// The link is owned by a single thread, which waits on it with poll() together with a
+// wake-up pipe.  The comms service read and write functions never touch the link
+// directly: received packets come out of one bounded channel, and packets to send go
+// into another along with a reply channel for the result of the write.  A writer that
+// fails or stalls can't block or take down the read side, and nothing has to sleep or
+// spin waiting for a mutex on the port.
+//
+// With forward error correction on, frames are coded into RS blocks on the way out
+// and the blocks decoded on the way in, beneath the framing (see fec.rs).  In CCSDS
+// mode, packets are put into TM frames on the I/O thread, which sends them in order
+// and owns the frame counters, and taken out of uplinked TC frames (see ccsds.rs).
+//
+// When uplink authentication is on, the I/O thread also checks each received packet
+// (see auth.rs) and drops any that fail before they go any further.  Packets with the
+// CFDP APID go to the CFDP entity (see cfdp.rs) rather than the comms service.
+//
+// With ARQ on (see arq.rs), the I/O thread numbers downlinked packets, keeps them
+// until they are acknowledged and sends them again as needed, and puts uplinked
+// packets back in order.  A write then only waits for its packet to be queued, and
+// the framing is done on the I/O thread, beneath the ARQ header.
+
+use crate::arq::Arq;
+use crate::auth::Authenticator;
+use crate::ax25;
+use crate::ccsds::{self, TcDemux, TmMux};
+use crate::config::{FecMode, FramingConfig, FramingMode, LinkConfig};
+use crate::fec::{self, FecStats};
+use crate::framing::{self, Deframer};
+use crate::link::RadioLink;
+use crate::telemetry::LinkTelemetry;
+use crate::ServiceResult;
+use comms_service::CommsTelemetry;
+use failure::*;
+use log::*;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Number of packets that can be waiting in each direction
+const QUEUE_DEPTH: usize = 32;
+// How long a writer will wait for the I/O thread to report the result of its write
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// Back-off after a link error, so a dead device doesn't turn into a busy loop
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+// A packet waiting to be written, with the channel its result goes back on
+struct Outgoing {
+    data: Vec<u8>,
+    reply: SyncSender<io::Result<()>>,
+}
+
+// Framing done one packet at a time, for every mode but CCSDS
+#[derive(Clone)]
+struct PacketFramer {
+    mode: FramingMode,
+    encoder: Option<Arc<fec::Encoder>>,
+    ax25: Option<ax25::Framer>,
+}
+
+impl PacketFramer {
+
+    fn encode(&self, msg: &[u8]) -> Vec<u8> {
+        let data = match self.mode {
+            FramingMode::None | FramingMode::Ccsds => msg.to_vec(),
+            FramingMode::Hdlc => framing::encode(msg),
+            FramingMode::Ax25 => match self.ax25 {
+                Some(ref framer) => framer.encode(msg),
+                None => msg.to_vec(),
+            },
+        };
+        match self.encoder {
+            Some(ref encoder) => encoder.encode(&data),
+            None => data,
+        }
+    }
+}
+
+// Handle to the radio I/O thread.  This is the connection type handed to the comms
+// service; clones all talk to the same thread.
+#[derive(Clone)]
+pub struct RadioHandle {
+    // Only the comms service read thread receives, the mutex just makes the handle shareable
+    inbound: Arc<Mutex<Receiver<Vec<u8>>>>,
+    // Packets for the CFDP entity, whose thread is the only one to receive them
+    cfdp_inbound: Arc<Mutex<Receiver<Vec<u8>>>>,
+    outbound: SyncSender<Outgoing>,
+    wake: RawFd,
+    // Set when packets are framed here rather than on the I/O thread
+    framer: Option<PacketFramer>,
+}
+
+impl RadioHandle {
+
+    // Hand the link over to a new I/O thread
+    pub fn spawn(
+        link: Box<dyn RadioLink>,
+        link_config: &LinkConfig,
+        framing_config: &FramingConfig,
+        arq: Option<Arq>,
+        auth: Option<Authenticator>,
+        cfdp_apid: Option<u16>,
+        telem: Arc<Mutex<CommsTelemetry>>,
+        link_telem: Arc<Mutex<LinkTelemetry>>,
+    ) -> ServiceResult<RadioHandle> {
+
+        let (inbound_tx, inbound_rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        let (cfdp_tx, cfdp_rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        let (outbound_tx, outbound_rx) = mpsc::sync_channel(QUEUE_DEPTH);
+
+        // Writers poke the pipe after queueing a packet so the I/O thread wakes up.  Both
+        // ends are non-blocking: a full pipe just means a wake-up is already pending.
+        let (wake_rd, wake_wr) = unistd::pipe()?;
+        fcntl(wake_rd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        fcntl(wake_wr, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+        let (encoder, decoder) = match framing_config.fec {
+            FecMode::None => (None, None),
+            FecMode::ReedSolomon => (
+                Some(Arc::new(fec::Encoder::new(
+                    framing_config.interleave,
+                    framing_config.randomize,
+                    framing::FLAG,
+                ))),
+                Some(fec::Decoder::new(framing_config.interleave, framing_config.randomize)),
+            ),
+        };
+
+        // The CCSDS uplink has its own coding, and the downlink is coded on the I/O
+        // thread along with the framing
+        let ccsds = framing_config.mode == FramingMode::Ccsds;
+        let (tm, tc, decoder) = if ccsds {
+            (
+                Some(TmMux::new(&framing_config.ccsds)),
+                Some(TcDemux::new(&framing_config.ccsds, framing_config.max_frame)),
+                None,
+            )
+        } else {
+            (None, None, decoder)
+        };
+
+        let ax25 = framing_config.mode == FramingMode::Ax25;
+        let tm_encoder = encoder.clone().filter(|_| ccsds);
+
+        // Frame packets on the writer's thread, except for CCSDS frames, whose counters
+        // have to follow the order they are sent in, and under ARQ, whose header goes
+        // inside the frame
+        let framer = if ccsds {
+            None
+        } else {
+            Some(PacketFramer {
+                mode: framing_config.mode,
+                encoder,
+                ax25: if ax25 {
+                    Some(ax25::Framer::new(&framing_config.ax25))
+                } else {
+                    None
+                },
+            })
+        };
+        let (framer, io_framer) = if arq.is_some() {
+            (None, framer)
+        } else {
+            (framer, None)
+        };
+
+        let io = RadioIo {
+            link,
+            mode: framing_config.mode,
+            deframer: Deframer::new(framing_config.max_frame),
+            decoder,
+            tm,
+            tc,
+            kiss: if ax25 {
+                Some(ax25::Deframer::new(&framing_config.ax25, framing_config.max_frame))
+            } else {
+                None
+            },
+            tm_encoder,
+            framer: io_framer,
+            arq,
+            idle_interval: framing_config.ccsds.idle_interval.filter(|_| ccsds),
+            last_sent: Instant::now(),
+            packet: vec![],
+            max_read: link_config.max_read,
+            timeout: link_config.timeout,
+            auth,
+            cfdp_apid,
+            cfdp: cfdp_tx,
+            inbound: inbound_tx,
+            outbound: outbound_rx,
+            wake: wake_rd,
+            telem,
+            link_telem,
+        };
+
+        thread::Builder::new()
+            .name("radio-io".to_owned())
+            .spawn(move || io.run())?;
+
+        Ok(RadioHandle {
+            inbound: Arc::new(Mutex::new(inbound_rx)),
+            cfdp_inbound: Arc::new(Mutex::new(cfdp_rx)),
+            outbound: outbound_tx,
+            wake: wake_wr,
+            framer,
+        })
+    }
+
+    // Wait for the next packet from the link
+    pub fn read(&self) -> ServiceResult<Vec<u8>> {
+        // A panic elsewhere can't leave the receiver in a bad state, so ignore poisoning
+        let inbound = self.inbound.lock().unwrap_or_else(|err| err.into_inner());
+
+        match inbound.recv() {
+            Ok(packet) => {
+                debug!("Read {} bytes from radio", packet.len());
+                Ok(packet)
+            }
+            Err(_) => {
+                // Don't let the comms service read thread spin on a dead I/O thread
+                thread::sleep(ERROR_BACKOFF);
+                bail!("Radio I/O thread has stopped")
+            }
+        }
+    }
+
+    // Wait up to `timeout` for the next CFDP packet from the link
+    pub fn read_pdu(&self, timeout: Duration) -> ServiceResult<Option<Vec<u8>>> {
+        let inbound = self.cfdp_inbound.lock().unwrap_or_else(|err| err.into_inner());
+
+        match inbound.recv_timeout(timeout) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(ERROR_BACKOFF);
+                bail!("Radio I/O thread has stopped")
+            }
+        }
+    }
+
+    // Queue a packet for the link and wait for it to be written, or with ARQ on, for
+    // it to be queued for sending
+    pub fn write(&self, msg: &[u8]) -> ServiceResult<()> {
+        let data = match self.framer {
+            Some(ref framer) => framer.encode(msg),
+            None => msg.to_vec(),
+        };
+        let len = data.len();
+
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        self.outbound
+            .send(Outgoing {
+                data,
+                reply: reply_tx,
+            })
+            .map_err(|_| format_err!("Radio I/O thread has stopped"))?;
+        let _ = unistd::write(self.wake, &[1]);
+
+        match reply_rx.recv_timeout(WRITE_TIMEOUT) {
+            Ok(Ok(())) => {
+                debug!("Wrote {} bytes to radio", len);
+                Ok(())
+            }
+            Ok(Err(err)) => bail!("Radio write failed: {}", err),
+            Err(_) => bail!("Timed out waiting for radio write"),
+        }
+    }
+}
+
+// State owned by the I/O thread
+struct RadioIo {
+    link: Box<dyn RadioLink>,
+    mode: FramingMode,
+    deframer: Deframer,
+    // RS block decoder, when error correction is on
+    decoder: Option<fec::Decoder>,
+    // CCSDS frame handling (FramingMode::Ccsds only)
+    tm: Option<TmMux>,
+    tc: Option<TcDemux>,
+    tm_encoder: Option<Arc<fec::Encoder>>,
+    // Per-packet framing, when it is done on this thread (ARQ only)
+    framer: Option<PacketFramer>,
+    arq: Option<Arq>,
+    // KISS/AX.25 receive side (FramingMode::Ax25 only)
+    kiss: Option<ax25::Deframer>,
+    idle_interval: Option<Duration>,
+    last_sent: Instant,
+    // Unframed packet in progress (FramingMode::None only)
+    packet: Vec<u8>,
+    max_read: usize,
+    timeout: Duration,
+    auth: Option<Authenticator>,
+    cfdp_apid: Option<u16>,
+    cfdp: SyncSender<Vec<u8>>,
+    inbound: SyncSender<Vec<u8>>,
+    outbound: Receiver<Outgoing>,
+    wake: RawFd,
+    telem: Arc<Mutex<CommsTelemetry>>,
+    link_telem: Arc<Mutex<LinkTelemetry>>,
+}
+
+impl RadioIo {
+
+    fn run(mut self) {
+        let mut buffer: Vec<u8> = vec![0; self.max_read];
+
+        loop {
+            // Resend anything whose ARQ timer has run out
+            self.arq_transmit();
+
+            // Only an unframed packet in progress needs a timeout, since the line going
+            // quiet is what ends it, or idle frames if the downlink is to be kept busy,
+            // or the next ARQ retransmission.  Otherwise block until there is something
+            // to do.
+            let timeout = if !self.packet.is_empty() {
+                self.timeout.as_millis() as i32
+            } else if let Some(interval) = self.idle_interval {
+                if self.last_sent.elapsed() >= interval {
+                    self.send_idle();
+                }
+                (interval - self.last_sent.elapsed().min(interval)).as_millis().max(1) as i32
+            } else if let Some(deadline) = self.arq.as_ref().and_then(|arq| arq.next_deadline()) {
+                deadline.saturating_duration_since(Instant::now()).as_millis().max(1) as i32
+            } else {
+                -1
+            };
+
+            let mut fds = [
+                PollFd::new(self.link.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(self.wake, PollFlags::POLLIN),
+            ];
+            let ready = match poll(&mut fds, timeout) {
+                Ok(num) => num,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => {
+                    error!("Failed to poll radio link: {}", err);
+                    thread::sleep(ERROR_BACKOFF);
+                    continue;
+                }
+            };
+
+            if ready == 0 {
+                if self.mode != FramingMode::None {
+                    continue;
+                }
+                let packet = self.packet.split_off(0);
+                if !self.deliver(packet) {
+                    break;
+                }
+                continue;
+            }
+
+            let link_events = fds[0].revents().unwrap_or_else(PollFlags::empty);
+            let wake_events = fds[1].revents().unwrap_or_else(PollFlags::empty);
+
+            if wake_events.contains(PollFlags::POLLIN) && !self.send_pending() {
+                break;
+            }
+
+            if link_events.intersects(PollFlags::POLLIN | PollFlags::POLLERR | PollFlags::POLLHUP)
+                && !self.receive(&mut buffer, link_events)
+            {
+                break;
+            }
+        }
+
+        info!("Radio I/O thread exiting");
+    }
+
+    // Write everything the writers have queued.  Returns false once every handle is gone.
+    fn send_pending(&mut self) -> bool {
+        // Empty the wake-up pipe; we're about to handle everything it was signalling
+        let mut scratch = [0; 64];
+        while let Ok(num) = unistd::read(self.wake, &mut scratch) {
+            if num == 0 {
+                break;
+            }
+        }
+
+        loop {
+            match self.outbound.try_recv() {
+                Ok(out) => {
+                    let result = match self.arq {
+                        Some(ref mut arq) => arq
+                            .queue(out.data)
+                            .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+                        None => {
+                            let data = self.downlink(out.data);
+                            self.link.write_all(&data)
+                        }
+                    };
+                    if let Err(ref err) = result {
+                        error!("Radio write failed: {}", err);
+                    }
+                    // The writer may have given up waiting, which is fine
+                    let _ = out.reply.send(result);
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    // Put a packet into TM frames, if that's the framing in use, or into a frame of
+    // its own if it wasn't framed by the writer
+    fn downlink(&mut self, packet: Vec<u8>) -> Vec<u8> {
+        let tm = match self.tm {
+            Some(ref mut tm) => tm,
+            None => {
+                return match self.framer {
+                    Some(ref framer) => framer.encode(&packet),
+                    None => packet,
+                }
+            }
+        };
+
+        self.last_sent = Instant::now();
+        let mut data = vec![];
+        for frame in tm.frames(&packet) {
+            data.extend(match self.tm_encoder {
+                Some(ref encoder) => encoder.encode(&frame),
+                None => ccsds::attach_sync(frame),
+            });
+        }
+        data
+    }
+
+    // Send whatever ARQ has ready: new packets that fit in the window, and any due to
+    // be sent again
+    fn arq_transmit(&mut self) {
+        let packets = match self.arq {
+            Some(ref mut arq) => arq.transmit(Instant::now()),
+            None => return,
+        };
+        for packet in packets {
+            self.send_now(packet);
+        }
+        self.count_arq();
+    }
+
+    // Frame and write a packet that no writer is waiting on
+    fn send_now(&mut self, packet: Vec<u8>) {
+        let data = self.downlink(packet);
+        if let Err(err) = self.link.write_all(&data) {
+            error!("Radio write failed: {}", err);
+            if let Ok(mut data) = self.telem.lock() {
+                data.failed_packets_down += 1;
+            }
+        }
+    }
+
+    // Send a TM frame with nothing in it, to keep the downlink busy
+    fn send_idle(&mut self) {
+        self.last_sent = Instant::now();
+        let frame = match self.tm {
+            Some(ref mut tm) => tm.idle_frame(),
+            None => return,
+        };
+        let data = match self.tm_encoder {
+            Some(ref encoder) => encoder.encode(&frame),
+            None => ccsds::attach_sync(frame),
+        };
+        if let Err(err) = self.link.write_all(&data) {
+            error!("Radio write failed: {}", err);
+        }
+    }
+
+    // Read from the link and pass on any complete packets.  Returns false once the
+    // read side of the handle is gone.
+    fn receive(&mut self, buffer: &mut Vec<u8>, events: PollFlags) -> bool {
+        let num = match self.link.read(buffer.as_mut_slice()) {
+            Ok(num) => num,
+            Err(err) => {
+                error!("Radio read failed: {}", err);
+                thread::sleep(ERROR_BACKOFF);
+                return true;
+            }
+        };
+
+        if num == 0 {
+            // The link reported an error or hang-up but had nothing to read
+            if events.intersects(PollFlags::POLLERR | PollFlags::POLLHUP) {
+                warn!("Radio link {} reported {:?}", self.link.describe(), events);
+                thread::sleep(ERROR_BACKOFF);
+            }
+            return true;
+        }
+
+        match self.mode {
+            FramingMode::None => {
+                self.packet.extend_from_slice(&buffer[0..num]);
+                if num < self.max_read {
+                    let packet = self.packet.split_off(0);
+                    return self.deliver(packet);
+                }
+            }
+            FramingMode::Ccsds => {
+                let stats = match self.tc {
+                    Some(ref mut tc) => tc.push(&buffer[0..num]),
+                    None => return true,
+                };
+                self.count_fec(FecStats {
+                    corrected: stats.corrected,
+                    uncorrectable: stats.uncorrectable,
+                });
+                if stats.bad_frames > 0 {
+                    self.count_bad_frames(stats.bad_frames);
+                }
+                while let Some(packet) = self.tc.as_mut().and_then(|tc| tc.next_packet()) {
+                    if !self.deliver(packet) {
+                        return false;
+                    }
+                }
+            }
+            FramingMode::Ax25 => {
+                let bad = match self.kiss {
+                    Some(ref mut kiss) => kiss.push(&buffer[0..num]),
+                    None => return true,
+                };
+                if bad > 0 {
+                    self.count_bad_frames(bad);
+                }
+                while let Some(packet) = self.kiss.as_mut().and_then(|kiss| kiss.next_frame()) {
+                    if !self.deliver(packet) {
+                        return false;
+                    }
+                }
+            }
+            FramingMode::Hdlc => {
+                let bad = match self.decoder {
+                    Some(ref mut decoder) => {
+                        let stats = decoder.push(&buffer[0..num]);
+                        let mut bad = 0;
+                        while let Some(block) = decoder.next_block() {
+                            // A lost block leaves a hole in the stream, so end whatever
+                            // frame was in progress rather than splice it onto the next
+                            bad += match block {
+                                Some(data) => self.deframer.push(&data),
+                                None => self.deframer.push(&[framing::FLAG]),
+                            };
+                        }
+                        self.count_fec(stats);
+                        bad
+                    }
+                    None => self.deframer.push(&buffer[0..num]),
+                };
+                if bad > 0 {
+                    self.count_bad_frames(bad);
+                }
+                while let Some(packet) = self.deframer.next_frame() {
+                    if !self.deliver(packet) {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    // Check a received packet's authentication, if it's on, pass it through ARQ, if
+    // that's on, and hand on whatever is now ready.  Returns false once the read side
+    // of the handle is gone.
+    fn deliver(&mut self, packet: Vec<u8>) -> bool {
+        if packet.is_empty() {
+            return true;
+        }
+
+        // With ARQ on, the trailer covers the whole ARQ packet, header and sequence
+        // number included, so a forged packet can't move the sequence on, take the
+        // place of a real one, or acknowledge anything on the ground's behalf
+        let packet = match self.auth {
+            Some(ref mut auth) => match auth.verify(packet) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    self.count_auth_failure(&err);
+                    return true;
+                }
+            },
+            None => packet,
+        };
+
+        let received = match self.arq {
+            Some(ref mut arq) => arq.receive(&packet),
+            None => return self.hand_on(packet),
+        };
+        for reply in received.replies {
+            self.send_now(reply);
+        }
+        // A NACK leaves packets due to be sent again straight away
+        self.arq_transmit();
+
+        for packet in received.packets {
+            if !self.hand_on(packet) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Hand a received packet to the comms service read thread, or the CFDP entity.  If
+    // it has fallen so far behind that its queue is full, the packet is dropped rather
+    // than stalling the link.
+    fn hand_on(&mut self, packet: Vec<u8>) -> bool {
+        if packet.is_empty() {
+            return true;
+        }
+
+        let apid = if packet.len() >= 2 {
+            Some((u16::from(packet[0] & 0x07) << 8) | u16::from(packet[1]))
+        } else {
+            None
+        };
+        let queue = if apid.is_some() && apid == self.cfdp_apid {
+            &self.cfdp
+        } else {
+            &self.inbound
+        };
+
+        match queue.try_send(packet) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Radio receive queue full, dropping packet");
+                if let Ok(mut data) = self.telem.lock() {
+                    data.failed_packets_up += 1;
+                }
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    // Record frames dropped by the framing layer.  They count as failed uplink packets in
+    // the comms telemetry, and are also tracked separately so line noise can be told
+    // apart from packets that framed correctly but then failed to parse.
+    fn count_bad_frames(&self, count: usize) {
+        warn!("Dropped {} bad frame(s) from radio", count);
+
+        if let Ok(mut data) = self.telem.lock() {
+            data.failed_packets_up += count as i32;
+        }
+        if let Ok(mut data) = self.link_telem.lock() {
+            data.bad_frames += count as i32;
+        }
+    }
+
+    // Record what the RS decoder found
+    fn count_fec(&self, stats: FecStats) {
+        if stats == FecStats::default() {
+            return;
+        }
+        if stats.uncorrectable > 0 {
+            warn!("Dropped {} uncorrectable RS codeword(s) from radio", stats.uncorrectable);
+        }
+
+        if let Ok(mut data) = self.link_telem.lock() {
+            data.corrected_codewords += stats.corrected as i32;
+            data.uncorrectable_codewords += stats.uncorrectable as i32;
+        }
+    }
+
+    // Copy the ARQ totals into the link telemetry.  Packets given up on also count as
+    // failed downlink packets.
+    fn count_arq(&self) {
+        let stats = match self.arq {
+            Some(ref arq) => arq.stats(),
+            None => return,
+        };
+
+        if let Ok(mut data) = self.link_telem.lock() {
+            let abandoned = stats.abandoned as i32 - data.abandoned_packets;
+            if abandoned > 0 {
+                if let Ok(mut comms) = self.telem.lock() {
+                    comms.failed_packets_down += abandoned;
+                }
+            }
+            data.retransmitted_packets = stats.retransmitted as i32;
+            data.acked_packets = stats.acked as i32;
+            data.abandoned_packets = stats.abandoned as i32;
+            data.duplicate_packets = stats.duplicates as i32;
+        }
+    }
+
+    // Record a packet dropped by uplink authentication, counted the same way as bad frames
+    fn count_auth_failure(&self, reason: &str) {
+        warn!("Dropped unauthenticated packet from radio: {}", reason);
+
+        if let Ok(mut data) = self.telem.lock() {
+            data.failed_packets_up += 1;
+        }
+        if let Ok(mut data) = self.link_telem.lock() {
+            data.auth_failures += 1;
+        }
+    }
+}
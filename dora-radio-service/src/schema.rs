@@ -1,5 +1,6 @@
 use juniper::FieldResult;
 use crate::model::Subsystem;
+use crate::objects::*;
 
 type Context = kubos_service::Context<Subsystem>;
 
@@ -23,25 +24,138 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
     }
 
     // Returns the file at the specified (full) path, optionally encoded in base64  
-    field run_command(&executor, 
-        path: Option<String>, args: Option<Vec<String>>,
-        stdout: Option<String>, stderr: Option<String>) -> FieldResult<String>
+    field download_file(&executor, path: Option<String>, encode: Option<bool>) -> FieldResult<String>
     {
-        Ok(executor.context().subsystem().run_command(path, args, stdout, stderr)?)
+        Ok(executor.context().subsystem().download_file(path, encode)?)
     }
 
-    // Returns the file at the specified (full) path, optionally encoded in base64  
-    field download_file(&executor, path: Option<String>, encode: Option<bool>) -> FieldResult<String>
+    // Returns part of a file: `length` bytes from `offset`, or the last `tailLines`
+    // lines (no more than `length` bytes of them).  The data is optionally gzipped,
+    // and encoded in base64 if compressed or asked for.
+    field read_file(&executor, path: String, offset: Option<f64>, length: Option<i32>, tail_lines: Option<i32>,
+        encode: Option<bool>, compress: Option<bool>) -> FieldResult<FileContents>
     {
-        Ok(executor.context().subsystem().download_file(path, encode)?)
+        let compress = compress.unwrap_or(false);
+        let (range, data) = executor.context().subsystem()
+            .read_file(path, offset, length, tail_lines, compress)?;
+        Ok(FileContents::new(range, data, compress, encode.unwrap_or(false)))
     }
 
-    // Uploads the contents in data to a file at the specified path, 
-    // optionally decodes data from base64 before writing to file
-    field upload_file(&executor, path: Option<String>, decode: Option<bool>, data: Option<String>) -> FieldResult<String>
+    // Lists a directory, optionally descending into subdirectories down to the given
+    // depth (all the way if none is given).  Symlinks are listed but not followed.
+    field list_directory(&executor, path: String, recursive: Option<bool>, depth: Option<i32>) -> FieldResult<DirectoryListing>
+    {
+        Ok(executor.context().subsystem().list_directory(path, recursive, depth)?.into())
+    }
+
+    // Returns the size, modification time, mode and type of a path
+    field stat(&executor, path: String) -> FieldResult<FileEntry>
+    {
+        Ok(executor.context().subsystem().stat(path)?.into())
+    }
+
+    // Returns the checksum of a file, or of `length` bytes of it from `offset` on
+    field checksum(&executor, path: String, hash_type: ChecksumType, offset: Option<f64>,
+        length: Option<f64>) -> FieldResult<FileChecksum>
+    {
+        Ok(executor.context().subsystem().file_checksum(path, hash_type.into(), offset, length)?.into())
+    }
+
+    // Returns one chunk of a download started with startDownload
+    field download_chunk(&executor, file_id: String, index: i32) -> FieldResult<DownloadChunk>
+    {
+        Ok(executor.context().subsystem().download_chunk(file_id, index)?.into())
+    }
+
+    // Returns the state of one download, or of all of them if no ID is given,
+    // including the chunks still missing on the ground
+    field download_status(&executor, file_id: Option<String>) -> FieldResult<Vec<DownloadInfo>>
+    {
+        Ok(executor.context().subsystem().download_status(file_id)?
+            .into_iter()
+            .map(DownloadInfo::from)
+            .collect())
+    }
+
+    // Returns the state of one upload, or of all of them if no ID is given,
+    // including the chunks still to be sent
+    field upload_status(&executor, file_id: Option<String>) -> FieldResult<Vec<UploadInfo>>
+    {
+        Ok(executor.context().subsystem().upload_status(file_id)?
+            .into_iter()
+            .map(UploadInfo::from)
+            .collect())
+    }
+
+    // Returns the installed versions and last update of one update target, or of all
+    // of them if none is given
+    field update_status(&executor, target: Option<String>) -> FieldResult<Vec<UpdateTargetInfo>>
+    {
+        Ok(executor.context().subsystem().update_status(target)?
+            .into_iter()
+            .map(UpdateTargetInfo::from)
+            .collect())
+    }
+
+    // Returns one time-tagged command, or all of them soonest first if no ID is
+    // given, with the results of any that have run
+    field queued_commands(&executor, id: Option<String>) -> FieldResult<Vec<QueuedCommand>>
+    {
+        Ok(executor.context().subsystem().queued_commands(id)?
+            .into_iter()
+            .map(QueuedCommand::from)
+            .collect())
+    }
+
+    // Returns one CFDP transaction, or all of them if no ID is given
+    field cfdp_transactions(&executor, id: Option<String>) -> FieldResult<Vec<CfdpTransaction>>
     {
-        Ok(executor.context().subsystem().upload_file(path, decode, data)?)
-    }    
+        Ok(executor.context().subsystem().cfdp_transactions(id)?
+            .into_iter()
+            .map(CfdpTransaction::from)
+            .collect())
+    }
+
+    // Seconds until the keep-alive watchdog reboots the OBC
+    field keep_alive_remaining(&executor) -> FieldResult<f64>
+    {
+        Ok(executor.context().subsystem().keep_alive_remaining()?)
+    }
+
+    // Returns the state of one background job, or of all of them if no ID is given
+    field job_status(&executor, job_id: Option<String>) -> FieldResult<Vec<JobInfo>>
+    {
+        Ok(executor.context().subsystem().job_status(job_id)?
+            .into_iter()
+            .map(JobInfo::from)
+            .collect())
+    }
+
+    // Returns a page of a background job's stdout or stderr starting at offset,
+    // optionally encoded in base64.  Pages are at most one radio frame's worth.
+    field job_output(&executor, job_id: String, stream: JobStream, offset: Option<f64>,
+        length: Option<i32>, encode: Option<bool>) -> FieldResult<JobOutput>
+    {
+        let page = executor.context().subsystem().job_output(job_id, stream.into(), offset, length)?;
+        Ok(JobOutput::new(page, encode.unwrap_or(false)))
+    }
+
+    // Returns terminal output from a shell session starting at cursor, optionally
+    // encoded in base64.  Pass the returned nextCursor to get the output after it.
+    field shell_read(&executor, session_id: String, cursor: Option<f64>, encode: Option<bool>) -> FieldResult<ShellOutput>
+    {
+        let output = executor.context().subsystem().shell_read(session_id, cursor)?;
+        Ok(ShellOutput::new(output, encode.unwrap_or(false)))
+    }
+
+    // Returns the open shell sessions
+    field shell_sessions(&executor) -> FieldResult<Vec<ShellSession>>
+    {
+        Ok(executor.context().subsystem().shell_sessions()?
+            .into_iter()
+            .map(ShellSession::from)
+            .collect())
+    }
 
     // Request number of bad uplink packets
     field failed_packets_up(&executor) -> FieldResult<i32>
@@ -67,6 +181,56 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
         Ok(executor.context().subsystem().packets_down()?)
     }
 
+    // Request number of uplink frames dropped by the framing layer
+    field bad_frames(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().bad_frames()?)
+    }
+
+    // Request number of uplink packets dropped for failing authentication
+    field auth_failures(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().auth_failures()?)
+    }
+
+    // Request number of uplink RS codewords (or CLTU codeblocks) with errors that were
+    // all corrected
+    field corrected_codewords(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().corrected_codewords()?)
+    }
+
+    // Request number of uplink RS codewords (or CLTU codeblocks) with too many errors
+    // to correct
+    field uncorrectable_codewords(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().uncorrectable_codewords()?)
+    }
+
+    // Request number of downlink packets sent again by ARQ
+    field retransmitted_packets(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().retransmitted_packets()?)
+    }
+
+    // Request number of downlink packets acknowledged by the ground under ARQ
+    field acked_packets(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().acked_packets()?)
+    }
+
+    // Request number of downlink packets ARQ gave up on after too many retries
+    field abandoned_packets(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().abandoned_packets()?)
+    }
+
+    // Request number of duplicate uplink packets dropped by ARQ
+    field duplicate_packets(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().duplicate_packets()?)
+    }
+
     // Request errors that have occured
     field errors(&executor) -> FieldResult<Vec<String>>
     {
@@ -78,5 +242,569 @@ pub struct MutationRoot;
 
 /// Base GraphQL mutation model
 graphql_object!(MutationRoot: Context as "Mutation" |&self| {
+    // Runs an allowed system command and returns its output and exit status.  With
+    // background set, it starts the command as a job and returns the job ID instead.
+    field run_command(&executor, 
+        path: Option<String>, args: Option<Vec<String>>,
+        stdout: Option<String>, stderr: Option<String>,
+        background: Option<bool>) -> FieldResult<RunCommandResponse>
+    {
+        if background.unwrap_or(false) {
+            let job = if stdout.is_some() || stderr.is_some() {
+                Err("Output of background jobs can't be redirected".to_owned())
+            } else {
+                executor.context().subsystem().start_job(path, args)
+            };
+            return Ok(match job {
+                Ok(job) => RunCommandResponse {
+                    errors: "".to_owned(),
+                    success: true,
+                    stdout: "".to_owned(),
+                    stderr: "".to_owned(),
+                    stdout_truncated: false,
+                    stderr_truncated: false,
+                    status: None,
+                    job_id: Some(job.id),
+                },
+                Err(err) => RunCommandResponse {
+                    errors: err,
+                    success: false,
+                    stdout: "".to_owned(),
+                    stderr: "".to_owned(),
+                    stdout_truncated: false,
+                    stderr_truncated: false,
+                    status: None,
+                    job_id: None,
+                },
+            });
+        }
 
+        Ok(match executor.context().subsystem().run_command(path, args, stdout, stderr) {
+            Ok(output) => RunCommandResponse {
+                errors: "".to_owned(),
+                success: true,
+                stdout: output.stdout,
+                stderr: output.stderr,
+                stdout_truncated: output.stdout_truncated,
+                stderr_truncated: output.stderr_truncated,
+                status: Some(output.status.into()),
+                job_id: None,
+            },
+            Err(err) => RunCommandResponse {
+                errors: err,
+                success: false,
+                stdout: "".to_owned(),
+                stderr: "".to_owned(),
+                stdout_truncated: false,
+                stderr_truncated: false,
+                status: None,
+                job_id: None,
+            },
+        })
+    }
+
+    // Kills a running background job and everything it started
+    field cancel_job(&executor, job_id: String) -> FieldResult<JobResponse>
+    {
+        Ok(match executor.context().subsystem().cancel_job(job_id) {
+            Ok(job) => JobResponse {
+                errors: "".to_owned(),
+                success: true,
+                job: Some(job.into()),
+            },
+            Err(err) => JobResponse {
+                errors: err,
+                success: false,
+                job: None,
+            },
+        })
+    }
+
+    // Deletes a finished background job's record and output
+    field cleanup_job(&executor, job_id: String) -> FieldResult<GenericResponse>
+    {
+        Ok(match executor.context().subsystem().cleanup_job(job_id) {
+            Ok(()) => GenericResponse {
+                errors: "".to_owned(),
+                success: true,
+            },
+            Err(err) => GenericResponse {
+                errors: err,
+                success: false,
+            },
+        })
+    }
+
+    // Starts a shell on a new pseudo-terminal (24x80 unless given)
+    field open_shell(&executor, rows: Option<i32>, cols: Option<i32>) -> FieldResult<ShellResponse>
+    {
+        Ok(match executor.context().subsystem().open_shell(rows, cols) {
+            Ok(session) => ShellResponse {
+                errors: "".to_owned(),
+                success: true,
+                session: Some(session.into()),
+                written: None,
+            },
+            Err(err) => ShellResponse {
+                errors: err,
+                success: false,
+                session: None,
+                written: None,
+            },
+        })
+    }
+
+    // Sends input to a shell session, optionally decoding it from base64 first
+    field shell_write(&executor, session_id: String, data: String, decode: Option<bool>) -> FieldResult<ShellResponse>
+    {
+        Ok(match executor.context().subsystem().shell_write(session_id, data, decode) {
+            Ok((written, session)) => ShellResponse {
+                errors: "".to_owned(),
+                success: true,
+                session: Some(session.into()),
+                written: Some(written as i32),
+            },
+            Err(err) => ShellResponse {
+                errors: err,
+                success: false,
+                session: None,
+                written: None,
+            },
+        })
+    }
+
+    // Changes the terminal size of a shell session
+    field resize_shell(&executor, session_id: String, rows: i32, cols: i32) -> FieldResult<ShellResponse>
+    {
+        Ok(match executor.context().subsystem().resize_shell(session_id, rows, cols) {
+            Ok(session) => ShellResponse {
+                errors: "".to_owned(),
+                success: true,
+                session: Some(session.into()),
+                written: None,
+            },
+            Err(err) => ShellResponse {
+                errors: err,
+                success: false,
+                session: None,
+                written: None,
+            },
+        })
+    }
+
+    // Hangs up on a shell session and everything running in it
+    field close_shell(&executor, session_id: String) -> FieldResult<GenericResponse>
+    {
+        Ok(match executor.context().subsystem().close_shell(session_id) {
+            Ok(()) => GenericResponse {
+                errors: "".to_owned(),
+                success: true,
+            },
+            Err(err) => GenericResponse {
+                errors: err,
+                success: false,
+            },
+        })
+    }
+
+    // Removes files, symlinks and directories.  Directories that aren't empty are only
+    // removed if `recursive` is set.
+    field delete_files(&executor, paths: Vec<String>, recursive: Option<bool>) -> FieldResult<FileOpResponse>
+    {
+        Ok(executor.context().subsystem().delete_files(paths, recursive).into())
+    }
+
+    // Moves files or directories to the target, or into it if it is a directory.
+    // Existing files are only replaced if `overwrite` is set.
+    field move_files(&executor, sources: Vec<String>, target: String, overwrite: Option<bool>) -> FieldResult<FileOpResponse>
+    {
+        Ok(executor.context().subsystem().move_files(sources, target, overwrite).into())
+    }
+
+    // Copies regular files to the target, or into it if it is a directory.  Existing
+    // files are only replaced if `overwrite` is set.
+    field copy_files(&executor, sources: Vec<String>, target: String, overwrite: Option<bool>) -> FieldResult<FileOpResponse>
+    {
+        Ok(executor.context().subsystem().copy_files(sources, target, overwrite).into())
+    }
+
+    // Creates directories, along with any missing parents if `parents` is set.  The
+    // mode is an octal string such as "0755".
+    field make_directories(&executor, paths: Vec<String>, parents: Option<bool>, mode: Option<String>) -> FieldResult<FileOpResponse>
+    {
+        Ok(executor.context().subsystem().make_directories(paths, parents, mode).into())
+    }
+
+    // Sets the permissions of files and directories to an octal mode such as "0644"
+    field change_mode(&executor, paths: Vec<String>, mode: String) -> FieldResult<FileOpResponse>
+    {
+        Ok(executor.context().subsystem().change_mode(paths, mode).into())
+    }
+
+    // Installs a signed update package (a gzipped tar archive already uploaded to the
+    // OBC) as a new version of a target, switches to it and restarts the target.  The
+    // signature is Ed25519 in hex; see update.rs for what is signed.  Follow the
+    // health check with updateStatus.
+    field install_update(&executor, target: String, version: String, package: String,
+        signature: String) -> FieldResult<UpdateResponse>
+    {
+        Ok(match executor.context().subsystem().install_update(target, version, package, signature) {
+            Ok(update) => UpdateResponse {
+                errors: "".to_owned(),
+                success: true,
+                update: Some(update.into()),
+            },
+            Err(err) => UpdateResponse {
+                errors: err,
+                success: false,
+                update: None,
+            },
+        })
+    }
+
+    // Switches a target back to the version before its last update and restarts it
+    field rollback_update(&executor, target: String) -> FieldResult<UpdateResponse>
+    {
+        Ok(match executor.context().subsystem().rollback_update(target) {
+            Ok(update) => UpdateResponse {
+                errors: "".to_owned(),
+                success: true,
+                update: Some(update.into()),
+            },
+            Err(err) => UpdateResponse {
+                errors: err,
+                success: false,
+                update: None,
+            },
+        })
+    }
+
+    // Queues a command to run at a time in seconds since the epoch.  The command has
+    // to be allowed for runCommand.
+    field queue_command(&executor, execute_at: f64, command: String,
+        args: Option<Vec<String>>) -> FieldResult<QueueResponse>
+    {
+        Ok(match executor.context().subsystem().queue_command(execute_at, command, args) {
+            Ok(entry) => QueueResponse {
+                errors: "".to_owned(),
+                success: true,
+                entry: Some(entry.into()),
+            },
+            Err(err) => QueueResponse {
+                errors: err,
+                success: false,
+                entry: None,
+            },
+        })
+    }
+
+    // Queues a GraphQL request to be sent at a time in seconds since the epoch, to this
+    // service or to the named KubOS service
+    field queue_graphql(&executor, execute_at: f64, query: String,
+        service: Option<String>) -> FieldResult<QueueResponse>
+    {
+        Ok(match executor.context().subsystem().queue_graphql(execute_at, query, service) {
+            Ok(entry) => QueueResponse {
+                errors: "".to_owned(),
+                success: true,
+                entry: Some(entry.into()),
+            },
+            Err(err) => QueueResponse {
+                errors: err,
+                success: false,
+                entry: None,
+            },
+        })
+    }
+
+    // Deletes a time-tagged command, cancelling it if it hasn't run yet
+    field delete_queued(&executor, id: String) -> FieldResult<GenericResponse>
+    {
+        Ok(match executor.context().subsystem().delete_queued(id) {
+            Ok(()) => GenericResponse {
+                errors: "".to_owned(),
+                success: true,
+            },
+            Err(err) => GenericResponse {
+                errors: err,
+                success: false,
+            },
+        })
+    }
+
+    // Deletes every time-tagged command that isn't running, or only the ones that have
+    // finished
+    field clear_queue(&executor, finished_only: Option<bool>) -> FieldResult<ClearQueueResponse>
+    {
+        Ok(match executor.context().subsystem().clear_queue(finished_only) {
+            Ok(deleted) => ClearQueueResponse {
+                errors: "".to_owned(),
+                success: true,
+                deleted: deleted as i32,
+            },
+            Err(err) => ClearQueueResponse {
+                errors: err,
+                success: false,
+                deleted: 0,
+            },
+        })
+    }
+
+    // Sends a file to the ground with CFDP, acknowledged (class 2) unless acknowledged
+    // is false
+    field cfdp_put(&executor, source: String, destination: String,
+        acknowledged: Option<bool>) -> FieldResult<CfdpResponse>
+    {
+        Ok(match executor.context().subsystem().cfdp_put(source, destination, acknowledged) {
+            Ok(transaction) => CfdpResponse {
+                errors: "".to_owned(),
+                success: true,
+                transaction: Some(transaction.into()),
+            },
+            Err(err) => CfdpResponse {
+                errors: err,
+                success: false,
+                transaction: None,
+            },
+        })
+    }
+
+    // Cancels an open CFDP transaction, telling the other entity
+    field cfdp_cancel(&executor, id: String) -> FieldResult<CfdpResponse>
+    {
+        Ok(match executor.context().subsystem().cfdp_cancel(id) {
+            Ok(transaction) => CfdpResponse {
+                errors: "".to_owned(),
+                success: true,
+                transaction: Some(transaction.into()),
+            },
+            Err(err) => CfdpResponse {
+                errors: err,
+                success: false,
+                transaction: None,
+            },
+        })
+    }
+
+    // Deletes the record of a CFDP transaction that has ended
+    field cfdp_delete(&executor, id: String) -> FieldResult<GenericResponse>
+    {
+        Ok(match executor.context().subsystem().cfdp_delete(id) {
+            Ok(()) => GenericResponse {
+                errors: "".to_owned(),
+                success: true,
+            },
+            Err(err) => GenericResponse {
+                errors: err,
+                success: false,
+            },
+        })
+    }
+
+    // Restarts the keep-alive watchdog countdown.  Must be sent at least once per
+    // watchdog timeout or the OBC is rebooted.
+    field keep_alive(&executor) -> FieldResult<KeepAliveResponse>
+    {
+        Ok(match executor.context().subsystem().keep_alive() {
+            Ok(remaining) => KeepAliveResponse {
+                errors: "".to_owned(),
+                success: true,
+                remaining,
+            },
+            Err(err) => KeepAliveResponse {
+                errors: err,
+                success: false,
+                remaining: 0.0,
+            },
+        })
+    }
+
+    // Snapshots a file for a chunked download and returns its size, hash and chunk
+    // count.  The chunk size defaults to the most that fits in one radio frame.
+    field start_download(&executor, path: String, chunk_size: Option<i32>) -> FieldResult<DownloadResponse>
+    {
+        Ok(match executor.context().subsystem().start_download(path, chunk_size) {
+            Ok(download) => DownloadResponse {
+                errors: "".to_owned(),
+                success: true,
+                download: Some(download.into()),
+            },
+            Err(err) => DownloadResponse {
+                errors: err,
+                success: false,
+                download: None,
+            },
+        })
+    }
+
+    // Packs a directory into a gzipped tar archive and starts a chunked download of
+    // it.  Files are chosen by glob patterns on their path within the directory and
+    // by modification time (seconds since the epoch).
+    field archive_directory(&executor, path: String, include: Option<Vec<String>>, exclude: Option<Vec<String>>,
+        modified_after: Option<f64>, modified_before: Option<f64>, chunk_size: Option<i32>) -> FieldResult<ArchiveResponse>
+    {
+        Ok(match executor.context().subsystem()
+            .archive_directory(path, include, exclude, modified_after, modified_before, chunk_size) {
+            Ok((download, files)) => ArchiveResponse {
+                errors: "".to_owned(),
+                success: true,
+                files: files as i32,
+                download: Some(download.into()),
+            },
+            Err(err) => ArchiveResponse {
+                errors: err,
+                success: false,
+                files: 0,
+                download: None,
+            },
+        })
+    }
+
+    // Marks chunks of a download as received by the ground
+    field ack_download_chunks(&executor, file_id: String, chunks: Vec<i32>) -> FieldResult<DownloadResponse>
+    {
+        Ok(match executor.context().subsystem().ack_download_chunks(file_id, chunks) {
+            Ok(download) => DownloadResponse {
+                errors: "".to_owned(),
+                success: true,
+                download: Some(download.into()),
+            },
+            Err(err) => DownloadResponse {
+                errors: err,
+                success: false,
+                download: None,
+            },
+        })
+    }
+
+    // Ends a download and removes its snapshot from the staging area
+    field finish_download(&executor, file_id: String) -> FieldResult<GenericResponse>
+    {
+        Ok(match executor.context().subsystem().finish_download(file_id) {
+            Ok(()) => GenericResponse {
+                errors: "".to_owned(),
+                success: true,
+            },
+            Err(err) => GenericResponse {
+                errors: err,
+                success: false,
+            },
+        })
+    }
+
+    // Uploads the contents in data to a file at the specified path, 
+    // optionally decodes data from base64 before writing to file
+    field upload_file(&executor, path: Option<String>, decode: Option<bool>, data: Option<String>) -> FieldResult<UploadFileResponse>
+    {
+        let file = path.clone().unwrap_or_default();
+        Ok(match executor.context().subsystem().upload_file(path, decode, data) {
+            Ok(num) => UploadFileResponse {
+                errors: "".to_owned(),
+                success: true,
+                path: file,
+                bytes_written: num as i32,
+            },
+            Err(err) => UploadFileResponse {
+                errors: err,
+                success: false,
+                path: file,
+                bytes_written: 0,
+            },
+        })
+    }
+
+    // Starts a chunked upload to the given path.  The file is only installed once all
+    // of it has arrived and matches the hash.  The mode is an octal string such as
+    // "0755" and the owner is "user[:group]".
+    field start_upload(&executor, path: String, size: f64, hash: String, hash_type: ChecksumType,
+        chunk_size: Option<i32>, mode: Option<String>, owner: Option<String>) -> FieldResult<UploadResponse>
+    {
+        Ok(match executor.context().subsystem()
+            .start_upload(path, size, hash, hash_type.into(), chunk_size, mode, owner) {
+            Ok(upload) => UploadResponse {
+                errors: "".to_owned(),
+                success: true,
+                upload: Some(upload.into()),
+            },
+            Err(err) => UploadResponse {
+                errors: err,
+                success: false,
+                upload: None,
+            },
+        })
+    }
+
+    // Rebuilds a file from an existing one and a bsdiff (BSDIFF40) patch already on
+    // the OBC, and installs it at the target (the original file by default) once it
+    // matches the hash.  The mode is an octal string such as "0755" and the owner is
+    // "user[:group]"; the original file's mode is kept if none is given.
+    field apply_patch(&executor, base: String, patch: String, target: Option<String>, hash: String,
+        hash_type: ChecksumType, mode: Option<String>, owner: Option<String>) -> FieldResult<PatchResponse>
+    {
+        Ok(match executor.context().subsystem()
+            .apply_patch(base, patch, target, hash, hash_type.into(), mode, owner) {
+            Ok((path, size)) => PatchResponse {
+                errors: "".to_owned(),
+                success: true,
+                path,
+                size: size as f64,
+            },
+            Err(err) => PatchResponse {
+                errors: err,
+                success: false,
+                path: "".to_owned(),
+                size: 0.0,
+            },
+        })
+    }
+
+    // Writes one base64-encoded chunk of an upload, checking it against the optional
+    // CRC-32 (in hex) first
+    field upload_chunk(&executor, file_id: String, index: i32, data: String, crc32: Option<String>) -> FieldResult<UploadResponse>
+    {
+        Ok(match executor.context().subsystem().upload_chunk(file_id, index, data, crc32) {
+            Ok(upload) => UploadResponse {
+                errors: "".to_owned(),
+                success: true,
+                upload: Some(upload.into()),
+            },
+            Err(err) => UploadResponse {
+                errors: err,
+                success: false,
+                upload: None,
+            },
+        })
+    }
+
+    // Verifies a complete upload and moves it into place
+    field finish_upload(&executor, file_id: String) -> FieldResult<UploadResponse>
+    {
+        Ok(match executor.context().subsystem().finish_upload(file_id) {
+            Ok(upload) => UploadResponse {
+                errors: "".to_owned(),
+                success: true,
+                upload: Some(upload.into()),
+            },
+            Err(err) => UploadResponse {
+                errors: err,
+                success: false,
+                upload: None,
+            },
+        })
+    }
+
+    // Abandons an upload and removes its staging file
+    field cancel_upload(&executor, file_id: String) -> FieldResult<GenericResponse>
+    {
+        Ok(match executor.context().subsystem().cancel_upload(file_id) {
+            Ok(()) => GenericResponse {
+                errors: "".to_owned(),
+                success: true,
+            },
+            Err(err) => GenericResponse {
+                errors: err,
+                success: false,
+            },
+        })
+    }
 });
\ No newline at end of file
@@ -0,0 +1,353 @@
+// Interactive shell sessions over the radio link
+//
+// KubOS has a shell service, but it isn't any use if the radio service is the only
+// thing still running.  This gives the ground a shell of its own, driven over as many
+// request/response round trips as it takes.  Each session runs the configured shell
+// on a pseudo-terminal, and a reader thread collects everything the shell writes into
+// a buffer.  Output is addressed by a cursor that counts bytes since the session was
+// opened: the ground asks for everything after the cursor it last saw, so a lost
+// response can simply be asked for again.  Only the newest output is kept; if the
+// ground falls too far behind, the oldest output is dropped and the response says so.
+//
+// The shell is not subject to the run_command allowlist, so it is off unless turned
+// on in the config file.  Sessions nobody has used for a while are closed.
+//
+// The terminal is non-blocking, so a shell that stops reading its input can't hold up
+// the caller (and with it every other session): a write gives up after a short wait
+// and says how much of the input the shell took.
+
+use crate::config::ShellConfig;
+use crate::transfer::{new_id, now};
+use log::*;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest a write waits for the shell to make room for its input
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Output collected from a shell, addressed by byte position since the session opened
+struct OutputBuffer {
+    data: Vec<u8>,
+    // Position of data[0]
+    start: u64,
+    max: usize,
+}
+
+impl OutputBuffer {
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+        if self.data.len() > self.max {
+            let excess = self.data.len() - self.max;
+            self.data.drain(0..excess);
+            self.start += excess as u64;
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+// Output read from a session
+pub struct ShellOutput {
+    // Position of the first byte of `data`.  Later than the cursor asked for if some
+    // of the output in between has been dropped.
+    pub cursor: u64,
+    pub data: Vec<u8>,
+    // Cursor to ask for next time
+    pub next_cursor: u64,
+    // Whether output between the requested cursor and `cursor` was dropped
+    pub dropped: bool,
+}
+
+// State of a session
+pub struct SessionInfo {
+    pub id: String,
+    pub pid: u32,
+    pub rows: u16,
+    pub cols: u16,
+    pub running: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub opened: u64,
+    // Seconds since the ground last used the session
+    pub idle: u64,
+}
+
+struct Session {
+    id: String,
+    master: File,
+    child: Child,
+    output: Arc<Mutex<OutputBuffer>>,
+    rows: u16,
+    cols: u16,
+    opened: u64,
+    last_used: Instant,
+}
+
+impl Session {
+
+    fn info(&mut self) -> SessionInfo {
+        let status = self.child.try_wait().ok().and_then(|status| status);
+        SessionInfo {
+            id: self.id.clone(),
+            pid: self.child.id(),
+            rows: self.rows,
+            cols: self.cols,
+            running: status.is_none(),
+            exit_code: status.and_then(|status| status.code()),
+            signal: status.and_then(|status| status.signal()),
+            opened: self.opened,
+            idle: self.last_used.elapsed().as_secs(),
+        }
+    }
+
+    // Hang up on the shell and everything it started
+    fn close(mut self) {
+        let group = Pid::from_raw(self.child.id() as i32);
+        let _ = killpg(group, Signal::SIGHUP);
+        let _ = killpg(group, Signal::SIGKILL);
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Sessions {
+    config: ShellConfig,
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions {
+
+    pub fn new(config: &ShellConfig) -> Sessions {
+        Sessions {
+            config: config.clone(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    // Start a shell on a new pseudo-terminal
+    pub fn open(&mut self, rows: u16, cols: u16) -> Result<SessionInfo, String> {
+        if !self.config.enabled {
+            return Err("Shell sessions are disabled".to_owned());
+        }
+        self.expire();
+        if self.sessions.len() >= self.config.max_sessions {
+            return Err(format!("Too many shell sessions open (limit {})", self.config.max_sessions));
+        }
+
+        let winsize = Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let pty = openpty(&winsize, None).map_err(|_| "Failed to open pseudo-terminal".to_owned())?;
+        let master = unsafe { File::from_raw_fd(pty.master) };
+        let slave = unsafe { File::from_raw_fd(pty.slave) };
+
+        // Keep the terminal out of every other process this service starts
+        for fd in &[pty.master, pty.slave] {
+            fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .map_err(|_| "Failed to set up pseudo-terminal".to_owned())?;
+        }
+        fcntl(pty.master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|_| "Failed to set up pseudo-terminal".to_owned())?;
+
+        let stdio = |file: &File| file.try_clone().map_err(|_| "Failed to set up pseudo-terminal".to_owned());
+        let mut command = Command::new(&self.config.shell);
+        command
+            .env("TERM", "vt100")
+            .stdin(stdio(&slave)?)
+            .stdout(stdio(&slave)?)
+            .stderr(slave);
+
+        // Make the shell a session leader with the terminal as its controlling tty, so
+        // job control and Ctrl-C work and it can be hung up on as a group
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let child = command.spawn().map_err(|_| "Could not start shell".to_owned())?;
+        // Close our copies of the slave side, so the reader sees the terminal close
+        // when the shell exits
+        drop(command);
+
+        let output = Arc::new(Mutex::new(OutputBuffer {
+            data: vec![],
+            start: 0,
+            max: self.config.buffer_size,
+        }));
+
+        let reader = master.try_clone().map_err(|_| "Failed to set up pseudo-terminal".to_owned())?;
+        let reader_output = output.clone();
+        let _ = thread::Builder::new()
+            .name("shell-reader".to_owned())
+            .spawn(move || read_output(reader, reader_output));
+
+        let id = new_id(&self.config.shell);
+        let mut session = Session {
+            id: id.clone(),
+            master,
+            child,
+            output,
+            rows,
+            cols,
+            opened: now(),
+            last_used: Instant::now(),
+        };
+        let info = session.info();
+
+        info!("Opened shell session {} (pid {})", id, info.pid);
+        self.sessions.insert(id, session);
+        Ok(info)
+    }
+
+    fn session(&mut self, id: &str) -> Result<&mut Session, String> {
+        let session = self
+            .sessions
+            .get_mut(id)
+            .ok_or_else(|| "Unknown shell session ID".to_owned())?;
+        session.last_used = Instant::now();
+        Ok(session)
+    }
+
+    // Send input to the shell, returning how many bytes it took.  Less than all of it
+    // if the terminal stayed full for the write timeout; the rest can be sent again.
+    pub fn write(&mut self, id: &str, data: &[u8]) -> Result<(usize, SessionInfo), String> {
+        let session = self.session(id)?;
+        let deadline = Instant::now() + WRITE_TIMEOUT;
+        let mut written = 0;
+
+        while written < data.len() {
+            match session.master.write(&data[written..]) {
+                Ok(num) => written += num,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    let mut fds = [PollFd::new(session.master.as_raw_fd(), PollFlags::POLLOUT)];
+                    let _ = poll(&mut fds, (deadline - now).as_millis() as i32);
+                }
+                Err(_) => return Err("Failed to write to shell".to_owned()),
+            }
+        }
+
+        if written < data.len() {
+            warn!(
+                "Shell session {} only took {} of {} bytes of input",
+                id,
+                written,
+                data.len()
+            );
+        }
+        Ok((written, session.info()))
+    }
+
+    // Read up to `max` bytes of output from `cursor` on
+    pub fn read(&mut self, id: &str, cursor: u64, max: usize) -> Result<ShellOutput, String> {
+        let session = self.session(id)?;
+        let output = session.output.lock().map_err(|_| "Failed to lock shell output".to_owned())?;
+
+        let from = cursor.max(output.start).min(output.end());
+        let offset = (from - output.start) as usize;
+        let len = max.min(output.data.len() - offset);
+
+        Ok(ShellOutput {
+            cursor: from,
+            data: output.data[offset..offset + len].to_vec(),
+            next_cursor: from + len as u64,
+            dropped: from > cursor,
+        })
+    }
+
+    pub fn resize(&mut self, id: &str, rows: u16, cols: u16) -> Result<SessionInfo, String> {
+        let session = self.session(id)?;
+        let winsize = Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        if unsafe { libc::ioctl(session.master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } < 0 {
+            return Err("Failed to resize terminal".to_owned());
+        }
+        session.rows = rows;
+        session.cols = cols;
+        Ok(session.info())
+    }
+
+    pub fn close(&mut self, id: &str) -> Result<(), String> {
+        let session = self
+            .sessions
+            .remove(id)
+            .ok_or_else(|| "Unknown shell session ID".to_owned())?;
+        session.close();
+        info!("Closed shell session {}", id);
+        Ok(())
+    }
+
+    pub fn list(&mut self) -> Vec<SessionInfo> {
+        let mut list: Vec<SessionInfo> = self.sessions.values_mut().map(Session::info).collect();
+        list.sort_by_key(|session| session.opened);
+        list
+    }
+
+    // Close sessions the ground hasn't touched within the idle timeout
+    pub fn expire(&mut self) {
+        let timeout = self.config.idle_timeout;
+        let idle: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|session| session.last_used.elapsed() >= timeout)
+            .map(|session| session.id.clone())
+            .collect();
+
+        for id in idle {
+            warn!("Closing idle shell session {}", id);
+            let _ = self.close(&id);
+        }
+    }
+}
+
+// Collect a shell's output until it closes the terminal
+fn read_output(mut master: File, output: Arc<Mutex<OutputBuffer>>) {
+    let mut buffer = [0; 4096];
+    loop {
+        match master.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(num) => {
+                if let Ok(mut output) = output.lock() {
+                    output.push(&buffer[0..num]);
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let mut fds = [PollFd::new(master.as_raw_fd(), PollFlags::POLLIN)];
+                let _ = poll(&mut fds, -1);
+            }
+            // EIO once every process has closed the terminal
+            Err(_) => break,
+        }
+    }
+}
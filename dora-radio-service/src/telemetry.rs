@@ -0,0 +1,23 @@
+// Radio link counters kept by this service in addition to the generic packet
+// counters in comms_service::CommsTelemetry
+
+#[derive(Clone, Debug, Default)]
+pub struct LinkTelemetry {
+    // Frames dropped by the framing layer (bad FCS, runt, oversized or aborted)
+    pub bad_frames: i32,
+    // Packets dropped for a bad or missing authentication trailer, or a replayed counter
+    pub auth_failures: i32,
+    // RS codewords (or CLTU codeblocks in CCSDS mode) received with errors that were
+    // all corrected
+    pub corrected_codewords: i32,
+    // RS codewords or CLTU codeblocks received with too many errors to correct
+    pub uncorrectable_codewords: i32,
+    // Downlinked packets sent again by ARQ after a timeout or NACK
+    pub retransmitted_packets: i32,
+    // Downlinked packets acknowledged by the ground under ARQ
+    pub acked_packets: i32,
+    // Downlinked packets ARQ gave up on after too many retries
+    pub abandoned_packets: i32,
+    // Uplinked packets dropped by ARQ as already received
+    pub duplicate_packets: i32,
+}
@@ -0,0 +1,825 @@
+// Chunked file transfers over the radio link
+//
+// A file too big for a single GraphQL response is moved in fixed-size chunks instead.
+// Starting a download takes a snapshot of the file in the staging area, so a growing
+// log can't change underneath the transfer, and records its size, SHA-256 and chunk
+// size in a manifest next to it.  The ground fetches chunks by index and acknowledges
+// them as they arrive.  After a dropped pass, or even a restart of this service, it
+// asks which chunks are still missing and carries on from there.
+//
+// Uploads work the same way in the other direction.  Chunks are written into a staging
+// file, and only once every chunk has arrived and the whole file matches the hash the
+// ground supplied is it moved into place at the target path.  Uploads that stop
+// making progress are deleted from the staging area after a timeout.
+
+use crate::checksum::{self, HashType};
+use crate::files::{self, Owner};
+use log::*;
+use nix::sys::statvfs::statvfs;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Seconds since the epoch, for transfer timestamps
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+// Short unique ID for a new transfer or job
+pub fn new_id(path: &str) -> String {
+    let seed = format!("{}{:?}", path, SystemTime::now());
+    checksum::sha256(seed.as_bytes())[0..12].to_owned()
+}
+
+// Bytes free in the filesystem holding a staging directory
+pub fn free_space(dir: &Path) -> Result<u64, String> {
+    let stats = statvfs(dir).map_err(|_| "Failed to check staging area free space".to_owned())?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+// Write a manifest next to its transfer data.  It goes to a temporary file first so
+// a reboot mid-write can't leave a corrupt manifest behind.
+pub fn save_manifest<T: Serialize>(path: &Path, manifest: &T) -> Result<(), String> {
+    let tmp = path.with_extension("json.tmp");
+    let data = serde_json::to_vec(manifest).map_err(|_| "Failed to encode manifest".to_owned())?;
+
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(&data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|_| "Failed to save transfer manifest".to_owned())
+}
+
+// Load every manifest of one type from a staging directory
+pub fn load_manifests<T: for<'de> Deserialize<'de>>(dir: &Path) -> Vec<T> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .filter_map(|path| {
+            let manifest = fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice(&data).ok());
+            if manifest.is_none() {
+                warn!("Ignoring unreadable transfer manifest {}", path.display());
+            }
+            manifest
+        })
+        .collect()
+}
+
+// A manifest is saved again after this many chunks, or once this long has passed since
+// the last save, rather than after every chunk.  A restart loses what changed in
+// between, and the ground fetches or sends those chunks again.
+const SAVE_EVERY: u32 = 64;
+const SAVE_INTERVAL_S: u64 = 60;
+
+// Whether a manifest with `unsaved` changes, last saved at `saved`, should be saved now
+fn save_due(unsaved: u32, saved: u64, time: u64) -> bool {
+    unsaved >= SAVE_EVERY || time.saturating_sub(saved) >= SAVE_INTERVAL_S
+}
+
+// Most chunks in one transfer, since chunk indexes are GraphQL Ints
+const MAX_CHUNKS: u64 = i32::max_value() as u64;
+
+// Number of chunks a transfer of `size` bytes needs
+fn chunk_count(size: u64, chunk_size: usize) -> Result<u64, String> {
+    let chunk_size = chunk_size as u64;
+    let num_chunks = (size + chunk_size - 1) / chunk_size;
+    if num_chunks > MAX_CHUNKS {
+        return Err(format!(
+            "A chunk size of {} bytes makes too many chunks, at most {} are allowed",
+            chunk_size, MAX_CHUNKS
+        ));
+    }
+    Ok(num_chunks)
+}
+
+// Indexes of the chunks not in a list of received ranges
+fn missing_chunks(received: &[(u64, u64)], num_chunks: u64) -> Vec<u64> {
+    gaps(received, num_chunks)
+        .into_iter()
+        .flat_map(|(start, end)| start..end)
+        .collect()
+}
+
+// Add [start, end) to a sorted list of ranges, merging where they touch
+pub fn add_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    let mut merged = (start, end);
+    let mut result = Vec::with_capacity(ranges.len() + 1);
+    for &(first, last) in ranges.iter() {
+        if last < merged.0 || first > merged.1 {
+            result.push((first, last));
+        } else {
+            merged = (merged.0.min(first), merged.1.max(last));
+        }
+    }
+    result.push(merged);
+    result.sort();
+    *ranges = result;
+}
+
+// Take [start, end) out of a sorted list of ranges
+pub fn remove_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    let mut result = Vec::with_capacity(ranges.len() + 1);
+    for &(first, last) in ranges.iter() {
+        if first < start {
+            result.push((first, last.min(start)));
+        }
+        if last > end {
+            result.push((first.max(end), last));
+        }
+    }
+    *ranges = result;
+}
+
+// The parts of [0, size) not covered by a sorted list of ranges
+pub fn gaps(ranges: &[(u64, u64)], size: u64) -> Vec<(u64, u64)> {
+    let mut missing = vec![];
+    let mut next = 0;
+    for &(start, end) in ranges {
+        if start > next {
+            missing.push((next, start.min(size)));
+        }
+        next = next.max(end);
+    }
+    if next < size {
+        missing.push((next, size));
+    }
+    missing.retain(|(start, end)| start < end);
+    missing
+}
+
+// State of one download, as saved in its manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Download {
+    pub id: String,
+    // File the snapshot was taken from
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    pub chunk_size: usize,
+    pub num_chunks: u64,
+    // Ranges of indexes of the chunks the ground has acknowledged
+    pub received: Vec<(u64, u64)>,
+    pub started: u64,
+    // Acknowledgements since the manifest was last saved, and when that was
+    #[serde(skip)]
+    unsaved: u32,
+    #[serde(skip)]
+    saved: u64,
+}
+
+impl Download {
+
+    // Indexes of the chunks the ground hasn't acknowledged yet
+    pub fn missing(&self) -> Vec<u64> {
+        missing_chunks(&self.received, self.num_chunks)
+    }
+}
+
+// One chunk of a download
+pub struct Chunk {
+    pub index: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub crc32: u32,
+}
+
+// The staging file of a download being set up
+pub struct Staging {
+    id: String,
+    path: String,
+    data_path: PathBuf,
+    chunk_size: usize,
+    size: u64,
+    hash: String,
+}
+
+impl Staging {
+
+    // Have `write` put the data in the staging file it is given, then take its size
+    // and hash.  The file is removed if anything fails.
+    pub fn write<F>(&mut self, write: F) -> Result<(), String>
+    where
+        F: FnOnce(&Path) -> Result<(), String>,
+    {
+        let data_path = &self.data_path;
+        let snapshot = write(data_path).and_then(|_| {
+            let size = fs::metadata(data_path)
+                .map(|meta| meta.len())
+                .map_err(|_| "Failed to copy file to staging area".to_owned())?;
+            let hash = checksum::sha256_file(data_path).map_err(|_| "Failed to hash file".to_owned())?;
+            Ok((size, hash))
+        });
+
+        match snapshot {
+            Ok((size, hash)) => {
+                self.size = size;
+                self.hash = hash;
+                Ok(())
+            }
+            Err(err) => {
+                let _ = fs::remove_file(data_path);
+                Err(err)
+            }
+        }
+    }
+}
+
+// All downloads known to the service
+pub struct Downloads {
+    dir: PathBuf,
+    max_chunk: usize,
+    transfers: HashMap<String, Download>,
+}
+
+impl Downloads {
+
+    // Pick up any downloads left in the staging area from before a restart.
+    // `max_chunk` is the largest chunk that fits in one radio frame.
+    pub fn new(staging_dir: &Path, max_chunk: usize) -> Downloads {
+        let dir = staging_dir.join("downloads");
+        if let Err(err) = fs::create_dir_all(&dir) {
+            error!("Failed to create download staging area {}: {}", dir.display(), err);
+        }
+
+        let transfers = load_manifests::<Download>(&dir)
+            .into_iter()
+            .map(|download| (download.id.clone(), download))
+            .collect();
+
+        Downloads {
+            dir,
+            max_chunk,
+            transfers,
+        }
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.data", id))
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // Set up a new download, in three steps so that the downloads lock isn't held
+    // while a big file is copied or archived and hashed: prepare picks the staging file,
+    // Staging::write fills it with no lock held, and register adds the download.
+    // `path` records where the data came from.
+    pub fn prepare(&self, path: &str, chunk_size: Option<usize>) -> Result<Staging, String> {
+        let chunk_size = chunk_size.unwrap_or(self.max_chunk);
+        if chunk_size == 0 || chunk_size > self.max_chunk {
+            return Err(format!(
+                "Chunk size must be between 1 and {} bytes",
+                self.max_chunk
+            ));
+        }
+
+        let id = new_id(path);
+        Ok(Staging {
+            data_path: self.data_path(&id),
+            id,
+            path: path.to_owned(),
+            chunk_size,
+            size: 0,
+            hash: String::new(),
+        })
+    }
+
+    pub fn register(&mut self, staging: Staging) -> Result<Download, String> {
+        let num_chunks = match chunk_count(staging.size, staging.chunk_size) {
+            Ok(num_chunks) => num_chunks,
+            Err(err) => {
+                let _ = fs::remove_file(&staging.data_path);
+                return Err(err);
+            }
+        };
+        let time = now();
+        let download = Download {
+            id: staging.id.clone(),
+            path: staging.path,
+            size: staging.size,
+            hash: staging.hash,
+            chunk_size: staging.chunk_size,
+            num_chunks,
+            received: vec![],
+            started: time,
+            unsaved: 0,
+            saved: time,
+        };
+
+        if let Err(err) = save_manifest(&self.manifest_path(&download.id), &download) {
+            let _ = fs::remove_file(&staging.data_path);
+            return Err(err);
+        }
+
+        info!("Started download {} of {} ({} chunks)", download.id, download.path, num_chunks);
+        self.transfers.insert(download.id.clone(), download.clone());
+        Ok(download)
+    }
+
+    // Read one chunk of a download
+    pub fn chunk(&self, id: &str, index: u64) -> Result<Chunk, String> {
+        let download = self.status(id)?;
+        if index >= download.num_chunks {
+            return Err(format!("Chunk index must be less than {}", download.num_chunks));
+        }
+
+        let offset = index * download.chunk_size as u64;
+        let len = (download.chunk_size as u64).min(download.size - offset) as usize;
+
+        let mut file = File::open(self.data_path(id)).map_err(|_| "Failed to open staged file".to_owned())?;
+        let mut data = vec![0; len];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(|_| "Failed to read staged file".to_owned())?;
+
+        Ok(Chunk {
+            index,
+            offset,
+            crc32: checksum::crc32(&data),
+            data,
+        })
+    }
+
+    // Mark chunks as received by the ground.  The manifest is only saved every so
+    // often, and once the last chunk is in.
+    pub fn ack(&mut self, id: &str, chunks: &[u64]) -> Result<Download, String> {
+        let manifest_path = self.manifest_path(id);
+        let download = self
+            .transfers
+            .get_mut(id)
+            .ok_or_else(|| "Unknown download ID".to_owned())?;
+
+        if let Some(index) = chunks.iter().find(|&&index| index >= download.num_chunks) {
+            return Err(format!("Chunk index {} must be less than {}", index, download.num_chunks));
+        }
+        for &index in chunks {
+            add_range(&mut download.received, index, index + 1);
+        }
+
+        download.unsaved = download.unsaved.saturating_add(chunks.len() as u32);
+        let time = now();
+        let complete = gaps(&download.received, download.num_chunks).is_empty();
+        if complete || save_due(download.unsaved, download.saved, time) {
+            save_manifest(&manifest_path, &*download)?;
+            download.unsaved = 0;
+            download.saved = time;
+        }
+        Ok(download.clone())
+    }
+
+    pub fn status(&self, id: &str) -> Result<Download, String> {
+        self.transfers
+            .get(id)
+            .cloned()
+            .ok_or_else(|| "Unknown download ID".to_owned())
+    }
+
+    pub fn list(&self) -> Vec<Download> {
+        let mut list: Vec<Download> = self.transfers.values().cloned().collect();
+        list.sort_by_key(|download| download.started);
+        list
+    }
+
+    // Drop a download and its snapshot, whether or not it completed
+    pub fn finish(&mut self, id: &str) -> Result<(), String> {
+        self.transfers
+            .remove(id)
+            .ok_or_else(|| "Unknown download ID".to_owned())?;
+
+        let _ = fs::remove_file(self.data_path(id));
+        fs::remove_file(self.manifest_path(id)).map_err(|_| "Failed to remove transfer manifest".to_owned())
+    }
+}
+
+// State of one upload, as saved in its manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Upload {
+    pub id: String,
+    // Where the file goes once it is complete and verified
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    pub hash_type: HashType,
+    pub chunk_size: usize,
+    pub num_chunks: u64,
+    // Ranges of indexes of the chunks written to the staging file
+    pub received: Vec<(u64, u64)>,
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub started: u64,
+    // Last time a chunk arrived, for expiring abandoned uploads
+    pub updated: u64,
+    // Chunks since the manifest was last saved, and when that was
+    #[serde(skip)]
+    unsaved: u32,
+    #[serde(skip)]
+    saved: u64,
+}
+
+impl Upload {
+
+    // Indexes of the chunks that haven't arrived yet
+    pub fn missing(&self) -> Vec<u64> {
+        missing_chunks(&self.received, self.num_chunks)
+    }
+}
+
+// Details the ground supplies when starting an upload
+pub struct UploadRequest {
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    pub hash_type: HashType,
+    pub chunk_size: Option<usize>,
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+}
+
+// All uploads known to the service
+pub struct Uploads {
+    dir: PathBuf,
+    max_chunk: usize,
+    max_size: u64,
+    timeout: Duration,
+    transfers: HashMap<String, Upload>,
+}
+
+impl Uploads {
+
+    // Pick up any uploads left in the staging area from before a restart.  Uploads with
+    // no new chunks for `timeout` are removed, and ones over `max_size` bytes refused.
+    pub fn new(staging_dir: &Path, max_chunk: usize, max_size: u64, timeout: Duration) -> Uploads {
+        let dir = staging_dir.join("uploads");
+        if let Err(err) = fs::create_dir_all(&dir) {
+            error!("Failed to create upload staging area {}: {}", dir.display(), err);
+        }
+
+        let transfers = load_manifests::<Upload>(&dir)
+            .into_iter()
+            .map(|upload| (upload.id.clone(), upload))
+            .collect();
+
+        let mut uploads = Uploads {
+            dir,
+            max_chunk,
+            max_size,
+            timeout,
+            transfers,
+        };
+        uploads.expire();
+        uploads
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // Set up a new upload and its staging file
+    pub fn start(&mut self, request: UploadRequest) -> Result<Upload, String> {
+        self.expire();
+
+        let chunk_size = request.chunk_size.unwrap_or(self.max_chunk);
+        if chunk_size == 0 || chunk_size > self.max_chunk {
+            return Err(format!(
+                "Chunk size must be between 1 and {} bytes",
+                self.max_chunk
+            ));
+        }
+
+        if request.size > self.max_size {
+            return Err(format!("Uploads must be at most {} bytes", self.max_size));
+        }
+        let num_chunks = chunk_count(request.size, chunk_size)?;
+
+        let hash = request.hash.to_lowercase();
+        if hash.len() != request.hash_type.hex_len() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Hash must be {} hex digits",
+                request.hash_type.hex_len()
+            ));
+        }
+
+        // Catch a bad target or owner now rather than after the whole file is uplinked
+        files::check_target(Path::new(&request.path))
+            .map_err(|_| "Target directory does not exist".to_owned())?;
+        if let Some(ref owner) = request.owner {
+            files::parse_owner(owner)?;
+        }
+
+        // The staging file starts out sparse, so make sure the whole file will fit
+        let free = free_space(&self.dir)?;
+        if request.size > free {
+            return Err(format!("Not enough space in the staging area, {} bytes free", free));
+        }
+
+        let id = new_id(&request.path);
+        let data_path = self.data_path(&id);
+        File::create(&data_path)
+            .and_then(|f| f.set_len(request.size))
+            .map_err(|_| "Failed to create staging file".to_owned())?;
+
+        let time = now();
+        let upload = Upload {
+            id: id.clone(),
+            path: request.path,
+            size: request.size,
+            hash,
+            hash_type: request.hash_type,
+            chunk_size,
+            num_chunks,
+            received: vec![],
+            mode: request.mode,
+            owner: request.owner,
+            started: time,
+            updated: time,
+            unsaved: 0,
+            saved: time,
+        };
+
+        if let Err(err) = save_manifest(&self.manifest_path(&id), &upload) {
+            let _ = fs::remove_file(&data_path);
+            return Err(err);
+        }
+
+        info!("Started upload {} to {} ({} chunks)", id, upload.path, num_chunks);
+        self.transfers.insert(id, upload.clone());
+        Ok(upload)
+    }
+
+    // Write one chunk into the staging file.  If the ground sent a CRC-32 for the chunk
+    // it is checked first.  The manifest is only saved every so often, and once the last
+    // chunk is in.
+    pub fn chunk(&mut self, id: &str, index: u64, data: &[u8], crc32: Option<u32>) -> Result<Upload, String> {
+        let data_path = self.data_path(id);
+        let manifest_path = self.manifest_path(id);
+        let upload = self
+            .transfers
+            .get_mut(id)
+            .ok_or_else(|| "Unknown upload ID".to_owned())?;
+
+        if index >= upload.num_chunks {
+            return Err(format!("Chunk index must be less than {}", upload.num_chunks));
+        }
+
+        let offset = index * upload.chunk_size as u64;
+        let expected = (upload.chunk_size as u64).min(upload.size - offset) as usize;
+        if data.len() != expected {
+            return Err(format!("Chunk {} must be {} bytes, not {}", index, expected, data.len()));
+        }
+
+        if let Some(crc) = crc32 {
+            if checksum::crc32(data) != crc {
+                return Err(format!("CRC mismatch in chunk {}", index));
+            }
+        }
+
+        OpenOptions::new()
+            .write(true)
+            .open(&data_path)
+            .and_then(|mut f| {
+                f.seek(SeekFrom::Start(offset))?;
+                f.write_all(data)
+            })
+            .map_err(|_| "Failed to write staging file".to_owned())?;
+
+        add_range(&mut upload.received, index, index + 1);
+        upload.updated = now();
+        upload.unsaved = upload.unsaved.saturating_add(1);
+        let complete = gaps(&upload.received, upload.num_chunks).is_empty();
+        if complete || save_due(upload.unsaved, upload.saved, upload.updated) {
+            save_manifest(&manifest_path, &*upload)?;
+            upload.unsaved = 0;
+            upload.saved = upload.updated;
+        }
+        Ok(upload.clone())
+    }
+
+    // Verify a complete upload and move it into place
+    pub fn finish(&mut self, id: &str) -> Result<Upload, String> {
+        let upload = self.status(id)?;
+
+        let missing = upload.missing();
+        if !missing.is_empty() {
+            return Err(format!("Upload is missing {} chunk(s)", missing.len()));
+        }
+
+        let data_path = self.data_path(id);
+        let hash = checksum::file_checksum(&data_path, upload.hash_type)
+            .map_err(|_| "Failed to hash staging file".to_owned())?;
+        if hash != upload.hash {
+            // The staging file is kept so the ground can resend chunks or cancel
+            return Err(format!("Hash mismatch: expected {}, got {}", upload.hash, hash));
+        }
+
+        let owner: Option<Owner> = match upload.owner {
+            Some(ref owner) => Some(files::parse_owner(owner)?),
+            None => None,
+        };
+        files::install_file(&data_path, Path::new(&upload.path), upload.mode, owner)?;
+
+        info!("Upload {} verified and installed at {}", id, upload.path);
+        self.remove(id);
+        Ok(upload)
+    }
+
+    // Abandon an upload and delete its staging file
+    pub fn cancel(&mut self, id: &str) -> Result<(), String> {
+        if !self.transfers.contains_key(id) {
+            return Err("Unknown upload ID".to_owned());
+        }
+        self.remove(id);
+        Ok(())
+    }
+
+    pub fn status(&self, id: &str) -> Result<Upload, String> {
+        self.transfers
+            .get(id)
+            .cloned()
+            .ok_or_else(|| "Unknown upload ID".to_owned())
+    }
+
+    pub fn list(&self) -> Vec<Upload> {
+        let mut list: Vec<Upload> = self.transfers.values().cloned().collect();
+        list.sort_by_key(|upload| upload.started);
+        list
+    }
+
+    // Delete uploads that have had no new chunks for longer than the timeout
+    pub fn expire(&mut self) {
+        let cutoff = now().saturating_sub(self.timeout.as_secs());
+        let expired: Vec<String> = self
+            .transfers
+            .values()
+            .filter(|upload| upload.updated < cutoff)
+            .map(|upload| upload.id.clone())
+            .collect();
+
+        for id in expired {
+            warn!("Removing abandoned upload {}", id);
+            self.remove(&id);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.transfers.remove(id);
+        let _ = fs::remove_file(self.data_path(id));
+        let _ = fs::remove_file(self.manifest_path(id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh staging directory for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dora-transfer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn adds_and_removes_ranges() {
+        let mut ranges = vec![];
+        add_range(&mut ranges, 10, 20);
+        add_range(&mut ranges, 30, 40);
+        assert_eq!(ranges, vec![(10, 20), (30, 40)]);
+        add_range(&mut ranges, 20, 25);
+        assert_eq!(ranges, vec![(10, 25), (30, 40)]);
+        add_range(&mut ranges, 0, 5);
+        add_range(&mut ranges, 12, 15);
+        assert_eq!(ranges, vec![(0, 5), (10, 25), (30, 40)]);
+        add_range(&mut ranges, 4, 31);
+        assert_eq!(ranges, vec![(0, 40)]);
+
+        remove_range(&mut ranges, 10, 20);
+        assert_eq!(ranges, vec![(0, 10), (20, 40)]);
+        remove_range(&mut ranges, 0, 5);
+        assert_eq!(ranges, vec![(5, 10), (20, 40)]);
+        remove_range(&mut ranges, 8, 30);
+        assert_eq!(ranges, vec![(5, 8), (30, 40)]);
+        remove_range(&mut ranges, 0, 100);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn finds_gaps() {
+        assert_eq!(gaps(&[], 10), vec![(0, 10)]);
+        assert!(gaps(&[], 0).is_empty());
+        assert!(gaps(&[(0, 10)], 10).is_empty());
+        assert_eq!(gaps(&[(2, 4), (6, 8)], 10), vec![(0, 2), (4, 6), (8, 10)]);
+        // Data past the end of the file doesn't count as a gap
+        assert_eq!(gaps(&[(0, 4), (12, 16)], 10), vec![(4, 10)]);
+    }
+
+    #[test]
+    fn counts_chunks_in_u64() {
+        assert_eq!(chunk_count(0, 100), Ok(0));
+        assert_eq!(chunk_count(100, 100), Ok(1));
+        assert_eq!(chunk_count(101, 100), Ok(2));
+        assert_eq!(chunk_count(5 << 30, 1024), Ok(5 << 20));
+        assert!(chunk_count(1 << 53, 1).is_err());
+    }
+
+    #[test]
+    fn saves_download_acks_in_batches() {
+        let dir = test_dir("acks");
+        let mut downloads = Downloads::new(&dir, 10);
+        let mut staging = downloads.prepare("/data", Some(1)).unwrap();
+        staging.write(|path| fs::write(path, vec![7; 100]).map_err(|err| err.to_string())).unwrap();
+        let download = downloads.register(staging).unwrap();
+        assert_eq!(download.num_chunks, 100);
+        assert_eq!(downloads.chunk(&download.id, 99).unwrap().offset, 99);
+        assert!(downloads.chunk(&download.id, 100).is_err());
+
+        let first: Vec<u64> = (0..10).collect();
+        assert_eq!(downloads.ack(&download.id, &first).unwrap().received, vec![(0, 10)]);
+        assert_eq!(Downloads::new(&dir, 10).status(&download.id).unwrap().missing().len(), 100);
+
+        let next: Vec<u64> = (10..70).collect();
+        downloads.ack(&download.id, &next).unwrap();
+        assert_eq!(Downloads::new(&dir, 10).status(&download.id).unwrap().missing().len(), 30);
+
+        let rest: Vec<u64> = (70..100).rev().collect();
+        assert!(downloads.ack(&download.id, &rest).unwrap().missing().is_empty());
+        assert!(Downloads::new(&dir, 10).status(&download.id).unwrap().missing().is_empty());
+        assert!(downloads.ack(&download.id, &[100]).is_err());
+    }
+
+    fn request(path: &Path, data: &[u8], chunk_size: usize) -> UploadRequest {
+        UploadRequest {
+            path: path.to_string_lossy().into_owned(),
+            size: data.len() as u64,
+            hash: checksum::sha256(data),
+            hash_type: HashType::Sha256,
+            chunk_size: Some(chunk_size),
+            mode: None,
+            owner: None,
+        }
+    }
+
+    #[test]
+    fn refuses_uploads_too_big_to_stage() {
+        let dir = test_dir("upload-size");
+        let target = dir.join("file");
+
+        let mut uploads = Uploads::new(&dir, 10, 1000, Duration::from_secs(60));
+        let mut big = request(&target, b"data", 10);
+        big.size = 1001;
+        assert!(uploads.start(big).is_err());
+
+        // Far more than any staging filesystem has free
+        let mut uploads = Uploads::new(&dir, 10, u64::max_value(), Duration::from_secs(60));
+        let mut huge = request(&target, b"data", 10);
+        huge.size = 1 << 52;
+        assert!(uploads.start(huge).is_err());
+        assert!(uploads.list().is_empty());
+    }
+
+    #[test]
+    fn saves_uploads_in_batches() {
+        let dir = test_dir("upload");
+        let target = dir.join("file");
+        let data: Vec<u8> = (0..100).collect();
+
+        let mut uploads = Uploads::new(&dir, 10, 1000, Duration::from_secs(60));
+        let upload = uploads.start(request(&target, &data, 1)).unwrap();
+        assert_eq!(upload.num_chunks, 100);
+
+        for index in 0..70 {
+            uploads.chunk(&upload.id, index, &data[index as usize..][..1], None).unwrap();
+        }
+        let saved = Uploads::new(&dir, 10, 1000, Duration::from_secs(60)).status(&upload.id).unwrap();
+        assert_eq!(saved.received, vec![(0, 64)]);
+
+        assert!(uploads.chunk(&upload.id, 70, b"xx", None).is_err());
+        assert!(uploads.chunk(&upload.id, 100, b"x", None).is_err());
+        assert!(uploads.finish(&upload.id).is_err());
+        for index in (70..100).rev() {
+            uploads.chunk(&upload.id, index, &data[index as usize..][..1], None).unwrap();
+        }
+        let saved = Uploads::new(&dir, 10, 1000, Duration::from_secs(60)).status(&upload.id).unwrap();
+        assert_eq!(saved.received, vec![(0, 100)]);
+
+        uploads.finish(&upload.id).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+    }
+}
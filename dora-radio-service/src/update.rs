@@ -0,0 +1,673 @@
+// Software updates of apps and services
+//
+// An update package is a gzipped tar archive of everything one version of a target
+// needs, uploaded to the OBC with the chunked upload beforehand.  Installing it takes
+// these steps:
+//
+// 1. The package is copied to a staging file only this service can write, and the
+//    copy's SHA-256 is checked against an Ed25519 signature from the ground, made
+//    with the update signing key.  The signed message names the target and version
+//    as well as the hash, so a signed package can't be installed under another name
+//    or version:
+//
+//        dora-update\n<target>\n<version>\n<sha256 of the package in hex>\n
+//
+// 2. The verified copy is unpacked to <install_dir>/<target>/<version>.
+// 3. The target's symlink is switched to the new version directory.
+// 4. The target is restarted, and after a delay its health check is run.
+// 5. If the health check fails, the symlink is switched back to the previous version
+//    and the target is restarted again.
+//
+// Steps 4 and 5 run on a thread of their own, and the ground follows them with
+// updateStatus.  The state of the last update of each target is saved next to its
+// versions.  If the radio service updates itself, restarting it ends the thread, so
+// an update still being checked when the service starts is checked by the new
+// version (without another restart).
+//
+// Only the last few versions of each target are kept, and never the one in use or
+// the one before it.
+
+use crate::checksum::{self, from_hex};
+use crate::command;
+use crate::config::{UpdateConfig, UpdateTarget};
+use crate::transfer::{now, save_manifest};
+use ed25519_dalek::{PublicKey, Signature};
+use flate2::read::GzDecoder;
+use log::*;
+use nix::unistd::{geteuid, sync};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::Archive;
+
+// Longest version string accepted
+const MAX_VERSION_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum UpdateState {
+    // The new version has been started and is waiting for its health check
+    Checking,
+    // The new version passed its health check
+    Active,
+    // The previous version was put back, after a failed health check or on request
+    RolledBack,
+    // The new version failed its health check and there was nothing to roll back to
+    Failed,
+}
+
+// The last update of a target, as saved in its manifest
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Update {
+    pub target: String,
+    pub version: String,
+    // Version running before the update, if any
+    pub previous: Option<String>,
+    // SHA-256 of the package
+    pub hash: String,
+    pub state: UpdateState,
+    pub message: String,
+    pub started: u64,
+    pub finished: Option<u64>,
+}
+
+// What is installed of one target
+pub struct TargetStatus {
+    pub target: String,
+    // Version the symlink points to
+    pub current: Option<String>,
+    // Every installed version, oldest first
+    pub versions: Vec<String>,
+    pub last_update: Option<Update>,
+}
+
+pub struct Updates {
+    config: UpdateConfig,
+    key: Option<PublicKey>,
+}
+
+impl Updates {
+
+    // Load the signing key, and carry on checking any update a restart interrupted.
+    // A missing or unusable key only disables updates, so it can't stop the radio
+    // service from starting.
+    pub fn new(config: &UpdateConfig) -> Updates {
+        let key = if config.enabled {
+            match load_key(&config.public_key_file) {
+                Ok(key) => Some(key),
+                Err(err) => {
+                    error!("Software updates are disabled: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let updates = Updates {
+            config: config.clone(),
+            key,
+        };
+
+        for target in &config.targets {
+            if let Some(update) = updates.last_update(&target.name) {
+                if update.state == UpdateState::Checking {
+                    info!("Resuming health check of {} {}", update.target, update.version);
+                    updates.spawn_check(target, update, false);
+                }
+            }
+        }
+
+        updates
+    }
+
+    fn target(&self, name: &str) -> Result<&UpdateTarget, String> {
+        self.config
+            .targets
+            .iter()
+            .find(|target| target.name == name)
+            .ok_or_else(|| format!("Unknown update target {}", name))
+    }
+
+    fn target_dir(&self, name: &str) -> PathBuf {
+        self.config.install_dir.join(name)
+    }
+
+    fn last_update(&self, name: &str) -> Option<Update> {
+        fs::read(self.target_dir(name).join("update.json"))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+    }
+
+    // Verify, install and switch to a new version of a target, then restart and check
+    // it in the background
+    pub fn install(&mut self, target: &str, version: &str, package: &Path, signature: &str) -> Result<Update, String> {
+        if !self.config.enabled {
+            return Err("Software updates are disabled".to_owned());
+        }
+        let key = self.key.ok_or_else(|| "No update signing key loaded".to_owned())?;
+        let target = self.target(target)?.clone();
+        check_version(version)?;
+
+        if let Some(last) = self.last_update(&target.name) {
+            if last.state == UpdateState::Checking {
+                return Err(format!("An update of {} is still being checked", target.name));
+            }
+        }
+
+        let dir = self.target_dir(&target.name);
+        let version_dir = dir.join(version);
+        if fs::symlink_metadata(&version_dir).is_ok() {
+            return Err(format!("Version {} of {} is already installed", version, target.name));
+        }
+
+        // Verify and unpack the same private copy, so the package can't be swapped
+        // between the two
+        fs::create_dir_all(&dir).map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+        let staged = dir.join(".package.tmp");
+        let result = stage(package, &staged).and_then(|(file, hash)| {
+            verify(&key, signed_message(&target.name, version, &hash).as_bytes(), signature)?;
+            unpack(file, &version_dir)?;
+            Ok(hash)
+        });
+        let _ = fs::remove_file(&staged);
+        let hash = result?;
+        let previous = current_version(&target.link);
+        if let Err(err) = switch(&target.link, &version_dir) {
+            let _ = fs::remove_dir_all(&version_dir);
+            return Err(err);
+        }
+
+        let update = Update {
+            target: target.name.clone(),
+            version: version.to_owned(),
+            previous,
+            hash,
+            state: UpdateState::Checking,
+            message: "Waiting for health check".to_owned(),
+            started: now(),
+            finished: None,
+        };
+        save_manifest(&dir.join("update.json"), &update)?;
+        info!("Installed {} {} (previously {:?})", target.name, version, update.previous);
+
+        self.spawn_check(&target, update.clone(), true);
+        Ok(update)
+    }
+
+    // Switch a target back to the version before its last update and restart it
+    pub fn rollback(&mut self, target: &str) -> Result<Update, String> {
+        if !self.config.enabled {
+            return Err("Software updates are disabled".to_owned());
+        }
+        let target = self.target(target)?.clone();
+
+        let mut update = self
+            .last_update(&target.name)
+            .ok_or_else(|| format!("No update of {} to roll back", target.name))?;
+        match update.state {
+            UpdateState::Active => (),
+            UpdateState::Checking => return Err(format!("An update of {} is still being checked", target.name)),
+            _ => return Err(format!("The last update of {} has already been undone", target.name)),
+        }
+        let previous = update
+            .previous
+            .clone()
+            .ok_or_else(|| format!("No previous version of {} to roll back to", target.name))?;
+
+        let dir = self.target_dir(&target.name);
+        switch(&target.link, &dir.join(&previous))?;
+        update.state = UpdateState::RolledBack;
+        update.message = format!("Rolled back to {} by the ground", previous);
+        update.finished = Some(now());
+        save_manifest(&dir.join("update.json"), &update)?;
+        info!("Rolled {} back to {}", target.name, previous);
+
+        // Restarting can take a while, and ends this service if it is the target
+        let config = self.config.clone();
+        let _ = thread::Builder::new()
+            .name("update-restart".to_owned())
+            .spawn(move || {
+                if let Err(err) = run(&config, &target.restart) {
+                    error!("Failed to restart {}: {}", target.name, err);
+                }
+            });
+
+        Ok(update)
+    }
+
+    pub fn status(&self, target: &str) -> Result<TargetStatus, String> {
+        let target = self.target(target)?;
+        Ok(TargetStatus {
+            target: target.name.clone(),
+            current: current_version(&target.link),
+            versions: versions(&self.target_dir(&target.name)),
+            last_update: self.last_update(&target.name),
+        })
+    }
+
+    pub fn list(&self) -> Vec<TargetStatus> {
+        self.config
+            .targets
+            .iter()
+            .filter_map(|target| self.status(&target.name).ok())
+            .collect()
+    }
+
+    fn spawn_check(&self, target: &UpdateTarget, update: Update, restart: bool) {
+        let (config, target) = (self.config.clone(), target.clone());
+        let _ = thread::Builder::new()
+            .name("update-check".to_owned())
+            .spawn(move || check(&config, &target, update, restart));
+    }
+}
+
+// Restart a newly installed version, check its health and roll it back if it's sick
+fn check(config: &UpdateConfig, target: &UpdateTarget, mut update: Update, restart: bool) {
+    let dir = config.install_dir.join(&target.name);
+
+    if restart {
+        if let Err(err) = run(config, &target.restart) {
+            warn!("Failed to restart {}: {}", target.name, err);
+        }
+    }
+    thread::sleep(config.health_delay);
+
+    match run(config, &target.health_check) {
+        Ok(()) => {
+            info!("{} {} passed its health check", target.name, update.version);
+            update.state = UpdateState::Active;
+            update.message = "Passed health check".to_owned();
+        }
+        Err(err) => match update.previous.clone() {
+            Some(previous) => {
+                warn!("{} {} failed its health check ({}), rolling back to {}", target.name, update.version, err, previous);
+                update.state = UpdateState::RolledBack;
+                update.message = format!("Health check failed ({}), rolled back to {}", err, previous);
+                if let Err(err) = switch(&target.link, &dir.join(&previous)) {
+                    error!("Failed to roll {} back: {}", target.name, err);
+                    update.state = UpdateState::Failed;
+                    update.message = format!("Health check failed, and rolling back failed too: {}", err);
+                }
+            }
+            None => {
+                warn!("{} {} failed its health check ({}) and there is no version to roll back to", target.name, update.version, err);
+                update.state = UpdateState::Failed;
+                update.message = format!("Health check failed ({}), no previous version to roll back to", err);
+            }
+        },
+    }
+
+    update.finished = Some(now());
+    // Saved before restarting, in case the restart ends this service
+    if let Err(err) = save_manifest(&dir.join("update.json"), &update) {
+        error!("Failed to save update state of {}: {}", target.name, err);
+    }
+
+    match update.state {
+        UpdateState::Active => prune(config, target, &update),
+        UpdateState::RolledBack => {
+            if let Err(err) = run(config, &target.restart) {
+                error!("Failed to restart {} after rolling back: {}", target.name, err);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Run one of a target's commands, succeeding only if it exits with 0
+fn run(config: &UpdateConfig, command_line: &[String]) -> Result<(), String> {
+    let (path, args) = command_line
+        .split_first()
+        .ok_or_else(|| "Empty command line".to_owned())?;
+    let status = command::execute_service(path, args, config.health_timeout)?;
+
+    match status {
+        ref status if status.timed_out => Err(format!("{} timed out", path)),
+        ref status if status.exit_code == Some(0) => Ok(()),
+        ref status => match (status.exit_code, status.signal) {
+            (Some(code), _) => Err(format!("{} exited with code {}", path, code)),
+            (_, Some(signal)) => Err(format!("{} was killed by signal {}", path, signal)),
+            _ => Err(format!("{} failed", path)),
+        },
+    }
+}
+
+// Remove the oldest versions beyond the number to keep, sparing the current and
+// previous ones
+fn prune(config: &UpdateConfig, target: &UpdateTarget, update: &Update) {
+    let dir = config.install_dir.join(&target.name);
+    let versions = versions(&dir);
+    let mut excess = versions.len().saturating_sub(config.keep_versions);
+
+    for version in &versions {
+        if excess == 0 {
+            break;
+        }
+        if *version == update.version || Some(version) == update.previous.as_ref() {
+            continue;
+        }
+        match fs::remove_dir_all(dir.join(version)) {
+            Ok(()) => info!("Removed old version {} of {}", version, target.name),
+            Err(err) => warn!("Failed to remove old version {} of {}: {}", version, target.name, err),
+        }
+        excess -= 1;
+    }
+}
+
+// Installed versions of a target, oldest first
+fn versions(dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut versions: Vec<(SystemTime, String)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        // Skips unfinished unpacks
+        .filter(|name| !name.starts_with('.'))
+        .map(|name| {
+            let installed = fs::metadata(dir.join(&name))
+                .and_then(|meta| meta.modified())
+                .unwrap_or(UNIX_EPOCH);
+            (installed, name)
+        })
+        .collect();
+
+    versions.sort();
+    versions.into_iter().map(|(_, name)| name).collect()
+}
+
+// Version the symlink points to
+fn current_version(link: &Path) -> Option<String> {
+    fs::read_link(link)
+        .ok()
+        .and_then(|dir| dir.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+// Point the symlink at a version directory.  The new link is made next to the old
+// one and renamed over it, so the link always points at one version or the other.
+fn switch(link: &Path, version_dir: &Path) -> Result<(), String> {
+    let name = link
+        .file_name()
+        .ok_or_else(|| format!("Invalid link path {}", link.display()))?;
+    let tmp = link.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent).map_err(|err| format!("Failed to create {}: {}", parent.display(), err))?;
+    }
+
+    let _ = fs::remove_file(&tmp);
+    symlink(version_dir, &tmp)
+        .and_then(|_| fs::rename(&tmp, link))
+        .map_err(|err| {
+            let _ = fs::remove_file(&tmp);
+            format!("Failed to switch {} to {}: {}", link.display(), version_dir.display(), err)
+        })
+}
+
+// Copy a package to a private staging file.  Returns the open copy, rewound, and its
+// SHA-256.
+fn stage(package: &Path, staged: &Path) -> Result<(File, String), String> {
+    let mut source = File::open(package).map_err(|_| "Failed to read update package".to_owned())?;
+    let _ = fs::remove_file(staged);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(staged)
+        .map_err(|err| format!("Failed to create {}: {}", staged.display(), err))?;
+
+    io::copy(&mut source, &mut file)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .map_err(|_| "Failed to copy update package".to_owned())?;
+    let hash = checksum::sha256_reader(&mut file).map_err(|_| "Failed to read update package".to_owned())?;
+    file.seek(SeekFrom::Start(0))
+        .map_err(|_| "Failed to read update package".to_owned())?;
+    Ok((file, hash))
+}
+
+// Unpack a package into a version directory.  It goes into a hidden directory first
+// and is only renamed into place once complete.
+fn unpack(package: File, version_dir: &Path) -> Result<(), String> {
+    let name = version_dir
+        .file_name()
+        .ok_or_else(|| format!("Invalid version directory {}", version_dir.display()))?;
+    let partial = version_dir.with_file_name(format!(".{}.partial", name.to_string_lossy()));
+    let _ = fs::remove_dir_all(&partial);
+
+    let result = fs::create_dir_all(&partial)
+        .map_err(|err| format!("Failed to create {}: {}", partial.display(), err))
+        .and_then(|_| {
+            let mut archive = Archive::new(GzDecoder::new(package));
+            archive.set_preserve_permissions(true);
+            // Entries with absolute paths or '..' are refused by unpack
+            archive
+                .unpack(&partial)
+                .map_err(|err| format!("Failed to unpack update package: {}", err))
+        })
+        .and_then(|_| {
+            // Make sure the files are on disk before anything points at them
+            sync();
+            fs::rename(&partial, version_dir)
+                .map_err(|err| format!("Failed to move {} into place: {}", version_dir.display(), err))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&partial);
+    }
+    result
+}
+
+fn check_version(version: &str) -> Result<(), String> {
+    let valid = !version.is_empty()
+        && version.len() <= MAX_VERSION_LEN
+        && !version.starts_with('.')
+        && version != "current"
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' || c == '+');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid version '{}'", version))
+    }
+}
+
+// What the ground signs for an update (see step 1 above)
+fn signed_message(target: &str, version: &str, hash: &str) -> String {
+    format!("dora-update\n{}\n{}\n{}\n", target, version, hash)
+}
+
+fn verify(key: &PublicKey, message: &[u8], signature: &str) -> Result<(), String> {
+    let signature = from_hex(signature)
+        .and_then(|bytes| Signature::try_from(&bytes[..]).ok())
+        .ok_or_else(|| "Signature must be 64 bytes in hex".to_owned())?;
+
+    key.verify_strict(message, &signature)
+        .map_err(|_| "Update package signature is not valid".to_owned())
+}
+
+// Load the update signing key: 32 bytes in hex.  Anyone who can change the key can
+// install anything, so it must not be writable by anyone but root or the service user.
+fn load_key(path: &Path) -> Result<PublicKey, String> {
+    let meta = fs::metadata(path)
+        .map_err(|err| format!("Failed to read update key file {}: {}", path.display(), err))?;
+    if meta.uid() != 0 && meta.uid() != geteuid().as_raw() {
+        return Err(format!("Update key file {} must be owned by root or the service user", path.display()));
+    }
+    if meta.mode() & 0o022 != 0 {
+        return Err(format!(
+            "Update key file {} must not be writable by group or others (mode {:o})",
+            path.display(),
+            meta.mode() & 0o777
+        ));
+    }
+
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read update key file {}: {}", path.display(), err))?;
+    from_hex(text.trim())
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| format!("Update key file {} must hold a 32-byte Ed25519 public key in hex", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use nix::sys::stat::utimes;
+    use nix::sys::time::{TimeVal, TimeValLike};
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dora-update-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn keypair() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn sign(keypair: &Keypair, target: &str, version: &str, hash: &str) -> String {
+        checksum::to_hex(&keypair.sign(signed_message(target, version, hash).as_bytes()).to_bytes())
+    }
+
+    // An installed version directory, last changed `age` seconds after the epoch
+    fn install_version(dir: &Path, version: &str, age: i64) {
+        let version_dir = dir.join(version);
+        fs::create_dir_all(&version_dir).unwrap();
+        utimes(&version_dir, &TimeVal::seconds(age), &TimeVal::seconds(age)).unwrap();
+    }
+
+    #[test]
+    fn checks_versions() {
+        assert!(check_version("1.2.3").is_ok());
+        assert!(check_version("2026.10-rc1+build_7").is_ok());
+        assert!(check_version("").is_err());
+        assert!(check_version(".hidden").is_err());
+        assert!(check_version("..").is_err());
+        assert!(check_version("current").is_err());
+        assert!(check_version("1/2").is_err());
+        assert!(check_version("1 2").is_err());
+        assert!(check_version(&"1".repeat(MAX_VERSION_LEN)).is_ok());
+        assert!(check_version(&"1".repeat(MAX_VERSION_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn verifies_the_signed_target_and_version() {
+        let keypair = keypair();
+        let hash = checksum::sha256(b"package");
+        let signature = sign(&keypair, "app", "1.0", &hash);
+
+        assert!(verify(&keypair.public, signed_message("app", "1.0", &hash).as_bytes(), &signature).is_ok());
+        assert!(verify(&keypair.public, signed_message("other", "1.0", &hash).as_bytes(), &signature).is_err());
+        assert!(verify(&keypair.public, signed_message("app", "1.1", &hash).as_bytes(), &signature).is_err());
+        let other_hash = checksum::sha256(b"other package");
+        assert!(verify(&keypair.public, signed_message("app", "1.0", &other_hash).as_bytes(), &signature).is_err());
+        assert!(verify(&keypair.public, signed_message("app", "1.0", &hash).as_bytes(), &signature[0..64]).is_err());
+        assert!(verify(&keypair.public, signed_message("app", "1.0", &hash).as_bytes(), "not hex").is_err());
+    }
+
+    #[test]
+    fn stages_a_private_copy() {
+        let dir = test_dir("stage");
+        fs::write(dir.join("package"), b"package").unwrap();
+
+        let (file, hash) = stage(&dir.join("package"), &dir.join(".package.tmp")).unwrap();
+        assert_eq!(hash, checksum::sha256(b"package"));
+        assert_eq!(file.metadata().unwrap().mode() & 0o777, 0o600);
+
+        // What is unpacked is the copy, whatever happens to the package afterwards
+        fs::write(dir.join("package"), b"changed").unwrap();
+        assert_eq!(checksum::sha256_reader(file).unwrap(), hash);
+
+        assert!(stage(&dir.join("missing"), &dir.join(".package.tmp")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn switches_links() {
+        let dir = test_dir("switch");
+        let link = dir.join("bin").join("current");
+        install_version(&dir, "1.0", 1);
+        install_version(&dir, "1.1", 2);
+
+        assert_eq!(current_version(&link), None);
+        switch(&link, &dir.join("1.0")).unwrap();
+        assert_eq!(current_version(&link), Some("1.0".to_owned()));
+        switch(&link, &dir.join("1.1")).unwrap();
+        assert_eq!(current_version(&link), Some("1.1".to_owned()));
+        assert_eq!(fs::read_link(&link).unwrap(), dir.join("1.1"));
+        assert!(fs::symlink_metadata(dir.join("bin").join(".current.tmp")).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lists_versions_oldest_first() {
+        let dir = test_dir("versions");
+        assert!(versions(&dir.join("missing")).is_empty());
+
+        install_version(&dir, "9", 200);
+        install_version(&dir, "10", 300);
+        install_version(&dir, "8", 100);
+        install_version(&dir, ".11.partial", 400);
+        fs::write(dir.join("update.json"), b"{}").unwrap();
+
+        assert_eq!(versions(&dir), vec!["8", "9", "10"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_old_versions_but_keeps_current_and_previous() {
+        let root = test_dir("prune");
+        let config = UpdateConfig {
+            enabled: true,
+            public_key_file: root.join("update.pub"),
+            install_dir: root.clone(),
+            keep_versions: 2,
+            health_delay: Duration::from_secs(0),
+            health_timeout: Duration::from_secs(1),
+            targets: vec![],
+        };
+        let target = UpdateTarget {
+            name: "app".to_owned(),
+            link: root.join("current"),
+            restart: vec![],
+            health_check: vec![],
+        };
+        let dir = root.join("app");
+        for (age, version) in ["1", "2", "3", "4"].iter().enumerate() {
+            install_version(&dir, version, age as i64 + 1);
+        }
+
+        // The previous version is the oldest, after a rollback and a new update
+        let update = Update {
+            target: "app".to_owned(),
+            version: "4".to_owned(),
+            previous: Some("1".to_owned()),
+            hash: String::new(),
+            state: UpdateState::Active,
+            message: String::new(),
+            started: 0,
+            finished: None,
+        };
+        prune(&config, &target, &update);
+        assert_eq!(versions(&dir), vec!["1", "4"]);
+
+        // Nothing more to remove
+        prune(&config, &target, &update);
+        assert_eq!(versions(&dir), vec!["1", "4"]);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,204 @@
+// Ground keep-alive watchdog
+//
+// If the ground stops talking to us for long enough, something is wrong on board that
+// a reboot might fix: a wedged radio driver, a leaked resource, a service stuck in a
+// bad state.  The ground sends a keepAlive mutation on every pass, which pushes the
+// deadline out by the configured timeout.  If the deadline passes without one, the
+// watchdog logs why and reboots the OBC.
+//
+// The countdown runs on the monotonic clock, since the OBC has no battery-backed RTC
+// and boots with a stale wall clock that jumps once the time is set.  The seconds
+// remaining are saved to a small state file whenever a keep-alive arrives and then
+// once an hour, so that restarting the service (or the OBC) doesn't quietly reset the
+// countdown.  A restart can add up to an hour to the countdown, which is little next
+// to a timeout of days and saves the flash a write every minute.  Before rebooting, the
+// watchdog saves a fresh countdown and appends the reason to a reboot log, so the
+// ground can find out what happened after the fact and the OBC doesn't go into a
+// reboot loop.
+
+use crate::config::WatchdogConfig;
+use crate::transfer::now;
+use crate::ServiceResult;
+use log::*;
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::unistd;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest the watchdog thread sleeps between checks, and so how often the countdown
+// is saved
+const SAVE_INTERVAL: u64 = 60 * 60;
+// How long to wait for the reboot command to take us down before rebooting directly
+const REBOOT_GRACE: u64 = 60;
+
+// What is saved in the state file
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    // Seconds left before the OBC is rebooted
+    remaining: u64,
+}
+
+pub struct Watchdog {
+    config: WatchdogConfig,
+    deadline: Mutex<Instant>,
+}
+
+impl Watchdog {
+
+    // Carry on the saved countdown, or start a new one if there isn't one, and start
+    // the watchdog thread
+    pub fn start(config: &WatchdogConfig) -> ServiceResult<Arc<Watchdog>> {
+        for path in &[&config.state_file, &config.reboot_log] {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let saved = fs::read(&config.state_file)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<State>(&data).ok());
+
+        let remaining = match saved {
+            Some(state) => {
+                // Never more than one timeout, in case the file was left by a longer one
+                let remaining = state.remaining.min(config.timeout.as_secs());
+                info!("Keep-alive countdown restored: {}s remaining", remaining);
+                remaining
+            }
+            None => {
+                info!("No saved keep-alive countdown, starting a new one");
+                config.timeout.as_secs()
+            }
+        };
+
+        let watchdog = Arc::new(Watchdog {
+            config: config.clone(),
+            deadline: Mutex::new(Instant::now()),
+        });
+        let _ = watchdog.set_remaining(remaining);
+
+        let thread_watchdog = watchdog.clone();
+        thread::Builder::new()
+            .name("keep-alive".to_owned())
+            .spawn(move || thread_watchdog.run())?;
+
+        Ok(watchdog)
+    }
+
+    // Push the deadline out by a full timeout.  Returns the seconds remaining.
+    pub fn keep_alive(&self) -> Result<u64, String> {
+        self.set_remaining(self.config.timeout.as_secs())?;
+        info!("Keep-alive received, next deadline in {}s", self.config.timeout.as_secs());
+        Ok(self.config.timeout.as_secs())
+    }
+
+    // Seconds until the OBC is rebooted
+    pub fn remaining(&self) -> u64 {
+        let deadline = *self.deadline.lock().unwrap_or_else(|err| err.into_inner());
+        remaining_until(deadline)
+    }
+
+    fn set_remaining(&self, remaining: u64) -> Result<(), String> {
+        let mut deadline = self.deadline.lock().unwrap_or_else(|err| err.into_inner());
+        *deadline = Instant::now() + Duration::from_secs(remaining);
+        self.save(remaining)
+    }
+
+    // Save the countdown as it stands.  The lock is held so this can't overwrite a
+    // keep-alive saved in the meantime.
+    fn save_remaining(&self) {
+        let deadline = self.deadline.lock().unwrap_or_else(|err| err.into_inner());
+        let _ = self.save(remaining_until(*deadline));
+    }
+
+    // Write the seconds remaining to the state file, through a temporary file so a
+    // reboot mid-write can't corrupt it
+    fn save(&self, remaining: u64) -> Result<(), String> {
+        let path = &self.config.state_file;
+        let tmp = path.with_extension("tmp");
+        let data = serde_json::to_vec(&State { remaining })
+            .map_err(|_| "Failed to encode keep-alive state".to_owned())?;
+
+        File::create(&tmp)
+            .and_then(|mut f| f.write_all(&data).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|err| {
+                error!("Failed to save keep-alive state to {}: {}", path.display(), err);
+                "Failed to save keep-alive state".to_owned()
+            })
+    }
+
+    fn run(&self) {
+        loop {
+            let remaining = self.remaining();
+            if remaining == 0 {
+                self.expire();
+            } else {
+                self.save_remaining();
+            }
+
+            thread::sleep(Duration::from_secs(remaining.min(SAVE_INTERVAL).max(1)));
+        }
+    }
+
+    // The deadline has passed: record why, then reboot
+    fn expire(&self) {
+        let reason = format!(
+            "No keep-alive received from the ground in {}s, rebooting",
+            self.config.timeout.as_secs()
+        );
+        error!("{}", reason);
+
+        // Start a new countdown first, so the rebooted OBC gets a full timeout to hear
+        // from the ground rather than rebooting again straight away
+        let _ = self.set_remaining(self.config.timeout.as_secs());
+        self.log_reboot(&reason);
+
+        // Prefer the configured command so init can shut services down cleanly.  If that
+        // fails, sync and reboot directly.
+        match Command::new(&self.config.reboot_command).status() {
+            Ok(status) if status.success() => {
+                // Give init time to take us down with everything else
+                thread::sleep(Duration::from_secs(REBOOT_GRACE));
+                warn!("Still running after '{}', forcing a reboot", self.config.reboot_command);
+            }
+            Ok(status) => error!("'{}' failed with {}", self.config.reboot_command, status),
+            Err(err) => error!("Failed to run '{}': {}", self.config.reboot_command, err),
+        }
+
+        unistd::sync();
+        if let Err(err) = reboot(RebootMode::RB_AUTOBOOT).map(|_| ()) {
+            error!("Failed to reboot: {}", err);
+        }
+    }
+
+    // Append the reboot reason to the reboot log.  The log lives on persistent storage,
+    // unlike the system log, so it survives the reboot.
+    fn log_reboot(&self, reason: &str) {
+        let path = &self.config.reboot_log;
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| {
+                writeln!(f, "{} {}", now(), reason)?;
+                f.sync_all()
+            });
+
+        if let Err(err) = result {
+            error!("Failed to write reboot log {}: {}", path.display(), err);
+        }
+    }
+}
+
+// Whole seconds left until a deadline, rounded up so a countdown that hasn't quite run
+// out isn't reported or saved as 0
+fn remaining_until(deadline: Instant) -> u64 {
+    let left = deadline.saturating_duration_since(Instant::now());
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}